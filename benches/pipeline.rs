@@ -0,0 +1,166 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use lz4::block::{compress_to_buffer, decompress_to_buffer};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use iracing_teleport::protocol::{Receiver, Sender};
+use iracing_teleport::telemetry::MAX_TELEMETRY_SIZE;
+
+// Size of the updates, in the range of a telemetry mapping without its session info
+const UPDATE_SIZE: usize = 512 * 1024;
+
+// Part of the compressible update that changes, like the variable buffer being written
+const ACTIVE_SIZE: usize = 16 * 1024;
+
+// Updates received before checking that receiving more doesn't allocate
+const WARM_UP_UPDATES: usize = 2;
+
+/// Counts the allocations, so the benchmarks can check the receive path makes none once
+/// it is running
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Fills `data` with floats the way telemetry variables look, with random mantissas but
+/// similar exponents, so LZ4 gets little out of it
+fn fill_floats(data: &mut [u8], rng: &mut StdRng) {
+    for value in data.chunks_exact_mut(4) {
+        value.copy_from_slice(&rng.random_range(0.0f32..100.0).to_le_bytes());
+    }
+}
+
+/// Updates to run through the pipeline: every variable changing, and a mostly zeroed
+/// mapping where only one buffer changes like in the garage
+fn updates() -> [(&'static str, Vec<u8>); 2] {
+    let mut rng = StdRng::seed_from_u64(60);
+
+    let mut noisy = vec![0u8; UPDATE_SIZE];
+    fill_floats(&mut noisy, &mut rng);
+
+    let mut sparse = vec![0u8; UPDATE_SIZE];
+    fill_floats(&mut sparse[..ACTIVE_SIZE], &mut rng);
+
+    [("noisy", noisy), ("sparse", sparse)]
+}
+
+/// Compresses `data` the way the source does, with the size prepended
+fn compress(data: &[u8], buffer: &mut [u8]) -> usize {
+    compress_to_buffer(data, None, true, buffer).expect("compress")
+}
+
+/// Compresses and fragments an update twice, returning the datagrams of two consecutive
+/// sequences
+fn datagrams(data: &[u8]) -> [Vec<Vec<u8>>; 2] {
+    let mut buffer = vec![0u8; UPDATE_SIZE * 2];
+    let len = compress(data, &mut buffer);
+
+    let mut sender = Sender::new(0);
+    [(); 2].map(|()| {
+        let mut datagrams = Vec::new();
+        sender
+            .send(&buffer[..len], 0, 0, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .expect("send");
+        datagrams
+    })
+}
+
+fn bench_compress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compress");
+    group.throughput(Throughput::Bytes(UPDATE_SIZE as u64));
+    let mut buffer = vec![0u8; UPDATE_SIZE * 2];
+
+    for (name, data) in updates() {
+        group.bench_function(name, |b| b.iter(|| compress(black_box(&data), &mut buffer)));
+    }
+    group.finish();
+}
+
+fn bench_send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compress_and_send");
+    group.throughput(Throughput::Bytes(UPDATE_SIZE as u64));
+    let mut buffer = vec![0u8; UPDATE_SIZE * 2];
+    let mut sender = Sender::new(0);
+
+    for (name, data) in updates() {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let len = compress(black_box(&data), &mut buffer);
+                sender
+                    .send(&buffer[..len], 0, 0, |datagram| {
+                        black_box(datagram);
+                        Ok(())
+                    })
+                    .expect("send")
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Reassembles and decompresses one of the sequences into `output`. Alternates between
+/// them, the receiver drops a sequence it just completed.
+fn receive(
+    receiver: &mut Receiver,
+    sequences: &[Vec<Vec<u8>>; 2],
+    next: &mut usize,
+    output: &mut [u8],
+) {
+    *next = 1 - *next;
+    for datagram in &sequences[*next] {
+        if let (Some(frame), _) = receiver.process_datagram(datagram) {
+            decompress_to_buffer(frame.payload, None, output).expect("decompress");
+        }
+    }
+}
+
+fn bench_receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("receive_and_decompress");
+    group.throughput(Throughput::Bytes(UPDATE_SIZE as u64));
+    let mut output = vec![0u8; UPDATE_SIZE];
+
+    for (name, data) in updates() {
+        let sequences = datagrams(&data);
+        let mut receiver = Receiver::new(MAX_TELEMETRY_SIZE);
+        let mut next = 0;
+
+        // Once the buffers are sized, a stream of same-size updates allocates nothing
+        for _ in 0..WARM_UP_UPDATES {
+            receive(&mut receiver, &sequences, &mut next, &mut output);
+        }
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for _ in 0..100 {
+            receive(&mut receiver, &sequences, &mut next, &mut output);
+        }
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert_eq!(allocations, 0, "receiving {} updates allocated", name);
+
+        group.bench_function(name, |b| {
+            b.iter(|| receive(&mut receiver, &sequences, &mut next, &mut output))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_compress, bench_send, bench_receive);
+criterion_main!(benches);
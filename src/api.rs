@@ -0,0 +1,133 @@
+use serde::Serialize;
+use serde_json::Value as Json;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::http::{HttpServer, Response};
+
+/// Counters of the last stats interval, as --api-addr reports them
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Interval {
+    pub rate: f64,
+    pub bandwidth_mbps: f64,
+    pub loss_percent: f64,
+    pub avg_latency_us: f64,
+}
+
+/// What `/status` reports, besides what is worked out per request
+#[derive(Serialize)]
+struct Status {
+    role: &'static str,
+    updates: u64,
+    lost: u64,
+    interval: Interval,
+    config: Json,
+    #[serde(skip)]
+    last_update: Option<Instant>,
+}
+
+/// Serves the state of the running instance as JSON on --api-addr, for dashboards that
+/// poll it: `GET /status` returns the role, whether updates are coming in, the age of the
+/// last one, the counters of the last stats interval and the configuration
+pub struct StatusApi {
+    status: Arc<Mutex<Status>>,
+    // Answers until dropped along with the API
+    _server: HttpServer,
+}
+
+impl StatusApi {
+    /// Starts serving, reporting the instance as connected while the last update is at
+    /// most `window` old
+    pub fn start<C: Serialize>(
+        addr: &str,
+        role: &'static str,
+        config: &C,
+        window: Duration,
+    ) -> io::Result<Self> {
+        let status = Arc::new(Mutex::new(Status {
+            role,
+            updates: 0,
+            lost: 0,
+            interval: Interval::default(),
+            config: serde_json::to_value(config).map_err(io::Error::other)?,
+            last_update: None,
+        }));
+        let shared = status.clone();
+        let server = HttpServer::start(addr, "status API", move |path| match path {
+            "/status" => Response::json(render(&shared.lock().unwrap(), window)),
+            _ => Response::text("404 Not Found", "Try /status\n".to_string()),
+        })?;
+
+        Ok(Self {
+            status,
+            _server: server,
+        })
+    }
+
+    /// Records an update, for the age of the last one
+    pub fn add_update(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.updates += 1;
+        status.last_update = Some(Instant::now());
+    }
+
+    /// Records the counters of a finished stats interval
+    pub fn set_interval(&self, interval: Interval, lost: u32) {
+        let mut status = self.status.lock().unwrap();
+        status.interval = interval;
+        status.lost += lost as u64;
+    }
+}
+
+/// Renders the status along with the age of the last update
+fn render(status: &Status, window: Duration) -> String {
+    let age = status.last_update.map(|last| last.elapsed());
+    let mut json = serde_json::to_value(status).unwrap_or_default();
+    json["connected"] = age.is_some_and(|age| age <= window).into();
+    json["last_update_age_ms"] = age.map(|age| age.as_millis() as u64).into();
+    json.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http;
+
+    fn status(api: &StatusApi) -> Json {
+        let response = http::get(api._server.local_addr(), "/status");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_status_reports_the_counters() {
+        let config = serde_json::json!({ "bind": "0.0.0.0:5000" });
+        let api =
+            StatusApi::start("127.0.0.1:0", "target", &config, Duration::from_secs(10)).unwrap();
+        let json = status(&api);
+        assert_eq!(json["role"], "target");
+        assert_eq!(json["connected"], false);
+        assert_eq!(json["last_update_age_ms"], Json::Null);
+        assert_eq!(json["config"]["bind"], "0.0.0.0:5000");
+
+        api.add_update();
+        api.set_interval(
+            Interval {
+                rate: 60.0,
+                ..Interval::default()
+            },
+            2,
+        );
+        let json = status(&api);
+        assert_eq!(json["connected"], true);
+        assert_eq!(json["updates"], 1);
+        assert_eq!(json["lost"], 2);
+        assert_eq!(json["interval"]["rate"], 60.0);
+        assert!(json["last_update_age_ms"].as_u64().unwrap() < 10_000);
+
+        let response = http::get(api._server.local_addr(), "/");
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+}
@@ -0,0 +1,152 @@
+use log::info;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::config::BridgeConfig;
+use crate::net;
+use crate::protocol::{
+    self, FLAG_HEARTBEAT, FLAG_KEYFRAME_REQUEST, FLAG_LOSS_REPORT, FLAG_PROBE, FLAG_PROBE_REPLY,
+    LOSS_REPORT_SIZE, MAX_DATAGRAM_SIZE,
+};
+use crate::stats::StatisticsPrinter;
+use crate::target::setup_multicast;
+
+// How long the source locked onto may stay silent before another one is forwarded
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Receives datagrams on one network and forwards them byte for byte to another. Unlike
+/// the relay nothing is reassembled, so fragments and the source time arrive as sent.
+pub fn run(config: &BridgeConfig, shutdown: Receiver<()>) -> io::Result<()> {
+    let Some(out_target) = config.out_target.as_deref() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The bridge requires an --out-target address to forward to",
+        ));
+    };
+    let destination = net::resolve(out_target)?;
+
+    let in_socket = UdpSocket::bind(&config.in_bind).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to bind to {}: {}", config.in_bind, e),
+        )
+    })?;
+    info!("Bridge bound to {}", config.in_bind);
+
+    if !config.in_unicast {
+        setup_multicast(&in_socket, &config.in_bind, &config.in_group)?;
+    }
+
+    let out_socket = UdpSocket::bind(&config.out_bind).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to bind to {}: {}", config.out_bind, e),
+        )
+    })?;
+    info!("Forwarding to {}", destination);
+
+    // Keyframe requests, probes and loss reports from targets downstream are picked up
+    // between datagrams
+    out_socket
+        .set_nonblocking(true)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set non-blocking: {}", e)))?;
+
+    // Set a short timeout on UDP receive to stay responsive to shutdown
+    in_socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set socket timeout: {}", e)))?;
+
+    let mut rcv_buf = [0u8; MAX_DATAGRAM_SIZE];
+    let mut request_buf = [0u8; LOSS_REPORT_SIZE + 1];
+    let mut source_id = config.filter_id;
+    let mut upstream: Option<SocketAddr> = None;
+    let mut last_datagram = Instant::now();
+    let mut stats = StatisticsPrinter::new("bridge");
+
+    loop {
+        // Check for shutdown signal
+        if shutdown.try_recv().is_ok() {
+            return Ok(());
+        }
+
+        // Pass keyframe requests, probes, loss reports and hello replies on to the source, which only sees
+        // the bridge. Its probe replies come back with the other datagrams.
+        while let Ok((len, _)) = out_socket.recv_from(&mut request_buf) {
+            let request = &request_buf[..len];
+            let requested = protocol::parse_keyframe_request(request)
+                .or_else(|| protocol::parse_probe(request).map(|(id, _)| id))
+                .or_else(|| protocol::parse_loss_report(request).map(|report| report.source_id))
+                .or_else(|| protocol::parse_hello_reply(request).map(|reply| reply.source_id));
+            if let Some(upstream) = upstream
+                && requested.is_some()
+                && requested == source_id
+            {
+                let _ = in_socket.send_to(request, upstream);
+            }
+        }
+
+        if config.filter_id.is_none()
+            && let Some(id) = source_id
+            && last_datagram.elapsed() >= SOURCE_TIMEOUT
+        {
+            info!("Source {} went quiet, forwarding the next source seen", id);
+            source_id = None;
+            upstream = None;
+        }
+
+        match in_socket.recv_from(&mut rcv_buf) {
+            Ok((amt, from)) => {
+                let datagram = &rcv_buf[..amt];
+                let Some(info) = protocol::datagram_info(datagram) else {
+                    continue;
+                };
+
+                if info.flags & (FLAG_KEYFRAME_REQUEST | FLAG_PROBE | FLAG_LOSS_REPORT) != 0 {
+                    continue;
+                }
+
+                // Lock onto the first source seen so streams sharing a group don't mix
+                if source_id.is_none() {
+                    info!("Forwarding source {} from {}", info.source_id, from);
+                }
+                if *source_id.get_or_insert(info.source_id) != info.source_id {
+                    continue;
+                }
+                upstream = Some(from);
+                last_datagram = Instant::now();
+
+                // The protocol fragments every stream the same way, so the datagrams fit
+                // the other network as they are and IP handles any smaller MTU on the way
+                if out_socket.send_to(datagram, destination).is_err() {
+                    continue;
+                }
+
+                stats.add_bytes(amt);
+                if info.fragment == 0 && info.flags & (FLAG_HEARTBEAT | FLAG_PROBE_REPLY) == 0 {
+                    stats.add_fragments(info.fragments);
+                    stats.add_latency(info.source_time_us);
+                    stats.add_update();
+                }
+
+                if stats.should_print() {
+                    stats.print_and_reset();
+                }
+            }
+            // Interrupted when a termination signal arrives, the shutdown check comes next.
+            // Windows reports a keyframe request that found no source as a reset.
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::ConnectionReset => {}
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("UDP receive error: {}", e),
+                ));
+            }
+        }
+    }
+}
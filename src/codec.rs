@@ -0,0 +1,240 @@
+use clap::ValueEnum;
+use lz4::block::CompressionMode;
+use lz4::{ContentChecksum, Decoder, EncoderBuilder};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor, Read, Write};
+
+use crate::protocol::{CODEC_LZ4, CODEC_LZ4_FRAME};
+
+// Identifies an LZ4 frame, in front of every payload compressed with --lz4-format frame
+const FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+
+// Bit of the frame descriptor flags set when the content size follows the block
+// descriptor
+const FRAME_CONTENT_SIZE: u8 = 1 << 3;
+
+// Most a frame adds to the blocks in it: the header with the content size, the end mark
+// and the content checksum
+pub const FRAME_OVERHEAD: usize = 19 + 4 + 4;
+
+// How much --quality fast trades ratio for speed, LZ4 skips ahead faster the higher it is
+const FAST_ACCELERATION: i32 = 8;
+
+// Level of the high compression LZ4 compresses with at --quality max, its own default
+const MAX_LEVEL: i32 = 9;
+
+/// How the LZ4 compressed updates are laid out
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lz4Format {
+    /// Bare blocks preceded by their size, the least overhead
+    #[default]
+    Block,
+    /// Self-describing frames with a content checksum, as the `lz4` tool reads them
+    Frame,
+}
+
+impl Lz4Format {
+    /// Returns the codec a hello announces for this format
+    pub fn codec(self) -> u8 {
+        match self {
+            Lz4Format::Block => CODEC_LZ4,
+            Lz4Format::Frame => CODEC_LZ4_FRAME,
+        }
+    }
+}
+
+/// How hard the source compresses, trading CPU time for bandwidth. Targets decompress
+/// every quality the same way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    /// Least CPU time, a larger payload
+    Fast,
+    /// LZ4's default, fast with a good ratio
+    #[default]
+    Balanced,
+    /// Smallest payload with LZ4's high compression, several times the CPU time
+    Max,
+}
+
+impl Quality {
+    /// Returns the mode to compress blocks with
+    pub fn block_mode(self) -> CompressionMode {
+        match self {
+            Quality::Fast => CompressionMode::FAST(FAST_ACCELERATION),
+            Quality::Balanced => CompressionMode::DEFAULT,
+            Quality::Max => CompressionMode::HIGHCOMPRESSION(MAX_LEVEL),
+        }
+    }
+
+    /// Returns the level to compress frames with. Frames have no acceleration, so fast
+    /// compresses them like balanced.
+    pub fn frame_level(self) -> u32 {
+        match self {
+            Quality::Fast | Quality::Balanced => 0,
+            Quality::Max => MAX_LEVEL as u32,
+        }
+    }
+
+    /// Returns the acceleration to compress with a dictionary at. High compression
+    /// doesn't take a dictionary, so max compresses like balanced then.
+    pub fn acceleration(self) -> i32 {
+        match self {
+            Quality::Fast => FAST_ACCELERATION,
+            Quality::Balanced | Quality::Max => 1,
+        }
+    }
+
+    /// Describes what the quality comes down to in the given format, for the log
+    pub fn describe(self, format: Lz4Format, dictionary: bool) -> String {
+        let layout = match (format, dictionary) {
+            (Lz4Format::Block, false) => "LZ4 blocks",
+            (Lz4Format::Block, true) => "LZ4 blocks with a dictionary",
+            (Lz4Format::Frame, _) => "LZ4 frames",
+        };
+        let setting = match (format, dictionary) {
+            (Lz4Format::Block, false) => match self.block_mode() {
+                CompressionMode::HIGHCOMPRESSION(level) => {
+                    format!("high compression level {}", level)
+                }
+                CompressionMode::FAST(acceleration) => format!("acceleration {}", acceleration),
+                CompressionMode::DEFAULT => "acceleration 1".to_string(),
+            },
+            (Lz4Format::Block, true) => format!("acceleration {}", self.acceleration()),
+            (Lz4Format::Frame, _) => match self.frame_level() {
+                0 => "level 0, the fastest frames have".to_string(),
+                level => format!("high compression level {}", level),
+            },
+        };
+        format!("{} at {}", layout, setting)
+    }
+}
+
+/// Compresses `data` into a single LZ4 frame in `buffer` at `level` (0 for the fastest)
+/// and returns its length. The frame carries the size of `data` and its checksum.
+pub fn compress_frame(data: &[u8], buffer: &mut [u8], level: u32) -> io::Result<usize> {
+    let mut encoder = EncoderBuilder::new()
+        .level(level)
+        .checksum(ContentChecksum::ChecksumEnabled)
+        .content_size(data.len() as u64)
+        .build(Cursor::new(buffer))?;
+    encoder.write_all(data)?;
+    let (cursor, result) = encoder.finish();
+    result?;
+    Ok(cursor.position() as usize)
+}
+
+/// Decompresses an LZ4 frame into `target` and returns its length. Fails if the frame is
+/// cut short, doesn't match its checksum or doesn't fit.
+pub fn decompress_frame(payload: &[u8], target: &mut [u8]) -> io::Result<usize> {
+    let mut decoder = Decoder::new(payload)?;
+    let mut len = 0;
+    while len < target.len() {
+        match decoder.read(&mut target[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    // Reads on to the end of the frame, where the checksum is checked
+    if decoder.read(&mut [0u8; 1])? != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed frame is larger than the buffer",
+        ));
+    }
+    decoder
+        .finish()
+        .1
+        .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "LZ4 frame is incomplete"))?;
+    Ok(len)
+}
+
+/// Returns the content size in the header of an LZ4 frame, or `None` if it isn't a frame
+/// or leaves the size out
+pub fn frame_content_size(payload: &[u8]) -> Option<usize> {
+    if payload.get(..4)? != FRAME_MAGIC || payload.get(4)? & FRAME_CONTENT_SIZE == 0 {
+        return None;
+    }
+    let size = payload.get(6..14)?;
+    usize::try_from(u64::from_le_bytes(size.try_into().expect("8 bytes"))).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip_and_are_checked() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut buffer =
+            vec![0u8; lz4::block::compress_bound(data.len()).unwrap() + FRAME_OVERHEAD];
+        let len = compress_frame(&data, &mut buffer, 0).unwrap();
+        let frame = &buffer[..len];
+        assert_eq!(frame[..4], FRAME_MAGIC);
+        assert_eq!(frame_content_size(frame), Some(data.len()));
+
+        let mut target = vec![0u8; data.len()];
+        assert_eq!(decompress_frame(frame, &mut target).unwrap(), data.len());
+        assert_eq!(target, data);
+
+        // Too small a buffer, a cut off frame and a flipped bit are all refused
+        assert!(decompress_frame(frame, &mut target[..100]).is_err());
+        assert!(decompress_frame(&frame[..len - 2], &mut target).is_err());
+        let mut corrupt = frame.to_vec();
+        corrupt[len - 1] ^= 1;
+        assert!(decompress_frame(&corrupt, &mut target).is_err());
+
+        // Block payloads start with their size instead
+        let block = lz4::block::compress(&data, None, true).unwrap();
+        assert_eq!(frame_content_size(&block), None);
+
+        // The buffer source.rs sizes has room for data that doesn't compress at all
+        let noise: Vec<u8> = (0..70_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let mut buffer =
+            vec![0u8; lz4::block::compress_bound(noise.len()).unwrap() + FRAME_OVERHEAD];
+        assert!(compress_frame(&noise, &mut buffer, MAX_LEVEL as u32).is_ok());
+    }
+
+    #[test]
+    fn test_qualities_trade_speed_for_size() {
+        // Repetitive like telemetry, but with some variation for the modes to differ on
+        let data: Vec<u8> = (0..40_000u32)
+            .map(|i| ((i % 97) ^ (i / 1000) ^ (i.wrapping_mul(31) % 7)) as u8)
+            .collect();
+
+        let mut block_sizes = Vec::new();
+        for quality in [Quality::Fast, Quality::Balanced, Quality::Max] {
+            let block = lz4::block::compress(&data, Some(quality.block_mode()), true).unwrap();
+            assert_eq!(lz4::block::decompress(&block, None).unwrap(), data);
+            block_sizes.push(block.len());
+
+            let mut buffer =
+                vec![0u8; lz4::block::compress_bound(data.len()).unwrap() + FRAME_OVERHEAD];
+            let len = compress_frame(&data, &mut buffer, quality.frame_level()).unwrap();
+            let mut target = vec![0u8; data.len()];
+            assert_eq!(
+                decompress_frame(&buffer[..len], &mut target).unwrap(),
+                data.len()
+            );
+            assert_eq!(target, data);
+        }
+        assert!(block_sizes[0] >= block_sizes[1], "{:?}", block_sizes);
+        assert!(block_sizes[1] > block_sizes[2], "{:?}", block_sizes);
+
+        assert_eq!(
+            Quality::Fast.describe(Lz4Format::Block, false),
+            "LZ4 blocks at acceleration 8"
+        );
+        assert_eq!(
+            Quality::Max.describe(Lz4Format::Frame, false),
+            "LZ4 frames at high compression level 9"
+        );
+        assert_eq!(
+            Quality::Max.describe(Lz4Format::Block, true),
+            "LZ4 blocks with a dictionary at acceleration 1"
+        );
+    }
+}
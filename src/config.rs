@@ -0,0 +1,1681 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, Command};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+use crate::codec::{Lz4Format, Quality};
+use crate::dump::MAX_DUMP_FRAMES;
+use crate::irsdk::Region;
+use crate::net::Dscp;
+use crate::protocol::{MAX_PARITY_FRAGMENTS, MAX_PAYLOAD_SIZE, MAX_REASSEMBLY_DEPTH};
+use crate::scheduling::Priority;
+use crate::target::Sink;
+use crate::telemetry::mock::MOCK_TELEMETRY_SIZE;
+use crate::telemetry::{DEFAULT_EVENT_NAME, DEFAULT_MAPPING_NAME, MAX_TELEMETRY_SIZE};
+
+// Time between iRacing's 60 Hz updates, which paced fragments have to fit into
+const FRAME_TIME_US: u64 = 16_667;
+
+/// File name looked up next to the executable when no `--config` is given
+pub const DEFAULT_CONFIG_FILE: &str = "iracing-teleport.toml";
+
+const TEMPLATE: &str = r#"# iracing-teleport configuration
+#
+# Options given on the command line take precedence over the values in this file.
+# Uncomment and adjust the settings you need.
+
+# Which side to run: "source" on the iRacing computer, "target" on the remote computer,
+# "relay" to forward a stream between networks, or "bridge" to pass it on untouched
+mode = "target"
+
+# Settings can also be grouped into named profiles at the end of this file, selected
+# with --profile <name>. A profile's values override the ones above, which all profiles
+# share, and options on the command line override both.
+
+# Local address to bind to
+# (source default: "0.0.0.0:0", target/relay default: "0.0.0.0:5000")
+# On a source, a port other than 0 sends every datagram from that port, for firewall
+# rules and NAT that match on the source port.
+# bind = "0.0.0.0:5000"
+
+# [source and target] Use a bound UDP socket inherited from the process that started
+# teleport instead of binding one, e.g. 3 for the first socket systemd passes with socket
+# activation, or a socket handle on Windows. bind is ignored then.
+# fd = 3
+
+# Use unicast instead of multicast
+# unicast = false
+
+# [source only] Address to send telemetry to
+# target = "239.255.0.1:5000"
+
+# [target and relay] Multicast group to join
+# group = "239.255.0.1"
+
+# [source only] Id to tag the stream with when several sources share a group
+# id = 0
+
+# [source only] Interval in milliseconds for heartbeats while iRacing has no new data,
+# so targets keep the telemetry open (0 to disable)
+# heartbeat_ms = 1000
+
+# [source only] Interval in milliseconds for resending the session info to targets that
+# joined late (0 to only send it when it changes or a target asks for it)
+# keyframe_ms = 1000
+
+# [source only] Mark the datagrams with a DSCP class ("EF", "CS5", "AF41" or 0-63) so
+# QoS-aware routers prioritize them over other traffic
+# dscp = "EF"
+
+# [source only] Interface to send multicast from, by IPv4 address or name as listed by
+# `iracing-teleport interfaces`, when the computer is connected to several networks
+# out_interface = "Ethernet 2"
+
+# [source only] Whether the multicast datagrams are also delivered to this computer, e.g.
+# to a target running next to the source. Leave unset to keep the OS default.
+# multicast_loop = true
+
+# [source only] Spread the fragments of each update evenly over this many microseconds
+# instead of sending them back to back, for switches with small buffers. Adds up to
+# this much latency.
+# pace = 4000
+
+# [source only] Send an update uncompressed when compressing it doesn't get it below
+# this fraction of its size (0 never compresses)
+# compression_threshold = 1.0
+
+# [source only] Parity fragments to add to each update, so targets can rebuild that many
+# lost fragments per update without a back channel. Costs bandwidth (0 to disable,
+# at most 63).
+# fec = 1
+
+# [source only] Send every datagram this many times, a lighter alternative to fec for
+# links that drop bursts of datagrams. Multiplies the bandwidth.
+# redundancy = 1
+
+# [source only] Append a hash of every update to it, so targets check what they
+# decompress end to end and skip updates that don't match. Costs CPU on both sides,
+# for tracking down suspected corruption. A non-cryptographic checksum (xxh3), it
+# catches corruption but doesn't prove who sent the update, and needs no key.
+# verify = false
+
+# [source and target] Compress with a dictionary made by `iracing-teleport train-dict`
+# from a recording, which shrinks small updates, e.g. with vars. Both sides need the
+# same file, targets skip updates compressed with another dictionary.
+# dict = "iracing.dict"
+
+# [source and target] Lay the compressed updates out as bare LZ4 blocks ("block"), or
+# as LZ4 frames with a content checksum ("frame") that generic LZ4 tools can read, at a
+# few bytes more per update. Both sides need the same format, and frames don't combine
+# with dict.
+# lz4_format = "block"
+
+# [source only] How hard to compress the updates: "fast" spends the least CPU time,
+# "max" sends the smallest updates at several times the CPU time, "balanced" is in
+# between. The source logs what it comes down to. Targets decompress them all the same.
+# quality = "balanced"
+
+# [source only] Never send more than this many megabits per second. Updates that don't
+# fit are dropped, so the targets get fewer updates per second instead of falling behind.
+# max_bandwidth = 5.0
+
+# [source only] Only send heartbeats while the telemetry doesn't change, e.g. while
+# sitting in the garage. Unchanged updates are still sent once a second.
+# skip_unchanged = false
+
+# [source] Only send these variables, the others arrive zeroed and cost next to no
+# bandwidth. Everything else in the mapping, like the session info, is still sent.
+# On a target the same key selects the exported variables instead.
+# vars = ["Throttle", "Brake", "Clutch"]
+
+# [source only] Between keyframes, only send the iRSDK header and this window of the
+# mapping, as "<offset>:<length>" in bytes. Targets write it back at the same offset and
+# keep the rest of their mapping from the last keyframe. print_header shows the offsets
+# of the variable buffers. Needs targets of this version.
+# region = "16384:4096"
+
+# [source only] Adapt the update rate to the link: halve it while the target reports
+# losing more than 2% of the updates and ramp it back up once the loss is gone. Needs
+# unicast, the target sends its reports every report_ms.
+# adaptive = false
+
+# [source only] Seconds the tick count of the telemetry may stay the same while iRacing
+# keeps signaling new data before the telemetry is reopened, for telemetry that freezes
+# after switching windows (0 to disable)
+# stall_timeout = 5
+
+# [target only] Seconds without updates or heartbeats from the source after which the
+# telemetry mapping is closed (0 keeps it open until the target exits)
+# idle_timeout = 10
+
+# [target only] Seconds after startup to exit with code 6 if no update has arrived by
+# then, e.g. because of the wrong group or a firewall, so a service manager restarts or
+# alerts instead of the target waiting silently (0 waits forever)
+# startup_timeout = 0
+
+# [target only] Milliseconds after which an update is too old to be worth writing,
+# counted from when the source read it. Such updates are skipped and counted as stale,
+# but still keep the telemetry open (0 writes every update)
+# max_age = 0
+
+# [target only] Milliseconds of updates to hold back and write at a steady 60 Hz, for
+# overlays that stutter when updates arrive in bursts over a busy network. Adds that
+# much latency. Updates are dropped when more than twice that arrive before they can be
+# written (0 to disable)
+# jitter_buffer = 0
+
+# [target only] Number of updates to reassemble at a time. On links that reorder
+# datagrams, the fragments of consecutive updates interleave, and with 1 the next update
+# discards the one in progress. Up to 8, each takes a buffer of up to max_size
+# reassembly_depth = 1
+
+# [target only] Seconds to keep retrying to bind while the port is still in use, e.g. by
+# a target that is just shutting down (0 gives up right away)
+# bind_retry = 0
+
+# [target only] Seconds without datagrams after which the target leaves and rejoins the
+# multicast group, for managed switches that forget idle group memberships and stop
+# forwarding the group after a pause (0 to disable)
+# rejoin = 60
+
+# [target only] Join the multicast group on each interface that is up in turn, waiting
+# interface_probe milliseconds for data on each, and keep the first one it arrives on.
+# For computers connected to several networks, instead of binding to the right one
+# interface_autoselect = false
+# interface_probe = 2000
+
+# [target only] Interval in milliseconds for reporting lost updates back to the source,
+# for sources running with adaptive (0 to disable)
+# report_ms = 1000
+
+# [target and relay] Only receive from the source with this id
+# (by default the first source seen is used)
+# filter_id = 0
+
+# [source and target] Names of the shared memory mapping and data-valid event.
+# The defaults are iRacing's, change them on both sides to relay another sim.
+# mmap_name = 'Local\IRSDKMemMapFileName'
+# event_name = 'Local\IRSDKDataValidEvent'
+
+# [target only] Write the telemetry somewhere else than the mapping above, for tools that
+# don't read the iRSDK mapping: "mmap:<name>" for a mapping by another name, or
+# "pipe:<name>" for a named pipe (a FIFO path on Linux). Every update goes to the pipe as
+# its length (little-endian u32) followed by the whole mapping.
+# sink = "pipe:teleport"
+
+# [source, target and relay] Largest telemetry mapping in bytes to handle, for sims or
+# versions with a larger mapping than iRacing's
+# max_size = 2097152
+
+# [source and target] Pin the main loop to a CPU core and/or raise its priority
+# ("normal" or "high") to reduce jitter when the machine is busy
+# cpu_affinity = 2
+# priority = "normal"
+
+# [source and target] Stop after this many seconds instead of running until
+# interrupted, e.g. for captures
+# duration = 60
+
+# [source and target] Stop after this many updates, e.g. to capture a lap. Combined
+# with duration, whichever is reached first stops.
+# count = 3600
+
+# [source and target] Print a single "READY role=... bind=..." line to stdout once the
+# socket is set up (and on the source, the telemetry opened), for applications that
+# launch teleport and wait for it to be live
+# ready_signal = false
+
+# [source and target] Also send the counters of every stats interval as a small UDP
+# datagram to `iracing-teleport monitor` running at this address, to keep an eye on a
+# rig from another machine
+# stats_export = "192.168.1.20:5100"
+
+# [source and target] Answer HTTP health checks at this address, for supervisors that
+# restart a stuck instance: 200 while updates or heartbeats came in within the last 10
+# seconds (on a target, its idle_timeout), 503 otherwise
+# health_addr = "127.0.0.1:5200"
+
+# [target only] Serve the state of the target as JSON at http://<address>/status, for
+# dashboards: whether updates come in, the age of the last one, rate, bandwidth, loss
+# and latency of the last stats interval, and these settings
+# api_addr = "127.0.0.1:5300"
+
+# [source and target] Show the state, rate, bandwidth, loss and latency on a single line
+# refreshed a few times per second, with events like connects and errors printed above
+# it, instead of a line of stats every 5 seconds. Falls back to the periodic stats when
+# the output is piped or redirected
+# status_line = false
+
+# [source only] Read and compress the telemetry and print the stats without opening a
+# socket, to check that the sim can be read before setting up the network
+# dry_run = false
+
+# [source only] Print the iRSDK header (version, status, tick rate, variables and buffer
+# sizes) whenever the telemetry mapping is opened
+# print_header = false
+
+# [source only] Stream the telemetry recorded in an .ibt file instead of reading it from
+# the sim, at ibt_speed times the recorded rate and starting ibt_start seconds in
+# ibt = 'C:\Users\me\Documents\iRacing\telemetry\session.ibt'
+# ibt_speed = 1.0
+# ibt_start = 0.0
+
+# [source only] Send synthetic telemetry of simulate_size bytes at 60 Hz instead of
+# reading the sim, to check the network and firewall before a race, e.g. on Windows
+# without iRacing running. Its stats are labeled SIMULATED.
+# simulate = false
+# simulate_size = 35880
+
+# [target only] Spin on the socket instead of blocking. Lowers the latency at the
+# cost of keeping a CPU core fully loaded.
+# busy_poll = false
+
+# [target only] Write to the shared memory mapping even if another application (or a
+# target that didn't exit cleanly) already created it, instead of waiting for it to close
+# force = false
+
+# [target only] Create the telemetry mapping at startup, zeroed, instead of on the first
+# update, for applications that give up when they don't find it as they start. They are
+# only told of new data once updates arrive. The mapping is kept, zeroed again, when the
+# source goes quiet
+# precreate = false
+
+# [target only] Write the first this many payloads as received, and what they decode to,
+# into teleport-frames in the working directory, for bug reports about updates that
+# don't decode. Up to 100, each takes up to twice max_size on disk (0 to disable)
+# dump_frames = 0
+
+# [target only] Write a frame for every tick a source sending fewer updates skips:
+# ticks still in the update's older buffers as they are, others interpolated (floats
+# move a share of the way, gears, flags and bitfields hold). Overlays move more
+# smoothly, but updates arrive up to the skipped ticks later and the frames in between
+# are estimates
+# interpolate = false
+
+# [target only] Also push selected variables as JSON objects over UDP
+# and/or to WebSocket clients
+# export_json = "127.0.0.1:9999"
+# ws = "0.0.0.0:8080"
+# vars = ["Speed", "RPM", "Gear", "Throttle", "Brake"]
+
+# [relay only] Receive like a target (bind/group/unicast_in) and forward
+# the compressed stream like a source (out_bind/target/unicast_out)
+# unicast_in = false
+# out_bind = "0.0.0.0:0"
+# target = "239.255.0.2:5000"
+# unicast_out = false
+
+# [bridge only] Receive on one network (in_bind/in_group/in_unicast) and pass the
+# datagrams on unchanged to another (out_bind/out_target/out_unicast)
+# in_bind = "0.0.0.0:5000"
+# in_group = "239.255.0.1"
+# in_unicast = false
+# out_target = "203.0.113.7:5000"
+# out_unicast = true
+
+# Profiles, e.g. `iracing-teleport --profile wan` for a target reached over the internet
+# [profile.lan]
+# bind = "192.168.1.10:5000"
+#
+# [profile.wan]
+# unicast = true
+# bind = "0.0.0.0:5000"
+# idle_timeout = 30
+"#;
+
+/// Options for running as the source
+#[derive(Args, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    /// Local address to send from. A port other than 0 gives the datagrams a fixed source
+    /// port for firewall rules and NAT (e.g., 0.0.0.0:5001)
+    #[arg(long, default_value = "0.0.0.0:0")]
+    pub bind: String,
+
+    /// Use this bound UDP socket inherited from the parent process instead of binding
+    /// one: a file descriptor on Unix (3 with systemd socket activation), a socket handle
+    /// on Windows
+    #[arg(long, value_name = "FD")]
+    pub fd: Option<u64>,
+
+    /// Target address to send data to (e.g., 127.0.0.1:5000)
+    #[arg(long, default_value = "239.255.0.1:5000")]
+    pub target: String,
+
+    /// Use unicast mode instead of multicast
+    #[arg(long)]
+    pub unicast: bool,
+
+    /// Source id to tag the stream with, so several sources can share a multicast group
+    #[arg(long, default_value_t = 0)]
+    pub id: u32,
+
+    /// Interval in milliseconds for heartbeats while there is no new data (0 to disable)
+    #[arg(long, default_value_t = 1000)]
+    pub heartbeat_ms: u64,
+
+    /// Interval in milliseconds for resending the session info to targets that joined late
+    /// (0 to only send it when it changes or a target asks for it)
+    #[arg(long, default_value_t = 1000)]
+    pub keyframe_ms: u64,
+
+    /// Mark datagrams with this DSCP class (e.g., EF, CS5, AF41 or 0-63) so QoS-aware
+    /// routers prioritize them
+    #[arg(long, value_name = "CLASS")]
+    pub dscp: Option<Dscp>,
+
+    /// Interface to send multicast from, by IPv4 address or name (see the interfaces
+    /// command), for computers with several networks
+    #[arg(long, value_name = "INTERFACE")]
+    pub out_interface: Option<String>,
+
+    /// Deliver the multicast datagrams to this computer too, for a target running on the
+    /// same machine (true or false, default: the OS default)
+    #[arg(long, value_name = "BOOL")]
+    pub multicast_loop: Option<bool>,
+
+    /// Spread the fragments of each update over this many microseconds instead of sending
+    /// them back to back (default when given without a value: 4000). Adds latency.
+    #[arg(long, value_name = "US", num_args = 0..=1, default_missing_value = "4000")]
+    pub pace: Option<u64>,
+
+    /// Send an update uncompressed when compressing doesn't get it below this fraction of
+    /// its size (0 to 1, 0 never compresses)
+    #[arg(long, value_name = "RATIO", default_value_t = 1.0)]
+    pub compression_threshold: f64,
+
+    /// Parity fragments to add to each update, so targets can rebuild that many lost
+    /// fragments per update (0 to disable)
+    #[arg(long, value_name = "FRAGMENTS", default_value_t = 0)]
+    pub fec: u8,
+
+    /// Send every datagram this many times (1 sends each once)
+    #[arg(long, value_name = "COPIES", default_value_t = 1)]
+    pub redundancy: u8,
+
+    /// Append a hash of every update, so targets check what they decompress against it.
+    /// A checksum against corruption, not authentication, so it needs no key.
+    #[arg(long, visible_alias = "checksum-only")]
+    pub verify: bool,
+
+    /// Compress with this dictionary made by train-dict, targets need the same --dict
+    #[arg(long, value_name = "PATH")]
+    pub dict: Option<PathBuf>,
+
+    /// Lay the compressed updates out as bare LZ4 blocks or as LZ4 frames with a content
+    /// checksum, targets need the same --lz4-format
+    #[arg(long, value_enum, default_value_t = Lz4Format::Block)]
+    pub lz4_format: Lz4Format,
+
+    /// How hard to compress: fast saves CPU time, max saves bandwidth. Targets don't need
+    /// to match it
+    #[arg(long, value_enum, default_value_t = Quality::Balanced)]
+    pub quality: Quality,
+
+    /// Drop updates instead of sending more than this many megabits per second,
+    /// lowering the update rate
+    #[arg(long, value_name = "MBPS")]
+    pub max_bandwidth: Option<f64>,
+
+    /// Send only heartbeats while the telemetry doesn't change, e.g. sitting in the garage
+    #[arg(long)]
+    pub skip_unchanged: bool,
+
+    /// Send fewer updates while the target reports loss and ramp back up once the link
+    /// recovers (unicast only)
+    #[arg(long)]
+    pub adaptive: bool,
+
+    /// Seconds the tick count may stay the same while new data is signaled before the
+    /// telemetry is reopened (0 to disable)
+    #[arg(long, value_name = "SECS", default_value_t = 5)]
+    pub stall_timeout: u64,
+
+    /// Name of the shared memory mapping, to use a sim other than iRacing
+    #[arg(long, default_value = DEFAULT_MAPPING_NAME)]
+    pub mmap_name: String,
+
+    /// Name of the data-valid event, to use a sim other than iRacing
+    #[arg(long, default_value = DEFAULT_EVENT_NAME)]
+    pub event_name: String,
+
+    /// Largest telemetry mapping in bytes to handle, for sims with a larger mapping
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_TELEMETRY_SIZE)]
+    pub max_size: usize,
+
+    /// Pin the main loop to this CPU core
+    #[arg(long)]
+    pub cpu_affinity: Option<usize>,
+
+    /// Scheduling priority of the main loop
+    #[arg(long, value_enum, default_value_t = Priority::Normal)]
+    pub priority: Priority,
+
+    /// Stop after this many seconds instead of running until interrupted
+    #[arg(long, value_name = "SECS")]
+    pub duration: Option<u64>,
+
+    /// Stop after this many updates, or at --duration if that comes first
+    #[arg(long, value_name = "N")]
+    pub count: Option<u64>,
+
+    /// Print a "READY role=... bind=..." line to stdout once up and running, for
+    /// applications that launch teleport
+    #[arg(long)]
+    pub ready_signal: bool,
+
+    /// Send the counters of every stats interval to `iracing-teleport monitor` at this
+    /// address (e.g., 192.168.1.20:5100)
+    #[arg(long, value_name = "ADDR")]
+    pub stats_export: Option<String>,
+
+    /// Answer HTTP health checks at this address (e.g., 127.0.0.1:5200): 200 while
+    /// updates or heartbeats come in, 503 otherwise
+    #[arg(long, value_name = "ADDR")]
+    pub health_addr: Option<String>,
+
+    /// Show the stats on a single line refreshed in place instead of a line every few
+    /// seconds, when the output is a terminal
+    #[arg(long)]
+    pub status_line: bool,
+
+    /// Testing only: randomly drop this share of the datagrams (0 to 1), to try out the
+    /// recovery features without a lossy link
+    #[arg(long, value_name = "P", default_value_t = 0.0, hide = true)]
+    pub drop_rate: f64,
+
+    /// Testing only: seed for --drop-rate and --reorder, so every run picks the same
+    /// datagrams
+    #[arg(long, value_name = "SEED", hide = true)]
+    pub drop_seed: Option<u64>,
+
+    /// Testing only: hold back this share of the datagrams (0 to 1) behind up to 3 later
+    /// ones, to try out the reassembly of datagrams arriving out of order
+    #[arg(long, value_name = "P", default_value_t = 0.0, hide = true)]
+    pub reorder: f64,
+
+    /// Read and compress the telemetry and report the stats, but don't send anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Print the iRSDK header of the mapping whenever it is opened, to check which sim
+    /// and session the source is reading
+    #[arg(long)]
+    pub print_header: bool,
+
+    /// Comma-separated variable names to send, the others are sent zeroed
+    /// (default: all variables)
+    #[arg(long, value_delimiter = ',')]
+    pub vars: Vec<String>,
+
+    /// Between keyframes only send the iRSDK header and this window of the mapping, as
+    /// <offset>:<length> in bytes (see --print-header for the offsets)
+    #[arg(long, value_name = "OFFSET:LENGTH")]
+    pub region: Option<String>,
+
+    /// Stream the telemetry recorded in this .ibt file instead of reading it from the sim
+    #[arg(long, value_name = "PATH")]
+    pub ibt: Option<PathBuf>,
+
+    /// Play the --ibt file back this many times faster than it was recorded
+    #[arg(long, value_name = "FACTOR", default_value_t = 1.0)]
+    pub ibt_speed: f64,
+
+    /// Start playing the --ibt file back this many seconds into the recording
+    #[arg(long, value_name = "SECS", default_value_t = 0.0)]
+    pub ibt_start: f64,
+
+    /// Send synthetic telemetry at 60 Hz instead of reading the sim, to test the network
+    /// without iRacing
+    #[arg(long)]
+    pub simulate: bool,
+
+    /// Size in bytes of the synthetic telemetry --simulate sends
+    #[arg(long, value_name = "BYTES", default_value_t = MOCK_TELEMETRY_SIZE)]
+    pub simulate_size: usize,
+}
+
+/// Options for running as the target
+#[derive(Args, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    /// Address to bind to for receiving (e.g., 127.0.0.1:5000)
+    #[arg(long, default_value = "0.0.0.0:5000")]
+    pub bind: String,
+
+    /// Use this bound UDP socket inherited from the parent process instead of binding
+    /// one: a file descriptor on Unix (3 with systemd socket activation), a socket handle
+    /// on Windows
+    #[arg(long, value_name = "FD")]
+    pub fd: Option<u64>,
+
+    /// Multicast group to join
+    #[arg(long, default_value = "239.255.0.1")]
+    pub group: String,
+
+    /// Use unicast mode instead of multicast
+    #[arg(long)]
+    pub unicast: bool,
+
+    /// Only receive from the source with this id (default: the first source seen)
+    #[arg(long)]
+    pub filter_id: Option<u32>,
+
+    /// Name of the shared memory mapping, to use a sim other than iRacing
+    #[arg(long, default_value = DEFAULT_MAPPING_NAME)]
+    pub mmap_name: String,
+
+    /// Name of the data-valid event, to use a sim other than iRacing
+    #[arg(long, default_value = DEFAULT_EVENT_NAME)]
+    pub event_name: String,
+
+    /// Where to write the telemetry instead of the mapping named by --mmap-name:
+    /// `mmap:<name>` for a mapping by another name, or `pipe:<name>` for length-prefixed
+    /// frames on a named pipe
+    #[arg(long, value_name = "SINK")]
+    pub sink: Option<String>,
+
+    /// Largest telemetry mapping in bytes to handle, for sims with a larger mapping
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_TELEMETRY_SIZE)]
+    pub max_size: usize,
+
+    /// Pin the main loop to this CPU core
+    #[arg(long)]
+    pub cpu_affinity: Option<usize>,
+
+    /// Scheduling priority of the main loop
+    #[arg(long, value_enum, default_value_t = Priority::Normal)]
+    pub priority: Priority,
+
+    /// Stop after this many seconds instead of running until interrupted
+    #[arg(long, value_name = "SECS")]
+    pub duration: Option<u64>,
+
+    /// Stop after this many updates, or at --duration if that comes first
+    #[arg(long, value_name = "N")]
+    pub count: Option<u64>,
+
+    /// Print a "READY role=... bind=..." line to stdout once up and running, for
+    /// applications that launch teleport
+    #[arg(long)]
+    pub ready_signal: bool,
+
+    /// Send the counters of every stats interval to `iracing-teleport monitor` at this
+    /// address (e.g., 192.168.1.20:5100)
+    #[arg(long, value_name = "ADDR")]
+    pub stats_export: Option<String>,
+
+    /// Answer HTTP health checks at this address (e.g., 127.0.0.1:5200): 200 while
+    /// updates or heartbeats come in, 503 otherwise
+    #[arg(long, value_name = "ADDR")]
+    pub health_addr: Option<String>,
+
+    /// Serve the state of the target as JSON at http://ADDR/status (e.g., 127.0.0.1:5300)
+    #[arg(long, value_name = "ADDR")]
+    pub api_addr: Option<String>,
+
+    /// Show the stats on a single line refreshed in place instead of a line every few
+    /// seconds, when the output is a terminal
+    #[arg(long)]
+    pub status_line: bool,
+
+    /// Testing only: randomly drop this share of the datagrams (0 to 1), to try out the
+    /// recovery features without a lossy link
+    #[arg(long, value_name = "P", default_value_t = 0.0, hide = true)]
+    pub drop_rate: f64,
+
+    /// Testing only: seed for --drop-rate, so every run drops the same datagrams
+    #[arg(long, value_name = "SEED", hide = true)]
+    pub drop_seed: Option<u64>,
+
+    /// Seconds without updates or heartbeats after which the telemetry is closed
+    /// (0 keeps it open)
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub idle_timeout: u64,
+
+    /// Seconds after startup to exit with an error if no update has arrived by then, for
+    /// service managers to restart or alert on (0 waits forever)
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub startup_timeout: u64,
+
+    /// Milliseconds after which an update is too old to write, counted from when the
+    /// source read it (0 writes every update)
+    #[arg(
+        long,
+        visible_alias = "payload-max-age",
+        value_name = "MS",
+        default_value_t = 0
+    )]
+    pub max_age: u64,
+
+    /// Milliseconds of updates to hold back and release at a steady 60 Hz, smoothing out
+    /// updates that arrive in bursts at the cost of that much latency (0 to disable)
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub jitter_buffer: u64,
+
+    /// Updates to reassemble at a time, so the fragments of updates a reordering network
+    /// interleaves complete independently (1 to 8)
+    #[arg(long, value_name = "UPDATES", default_value_t = 1)]
+    pub reassembly_depth: usize,
+
+    /// Seconds to keep retrying to bind while the port is in use (0 gives up right away)
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub bind_retry: u64,
+
+    /// Seconds without datagrams after which the multicast group is rejoined, for
+    /// switches that forget idle memberships (0 to disable)
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub rejoin: u64,
+
+    /// Join the multicast group on each interface that is up in turn and keep the first
+    /// one data arrives on, instead of the one --bind gives
+    #[arg(long)]
+    pub interface_autoselect: bool,
+
+    /// Milliseconds to wait for data on each interface with --interface-autoselect
+    #[arg(long, value_name = "MS", default_value_t = 2000)]
+    pub interface_probe: u64,
+
+    /// Interval in milliseconds for reporting lost updates back to the source, which
+    /// adapts its rate to them with --adaptive (0 to disable)
+    #[arg(long, default_value_t = 1000)]
+    pub report_ms: u64,
+
+    /// Spin on the socket instead of blocking, trading a CPU core for lower latency
+    #[arg(long)]
+    pub busy_poll: bool,
+
+    /// Write to the shared memory mapping even if another application already created it
+    #[arg(long)]
+    pub force: bool,
+
+    /// Create the telemetry mapping at startup instead of on the first update, for
+    /// applications that only look for it as they start
+    #[arg(long)]
+    pub precreate: bool,
+
+    /// Write the first N complete payloads and what they decode to into teleport-frames
+    /// in the working directory, to attach to a bug report (up to 100)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub dump_frames: usize,
+
+    /// Write frames for the ticks skipped by a source sending fewer updates than the sim
+    /// ticks, interpolating floats and holding other values. Smoother, but adds latency
+    #[arg(long)]
+    pub interpolate: bool,
+
+    /// Decompress with this dictionary made by train-dict, the same the source uses
+    #[arg(long, value_name = "PATH")]
+    pub dict: Option<PathBuf>,
+
+    /// Expect the updates as bare LZ4 blocks or as LZ4 frames, the same the source uses
+    #[arg(long, value_enum, default_value_t = Lz4Format::Block)]
+    pub lz4_format: Lz4Format,
+
+    /// Also push selected variables as JSON objects over UDP (e.g., 127.0.0.1:9999)
+    #[arg(long)]
+    pub export_json: Option<String>,
+
+    /// Serve the selected variables as JSON to WebSocket clients (e.g., 0.0.0.0:8080)
+    #[arg(long)]
+    pub ws: Option<String>,
+
+    /// Comma-separated variable names to include in the JSON export and WebSocket updates
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "Speed,RPM,Gear,Throttle,Brake"
+    )]
+    pub vars: Vec<String>,
+}
+
+/// Options for running as a relay
+#[derive(Args, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Address to bind to for receiving (e.g., 127.0.0.1:5000)
+    #[arg(long, default_value = "0.0.0.0:5000")]
+    pub bind: String,
+
+    /// Multicast group to join for receiving
+    #[arg(long, default_value = "239.255.0.1")]
+    pub group: String,
+
+    /// Receive via unicast instead of joining the multicast group
+    #[arg(long)]
+    pub unicast_in: bool,
+
+    /// Only receive from the source with this id (default: the first source seen)
+    #[arg(long)]
+    pub filter_id: Option<u32>,
+
+    /// Largest telemetry mapping in bytes to handle, for sims with a larger mapping
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_TELEMETRY_SIZE)]
+    pub max_size: usize,
+
+    /// Local bind address for forwarding (e.g., 192.168.1.5:0)
+    #[arg(long, default_value = "0.0.0.0:0")]
+    pub out_bind: String,
+
+    /// Address to forward data to (e.g., 239.255.0.2:5000) [required]
+    #[arg(long)]
+    pub target: Option<String>,
+
+    /// Forward via unicast instead of multicast
+    #[arg(long)]
+    pub unicast_out: bool,
+}
+
+/// Options for running as a bridge
+#[derive(Args, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    /// Address to bind to for receiving (e.g., 192.168.10.5:5000)
+    #[arg(long, default_value = "0.0.0.0:5000")]
+    pub in_bind: String,
+
+    /// Multicast group to join for receiving
+    #[arg(long, default_value = "239.255.0.1")]
+    pub in_group: String,
+
+    /// Receive via unicast instead of joining the multicast group
+    #[arg(long)]
+    pub in_unicast: bool,
+
+    /// Only forward the source with this id (default: the first source seen)
+    #[arg(long)]
+    pub filter_id: Option<u32>,
+
+    /// Local bind address for forwarding (e.g., 10.0.0.5:0)
+    #[arg(long, default_value = "0.0.0.0:0")]
+    pub out_bind: String,
+
+    /// Address to forward the datagrams to (e.g., 203.0.113.7:5000) [required]
+    #[arg(long)]
+    pub out_target: Option<String>,
+
+    /// Forward via unicast instead of multicast
+    #[arg(long)]
+    pub out_unicast: bool,
+}
+
+impl SourceConfig {
+    /// Checks the addresses for common mistakes before any socket is opened
+    pub fn validate(&self) -> io::Result<()> {
+        address("--bind", &self.bind)?;
+        destination("--target", &self.target, self.unicast, "--unicast")?;
+        max_size(self.max_size)?;
+        count(self.count)?;
+        probability("--drop-rate", self.drop_rate)?;
+        if let Some(stats_export) = &self.stats_export {
+            address("--stats-export", stats_export)?;
+        }
+        if let Some(health_addr) = &self.health_addr {
+            address("--health-addr", health_addr)?;
+        }
+        probability("--reorder", self.reorder)?;
+        lz4_format(self.lz4_format, self.dict.is_some())?;
+        if let Some(region) = &self.region {
+            let parsed = Region::parse(region)?;
+            if parsed.end().is_none_or(|end| end > self.max_size) {
+                return Err(invalid_input(format!(
+                    "--region {} reaches past the {} bytes of --max-size",
+                    region, self.max_size
+                )));
+            }
+        }
+
+        if self.fec > MAX_PARITY_FRAGMENTS {
+            return Err(invalid_input(format!(
+                "--fec {} is more than the {} parity fragments an update can have",
+                self.fec, MAX_PARITY_FRAGMENTS
+            )));
+        }
+
+        if self.redundancy == 0 {
+            return Err(invalid_input(
+                "--redundancy must be at least 1, which sends each datagram once".to_string(),
+            ));
+        }
+
+        if let Some(mbps) = self.max_bandwidth
+            && !(mbps > 0.0 && mbps.is_finite())
+        {
+            return Err(invalid_input(format!(
+                "--max-bandwidth {} must be a positive number of Mbps",
+                mbps
+            )));
+        }
+
+        if !(self.ibt_speed > 0.0 && self.ibt_speed.is_finite()) {
+            return Err(invalid_input(format!(
+                "--ibt-speed {} must be a positive factor",
+                self.ibt_speed
+            )));
+        }
+
+        if self.simulate && self.ibt.is_some() {
+            return Err(invalid_input(
+                "--simulate and --ibt both replace the sim, pick one".to_string(),
+            ));
+        }
+
+        if self.simulate_size == 0 || self.simulate_size > self.max_size {
+            return Err(invalid_input(format!(
+                "--simulate-size {} must be between 1 and the {} bytes of --max-size",
+                self.simulate_size, self.max_size
+            )));
+        }
+
+        if !(self.ibt_start >= 0.0 && self.ibt_start.is_finite()) {
+            return Err(invalid_input(format!(
+                "--ibt-start {} must be a number of seconds from the start of the file",
+                self.ibt_start
+            )));
+        }
+
+        if self.adaptive && !self.unicast {
+            return Err(invalid_input(
+                "--adaptive requires --unicast, a multicast source would slow down every target for the one with the worst link".to_string(),
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.compression_threshold) {
+            return Err(invalid_input(format!(
+                "--compression-threshold {} must be between 0 and 1",
+                self.compression_threshold
+            )));
+        }
+
+        match self.pace {
+            Some(pace) if pace >= FRAME_TIME_US => Err(invalid_input(format!(
+                "--pace {} must be shorter than the {} µs between updates",
+                pace, FRAME_TIME_US
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TargetConfig {
+    /// Checks the addresses for common mistakes before any socket is opened
+    pub fn validate(&self) -> io::Result<()> {
+        address("--bind", &self.bind)?;
+        if !self.unicast {
+            group("--group", &self.group, "--unicast")?;
+        }
+        max_size(self.max_size)?;
+        count(self.count)?;
+        probability("--drop-rate", self.drop_rate)?;
+        lz4_format(self.lz4_format, self.dict.is_some())?;
+        if self.interface_autoselect {
+            interface_autoselect(self)?;
+        }
+        if self.dump_frames > MAX_DUMP_FRAMES {
+            return Err(invalid_input(format!(
+                "--dump-frames {} is more than the {} allowed",
+                self.dump_frames, MAX_DUMP_FRAMES
+            )));
+        }
+        if !(1..=MAX_REASSEMBLY_DEPTH).contains(&self.reassembly_depth) {
+            return Err(invalid_input(format!(
+                "--reassembly-depth {} must be between 1 and {}",
+                self.reassembly_depth, MAX_REASSEMBLY_DEPTH
+            )));
+        }
+        if let Some(stats_export) = &self.stats_export {
+            address("--stats-export", stats_export)?;
+        }
+        if let Some(health_addr) = &self.health_addr {
+            address("--health-addr", health_addr)?;
+        }
+        if let Some(api_addr) = &self.api_addr {
+            address("--api-addr", api_addr)?;
+        }
+        if let Some(sink) = &self.sink {
+            Sink::parse(sink)?;
+        }
+        if let Some(export_json) = &self.export_json {
+            address("--export-json", export_json)?;
+        }
+        if let Some(ws) = &self.ws {
+            address("--ws", ws)?;
+        }
+        Ok(())
+    }
+}
+
+impl RelayConfig {
+    /// Checks the addresses for common mistakes before any socket is opened
+    pub fn validate(&self) -> io::Result<()> {
+        address("--bind", &self.bind)?;
+        if !self.unicast_in {
+            group("--group", &self.group, "--unicast-in")?;
+        }
+        max_size(self.max_size)?;
+        address("--out-bind", &self.out_bind)?;
+        match &self.target {
+            Some(target) => destination("--target", target, self.unicast_out, "--unicast-out"),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The relay requires a --target address to forward to",
+            )),
+        }
+    }
+}
+
+impl BridgeConfig {
+    /// Checks the addresses for common mistakes before any socket is opened
+    pub fn validate(&self) -> io::Result<()> {
+        address("--in-bind", &self.in_bind)?;
+        if !self.in_unicast {
+            group("--in-group", &self.in_group, "--in-unicast")?;
+        }
+        address("--out-bind", &self.out_bind)?;
+        match &self.out_target {
+            Some(target) => destination("--out-target", target, self.out_unicast, "--out-unicast"),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The bridge requires an --out-target address to forward to",
+            )),
+        }
+    }
+}
+
+/// Resolves a `host:port` address, explaining what is wrong with it if it can't be used
+fn address(option: &str, value: &str) -> io::Result<SocketAddr> {
+    let has_port = value
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !has_port {
+        return Err(invalid_input(format!(
+            "{} '{}' needs an address and a port, e.g. 192.168.1.10:5000",
+            option, value
+        )));
+    }
+
+    match value.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => Ok(addr),
+        Ok(None) => Err(invalid_input(format!(
+            "{} '{}' did not resolve to any address",
+            option, value
+        ))),
+        Err(e) => Err(invalid_input(format!(
+            "{} '{}' is not a valid address or host name: {}",
+            option, value, e
+        ))),
+    }
+}
+
+/// Checks that a destination matches the unicast/multicast mode it is sent with
+fn destination(option: &str, value: &str, unicast: bool, unicast_option: &str) -> io::Result<()> {
+    let ip = address(option, value)?.ip();
+
+    if unicast && ip.is_multicast() {
+        return Err(invalid_input(format!(
+            "{} {} looks like a multicast group but {} was set. Remove {} to use multicast, \
+             or use the address of the receiving computer.",
+            option, value, unicast_option, unicast_option
+        )));
+    }
+
+    if !unicast && !ip.is_multicast() {
+        return Err(invalid_input(format!(
+            "{} {} is not a multicast group (224.0.0.0 to 239.255.255.255). Add {} to send \
+             to a single computer, or use a multicast group like 239.255.0.1.",
+            option, value, unicast_option
+        )));
+    }
+
+    Ok(())
+}
+
+/// Checks that --interface-autoselect has interfaces to pick from and time to probe them
+fn interface_autoselect(config: &TargetConfig) -> io::Result<()> {
+    if config.unicast {
+        return Err(invalid_input(
+            "--interface-autoselect picks the interface to join the multicast group on, \
+             it doesn't apply to --unicast"
+                .to_string(),
+        ));
+    }
+    if let Ok(addr) = config.bind.parse::<SocketAddr>()
+        && !addr.ip().is_unspecified()
+    {
+        return Err(invalid_input(format!(
+            "--bind {} limits the target to one interface, bind to 0.0.0.0:{} to let \
+             --interface-autoselect pick one",
+            config.bind,
+            addr.port()
+        )));
+    }
+    if config.interface_probe == 0 {
+        return Err(invalid_input(
+            "--interface-probe must be at least 1 millisecond".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that a multicast group to join is an IPv4 multicast address
+fn group(option: &str, value: &str, unicast_option: &str) -> io::Result<()> {
+    match value.parse::<Ipv4Addr>() {
+        Ok(ip) if ip.is_multicast() => Ok(()),
+        Ok(_) => Err(invalid_input(format!(
+            "{} {} is not a multicast group (224.0.0.0 to 239.255.255.255). Use a group like \
+             239.255.0.1, or add {} to receive from a single computer.",
+            option, value, unicast_option
+        ))),
+        Err(_) => Err(invalid_input(format!(
+            "{} '{}' must be an IPv4 multicast address without a port, e.g. 239.255.0.1",
+            option, value
+        ))),
+    }
+}
+
+/// Checks that a telemetry size limit fits at least one fragment and what the protocol
+/// can advertise
+fn max_size(value: usize) -> io::Result<()> {
+    if value < MAX_PAYLOAD_SIZE || value > u32::MAX as usize {
+        return Err(invalid_input(format!(
+            "--max-size {} must be between {} bytes (a single fragment) and {} bytes",
+            value,
+            MAX_PAYLOAD_SIZE,
+            u32::MAX
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that a limit on the number of updates lets at least one through
+fn count(value: Option<u64>) -> io::Result<()> {
+    match value {
+        Some(0) => Err(invalid_input(
+            "--count must be at least 1, leave it out to run until interrupted".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn probability(option: &str, value: f64) -> io::Result<()> {
+    if !(0.0..=1.0).contains(&value) {
+        return Err(invalid_input(format!(
+            "{} {} must be a probability between 0 and 1",
+            option, value
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the LZ4 format works with the other compression options, frames can't
+/// be compressed with a dictionary
+fn lz4_format(format: Lz4Format, dict: bool) -> io::Result<()> {
+    if format == Lz4Format::Frame && dict {
+        return Err(invalid_input(
+            "--lz4-format frame doesn't support --dict, use the block format with it".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Returns the config file to use: the explicit path, or the default file next to the
+/// executable if it exists
+pub fn resolve_path(explicit: Option<PathBuf>) -> Option<PathBuf> {
+    explicit.or_else(|| {
+        let path = std::env::current_exe()
+            .ok()?
+            .with_file_name(DEFAULT_CONFIG_FILE);
+        path.is_file().then_some(path)
+    })
+}
+
+/// Loads a config file into a TOML table
+pub fn load(path: &Path) -> io::Result<toml::Table> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read config file {}: {}", path.display(), e),
+        )
+    })?;
+
+    content.parse::<toml::Table>().map_err(|e| invalid(path, e))
+}
+
+/// Applies the named profile of the config file: its `[profile.<name>]` table overrides
+/// the values at the top of the file, which the profiles share. The profiles are removed
+/// either way, so only the selected one takes effect.
+pub fn profile(path: &Path, mut file: toml::Table, name: Option<&str>) -> io::Result<toml::Table> {
+    let profiles = match file.remove("profile") {
+        None => toml::Table::new(),
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(invalid(path, "`profile` must be a table of profiles")),
+    };
+    let Some(name) = name else {
+        return Ok(file);
+    };
+
+    match profiles.get(name) {
+        Some(toml::Value::Table(profile)) => {
+            file.extend(profile.clone());
+            Ok(file)
+        }
+        Some(_) => Err(invalid(path, format!("`profile.{}` must be a table", name))),
+        None if profiles.is_empty() => Err(invalid(
+            path,
+            format!("no profile '{}', the file defines no profiles", name),
+        )),
+        None => Err(invalid(
+            path,
+            format!(
+                "no profile '{}', available profiles: {}",
+                name,
+                profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+        )),
+    }
+}
+
+/// Returns the mode ("source", "target", "relay" or "bridge") named in the config file, if any
+pub fn mode(path: &Path, file: &toml::Table) -> io::Result<Option<String>> {
+    match file.get("mode") {
+        None => Ok(None),
+        Some(toml::Value::String(mode)) => Ok(Some(mode.clone())),
+        Some(_) => Err(invalid(path, "`mode` must be a string")),
+    }
+}
+
+/// Overlays the values from the config file onto the parsed command line arguments.
+/// Arguments explicitly given on the command line are left untouched.
+pub fn merge<T>(args: T, matches: &ArgMatches, path: &Path, file: &toml::Table) -> io::Result<T>
+where
+    T: Args + Serialize + DeserializeOwned,
+{
+    let command = T::augment_args(Command::new("config"));
+    let mut merged = toml::Table::try_from(&args).map_err(|e| invalid(path, e))?;
+
+    for (key, value) in file {
+        if key == "mode" {
+            continue;
+        }
+
+        // Unknown keys are passed through so deserialization can report them
+        let known = command.get_arguments().any(|arg| arg.get_id() == key);
+        if known && matches.value_source(key) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+
+        merged.insert(key.clone(), value.clone());
+    }
+
+    merged.try_into().map_err(|e| invalid(path, e))
+}
+
+/// Writes a commented config template, refusing to overwrite an existing file
+pub fn write_template(path: &Path) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| io::Write::write_all(&mut file, TEMPLATE.as_bytes()))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to write config file {}: {}", path.display(), e),
+            )
+        })
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid config file {}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        target: TargetConfig,
+    }
+
+    fn parse(args: &[&str], file: &str) -> io::Result<TargetConfig> {
+        let matches = TestCli::command().get_matches_from(args);
+        let cli = TestCli::from_arg_matches(&matches).unwrap();
+        let file = file.parse::<toml::Table>().unwrap();
+        merge(cli.target, &matches, Path::new("test.toml"), &file)
+    }
+
+    #[test]
+    fn test_file_values_override_defaults() {
+        let config = parse(&["test"], "group = \"239.255.0.2\"\nunicast = true").unwrap();
+        assert_eq!(config.group, "239.255.0.2");
+        assert_eq!(config.bind, "0.0.0.0:5000");
+        assert!(config.unicast);
+    }
+
+    #[test]
+    fn test_cli_values_override_file() {
+        let config = parse(
+            &["test", "--group", "239.255.0.3"],
+            "group = \"239.255.0.2\"\nmode = \"target\"",
+        )
+        .unwrap();
+        assert_eq!(config.group, "239.255.0.3");
+    }
+
+    #[test]
+    fn test_unknown_key_is_rejected() {
+        assert!(parse(&["test"], "grop = \"239.255.0.2\"").is_err());
+    }
+
+    #[derive(Parser)]
+    struct SourceCli {
+        #[command(flatten)]
+        source: SourceConfig,
+    }
+
+    fn source(args: &[&str]) -> SourceConfig {
+        let matches = SourceCli::command().get_matches_from(args);
+        SourceCli::from_arg_matches(&matches).unwrap().source
+    }
+
+    #[test]
+    fn test_pace_must_fit_a_frame() {
+        assert_eq!(source(&["test"]).pace, None);
+        assert_eq!(source(&["test", "--pace"]).pace, Some(4000));
+
+        let config = source(&["test", "--pace", "16667"]);
+        assert!(
+            config
+                .validate()
+                .unwrap_err()
+                .to_string()
+                .contains("--pace")
+        );
+    }
+
+    #[test]
+    fn test_redundancy_sends_at_least_once() {
+        source(&["test", "--redundancy", "3"]).validate().unwrap();
+        let e = source(&["test", "--redundancy", "0"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--redundancy"));
+    }
+
+    #[test]
+    fn test_fec_fits_the_flags() {
+        source(&["test", "--fec", "63"]).validate().unwrap();
+        let e = source(&["test", "--fec", "64"]).validate().unwrap_err();
+        assert!(e.to_string().contains("--fec"));
+    }
+
+    #[test]
+    fn test_checksum_only_is_verify() {
+        assert!(!source(&["test"]).verify);
+        assert!(source(&["test", "--verify"]).verify);
+        assert!(source(&["test", "--checksum-only"]).verify);
+    }
+
+    #[test]
+    fn test_max_bandwidth_is_positive() {
+        assert_eq!(source(&["test"]).max_bandwidth, None);
+        source(&["test", "--max-bandwidth", "5"])
+            .validate()
+            .unwrap();
+        let e = source(&["test", "--max-bandwidth", "0"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--max-bandwidth"));
+    }
+
+    #[test]
+    fn test_ibt_playback_options() {
+        let config = source(&["test", "--ibt", "session.ibt", "--ibt-start", "90"]);
+        assert_eq!(config.ibt, Some(PathBuf::from("session.ibt")));
+        assert_eq!(config.ibt_speed, 1.0);
+        config.validate().unwrap();
+
+        let e = source(&["test", "--ibt-speed", "0"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--ibt-speed"));
+        let e = source(&["test", "--ibt-start=-1"]).validate().unwrap_err();
+        assert!(e.to_string().contains("--ibt-start"));
+    }
+
+    #[test]
+    fn test_simulate_options() {
+        let config = source(&["test", "--simulate"]);
+        assert_eq!(config.simulate_size, MOCK_TELEMETRY_SIZE);
+        config.validate().unwrap();
+
+        let e = source(&["test", "--simulate", "--ibt", "session.ibt"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("pick one"));
+        let e = source(&["test", "--simulate-size", "0"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--simulate-size"));
+    }
+
+    #[test]
+    fn test_adaptive_requires_unicast() {
+        let e = source(&["test", "--adaptive"]).validate().unwrap_err();
+        assert!(e.to_string().contains("--unicast"));
+        source(&[
+            "test",
+            "--target",
+            "192.168.1.10:5000",
+            "--unicast",
+            "--adaptive",
+        ])
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_compression_threshold_is_a_fraction() {
+        assert_eq!(source(&["test"]).compression_threshold, 1.0);
+
+        let config = source(&["test", "--compression-threshold", "1.5"]);
+        let e = config.validate().unwrap_err();
+        assert!(e.to_string().contains("--compression-threshold"));
+    }
+
+    #[test]
+    fn test_lz4_frames_exclude_a_dictionary() {
+        assert_eq!(source(&["test"]).lz4_format, Lz4Format::Block);
+        assert_eq!(source(&["test"]).quality, Quality::Balanced);
+        assert_eq!(source(&["test", "--quality", "max"]).quality, Quality::Max);
+        source(&["test", "--lz4-format", "frame"])
+            .validate()
+            .unwrap();
+
+        let config = source(&["test", "--lz4-format", "frame", "--dict", "a.dict"]);
+        let e = config.validate().unwrap_err();
+        assert!(e.to_string().contains("--dict"));
+    }
+
+    #[test]
+    fn test_region_fits_the_max_size() {
+        assert_eq!(source(&["test"]).region, None);
+        source(&["test", "--region", "16384:4096"])
+            .validate()
+            .unwrap();
+
+        let config = source(&["test", "--region", "16384:4096", "--max-size", "18000"]);
+        let e = config.validate().unwrap_err();
+        assert!(e.to_string().contains("--max-size"));
+        let e = source(&["test", "--region", "16384"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("<offset>:<length>"));
+    }
+
+    #[test]
+    fn test_multicast_loop_defaults_to_the_os() {
+        assert_eq!(source(&["test"]).multicast_loop, None);
+        assert_eq!(
+            source(&["test", "--multicast-loop", "false"]).multicast_loop,
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_dscp_by_name_or_number() {
+        let config = source(&["test", "--dscp", "af41"]);
+        assert_eq!(config.dscp, "AF41".parse().ok());
+
+        let matches = SourceCli::command().get_matches_from(["test"]);
+        let cli = SourceCli::from_arg_matches(&matches).unwrap();
+        let file = "dscp = \"EF\"".parse::<toml::Table>().unwrap();
+        let config = merge(cli.source, &matches, Path::new("test.toml"), &file).unwrap();
+        assert_eq!(config.dscp, "46".parse().ok());
+
+        let file = "dscp = \"XY\"".parse::<toml::Table>().unwrap();
+        let cli = SourceCli::from_arg_matches(&matches).unwrap();
+        assert!(merge(cli.source, &matches, Path::new("test.toml"), &file).is_err());
+    }
+
+    /// Parses the target's command line `args`, for tests elsewhere too
+    pub(crate) fn target(args: &[&str]) -> TargetConfig {
+        parse(args, "").unwrap()
+    }
+
+    #[test]
+    fn test_count_lets_an_update_through() {
+        assert_eq!(target(&["test"]).count, None);
+        target(&["test", "--count", "1"]).validate().unwrap();
+        let e = source(&["test", "--count", "0"]).validate().unwrap_err();
+        assert!(e.to_string().contains("--count"));
+    }
+
+    #[test]
+    fn test_reassembly_depth_is_limited() {
+        assert_eq!(target(&["test"]).reassembly_depth, 1);
+        target(&["test", "--reassembly-depth", "4"])
+            .validate()
+            .unwrap();
+        for depth in ["0", "9"] {
+            let e = target(&["test", "--reassembly-depth", depth])
+                .validate()
+                .unwrap_err();
+            assert!(e.to_string().contains("--reassembly-depth"));
+        }
+    }
+
+    #[test]
+    fn test_interface_autoselect_options() {
+        let config = target(&["test"]);
+        assert!(!config.interface_autoselect);
+        assert_eq!(config.interface_probe, 2000);
+        target(&["test", "--interface-autoselect", "--interface-probe", "500"])
+            .validate()
+            .unwrap();
+
+        let e = target(&["test", "--interface-autoselect", "--unicast"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--unicast"));
+        let e = target(&[
+            "test",
+            "--interface-autoselect",
+            "--bind",
+            "192.168.1.5:5000",
+        ])
+        .validate()
+        .unwrap_err();
+        assert!(e.to_string().contains("0.0.0.0:5000"));
+        let e = target(&["test", "--interface-autoselect", "--interface-probe", "0"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--interface-probe"));
+    }
+
+    #[test]
+    fn test_fault_injection_rates_are_probabilities() {
+        assert_eq!(source(&["test"]).drop_rate, 0.0);
+        target(&["test", "--drop-rate", "0.1", "--drop-seed", "7"])
+            .validate()
+            .unwrap();
+        let e = source(&["test", "--drop-rate", "1.5"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--drop-rate"));
+        let e = source(&["test", "--reorder=-0.5"]).validate().unwrap_err();
+        assert!(e.to_string().contains("--reorder"));
+    }
+
+    #[test]
+    fn test_max_size_fits_a_fragment() {
+        assert_eq!(target(&["test"]).max_size, MAX_TELEMETRY_SIZE);
+        target(&["test", "--max-size", "4194304"])
+            .validate()
+            .unwrap();
+
+        let e = target(&["test", "--max-size", "1024"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--max-size"));
+        assert!(source(&["test", "--max-size", "0"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        source(&["test"]).validate().unwrap();
+        target(&["test"]).validate().unwrap();
+    }
+
+    #[test]
+    fn test_address_without_port_is_rejected() {
+        let e = source(&["test", "--target", "192.168.1.10", "--unicast"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("needs an address and a port"));
+        assert!(target(&["test", "--bind", "0.0.0.0"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_target_must_match_unicast() {
+        let e = source(&["test", "--unicast"]).validate().unwrap_err();
+        assert!(e.to_string().contains("looks like a multicast group"));
+
+        let e = source(&["test", "--target", "192.168.1.10:5000"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("is not a multicast group"));
+
+        source(&["test", "--target", "192.168.1.10:5000", "--unicast"])
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_group_must_be_multicast() {
+        assert!(
+            target(&["test", "--group", "192.168.1.10"])
+                .validate()
+                .is_err()
+        );
+        assert!(
+            target(&["test", "--group", "239.255.0.1:5000"])
+                .validate()
+                .is_err()
+        );
+
+        // The group isn't used with unicast
+        target(&["test", "--group", "192.168.1.10", "--unicast"])
+            .validate()
+            .unwrap();
+    }
+
+    #[derive(Parser)]
+    struct BridgeCli {
+        #[command(flatten)]
+        bridge: BridgeConfig,
+    }
+
+    fn bridge(args: &[&str]) -> BridgeConfig {
+        let matches = BridgeCli::command().get_matches_from(args);
+        BridgeCli::from_arg_matches(&matches).unwrap().bridge
+    }
+
+    #[test]
+    fn test_bridge_sides_are_checked_separately() {
+        let e = bridge(&["test"]).validate().unwrap_err();
+        assert!(e.to_string().contains("--out-target"));
+
+        // Multicast in, unicast out
+        bridge(&["test", "--out-target", "203.0.113.7:5000", "--out-unicast"])
+            .validate()
+            .unwrap();
+
+        // Unicast in, multicast out
+        bridge(&[
+            "test",
+            "--in-unicast",
+            "--in-group",
+            "192.168.1.10",
+            "--out-target",
+            "239.255.0.2:5000",
+        ])
+        .validate()
+        .unwrap();
+
+        let e = bridge(&["test", "--out-target", "203.0.113.7:5000"])
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("--out-unicast"));
+    }
+
+    #[test]
+    fn test_profile_overrides_shared_values() {
+        let file = r#"
+            mode = "target"
+            group = "239.255.0.2"
+            idle_timeout = 30
+
+            [profile.lan]
+            bind = "192.168.1.10:5000"
+
+            [profile.wan]
+            unicast = true
+            idle_timeout = 60
+        "#
+        .parse::<toml::Table>()
+        .unwrap();
+        let path = Path::new("test.toml");
+        let select = |name| {
+            let file = profile(path, file.clone(), name).unwrap();
+            let matches = TestCli::command().get_matches_from(["test"]);
+            let cli = TestCli::from_arg_matches(&matches).unwrap();
+            merge(cli.target, &matches, path, &file).unwrap()
+        };
+
+        let shared = select(None);
+        assert_eq!(shared.bind, "0.0.0.0:5000");
+        assert_eq!(shared.idle_timeout, 30);
+
+        let lan = select(Some("lan"));
+        assert_eq!(lan.bind, "192.168.1.10:5000");
+        assert_eq!(lan.group, "239.255.0.2");
+        assert!(!lan.unicast);
+
+        let wan = select(Some("wan"));
+        assert!(wan.unicast);
+        assert_eq!(wan.idle_timeout, 60);
+
+        let e = profile(path, file.clone(), Some("office")).unwrap_err();
+        assert!(
+            e.to_string().contains("available profiles: lan, wan"),
+            "{}",
+            e
+        );
+        let e = profile(path, toml::Table::new(), Some("lan")).unwrap_err();
+        assert!(e.to_string().contains("defines no profiles"), "{}", e);
+    }
+
+    #[test]
+    fn test_template_parses() {
+        let file = TEMPLATE.parse::<toml::Table>().unwrap();
+        assert_eq!(
+            mode(Path::new("test.toml"), &file).unwrap().as_deref(),
+            Some("target")
+        );
+        assert!(parse(&["test"], TEMPLATE).is_ok());
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::ptr::NonNull;
+use xxhash_rust::xxh3::xxh3_64;
+
+// LZ4 only looks this far back for matches, a larger dictionary is never used
+pub const MAX_DICTIONARY_SIZE: usize = 64 * 1024;
+
+// Size of the pieces a dictionary is trained from. Telemetry updates keep their layout,
+// so the same content shows up at the same offsets of every update.
+const SEGMENT_SIZE: usize = 32;
+
+// Candidate segments kept while training before the ones seen only once are pruned
+const MAX_CANDIDATES: usize = 1 << 20;
+
+// Opaque LZ4_stream_t
+#[repr(C)]
+struct Lz4Stream {
+    _private: [u8; 0],
+}
+
+// The dictionary functions of the LZ4 library linked in by the lz4 crate, which doesn't
+// wrap them
+unsafe extern "C" {
+    fn LZ4_createStream() -> *mut Lz4Stream;
+    fn LZ4_freeStream(stream: *mut Lz4Stream) -> c_int;
+    fn LZ4_loadDict(stream: *mut Lz4Stream, dictionary: *const c_char, size: c_int) -> c_int;
+    fn LZ4_compress_fast_continue(
+        stream: *mut Lz4Stream,
+        source: *const c_char,
+        dest: *mut c_char,
+        source_size: c_int,
+        dest_capacity: c_int,
+        acceleration: c_int,
+    ) -> c_int;
+    fn LZ4_decompress_safe_usingDict(
+        source: *const c_char,
+        dest: *mut c_char,
+        compressed_size: c_int,
+        dest_capacity: c_int,
+        dictionary: *const c_char,
+        dictionary_size: c_int,
+    ) -> c_int;
+}
+
+/// Content LZ4 can refer to when compressing an update, so what every update has in
+/// common doesn't need to be sent. Source and targets have to use the same one, which
+/// they tell apart by its id.
+#[derive(Clone)]
+pub struct Dictionary {
+    data: Vec<u8>,
+    id: u32,
+}
+
+impl Dictionary {
+    pub fn new(data: Vec<u8>) -> io::Result<Self> {
+        if data.is_empty() || data.len() > MAX_DICTIONARY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "A dictionary has to be 1 to {} bytes, not {}",
+                    MAX_DICTIONARY_SIZE,
+                    data.len()
+                ),
+            ));
+        }
+        let id = xxh3_64(&data) as u32;
+        Ok(Self { data, id })
+    }
+
+    /// Reads a dictionary written by `train-dict`
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read dictionary {}: {}", path.display(), e),
+            )
+        })?;
+        Self::new(data).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Identifies the dictionary in the updates compressed with it
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Decompresses a payload compressed with this dictionary into `target`, like
+    /// `lz4::block::decompress_to_buffer` with its size prefix, and returns its length
+    pub fn decompress_to_buffer(&self, payload: &[u8], target: &mut [u8]) -> io::Result<usize> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let (prefix, block) = payload
+            .split_at_checked(4)
+            .ok_or_else(|| invalid("payload too short for its size"))?;
+        let size = i32::from_le_bytes(prefix.try_into().expect("4 bytes"));
+        let size = usize::try_from(size).map_err(|_| invalid("invalid decompressed size"))?;
+        let target = target
+            .get_mut(..size)
+            .ok_or_else(|| invalid("decompressed size is larger than the buffer"))?;
+
+        let len = unsafe {
+            LZ4_decompress_safe_usingDict(
+                block.as_ptr() as *const c_char,
+                target.as_mut_ptr() as *mut c_char,
+                block.len() as c_int,
+                size as c_int,
+                self.data.as_ptr() as *const c_char,
+                self.data.len() as c_int,
+            )
+        };
+        if len < 0 || len as usize != size {
+            return Err(invalid("decompression with the dictionary failed"));
+        }
+        Ok(size)
+    }
+}
+
+/// Compresses updates with a dictionary, reusing the same LZ4 stream for each
+pub struct Compressor {
+    dictionary: Dictionary,
+    stream: NonNull<Lz4Stream>,
+    acceleration: c_int,
+}
+
+// The stream is only ever used through `&mut self`
+unsafe impl Send for Compressor {}
+
+impl Compressor {
+    pub fn new(dictionary: Dictionary) -> io::Result<Self> {
+        let stream = NonNull::new(unsafe { LZ4_createStream() })
+            .ok_or_else(|| io::Error::other("failed to create an LZ4 stream"))?;
+        Ok(Self {
+            dictionary,
+            stream,
+            acceleration: 1,
+        })
+    }
+
+    /// Trades compression ratio for speed, 1 is LZ4's default and higher is faster
+    pub fn set_acceleration(&mut self, acceleration: i32) {
+        self.acceleration = acceleration.max(1);
+    }
+
+    pub fn dictionary(&self) -> &Dictionary {
+        &self.dictionary
+    }
+
+    /// Compresses `data` into `buffer` like `lz4::block::compress_to_buffer` with its size
+    /// prefix, and returns the length of the payload
+    pub fn compress_to_buffer(&mut self, data: &[u8], buffer: &mut [u8]) -> io::Result<usize> {
+        let (prefix, block) = buffer
+            .split_at_mut_checked(4)
+            .ok_or_else(|| io::Error::other("buffer too small"))?;
+        let size = c_int::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "data too large"))?;
+        let capacity = c_int::try_from(block.len()).unwrap_or(c_int::MAX);
+
+        let dictionary = self.dictionary.as_slice();
+        let len = unsafe {
+            // Every update starts from the dictionary, not from the previous update
+            LZ4_loadDict(
+                self.stream.as_ptr(),
+                dictionary.as_ptr() as *const c_char,
+                dictionary.len() as c_int,
+            );
+            LZ4_compress_fast_continue(
+                self.stream.as_ptr(),
+                data.as_ptr() as *const c_char,
+                block.as_mut_ptr() as *mut c_char,
+                size,
+                capacity,
+                self.acceleration,
+            )
+        };
+        if len <= 0 {
+            return Err(io::Error::other("compressed data doesn't fit the buffer"));
+        }
+        prefix.copy_from_slice(&size.to_le_bytes());
+        Ok(4 + len as usize)
+    }
+}
+
+impl Drop for Compressor {
+    fn drop(&mut self) {
+        unsafe {
+            LZ4_freeStream(self.stream.as_ptr());
+        }
+    }
+}
+
+struct Candidate {
+    segment: [u8; SEGMENT_SIZE],
+    offset: usize,
+    samples: u32,
+    last_sample: u32,
+}
+
+/// Builds a dictionary from sample updates out of the segments most of them share
+#[derive(Default)]
+pub struct Trainer {
+    candidates: HashMap<u64, Candidate>,
+    samples: u32,
+}
+
+impl Trainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an update to train from
+    pub fn add(&mut self, sample: &[u8]) {
+        self.samples += 1;
+        for (i, segment) in sample.chunks_exact(SEGMENT_SIZE).enumerate() {
+            // Runs of the same byte compress well without a dictionary
+            if segment.iter().all(|&b| b == segment[0]) {
+                continue;
+            }
+            let candidate = self
+                .candidates
+                .entry(xxh3_64(segment))
+                .or_insert_with(|| Candidate {
+                    segment: segment.try_into().expect("segment size"),
+                    offset: i * SEGMENT_SIZE,
+                    samples: 0,
+                    last_sample: 0,
+                });
+            // Counted once per update, however often it repeats within it
+            if candidate.last_sample != self.samples {
+                candidate.last_sample = self.samples;
+                candidate.samples += 1;
+            }
+        }
+
+        if self.candidates.len() > MAX_CANDIDATES {
+            self.candidates.retain(|_, candidate| candidate.samples > 1);
+        }
+    }
+
+    /// Returns the number of updates added
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Picks the segments shared by the most updates, up to `size` bytes. They are laid
+    /// out in the order they appear in the updates, which keeps neighbouring segments
+    /// together so LZ4 finds longer matches.
+    pub fn finish(self, size: usize) -> Vec<u8> {
+        let size = size.min(MAX_DICTIONARY_SIZE);
+        // Content of a single update is only worth keeping if there's nothing else
+        let min_samples = if self.samples > 1 { 2 } else { 1 };
+        let mut candidates: Vec<_> = self
+            .candidates
+            .into_values()
+            .filter(|candidate| candidate.samples >= min_samples)
+            .collect();
+        candidates.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.offset.cmp(&b.offset)));
+        candidates.truncate(size / SEGMENT_SIZE);
+        candidates.sort_by_key(|candidate| candidate.offset);
+
+        candidates
+            .iter()
+            .flat_map(|candidate| candidate.segment)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An update with a fixed layout description and a few changing values
+    fn sample(value: u32) -> Vec<u8> {
+        let mut sample = Vec::new();
+        for i in 0..200u32 {
+            sample.extend_from_slice(format!("Variable{:04} unit description ", i).as_bytes());
+        }
+        sample.resize(sample.len().next_multiple_of(SEGMENT_SIZE), 0);
+        for i in 0..64 {
+            sample.extend_from_slice(&(value.wrapping_mul(2654435761) ^ i).to_le_bytes());
+        }
+        sample
+    }
+
+    #[test]
+    fn test_trained_dictionary_shrinks_updates() {
+        let mut trainer = Trainer::new();
+        for value in 0..10 {
+            trainer.add(&sample(value));
+        }
+        let dictionary = Dictionary::new(trainer.finish(MAX_DICTIONARY_SIZE)).unwrap();
+        // The changing values differ in every sample, only the layout is shared
+        assert!(dictionary.as_slice().len() < sample(0).len());
+
+        let data = sample(100);
+        let mut compressor = Compressor::new(dictionary.clone()).unwrap();
+        let mut buffer = vec![0u8; data.len() * 2];
+        let len = compressor.compress_to_buffer(&data, &mut buffer).unwrap();
+        let plain = lz4::block::compress(&data, None, true).unwrap();
+        assert!(len < plain.len() / 2, "{} vs {}", len, plain.len());
+
+        let mut decompressed = vec![0u8; data.len()];
+        assert_eq!(
+            dictionary
+                .decompress_to_buffer(&buffer[..len], &mut decompressed)
+                .unwrap(),
+            data.len()
+        );
+        assert_eq!(decompressed, data);
+
+        // The same stream compresses the next update from the dictionary again
+        let len = compressor.compress_to_buffer(&data, &mut buffer).unwrap();
+        dictionary
+            .decompress_to_buffer(&buffer[..len], &mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        // Without the dictionary it doesn't decompress to the update
+        decompressed.fill(0);
+        let other = Dictionary::new(vec![1; 1024]).unwrap();
+        assert_ne!(other.id(), dictionary.id());
+        assert!(
+            other
+                .decompress_to_buffer(&buffer[..len], &mut decompressed)
+                .is_err()
+                || decompressed != data
+        );
+    }
+
+    #[test]
+    fn test_dictionary_size_is_checked() {
+        assert!(Dictionary::new(Vec::new()).is_err());
+        assert!(Dictionary::new(vec![0; MAX_DICTIONARY_SIZE + 1]).is_err());
+        assert!(Dictionary::new(vec![0; MAX_DICTIONARY_SIZE]).is_ok());
+    }
+}
@@ -0,0 +1,216 @@
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::wallclock;
+
+// Directory in the working directory the target writes --dump-frames to
+pub const DUMP_DIR: &str = "teleport-frames";
+
+// Most payloads --dump-frames writes, each takes up to twice --max-size on disk
+pub const MAX_DUMP_FRAMES: usize = 100;
+
+// Identifies a dumped payload, followed by the layout version
+const DUMP_MAGIC: &[u8; 4] = b"TPFD";
+const DUMP_VERSION: u8 = 1;
+
+/// What became of a dumped payload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Written = 0,
+    Corrupt = 1,
+    HashMismatch = 2,
+    SessionInfo = 3,
+}
+
+/// Writes the first complete payloads the target receives to disk, for bug reports
+/// about updates that don't decode. Every payload goes to `frame-NNN.payload` with a
+/// header in front:
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 4    | `TPFD`                                  |
+/// | 4      | 1    | Layout version (1)                      |
+/// | 5      | 1    | `Outcome`                               |
+/// | 6      | 2    | Datagram flags                          |
+/// | 8      | 4    | Source id                               |
+/// | 12     | 4    | Advertised mapping size                 |
+/// | 16     | 2    | Fragments                               |
+/// | 18     | 4    | Payload length                          |
+/// | 22     | 8    | Wall-clock time received, Unix µs       |
+/// | 30     | ...  | Payload, with any hash or dictionary id |
+///
+/// All little-endian. What it decoded to, if it did, goes to `frame-NNN.decoded`.
+pub struct FrameDump {
+    dir: PathBuf,
+    remaining: usize,
+    next: usize,
+}
+
+/// A complete payload and what the datagrams said about it
+pub struct DumpedFrame<'a> {
+    pub flags: u16,
+    pub source_id: u32,
+    pub mapping_size: u32,
+    pub fragments: u16,
+    pub payload: &'a [u8],
+}
+
+impl FrameDump {
+    /// Starts a dump into `dir`, removing the payloads an earlier run dumped there so
+    /// the directory only holds this run's
+    pub fn new(dir: &Path, count: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)
+            .and_then(|_| clear(dir))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Failed to prepare {} for --dump-frames: {}",
+                        dir.display(),
+                        e
+                    ),
+                )
+            })?;
+        info!(
+            "Writing the first {} payloads to {} (--dump-frames)",
+            count,
+            dir.display()
+        );
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            remaining: count,
+            next: 0,
+        })
+    }
+
+    /// Writes a payload and what it decoded to, until the count is reached. Failing to
+    /// write stops the dump rather than the target.
+    pub fn write(&mut self, frame: &DumpedFrame, outcome: Outcome, decoded: Option<&[u8]>) {
+        if self.remaining == 0 {
+            return;
+        }
+        let name = format!("frame-{:03}", self.next);
+        match self.write_files(&name, frame, outcome, decoded) {
+            Ok(path) => {
+                info!("Dumped payload to {}", path.display());
+                self.remaining -= 1;
+                self.next += 1;
+                if self.remaining == 0 {
+                    info!(
+                        "Done dumping payloads, attach {} to the issue",
+                        self.dir.display()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to dump payload, stopping --dump-frames: {}", e);
+                self.remaining = 0;
+            }
+        }
+    }
+
+    fn write_files(
+        &self,
+        name: &str,
+        frame: &DumpedFrame,
+        outcome: Outcome,
+        decoded: Option<&[u8]>,
+    ) -> io::Result<PathBuf> {
+        let mut data = Vec::with_capacity(30 + frame.payload.len());
+        data.extend_from_slice(DUMP_MAGIC);
+        data.push(DUMP_VERSION);
+        data.push(outcome as u8);
+        data.extend_from_slice(&frame.flags.to_le_bytes());
+        data.extend_from_slice(&frame.source_id.to_le_bytes());
+        data.extend_from_slice(&frame.mapping_size.to_le_bytes());
+        data.extend_from_slice(&frame.fragments.to_le_bytes());
+        data.extend_from_slice(&(frame.payload.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(wallclock::now().as_micros() as u64).to_le_bytes());
+        data.extend_from_slice(frame.payload);
+
+        let path = self.dir.join(format!("{}.payload", name));
+        fs::write(&path, data)?;
+        if let Some(decoded) = decoded {
+            fs::write(self.dir.join(format!("{}.decoded", name)), decoded)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Removes the files of an earlier dump from `dir`, leaving anything else in it
+fn clear(dir: &Path) -> io::Result<()> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let dumped = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| {
+                name.starts_with("frame-")
+                    && (name.ends_with(".payload") || name.ends_with(".decoded"))
+            });
+        if dumped && path.is_file() {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        info!(
+            "Removed {} files of an earlier dump from {}",
+            removed,
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_stops_at_the_count() {
+        let dir = std::env::temp_dir().join(format!("teleport-dump-{}", std::process::id()));
+        let mut dump = FrameDump::new(&dir, 2).unwrap();
+        let frame = DumpedFrame {
+            flags: 0x0102,
+            source_id: 7,
+            mapping_size: 4096,
+            fragments: 3,
+            payload: b"compressed",
+        };
+        dump.write(&frame, Outcome::Written, Some(b"decoded"));
+        dump.write(&frame, Outcome::Corrupt, None);
+        dump.write(&frame, Outcome::Written, Some(b"decoded"));
+
+        let payload = fs::read(dir.join("frame-000.payload")).unwrap();
+        assert_eq!(&payload[..6], b"TPFD\x01\x00");
+        assert_eq!(&payload[6..8], &0x0102u16.to_le_bytes());
+        assert_eq!(&payload[18..22], &10u32.to_le_bytes());
+        assert_eq!(&payload[30..], b"compressed");
+        assert_eq!(fs::read(dir.join("frame-000.decoded")).unwrap(), b"decoded");
+
+        // The corrupt one has nothing decoded, and the third isn't written
+        assert_eq!(fs::read(dir.join("frame-001.payload")).unwrap()[5], 1);
+        assert!(!dir.join("frame-001.decoded").exists());
+        assert!(!dir.join("frame-002.payload").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dump_replaces_an_earlier_one() {
+        let dir = std::env::temp_dir().join(format!("teleport-redump-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("frame-000.payload"), b"earlier").unwrap();
+        fs::write(dir.join("frame-007.decoded"), b"earlier").unwrap();
+        fs::write(dir.join("notes.txt"), b"kept").unwrap();
+
+        FrameDump::new(&dir, 1).unwrap();
+        assert!(!dir.join("frame-000.payload").exists());
+        assert!(!dir.join("frame-007.decoded").exists());
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
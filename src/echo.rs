@@ -0,0 +1,295 @@
+use log::{info, warn};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::net;
+use crate::protocol::MAX_DATAGRAM_SIZE;
+use crate::wallclock;
+
+// Starts every ping, followed by its number. An echo reflects anything, the ping only
+// counts its own.
+const PING_MAGIC: [u8; 4] = *b"TPNG";
+const PING_HEADER_SIZE: usize = PING_MAGIC.len() + 4;
+
+// Most pings sent in one run, each is tracked until its reply arrives
+pub const MAX_PING_COUNT: u32 = 100_000;
+
+// Time to wait for the replies to the last pings before giving up on them
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reflects every datagram received on `addr` back to its sender, printing where it came
+/// from and its size, until shut down
+pub fn run(addr: &str, shutdown: Receiver<()>) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind to {}: {}", addr, e)))?;
+    info!(
+        "Echoing datagrams received on {}, run `iracing-teleport ping` on the other computer",
+        addr
+    );
+    serve(&socket, &shutdown)
+}
+
+fn serve(socket: &UdpSocket, shutdown: &Receiver<()>) -> io::Result<()> {
+    // Set a short timeout on UDP receive to stay responsive to shutdown
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set socket timeout: {}", e)))?;
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        if shutdown.try_recv().is_ok() {
+            return Ok(());
+        }
+
+        match socket.recv_from(&mut buf) {
+            Ok((amt, from)) => {
+                info!("{} {} bytes from {}", wallclock::timestamp(), amt, from);
+                if let Err(e) = socket.send_to(&buf[..amt], from) {
+                    warn!("Failed to echo back to {}: {}", from, e);
+                }
+            }
+            // Windows reports a reply that found nobody listening as a reset
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                        | io::ErrorKind::ConnectionReset
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// What a run of pings came back with
+#[derive(Debug, Default)]
+pub struct PingSummary {
+    pub sent: u32,
+    pub received: u32,
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    total: Duration,
+}
+
+impl PingSummary {
+    fn add(&mut self, rtt: Duration) {
+        self.received += 1;
+        self.total += rtt;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        (self.received > 0).then(|| self.total / self.received)
+    }
+
+    /// Formats the summary like the last lines of `ping`
+    pub fn render(&self) -> String {
+        let lost = self.sent - self.received;
+        let mut line = format!(
+            "{} sent, {} received, {:.1}% lost",
+            self.sent,
+            self.received,
+            if self.sent > 0 {
+                lost as f64 * 100.0 / self.sent as f64
+            } else {
+                0.0
+            }
+        );
+        if let (Some(min), Some(avg), Some(max)) = (self.min, self.avg(), self.max) {
+            line.push_str(&format!(
+                " | RTT min/avg/max: {:.2}/{:.2}/{:.2} ms",
+                min.as_secs_f64() * 1000.0,
+                avg.as_secs_f64() * 1000.0,
+                max.as_secs_f64() * 1000.0
+            ));
+        }
+        line
+    }
+}
+
+/// Sends `count` datagrams of `size` bytes to the echo at `target`, one every `interval`,
+/// and times the replies. Fails if none come back, or if `count` is 0 or more than
+/// `MAX_PING_COUNT`.
+pub fn ping(
+    target: &str,
+    count: u32,
+    interval: Duration,
+    size: usize,
+    shutdown: &Receiver<()>,
+) -> io::Result<PingSummary> {
+    if !(1..=MAX_PING_COUNT).contains(&count) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The ping count must be 1 to {}", MAX_PING_COUNT),
+        ));
+    }
+    let destination = net::resolve(target)?;
+    let size = size.clamp(PING_HEADER_SIZE, MAX_DATAGRAM_SIZE);
+    let bind = match destination {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind UDP socket: {}", e)))?;
+    info!(
+        "Pinging the echo at {} with {} datagrams of {} bytes",
+        destination, count, size
+    );
+
+    let mut summary = PingSummary::default();
+    let mut sent_at = vec![None; count as usize];
+    let mut datagram = vec![0u8; size];
+    datagram[..PING_MAGIC.len()].copy_from_slice(&PING_MAGIC);
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    for sequence in 0..count {
+        if shutdown.try_recv().is_ok() {
+            break;
+        }
+        datagram[PING_MAGIC.len()..PING_HEADER_SIZE].copy_from_slice(&sequence.to_le_bytes());
+        if let Err(e) = socket.send_to(&datagram, destination) {
+            warn!("Failed to send ping {}: {}", sequence, e);
+        }
+        sent_at[sequence as usize] = Some(Instant::now());
+        summary.sent += 1;
+
+        // The next ping goes out after the interval, the last one is waited for until it
+        // and the ones before it are back or the timeout is up
+        let last = sequence + 1 == count;
+        let wait = if last { REPLY_TIMEOUT } else { interval };
+        receive_replies(&socket, &mut buf, &mut sent_at, &mut summary, wait, last)?;
+    }
+
+    info!("{}", summary.render());
+    if summary.received == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "No replies from {}. Check that `iracing-teleport echo` runs there on that \
+                 port and that no firewall blocks UDP in either direction.",
+                destination
+            ),
+        ));
+    }
+    Ok(summary)
+}
+
+/// Times the replies that arrive within `wait`, each ping counts once. Returns early
+/// once every ping is back if `until_all_back`.
+fn receive_replies(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    sent_at: &mut [Option<Instant>],
+    summary: &mut PingSummary,
+    wait: Duration,
+    until_all_back: bool,
+) -> io::Result<()> {
+    let deadline = Instant::now() + wait;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        let (amt, from) = match socket.recv_from(buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                        | io::ErrorKind::ConnectionReset
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let Some(sequence) = parse_ping(&buf[..amt]) else {
+            continue;
+        };
+        if let Some(sent) = sent_at.get_mut(sequence as usize).and_then(Option::take) {
+            let rtt = sent.elapsed();
+            info!(
+                "{} bytes from {}: seq={} time={:.2} ms",
+                amt,
+                from,
+                sequence,
+                rtt.as_secs_f64() * 1000.0
+            );
+            summary.add(rtt);
+        }
+        if until_all_back && summary.received == summary.sent {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of a ping, or `None` if `data` isn't one
+fn parse_ping(data: &[u8]) -> Option<u32> {
+    if data.get(..PING_MAGIC.len())? != PING_MAGIC {
+        return None;
+    }
+    let sequence = data.get(PING_MAGIC.len()..PING_HEADER_SIZE)?;
+    Some(u32::from_le_bytes(sequence.try_into().expect("4 bytes")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_pings_are_echoed_and_timed() {
+        let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = echo.local_addr().unwrap().to_string();
+        let (stop_echo, echo_shutdown) = mpsc::channel();
+        let server = thread::spawn(move || serve(&echo, &echo_shutdown));
+
+        let (_keep, shutdown) = mpsc::channel();
+        let started = Instant::now();
+        let summary = ping(&addr, 5, Duration::from_millis(10), 1200, &shutdown).unwrap();
+        assert_eq!((summary.sent, summary.received), (5, 5));
+        // Paced by the interval, not by the replies
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert!(summary.min <= summary.avg() && summary.avg() <= summary.max);
+        let line = summary.render();
+        assert!(
+            line.starts_with("5 sent, 5 received, 0.0% lost"),
+            "{}",
+            line
+        );
+
+        stop_echo.send(()).unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_ping_without_an_echo_fails() {
+        // Bound but never answering
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap().to_string();
+
+        let (_keep, shutdown) = mpsc::channel();
+        let e = ping(&addr, 1, Duration::ZERO, 64, &shutdown).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(e.to_string().contains("iracing-teleport echo"), "{}", e);
+
+        // Nothing to wait for without pings
+        let e = ping(&addr, 0, Duration::ZERO, 64, &shutdown).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_only_pings_count() {
+        let mut ping = PING_MAGIC.to_vec();
+        ping.extend_from_slice(&7u32.to_le_bytes());
+        assert_eq!(parse_ping(&ping), Some(7));
+        assert_eq!(parse_ping(&ping[..6]), None);
+        assert_eq!(parse_ping(b"hello there"), None);
+    }
+}
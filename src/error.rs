@@ -0,0 +1,96 @@
+use std::{error, fmt, io};
+
+use crate::telemetry::TelemetryError;
+
+/// Errors that stop a source or target, grouped by what failed so applications embedding
+/// teleport can handle them without parsing the messages
+#[derive(Debug)]
+pub enum TeleportError {
+    /// The telemetry couldn't be opened, created, read or signaled
+    Telemetry {
+        action: &'static str,
+        error: TelemetryError,
+    },
+    /// A socket couldn't be set up, or sending or receiving failed for good
+    Transport(io::Error),
+    /// A peer sent data that can't be handled
+    Protocol(String),
+    /// The options can't be run as given
+    Config(String),
+    /// Nothing to teleport arrived within the --startup-timeout
+    NoData(String),
+    /// A file couldn't be written, e.g. a trained dictionary
+    File(io::Error),
+}
+
+impl TeleportError {
+    /// Wraps a failure of the telemetry, `action` completing "Failed to ..."
+    pub fn telemetry(action: &'static str, error: TelemetryError) -> Self {
+        TeleportError::Telemetry { action, error }
+    }
+}
+
+impl fmt::Display for TeleportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TeleportError::Telemetry { action, error } => {
+                write!(f, "Failed to {}: {}", action, error)
+            }
+            TeleportError::Transport(e) | TeleportError::File(e) => write!(f, "{}", e),
+            TeleportError::Protocol(message) => write!(f, "Protocol error: {}", message),
+            TeleportError::Config(message) | TeleportError::NoData(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl error::Error for TeleportError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TeleportError::Telemetry { error, .. } => Some(error),
+            TeleportError::Transport(e) | TeleportError::File(e) => Some(e),
+            TeleportError::Protocol(_) | TeleportError::Config(_) | TeleportError::NoData(_) => {
+                None
+            }
+        }
+    }
+}
+
+impl From<io::Error> for TeleportError {
+    fn from(e: io::Error) -> Self {
+        TeleportError::Transport(e)
+    }
+}
+
+impl From<TeleportError> for io::Error {
+    fn from(e: TeleportError) -> Self {
+        match e {
+            TeleportError::Transport(e) | TeleportError::File(e) => e,
+            TeleportError::Config(message) => io::Error::new(io::ErrorKind::InvalidInput, message),
+            e => io::Error::other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_match_the_wrapped_errors() {
+        let e = TeleportError::from(io::Error::other("Failed to bind UDP socket: in use"));
+        assert_eq!(e.to_string(), "Failed to bind UDP socket: in use");
+        assert!(error::Error::source(&e).is_some());
+
+        let e = TeleportError::telemetry("create telemetry", TelemetryError::AlreadyExists);
+        assert_eq!(
+            e.to_string(),
+            "Failed to create telemetry: Telemetry mapping already exists"
+        );
+
+        let e = io::Error::from(TeleportError::Config("no dry run".into()));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "no dry run");
+    }
+}
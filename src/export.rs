@@ -0,0 +1,344 @@
+use log::{info, warn};
+use serde_json::{Map, Number, Value as Json};
+use std::io;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, Utf8Bytes};
+
+use crate::irsdk::{self, Value, VarHeader, VarType};
+
+// Updates queued per WebSocket client, further updates are dropped until it catches up
+const WS_CLIENT_QUEUE: usize = 4;
+
+// How long a WebSocket client waits for incoming messages between updates
+const WS_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// How long a WebSocket client waits for an update before checking for incoming messages
+const WS_UPDATE_WAIT: Duration = Duration::from_millis(100);
+
+// Time limit for the handshake and for writing a single update
+const WS_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Builds JSON objects with a selection of telemetry variables for consumers that
+/// don't speak the iRacing shared memory format
+pub struct JsonExporter {
+    names: Vec<String>,
+    // Selected variables, resolved once per variable header layout
+    layout: Option<((i32, i32, i32), Vec<VarHeader>)>,
+    missing_header_reported: bool,
+}
+
+impl JsonExporter {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            names,
+            layout: None,
+            missing_header_reported: false,
+        }
+    }
+
+    /// Serializes the selected variables from the latest variable buffer of `mapping`
+    pub fn encode(&mut self, mapping: &[u8]) -> Option<String> {
+        let Some(header) = irsdk::Header::parse(mapping) else {
+            if !self.missing_header_reported {
+                warn!("Telemetry has no iRacing header, nothing to export");
+                self.missing_header_reported = true;
+            }
+            return None;
+        };
+
+        // The variable headers only change when the simulator restarts
+        let key = (header.num_vars, header.var_header_offset, header.buf_len);
+        if self.layout.as_ref().is_none_or(|(k, _)| *k != key) {
+            self.layout = Some((key, self.resolve(&header, mapping)));
+        }
+
+        let (tick, buf) = header.latest_var_buf(mapping)?;
+        let (_, vars) = self.layout.as_ref()?;
+
+        let mut object = Map::new();
+        object.insert("tick".into(), tick.into());
+        for var in vars {
+            let mut values = (0..var.count).filter_map(|i| var.value(buf, i).map(to_json));
+            let value = if var.var_type == VarType::Char {
+                // Character arrays are strings
+                let bytes: Vec<u8> = (0..var.count)
+                    .filter_map(|i| match var.value(buf, i) {
+                        Some(Value::Char(c)) if c != 0 => Some(c),
+                        _ => None,
+                    })
+                    .collect();
+                String::from_utf8_lossy(&bytes).into_owned().into()
+            } else if var.count == 1 {
+                values.next().unwrap_or(Json::Null)
+            } else {
+                Json::Array(values.collect())
+            };
+            object.insert(var.name.clone(), value);
+        }
+
+        Some(Json::Object(object).to_string())
+    }
+
+    fn resolve(&self, header: &irsdk::Header, mapping: &[u8]) -> Vec<VarHeader> {
+        let available = header.var_headers(mapping);
+        let mut selected = Vec::with_capacity(self.names.len());
+        for name in &self.names {
+            match available.iter().find(|var| &var.name == name) {
+                Some(var) => selected.push(var.clone()),
+                None => warn!("Telemetry variable '{}' not found, skipping it", name),
+            }
+        }
+        selected
+    }
+}
+
+fn to_json(value: Value) -> Json {
+    match value {
+        Value::Char(c) => c.into(),
+        Value::Bool(b) => b.into(),
+        Value::Int(i) => i.into(),
+        Value::BitField(bits) => bits.into(),
+        Value::Float(f) => Number::from_f64(f as f64).map_or(Json::Null, Json::Number),
+        Value::Double(d) => Number::from_f64(d).map_or(Json::Null, Json::Number),
+    }
+}
+
+/// Pushes one JSON object per update over UDP
+pub struct UdpExport {
+    socket: UdpSocket,
+}
+
+impl UdpExport {
+    pub fn new(addr: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(addr).map(|_| socket))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to set up JSON export to {}: {}", addr, e),
+                )
+            })?;
+        info!("Exporting JSON to {}", addr);
+
+        Ok(Self { socket })
+    }
+
+    pub fn send(&self, json: &str) {
+        // The export is best-effort and must not interrupt the telemetry mapping
+        if let Err(e) = self.socket.send(json.as_bytes()) {
+            warn!("Failed to send JSON export: {}", e);
+        }
+    }
+}
+
+/// Pushes one JSON object per update to all connected WebSocket clients. Each client is
+/// served from its own thread, so a slow client only misses updates and never holds up
+/// the receive loop or the other clients.
+pub struct WsBroadcast {
+    clients: Arc<Mutex<Vec<SyncSender<Utf8Bytes>>>>,
+    #[cfg(test)]
+    local_addr: std::net::SocketAddr,
+}
+
+impl WsBroadcast {
+    pub fn start(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to start WebSocket server on {}: {}", addr, e),
+            )
+        })?;
+        info!("WebSocket server listening on {}", listener.local_addr()?);
+
+        let broadcast = Self {
+            clients: Arc::new(Mutex::new(Vec::new())),
+            #[cfg(test)]
+            local_addr: listener.local_addr()?,
+        };
+
+        // Only a weak reference, so dropping the broadcast disconnects all clients
+        let clients = Arc::downgrade(&broadcast.clients);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Some(clients) = clients.upgrade() else {
+                    break;
+                };
+
+                match stream {
+                    Ok(stream) => {
+                        let (tx, rx) = mpsc::sync_channel(WS_CLIENT_QUEUE);
+                        clients.lock().unwrap().push(tx);
+                        thread::spawn(move || serve_client(stream, rx));
+                    }
+                    Err(e) => warn!("Failed to accept WebSocket client: {}", e),
+                }
+            }
+        });
+
+        Ok(broadcast)
+    }
+
+    pub fn send(&self, json: &str) {
+        let text = Utf8Bytes::from(json);
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.try_send(text.clone()) {
+                // A client with a full queue skips this update
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+fn serve_client(stream: TcpStream, updates: Receiver<Utf8Bytes>) {
+    let peer = stream
+        .peer_addr()
+        .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+
+    let _ = stream.set_read_timeout(Some(WS_IO_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(WS_IO_TIMEOUT));
+    let mut ws = match tungstenite::accept(stream) {
+        Ok(ws) => ws,
+        Err(e) => {
+            warn!("WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    info!("WebSocket client connected: {}", peer);
+
+    // Poll for incoming messages between updates so pings and close requests are answered
+    let _ = ws.get_ref().set_read_timeout(Some(WS_POLL_INTERVAL));
+
+    loop {
+        match updates.recv_timeout(WS_UPDATE_WAIT) {
+            Ok(text) => {
+                if ws.send(Message::Text(text)).is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                // The target is shutting down
+                let _ = ws.close(None);
+                let _ = ws.flush();
+                break;
+            }
+        }
+
+        match ws.read() {
+            Ok(Message::Close(_)) => {
+                // Send the close reply queued by tungstenite
+                let _ = ws.flush();
+                break;
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(_) => break,
+        }
+    }
+
+    info!("WebSocket client disconnected: {}", peer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irsdk::{Header, IRSDK_VER, VAR_HEADER_SIZE, VarBuf};
+
+    fn var(name: &str, var_type: VarType, offset: usize, count: usize) -> VarHeader {
+        VarHeader {
+            var_type,
+            offset,
+            count,
+            name: name.into(),
+            desc: String::new(),
+            unit: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_selected_variables_are_exported() {
+        let mut mapping = vec![0u8; 4096];
+        let vars = [
+            var("Speed", VarType::Float, 0, 1),
+            var("Gear", VarType::Int, 4, 1),
+            var("OnPitRoad", VarType::Bool, 8, 1),
+            var("CarIdxLap", VarType::Int, 12, 2),
+        ];
+        for (i, var) in vars.iter().enumerate() {
+            var.write(&mut mapping[256 + i * VAR_HEADER_SIZE..]);
+        }
+
+        let mut var_bufs = [VarBuf::default(); 4];
+        var_bufs[0] = VarBuf {
+            tick_count: 7,
+            buf_offset: 2048,
+        };
+        Header {
+            ver: IRSDK_VER,
+            num_vars: vars.len() as i32,
+            var_header_offset: 256,
+            num_buf: 1,
+            buf_len: 32,
+            var_bufs,
+            ..Default::default()
+        }
+        .write(&mut mapping);
+
+        mapping[2048..2052].copy_from_slice(&12.5f32.to_le_bytes());
+        mapping[2052..2056].copy_from_slice(&4i32.to_le_bytes());
+        mapping[2056] = 1;
+        mapping[2060..2064].copy_from_slice(&3i32.to_le_bytes());
+        mapping[2064..2068].copy_from_slice(&5i32.to_le_bytes());
+
+        let mut exporter = JsonExporter::new(
+            ["Gear", "Speed", "CarIdxLap", "OnPitRoad", "Unknown"]
+                .map(String::from)
+                .to_vec(),
+        );
+        assert_eq!(
+            exporter.encode(&mapping).unwrap(),
+            r#"{"CarIdxLap":[3,5],"Gear":4,"OnPitRoad":true,"Speed":12.5,"tick":7}"#
+        );
+    }
+
+    #[test]
+    fn test_no_export_without_header() {
+        let mut exporter = JsonExporter::new(vec!["Speed".into()]);
+        assert_eq!(exporter.encode(&[0u8; 1024]), None);
+    }
+
+    #[test]
+    fn test_websocket_clients_receive_updates() {
+        let broadcast = WsBroadcast::start("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", broadcast.local_addr);
+        let (mut client, _) = tungstenite::connect(url).unwrap();
+
+        // The client is registered asynchronously, keep sending until it shows up
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while broadcast.clients.lock().unwrap().is_empty() {
+            assert!(std::time::Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        broadcast.send(r#"{"tick":1}"#);
+        assert_eq!(client.read().unwrap(), Message::text(r#"{"tick":1}"#));
+
+        // Dropping the broadcast closes the connection
+        drop(broadcast);
+        loop {
+            match client.read() {
+                Ok(Message::Close(_)) => break,
+                Ok(_) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::http::{HttpServer, Response};
+
+// Time without updates or heartbeats after which a source is reported unhealthy, and
+// a target running with --idle-timeout 0
+pub const DEFAULT_HEALTH_WINDOW: Duration = Duration::from_secs(10);
+
+/// Answers health checks on --health-addr for supervisors that restart a stuck
+/// instance: any request gets a 200 while updates or heartbeats came in within the
+/// window, and a 503 otherwise
+pub struct HealthCheck {
+    started: Instant,
+    // Milliseconds since `started` of the last activity plus one, 0 before any
+    last_activity: Arc<AtomicU64>,
+    // Answers until dropped along with the check
+    _server: HttpServer,
+}
+
+impl HealthCheck {
+    pub fn start(addr: &str, window: Duration) -> io::Result<Self> {
+        let started = Instant::now();
+        let last_activity = Arc::new(AtomicU64::new(0));
+        let activity = last_activity.clone();
+        let server = HttpServer::start(addr, "health check", move |_| {
+            let idle = match activity.load(Ordering::Relaxed) {
+                0 => None,
+                ms => Some(
+                    started
+                        .elapsed()
+                        .saturating_sub(Duration::from_millis(ms - 1)),
+                ),
+            };
+            check(idle, window)
+        })?;
+
+        Ok(Self {
+            started,
+            last_activity,
+            _server: server,
+        })
+    }
+
+    /// Records an update or heartbeat, which keeps the instance healthy for the window
+    pub fn mark(&self) {
+        let ms = self.started.elapsed().as_millis() as u64 + 1;
+        self.last_activity.store(ms, Ordering::Relaxed);
+    }
+}
+
+/// The answer to a health check, `idle` being how long ago the last activity was, if
+/// there was any
+fn check(idle: Option<Duration>, window: Duration) -> Response {
+    match idle {
+        Some(idle) if idle <= window => Response::text("200 OK", "healthy\n".to_string()),
+        Some(idle) => Response::text(
+            "503 Service Unavailable",
+            format!("no updates for {} seconds\n", idle.as_secs()),
+        ),
+        None => Response::text("503 Service Unavailable", "no updates yet\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http;
+    use std::thread;
+
+    #[test]
+    fn test_health_follows_the_activity() {
+        let health = HealthCheck::start("127.0.0.1:0", Duration::from_millis(200)).unwrap();
+        let check = || http::get(health._server.local_addr(), "/health");
+        let response = check();
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.ends_with("no updates yet\n"), "{}", response);
+
+        health.mark();
+        let response = check();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        thread::sleep(Duration::from_millis(300));
+        assert!(check().starts_with("HTTP/1.1 503"));
+    }
+}
@@ -0,0 +1,184 @@
+use log::{info, warn};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Time limit for reading the request and writing the response, so a client that
+// doesn't send anything can't hold up the others
+const HTTP_IO_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Longest request read, only the request line matters
+const HTTP_MAX_REQUEST: usize = 1024;
+
+/// The answer to a request
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body,
+        }
+    }
+
+    pub fn json(body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+}
+
+/// Serves the small read-only HTTP endpoints, like --health-addr and --api-addr, one
+/// request at a time from a thread of its own. Only GET is answered, `handler` gets the
+/// path. The thread stops when the server is dropped.
+pub struct HttpServer {
+    local_addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl HttpServer {
+    pub fn start<F>(addr: &str, name: &str, handler: F) -> io::Result<Self>
+    where
+        F: Fn(&str) -> Response + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to start the {} on {}: {}", name, addr, e),
+            )
+        })?;
+        let local_addr = listener.local_addr()?;
+        info!("{} listening on {}", capitalize(name), local_addr);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let result = stream.and_then(|stream| respond(stream, &handler));
+                if let Err(e) = result {
+                    warn!("Failed to answer a request to the {}: {}", name, e);
+                }
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            stopped,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        // Wakes the thread up from accepting, it sees the flag and stops
+        self.stopped.store(true, Ordering::Relaxed);
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect_timeout(&addr, HTTP_IO_TIMEOUT);
+    }
+}
+
+/// Reads a request and answers it with what `handler` returns for its path
+fn respond<F>(mut stream: TcpStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&str) -> Response,
+{
+    stream.set_read_timeout(Some(HTTP_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_IO_TIMEOUT))?;
+
+    // Read up to the end of the headers, closing with unread data would reset the
+    // connection before the client has the response
+    let mut request = [0u8; HTTP_MAX_REQUEST];
+    let mut len = 0;
+    while len < request.len() && !request[..len].ends_with(b"\r\n\r\n") {
+        match stream.read(&mut request[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(path)) => handler(path),
+        _ => Response::text("405 Method Not Allowed", "Only GET is supported\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// Sends a GET for `path` and returns the whole response
+#[cfg(test)]
+pub fn get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_answers_get_until_dropped() {
+        let server = HttpServer::start("127.0.0.1:0", "test server", |path| {
+            Response::text("200 OK", format!("{}\n", path))
+        })
+        .unwrap();
+        let addr = server.local_addr();
+        let response = get(addr, "/status");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n/status\n"), "{}", response);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"POST /status HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"), "{}", response);
+
+        // The port is released once the thread is gone
+        drop(server);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(addr).is_ok() {
+            assert!(std::time::Instant::now() < deadline, "still listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
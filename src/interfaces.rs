@@ -0,0 +1,219 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A local network interface as shown by the `interfaces` command
+pub struct Interface {
+    pub name: String,
+    pub up: bool,
+    pub multicast: bool,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl Interface {
+    /// Returns the first IPv4 address of the interface
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addrs.iter().find_map(|addr| match addr {
+            IpAddr::V4(ip) => Some(*ip),
+            IpAddr::V6(_) => None,
+        })
+    }
+}
+
+/// Lists the local network interfaces with their addresses
+pub fn list() -> Vec<Interface> {
+    netdev::get_interfaces()
+        .into_iter()
+        .map(|interface| Interface {
+            // Windows names interfaces by GUID, the friendly name is the one users know
+            name: interface
+                .friendly_name
+                .clone()
+                .unwrap_or_else(|| interface.name.clone()),
+            up: interface.is_up(),
+            multicast: interface.is_multicast(),
+            addrs: interface.ip_addrs(),
+        })
+        .collect()
+}
+
+/// Returns the IPv4 address of an interface given by its address or its name
+pub fn ipv4_address(interfaces: &[Interface], value: &str) -> io::Result<Ipv4Addr> {
+    if let Ok(ip) = value.parse::<Ipv4Addr>() {
+        return Ok(ip);
+    }
+
+    let interface = interfaces
+        .iter()
+        .find(|interface| interface.name.eq_ignore_ascii_case(value))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No network interface named '{}', run `iracing-teleport interfaces` to list them",
+                    value
+                ),
+            )
+        })?;
+
+    interface.ipv4().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Network interface '{}' has no IPv4 address", value),
+        )
+    })
+}
+
+/// Returns the name and IPv4 address of the interfaces a multicast group can be joined
+/// on: up, supporting multicast and with an IPv4 address
+pub fn multicast_candidates(interfaces: &[Interface]) -> Vec<(&str, Ipv4Addr)> {
+    interfaces
+        .iter()
+        .filter(|interface| interface.up && interface.multicast)
+        .filter_map(|interface| Some((interface.name.as_str(), interface.ipv4()?)))
+        .collect()
+}
+
+/// Formats the interfaces as a table, one row per address
+pub fn format_table(interfaces: &[Interface]) -> String {
+    let name_width = interfaces
+        .iter()
+        .map(|interface| interface.name.chars().count())
+        .chain(["NAME".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:<name_width$}  {:<4}  {:<9}  ADDRESS\n",
+        "NAME", "UP", "MULTICAST"
+    );
+
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    for interface in interfaces {
+        let addrs: Vec<String> = match interface.addrs.as_slice() {
+            [] => vec!["-".to_string()],
+            addrs => addrs.iter().map(IpAddr::to_string).collect(),
+        };
+
+        for (i, addr) in addrs.iter().enumerate() {
+            // Only the first row of an interface repeats its details
+            let (name, up, multicast) = if i == 0 {
+                (
+                    interface.name.as_str(),
+                    yes_no(interface.up),
+                    yes_no(interface.multicast),
+                )
+            } else {
+                ("", "", "")
+            };
+            table.push_str(&format!(
+                "{:<name_width$}  {:<4}  {:<9}  {}\n",
+                name, up, multicast, addr
+            ));
+        }
+    }
+
+    table
+}
+
+/// Prints the local network interfaces for picking a `--bind` address
+pub fn print() -> io::Result<()> {
+    let interfaces = list();
+    if interfaces.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No network interfaces found",
+        ));
+    }
+
+    print!("{}", format_table(&interfaces));
+    println!();
+    println!(
+        "Bind the target to the IPv4 address of an interface that is up and supports \
+         multicast, e.g. --bind <address>:5000"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipv4_address_by_address_or_name() {
+        let interfaces = [
+            Interface {
+                name: "Ethernet 2".into(),
+                up: true,
+                multicast: true,
+                addrs: vec!["fe80::1".parse().unwrap(), "192.168.1.5".parse().unwrap()],
+            },
+            Interface {
+                name: "lo".into(),
+                up: true,
+                multicast: false,
+                addrs: vec!["::1".parse().unwrap()],
+            },
+        ];
+
+        let ip = |value| ipv4_address(&interfaces, value);
+        assert_eq!(ip("10.0.0.5").unwrap(), Ipv4Addr::new(10, 0, 0, 5));
+        assert_eq!(ip("ethernet 2").unwrap(), Ipv4Addr::new(192, 168, 1, 5));
+        assert!(ip("lo").is_err());
+        assert!(ip("wlan0").is_err());
+    }
+
+    #[test]
+    fn test_multicast_candidates() {
+        let interface = |name: &str, up, multicast, addrs: &[&str]| Interface {
+            name: name.into(),
+            up,
+            multicast,
+            addrs: addrs.iter().map(|addr| addr.parse().unwrap()).collect(),
+        };
+        let interfaces = [
+            interface("lo", true, false, &["127.0.0.1"]),
+            interface("Ethernet", true, true, &["fe80::1", "192.168.1.5"]),
+            interface("Wi-Fi", false, true, &["10.0.0.7"]),
+            interface("VPN", true, true, &["fd00::2"]),
+            interface("Ethernet 2", true, true, &["172.16.0.3"]),
+        ];
+
+        assert_eq!(
+            multicast_candidates(&interfaces),
+            [
+                ("Ethernet", Ipv4Addr::new(192, 168, 1, 5)),
+                ("Ethernet 2", Ipv4Addr::new(172, 16, 0, 3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_table() {
+        let interfaces = [
+            Interface {
+                name: "lo".into(),
+                up: true,
+                multicast: false,
+                addrs: vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+            },
+            Interface {
+                name: "Ethernet 2".into(),
+                up: false,
+                multicast: true,
+                addrs: Vec::new(),
+            },
+        ];
+
+        let table = format_table(&interfaces);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "NAME        UP    MULTICAST  ADDRESS",
+                "lo          yes   no         127.0.0.1",
+                "                             ::1",
+                "Ethernet 2  no    yes        -",
+            ]
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use std::f64::consts::TAU;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::irsdk::{Header, MAX_BUFS, VarHeader, VarType};
+
+// Most ticks between two updates that are filled in. Larger gaps come from lost updates
+// or a source that paused, and are written as they are.
+const MAX_STEPS: i32 = 8;
+
+/// Fills in the ticks a source sending fewer updates skips, for --interpolate. When an
+/// update is several ticks ahead of the last one written, the frames for the ticks in
+/// between are written first, one per tick, and the update's own values only on the last.
+///
+/// iRacing keeps the last few ticks in its rotating variable buffers, so recent ticks are
+/// usually in the update and are written as they are. Older ticks are interpolated:
+/// floats and doubles a share of the way to the next known tick, everything else (gears,
+/// flags, bitfields) holds its previous value.
+///
+/// This delays each update by the ticks filled in before it, and interpolated frames are
+/// estimates: a value that peaked between two updates never shows its peak.
+#[derive(Default)]
+pub struct Interpolator {
+    // Layout of the variables, parsed again whenever the header changes it
+    layout: Option<(LayoutKey, Vec<VarHeader>)>,
+    // Latest variable buffer as last written, and its tick
+    written: Vec<u8>,
+    written_tick: i32,
+    pending: Option<Pending>,
+}
+
+// Header fields the variable layout depends on
+type LayoutKey = (i32, i32, i32);
+
+/// Frames still to write on the way from one update to the next
+struct Pending {
+    // Ticks whose values are known, in order: the last frame written, those left in the
+    // older variable buffers of the update, and the update itself
+    known: Vec<(i32, Vec<u8>)>,
+    header: Header,
+    buf_index: usize,
+    buf_range: Range<usize>,
+    tick: i32,
+    interval: Duration,
+    next: Instant,
+}
+
+impl Interpolator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts over, e.g. with a newly created mapping
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Takes an update just written to `mapping`. If it is several ticks ahead of the last
+    /// frame, the mapping is rewritten to the first frame in between.
+    pub fn update(&mut self, mapping: &mut [u8], now: Instant) {
+        self.pending = None;
+
+        let Some(header) = Header::parse(mapping) else {
+            self.written.clear();
+            return;
+        };
+        let Some((buf_index, tick, buf_range)) = latest_var_buf(&header, mapping.len()) else {
+            self.written.clear();
+            return;
+        };
+        let key = (header.num_vars, header.var_header_offset, header.buf_len);
+        if self
+            .layout
+            .as_ref()
+            .is_none_or(|(layout, _)| *layout != key)
+        {
+            self.layout = Some((key, header.var_headers(mapping)));
+            self.written.clear();
+        }
+
+        // Ticks come from the network, ones too far apart to subtract are a gap like any
+        // other too large to fill
+        let steps = tick.checked_sub(self.written_tick);
+        if self.written.len() != buf_range.len()
+            || steps.is_none_or(|steps| !(2..=MAX_STEPS).contains(&steps))
+            || header.tick_rate <= 0
+        {
+            self.written.clear();
+            self.written.extend_from_slice(&mapping[buf_range]);
+            self.written_tick = tick;
+            return;
+        }
+
+        let mut known = vec![(self.written_tick, std::mem::take(&mut self.written))];
+        known.extend(
+            older_var_bufs(&header, buf_index, mapping).filter_map(|(older, range)| {
+                (older > self.written_tick && older < tick)
+                    .then(|| (older, mapping[range].to_vec()))
+            }),
+        );
+        known.push((tick, mapping[buf_range.clone()].to_vec()));
+        known.sort_by_key(|(tick, _)| *tick);
+
+        self.pending = Some(Pending {
+            known,
+            header,
+            buf_index,
+            buf_range,
+            tick: self.written_tick,
+            interval: Duration::from_secs_f64(1.0 / header.tick_rate as f64),
+            next: now,
+        });
+        self.advance(mapping, now);
+    }
+
+    /// Returns when the next frame in between is due, if there is one
+    pub fn next_frame(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|pending| pending.next)
+    }
+
+    /// Writes the next frame to `mapping` if it is due, returning whether it did
+    pub fn write_due(&mut self, mapping: &mut [u8], now: Instant) -> bool {
+        let Some(pending) = &self.pending else {
+            return false;
+        };
+        if now < pending.next {
+            return false;
+        }
+
+        // The mapping must still be laid out like the update the frames lead to
+        if Header::parse(mapping).is_none() || pending.buf_range.end > mapping.len() {
+            self.reset();
+            return false;
+        }
+        self.advance(mapping, now);
+        true
+    }
+
+    /// Writes the frame of the next tick to the latest variable buffer
+    fn advance(&mut self, mapping: &mut [u8], now: Instant) {
+        let Some(pending) = &mut self.pending else {
+            return;
+        };
+        pending.tick += 1;
+        let tick = pending.tick;
+        let vars = self.layout.as_ref().map_or(&[][..], |(_, vars)| vars);
+
+        // The known ticks on either side, which are the same if this tick is known
+        let after = pending
+            .known
+            .iter()
+            .position(|(known, _)| *known >= tick)
+            .unwrap_or(pending.known.len() - 1);
+        let (after_tick, after_buf) = &pending.known[after];
+        let buf = &mut mapping[pending.buf_range.clone()];
+        if *after_tick == tick || after == 0 {
+            buf.copy_from_slice(after_buf);
+        } else {
+            let (before_tick, before_buf) = &pending.known[after - 1];
+            let t = (tick - before_tick) as f64 / (after_tick - before_tick) as f64;
+            interpolate(vars, before_buf, after_buf, t, buf);
+        }
+
+        // Older buffers ahead of the frame are hidden until the update's own frame, or
+        // readers would take them for the latest
+        let done = tick >= pending.known.last().map_or(tick, |(last, _)| *last);
+        let mut header = pending.header;
+        if !done {
+            header.var_bufs[pending.buf_index].tick_count = tick;
+            for var_buf in &mut header.var_bufs {
+                if var_buf.tick_count > tick {
+                    var_buf.tick_count = 0;
+                }
+            }
+        }
+        header.write(mapping);
+        pending.next = now + pending.interval;
+
+        self.written.clear();
+        self.written
+            .extend_from_slice(&mapping[pending.buf_range.clone()]);
+        self.written_tick = tick;
+        if done {
+            self.pending = None;
+        }
+    }
+}
+
+/// Returns the index, tick and byte range of the variable buffer with the latest tick
+fn latest_var_buf(header: &Header, size: usize) -> Option<(usize, i32, Range<usize>)> {
+    let num_buf = usize::try_from(header.num_buf).ok()?.min(MAX_BUFS);
+    let (index, latest) = header.var_bufs[..num_buf]
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, var_buf)| var_buf.tick_count)?;
+    let range = var_buf_range(header, index, size)?;
+    Some((index, latest.tick_count, range))
+}
+
+/// Returns the ticks and byte ranges of the variable buffers other than `latest`
+fn older_var_bufs<'a>(
+    header: &'a Header,
+    latest: usize,
+    mapping: &'a [u8],
+) -> impl Iterator<Item = (i32, Range<usize>)> + 'a {
+    let num_buf = usize::try_from(header.num_buf).unwrap_or(0).min(MAX_BUFS);
+    (0..num_buf)
+        .filter(move |&i| i != latest)
+        .filter_map(move |i| {
+            Some((
+                header.var_bufs[i].tick_count,
+                var_buf_range(header, i, mapping.len())?,
+            ))
+        })
+}
+
+fn var_buf_range(header: &Header, index: usize, size: usize) -> Option<Range<usize>> {
+    let len = usize::try_from(header.buf_len).ok()?;
+    let start = usize::try_from(header.var_bufs[index].buf_offset).ok()?;
+    let end = start.checked_add(len)?;
+    (end <= size).then_some(start..end)
+}
+
+/// Writes the variables a fraction `t` of the way from `from` to `to` into `out`. Values
+/// that aren't floats or doubles keep their value from `from`.
+fn interpolate(vars: &[VarHeader], from: &[u8], to: &[u8], t: f64, out: &mut [u8]) {
+    out.copy_from_slice(from);
+    for var in vars {
+        if !matches!(var.var_type, VarType::Float | VarType::Double) {
+            continue;
+        }
+
+        let size = var.var_type.size();
+        for index in 0..var.count {
+            let start = var.offset + index * size;
+            let range = start..start + size;
+            if range.end > out.len() {
+                break;
+            }
+
+            let read = |buf: &[u8]| match var.var_type {
+                VarType::Float => f32::from_le_bytes(buf[range.clone()].try_into().unwrap()) as f64,
+                _ => f64::from_le_bytes(buf[range.clone()].try_into().unwrap()),
+            };
+            let (a, b) = (read(from), read(to));
+            if wraps(var, a, b) {
+                continue;
+            }
+
+            let value = a + (b - a) * t;
+            match var.var_type {
+                VarType::Float => out[range].copy_from_slice(&(value as f32).to_le_bytes()),
+                _ => out[range].copy_from_slice(&value.to_le_bytes()),
+            }
+        }
+    }
+}
+
+/// Returns whether a value jumped around the end of its range, like a heading passing
+/// from +π to -π or the lap distance passing the line. Interpolating it would sweep
+/// through every value in between.
+fn wraps(var: &VarHeader, from: f64, to: f64) -> bool {
+    let range = match var.unit.as_str() {
+        "rad" => TAU,
+        "%" => 1.0,
+        _ => return false,
+    };
+    (to - from).abs() > range / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irsdk::{HEADER_SIZE, IRSDK_VER, VAR_HEADER_SIZE, VarBuf};
+
+    const BUF_OFFSET: usize = 1024;
+    const BUF_LEN: usize = 12;
+
+    fn var(name: &str, var_type: VarType, offset: usize, unit: &str) -> VarHeader {
+        VarHeader {
+            var_type,
+            offset,
+            count: 1,
+            name: name.to_string(),
+            desc: String::new(),
+            unit: unit.to_string(),
+        }
+    }
+
+    // Tick, Speed, Gear and LapDistPct of a variable buffer
+    type Frame = (i32, f32, i32, f32);
+
+    /// A mapping with a variable buffer for each frame, the last one being the latest
+    fn mapping(frames: &[Frame]) -> Vec<u8> {
+        let vars = [
+            var("Speed", VarType::Float, 0, "m/s"),
+            var("Gear", VarType::Int, 4, ""),
+            var("LapDistPct", VarType::Float, 8, "%"),
+        ];
+        let mut mapping = vec![0u8; BUF_OFFSET + BUF_LEN * frames.len()];
+        let mut var_bufs = [VarBuf::default(); MAX_BUFS];
+        for (i, &(tick, speed, gear, lap_dist)) in frames.iter().enumerate() {
+            let offset = BUF_OFFSET + i * BUF_LEN;
+            var_bufs[i] = VarBuf {
+                tick_count: tick,
+                buf_offset: offset as i32,
+            };
+            let buf = &mut mapping[offset..offset + BUF_LEN];
+            buf[0..4].copy_from_slice(&speed.to_le_bytes());
+            buf[4..8].copy_from_slice(&gear.to_le_bytes());
+            buf[8..12].copy_from_slice(&lap_dist.to_le_bytes());
+        }
+        Header {
+            ver: IRSDK_VER,
+            tick_rate: 60,
+            num_vars: vars.len() as i32,
+            var_header_offset: HEADER_SIZE as i32,
+            num_buf: frames.len() as i32,
+            buf_len: BUF_LEN as i32,
+            var_bufs,
+            ..Default::default()
+        }
+        .write(&mut mapping);
+        for (i, var) in vars.iter().enumerate() {
+            var.write(&mut mapping[HEADER_SIZE + i * VAR_HEADER_SIZE..]);
+        }
+        mapping
+    }
+
+    /// Returns the frame readers see: the variable buffer with the latest tick
+    fn latest(mapping: &[u8]) -> Frame {
+        let header = Header::parse(mapping).unwrap();
+        let (_, tick, range) = latest_var_buf(&header, mapping.len()).unwrap();
+        let buf = &mapping[range];
+        (
+            tick,
+            f32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            i32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            f32::from_le_bytes(buf[8..12].try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_skipped_ticks_are_interpolated() {
+        let mut interpolator = Interpolator::new();
+        let start = Instant::now();
+
+        let mut first = mapping(&[(100, 10.0, 3, 0.99)]);
+        interpolator.update(&mut first, start);
+        assert_eq!(latest(&first), (100, 10.0, 3, 0.99));
+        assert!(interpolator.next_frame().is_none());
+
+        // Two ticks later: the tick in between is written first, the gear is held and the
+        // lap distance passing the line isn't swept back across the lap
+        let mut second = mapping(&[(102, 20.0, 4, 0.01)]);
+        interpolator.update(&mut second, start);
+        assert_eq!(latest(&second), (101, 15.0, 3, 0.99));
+
+        let due = interpolator.next_frame().unwrap();
+        assert!(due > start);
+        assert!(!interpolator.write_due(&mut second, start));
+        assert!(interpolator.write_due(&mut second, due));
+        assert_eq!(latest(&second), (102, 20.0, 4, 0.01));
+        assert!(interpolator.next_frame().is_none());
+
+        // Updates one tick apart and gaps too large to fill are written as they are
+        let mut next = mapping(&[(103, 30.0, 4, 0.02)]);
+        interpolator.update(&mut next, start);
+        assert_eq!(latest(&next), (103, 30.0, 4, 0.02));
+        let mut late = mapping(&[(203, 40.0, 5, 0.5)]);
+        interpolator.update(&mut late, start);
+        assert_eq!(latest(&late), (203, 40.0, 5, 0.5));
+    }
+
+    #[test]
+    fn test_ticks_in_older_buffers_are_written_as_they_are() {
+        let mut interpolator = Interpolator::new();
+        let start = Instant::now();
+        interpolator.update(&mut mapping(&[(100, 10.0, 3, 0.1)]), start);
+
+        // Tick 102 is still in the update, 101 isn't
+        let mut update = mapping(&[(99, 0.0, 2, 0.1), (102, 40.0, 3, 0.1), (103, 50.0, 4, 0.1)]);
+        interpolator.update(&mut update, start);
+        assert_eq!(latest(&update), (101, 25.0, 3, 0.1));
+
+        let mut frames = Vec::new();
+        while let Some(due) = interpolator.next_frame() {
+            assert!(interpolator.write_due(&mut update, due));
+            frames.push(latest(&update));
+        }
+        assert_eq!(frames, [(102, 40.0, 3, 0.1), (103, 50.0, 4, 0.1)]);
+        // The update's own header is back once its frame is written
+        assert_eq!(
+            Header::parse(&update).unwrap(),
+            Header::parse(&mapping(&[
+                (99, 0.0, 2, 0.1),
+                (102, 40.0, 3, 0.1),
+                (103, 50.0, 4, 0.1)
+            ]))
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_update_during_frames_starts_from_the_last_frame() {
+        let mut interpolator = Interpolator::new();
+        let start = Instant::now();
+        interpolator.update(&mut mapping(&[(100, 0.0, 1, 0.1)]), start);
+
+        let mut second = mapping(&[(104, 40.0, 1, 0.1)]);
+        interpolator.update(&mut second, start);
+        assert_eq!(latest(&second).0, 101);
+
+        // Arrives before the frames caught up, carries on from tick 101 at 10 m/s
+        let mut third = mapping(&[(106, 60.0, 1, 0.1)]);
+        interpolator.update(&mut third, start);
+        let (tick, speed, _, _) = latest(&third);
+        assert_eq!(tick, 102);
+        assert!((speed - 20.0).abs() < 1e-3, "{}", speed);
+    }
+
+    #[test]
+    fn test_non_irsdk_mappings_are_left_alone() {
+        let mut interpolator = Interpolator::new();
+        let mut data = vec![7u8; 256];
+        interpolator.update(&mut data, Instant::now());
+        assert!(interpolator.next_frame().is_none());
+        assert!(data.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn test_ticks_far_apart_are_written_as_they_are() {
+        let mut interpolator = Interpolator::new();
+        let start = Instant::now();
+        for tick in [2_000_000_000, -2_000_000_000, 2_000_000_000] {
+            let mut update = mapping(&[(tick, 10.0, 3, 0.1)]);
+            interpolator.update(&mut update, start);
+            assert_eq!(latest(&update), (tick, 10.0, 3, 0.1));
+            assert!(interpolator.next_frame().is_none());
+        }
+    }
+}
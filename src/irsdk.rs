@@ -0,0 +1,664 @@
+use std::io;
+use std::ops::Range;
+
+// Size of the iRSDK header at the start of the shared memory
+pub const HEADER_SIZE: usize = 112;
+
+// Maximum number of variable buffers in the header
+pub const MAX_BUFS: usize = 4;
+
+// Header version written by iRacing
+pub const IRSDK_VER: i32 = 2;
+
+// Bit of the status field set while iRacing is connected to a session (irsdk_stConnected)
+pub const STATUS_CONNECTED: i32 = 1;
+
+// Size of a single entry in the variable header table
+pub const VAR_HEADER_SIZE: usize = 144;
+
+// Lengths of the fixed-size strings in a variable header
+const VAR_NAME_LEN: usize = 32;
+const VAR_DESC_LEN: usize = 64;
+const VAR_UNIT_LEN: usize = 32;
+
+/// A rotating buffer holding one snapshot of all variables
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VarBuf {
+    pub tick_count: i32, // Tick at which this buffer was last written
+    pub buf_offset: i32, // Offset of the buffer from the start of the mapping
+}
+
+/// The iRSDK header at the start of the shared memory (`irsdk_header`)
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Header {
+    pub ver: i32,                 // API version
+    pub status: i32,              // Bitfield of irsdk_StatusField
+    pub tick_rate: i32,           // Ticks per second
+    pub session_info_update: i32, // Incremented when the session info changes
+    pub session_info_len: i32,    // Length of the session info region
+    pub session_info_offset: i32, // Offset of the session info YAML
+    pub num_vars: i32,            // Number of variable headers
+    pub var_header_offset: i32,   // Offset of the variable header table
+    pub num_buf: i32,             // Number of variable buffers in use
+    pub buf_len: i32,             // Length of a single variable buffer
+    pub var_bufs: [VarBuf; MAX_BUFS],
+}
+
+impl Header {
+    /// Parses the header at the start of `data`, returning `None` if it doesn't look like
+    /// an iRSDK mapping
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let int = |index: usize| {
+            let offset = index * 4;
+            i32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+        };
+
+        let header = Self {
+            ver: int(0),
+            status: int(1),
+            tick_rate: int(2),
+            session_info_update: int(3),
+            session_info_len: int(4),
+            session_info_offset: int(5),
+            num_vars: int(6),
+            var_header_offset: int(7),
+            num_buf: int(8),
+            buf_len: int(9),
+            // int(10) and int(11) are padding
+            var_bufs: std::array::from_fn(|i| VarBuf {
+                tick_count: int(12 + i * 4),
+                buf_offset: int(13 + i * 4),
+            }),
+        };
+
+        (header.ver == IRSDK_VER).then_some(header)
+    }
+
+    /// Returns the byte range of the session info region, if it lies within a mapping of `size` bytes
+    pub fn session_info_range(&self, size: usize) -> Option<Range<usize>> {
+        let start = usize::try_from(self.session_info_offset).ok()?;
+        let len = usize::try_from(self.session_info_len).ok()?;
+        let end = start.checked_add(len)?;
+        (start >= HEADER_SIZE && len > 0 && end <= size).then_some(start..end)
+    }
+
+    /// Returns the tick count and contents of the variable buffer with the most recent data
+    pub fn latest_var_buf<'a>(&self, data: &'a [u8]) -> Option<(i32, &'a [u8])> {
+        let num_buf = usize::try_from(self.num_buf).ok()?.min(MAX_BUFS);
+        let len = usize::try_from(self.buf_len).ok()?;
+        let latest = self.var_bufs[..num_buf]
+            .iter()
+            .max_by_key(|var_buf| var_buf.tick_count)?;
+        let start = usize::try_from(latest.buf_offset).ok()?;
+        let buf = data.get(start..start.checked_add(len)?)?;
+        Some((latest.tick_count, buf))
+    }
+
+    /// Parses the variable header table, skipping entries that are malformed
+    pub fn var_headers(&self, data: &[u8]) -> Vec<VarHeader> {
+        let (Ok(start), Ok(count)) = (
+            usize::try_from(self.var_header_offset),
+            usize::try_from(self.num_vars),
+        ) else {
+            return Vec::new();
+        };
+
+        (0..count)
+            .map_while(|i| data.get(start + i * VAR_HEADER_SIZE..start + (i + 1) * VAR_HEADER_SIZE))
+            .filter_map(VarHeader::parse)
+            .collect()
+    }
+
+    /// Returns the byte ranges of the mapping that hold variables other than `selected`,
+    /// across all variable buffers in use
+    pub fn unselected_ranges(&self, selected: &[VarHeader]) -> Vec<Range<usize>> {
+        let (Ok(num_buf), Ok(len)) = (usize::try_from(self.num_buf), usize::try_from(self.buf_len))
+        else {
+            return Vec::new();
+        };
+
+        let mut kept: Vec<Range<usize>> = selected
+            .iter()
+            .map(|var| var.offset..(var.offset + var.var_type.size() * var.count).min(len))
+            .collect();
+        kept.sort_by_key(|range| range.start);
+
+        let mut ranges = Vec::new();
+        for var_buf in &self.var_bufs[..num_buf.min(MAX_BUFS)] {
+            let Ok(base) = usize::try_from(var_buf.buf_offset) else {
+                continue;
+            };
+            let mut start = 0;
+            for range in &kept {
+                if range.start > start {
+                    ranges.push(base + start..base + range.start);
+                }
+                start = start.max(range.end);
+            }
+            if start < len {
+                ranges.push(base + start..base + len);
+            }
+        }
+        ranges
+    }
+
+    /// Describes the header as a block of key/value lines, for --print-header
+    pub fn describe(&self) -> String {
+        let status = if self.status & STATUS_CONNECTED != 0 {
+            "connected"
+        } else {
+            "not connected"
+        };
+
+        let mut description = format!(
+            "Version:          {}\n\
+             Status:           {} ({:#x})\n\
+             Tick rate:        {} Hz\n\
+             Variables:        {} (headers at offset {})\n\
+             Session info:     {} bytes at offset {} (update {})\n\
+             Variable buffers: {} of {} bytes\n",
+            self.ver,
+            status,
+            self.status,
+            self.tick_rate,
+            self.num_vars,
+            self.var_header_offset,
+            self.session_info_len,
+            self.session_info_offset,
+            self.session_info_update,
+            self.num_buf,
+            self.buf_len,
+        );
+
+        let num_buf = usize::try_from(self.num_buf).unwrap_or(0).min(MAX_BUFS);
+        for (i, var_buf) in self.var_bufs[..num_buf].iter().enumerate() {
+            description.push_str(&format!(
+                "  Buffer {}:       tick {} at offset {}\n",
+                i, var_buf.tick_count, var_buf.buf_offset
+            ));
+        }
+
+        description
+    }
+
+    /// Writes the header to the start of `data`
+    pub fn write(&self, data: &mut [u8]) {
+        let mut put = |index: usize, value: i32| {
+            data[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        };
+
+        put(0, self.ver);
+        put(1, self.status);
+        put(2, self.tick_rate);
+        put(3, self.session_info_update);
+        put(4, self.session_info_len);
+        put(5, self.session_info_offset);
+        put(6, self.num_vars);
+        put(7, self.var_header_offset);
+        put(8, self.num_buf);
+        put(9, self.buf_len);
+        for (i, var_buf) in self.var_bufs.iter().enumerate() {
+            put(12 + i * 4, var_buf.tick_count);
+            put(13 + i * 4, var_buf.buf_offset);
+        }
+    }
+}
+
+/// Data type of a telemetry variable (`irsdk_VarType`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VarType {
+    Char,
+    Bool,
+    Int,
+    BitField,
+    Float,
+    Double,
+}
+
+impl VarType {
+    fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Char),
+            1 => Some(Self::Bool),
+            2 => Some(Self::Int),
+            3 => Some(Self::BitField),
+            4 => Some(Self::Float),
+            5 => Some(Self::Double),
+            _ => None,
+        }
+    }
+
+    /// Size of a single value in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Self::Char | Self::Bool => 1,
+            Self::Int | Self::BitField | Self::Float => 4,
+            Self::Double => 8,
+        }
+    }
+}
+
+/// A single telemetry value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Char(u8),
+    Bool(bool),
+    Int(i32),
+    BitField(u32),
+    Float(f32),
+    Double(f64),
+}
+
+/// Describes where and how a variable is stored in the variable buffers (`irsdk_varHeader`)
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct VarHeader {
+    pub var_type: VarType,
+    pub offset: usize, // Offset from the start of a variable buffer
+    pub count: usize,  // Number of values, more than one for arrays
+    pub name: String,
+    pub desc: String,
+    pub unit: String,
+}
+
+impl VarHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let int = |offset: usize| i32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let string = |offset: usize, len: usize| {
+            let bytes = &data[offset..offset + len];
+            let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        };
+
+        // int(12) holds countAsTime and padding
+        let header = Self {
+            var_type: VarType::from_raw(int(0))?,
+            offset: usize::try_from(int(4)).ok()?,
+            count: usize::try_from(int(8)).ok()?,
+            name: string(16, VAR_NAME_LEN),
+            desc: string(16 + VAR_NAME_LEN, VAR_DESC_LEN),
+            unit: string(16 + VAR_NAME_LEN + VAR_DESC_LEN, VAR_UNIT_LEN),
+        };
+
+        (!header.name.is_empty()).then_some(header)
+    }
+
+    /// Reads the value at `index` (for arrays) from a variable buffer
+    pub fn value(&self, buf: &[u8], index: usize) -> Option<Value> {
+        if index >= self.count {
+            return None;
+        }
+
+        let size = self.var_type.size();
+        let start = self.offset.checked_add(index * size)?;
+        let bytes = buf.get(start..start + size)?;
+        let word = || u32::from_le_bytes(bytes[..4].try_into().unwrap());
+
+        Some(match self.var_type {
+            VarType::Char => Value::Char(bytes[0]),
+            VarType::Bool => Value::Bool(bytes[0] != 0),
+            VarType::Int => Value::Int(word() as i32),
+            VarType::BitField => Value::BitField(word()),
+            VarType::Float => Value::Float(f32::from_bits(word())),
+            VarType::Double => Value::Double(f64::from_le_bytes(bytes.try_into().unwrap())),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn write(&self, data: &mut [u8]) {
+        let var_type = match self.var_type {
+            VarType::Char => 0i32,
+            VarType::Bool => 1,
+            VarType::Int => 2,
+            VarType::BitField => 3,
+            VarType::Float => 4,
+            VarType::Double => 5,
+        };
+
+        data[..VAR_HEADER_SIZE].fill(0);
+        data[0..4].copy_from_slice(&var_type.to_le_bytes());
+        data[4..8].copy_from_slice(&(self.offset as i32).to_le_bytes());
+        data[8..12].copy_from_slice(&(self.count as i32).to_le_bytes());
+        for (offset, text) in [
+            (16, &self.name),
+            (16 + VAR_NAME_LEN, &self.desc),
+            (16 + VAR_NAME_LEN + VAR_DESC_LEN, &self.unit),
+        ] {
+            data[offset..offset + text.len()].copy_from_slice(text.as_bytes());
+        }
+    }
+}
+
+/// Copies `data` into `out`, leaving out the session info region
+pub fn strip_session_info(data: &[u8], session_info: Range<usize>, out: &mut Vec<u8>) {
+    out.clear();
+    out.extend_from_slice(&data[..session_info.start]);
+    out.extend_from_slice(&data[session_info.end..]);
+}
+
+/// A window of the mapping given with --region, the only part of it the source sends
+/// besides the header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Region {
+    /// Parses `<offset>:<length>` in bytes
+    pub fn parse(region: &str) -> io::Result<Self> {
+        let parsed = region
+            .split_once(':')
+            .and_then(|(offset, len)| Some((offset.parse().ok()?, len.parse().ok()?)));
+        match parsed {
+            Some((offset, len)) if len > 0 => Ok(Self { offset, len }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid --region '{}', expected <offset>:<length> in bytes",
+                    region
+                ),
+            )),
+        }
+    }
+
+    /// Returns the end of the region, or `None` if it overflows
+    pub fn end(&self) -> Option<usize> {
+        self.offset.checked_add(self.len)
+    }
+}
+
+/// Copies the header of `data` into `out`, followed by the offset of `region` and its
+/// bytes. Returns `false` if `data` is too small for them.
+pub fn extract_region(data: &[u8], region: &Region, out: &mut Vec<u8>) -> bool {
+    let (Some(header), Some(bytes)) = (
+        data.get(..HEADER_SIZE),
+        region.end().and_then(|end| data.get(region.offset..end)),
+    ) else {
+        return false;
+    };
+    out.clear();
+    out.extend_from_slice(header);
+    out.extend_from_slice(&(region.offset as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+    true
+}
+
+/// Writes a frame produced by `extract_region` back into `mapping`, the header at the
+/// start and the region at its offset. Returns the range of the region, or `None` if the
+/// frame doesn't fit the mapping.
+pub fn restore_region(frame: &[u8], mapping: &mut [u8]) -> Option<Range<usize>> {
+    let header = frame.get(..HEADER_SIZE)?;
+    Header::parse(header)?;
+    let offset = frame.get(HEADER_SIZE..HEADER_SIZE + 4)?;
+    let offset = u32::from_le_bytes(offset.try_into().expect("4 bytes")) as usize;
+    let bytes = &frame[HEADER_SIZE + 4..];
+    let range = offset..offset.checked_add(bytes.len())?;
+    if range.end > mapping.len() || mapping.len() < HEADER_SIZE {
+        return None;
+    }
+
+    mapping[..HEADER_SIZE].copy_from_slice(header);
+    mapping[range.clone()].copy_from_slice(bytes);
+    Some(range)
+}
+
+/// Writes a frame produced by `strip_session_info` back into `mapping`, skipping over
+/// the session info region. Returns the session info range, or `None` if the frame
+/// doesn't fit the mapping.
+pub fn restore_stripped(frame: &[u8], mapping: &mut [u8]) -> Option<Range<usize>> {
+    let session_info = Header::parse(frame)?.session_info_range(mapping.len())?;
+    if session_info.start > frame.len() {
+        return None;
+    }
+
+    let (head, tail) = frame.split_at(session_info.start);
+    if session_info.end + tail.len() > mapping.len() {
+        return None;
+    }
+
+    mapping[..head.len()].copy_from_slice(head);
+    mapping[session_info.end..session_info.end + tail.len()].copy_from_slice(tail);
+    Some(session_info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_mapping(size: usize, session_info: Range<usize>) -> Vec<u8> {
+        let mut mapping: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        Header {
+            ver: IRSDK_VER,
+            tick_rate: 60,
+            session_info_update: 1,
+            session_info_offset: session_info.start as i32,
+            session_info_len: session_info.len() as i32,
+            ..Default::default()
+        }
+        .write(&mut mapping);
+        mapping
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mapping = create_mapping(4096, 1024..2048);
+        let header = Header::parse(&mapping).unwrap();
+        assert_eq!(header.tick_rate, 60);
+        assert_eq!(header.session_info_range(mapping.len()), Some(1024..2048));
+
+        // Truncated or foreign data is rejected
+        assert!(Header::parse(&mapping[..HEADER_SIZE - 1]).is_none());
+        assert!(Header::parse(&[0xAB; HEADER_SIZE]).is_none());
+    }
+
+    #[test]
+    fn test_unselected_ranges() {
+        let header = Header {
+            ver: IRSDK_VER,
+            num_buf: 2,
+            buf_len: 64,
+            var_bufs: [
+                VarBuf {
+                    tick_count: 1,
+                    buf_offset: 1000,
+                },
+                VarBuf {
+                    tick_count: 2,
+                    buf_offset: 2000,
+                },
+                VarBuf::default(),
+                VarBuf::default(),
+            ],
+            ..Default::default()
+        };
+        let var = |offset, var_type, count| VarHeader {
+            var_type,
+            offset,
+            count,
+            name: "Var".into(),
+            desc: String::new(),
+            unit: String::new(),
+        };
+
+        // An array at 8..20 and a float at 32..36, listed out of order
+        let selected = [var(32, VarType::Float, 1), var(8, VarType::Int, 3)];
+        assert_eq!(
+            header.unselected_ranges(&selected),
+            [
+                1000..1008,
+                1020..1032,
+                1036..1064,
+                2000..2008,
+                2020..2032,
+                2036..2064
+            ]
+        );
+
+        // Nothing selected clears the whole buffers
+        assert_eq!(header.unselected_ranges(&[]), [1000..1064, 2000..2064]);
+    }
+
+    #[test]
+    fn test_describe_header() {
+        let mut header = Header::parse(&create_mapping(4096, 1024..2048)).unwrap();
+        header.status = 1;
+        header.num_vars = 3;
+        header.var_header_offset = 144;
+        header.num_buf = 2;
+        header.buf_len = 256;
+        header.var_bufs[0] = VarBuf {
+            tick_count: 100,
+            buf_offset: 2048,
+        };
+        header.var_bufs[1] = VarBuf {
+            tick_count: 101,
+            buf_offset: 2304,
+        };
+
+        let description = header.describe();
+        let lines: Vec<&str> = description.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "Version:          2",
+                "Status:           connected (0x1)",
+                "Tick rate:        60 Hz",
+                "Variables:        3 (headers at offset 144)",
+                "Session info:     1024 bytes at offset 1024 (update 1)",
+                "Variable buffers: 2 of 256 bytes",
+                "  Buffer 0:       tick 100 at offset 2048",
+                "  Buffer 1:       tick 101 at offset 2304",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_info_range_validation() {
+        let header = Header::parse(&create_mapping(4096, 1024..2048)).unwrap();
+        assert!(header.session_info_range(2047).is_none());
+
+        let overlapping = Header {
+            session_info_offset: 8,
+            ..header
+        };
+        assert!(overlapping.session_info_range(4096).is_none());
+
+        let negative = Header {
+            session_info_len: -1,
+            ..header
+        };
+        assert!(negative.session_info_range(4096).is_none());
+    }
+
+    #[test]
+    fn test_strip_and_restore_session_info() {
+        let mapping = create_mapping(4096, 1024..2048);
+        let mut stripped = Vec::new();
+        strip_session_info(&mapping, 1024..2048, &mut stripped);
+        assert_eq!(stripped.len(), 4096 - 1024);
+
+        let mut restored = vec![0u8; 4096];
+        assert_eq!(restore_stripped(&stripped, &mut restored), Some(1024..2048));
+        assert_eq!(restored[..1024], mapping[..1024]);
+        assert_eq!(restored[2048..], mapping[2048..]);
+        assert!(restored[1024..2048].iter().all(|&b| b == 0));
+
+        // A mapping too small for the frame is rejected
+        let mut small = vec![0u8; 3000];
+        assert!(restore_stripped(&stripped, &mut small).is_none());
+    }
+
+    #[test]
+    fn test_extract_and_restore_region() {
+        let mapping = create_mapping(4096, 1024..2048);
+        let region = Region::parse("3000:500").unwrap();
+        let mut frame = Vec::new();
+        assert!(extract_region(&mapping, &region, &mut frame));
+        assert_eq!(frame.len(), HEADER_SIZE + 4 + 500);
+
+        // Only the header and the region are written
+        let mut restored = vec![0u8; 4096];
+        assert_eq!(restore_region(&frame, &mut restored), Some(3000..3500));
+        assert_eq!(restored[..HEADER_SIZE], mapping[..HEADER_SIZE]);
+        assert_eq!(restored[3000..3500], mapping[3000..3500]);
+        assert!(restored[HEADER_SIZE..3000].iter().all(|&b| b == 0));
+        assert!(restored[3500..].iter().all(|&b| b == 0));
+
+        // Regions outside the mapping are rejected on either side
+        let outside = Region::parse("4000:100").unwrap();
+        assert!(!extract_region(&mapping, &outside, &mut frame));
+        assert!(restore_region(&frame[..HEADER_SIZE + 4 + 500], &mut restored[..3400]).is_none());
+
+        assert!(Region::parse("3000").is_err());
+        assert!(Region::parse("3000:0").is_err());
+        assert!(Region::parse("-1:10").is_err());
+    }
+
+    #[test]
+    fn test_var_headers_and_values() {
+        let mut mapping = vec![0u8; 4096];
+        let vars = [
+            VarHeader {
+                var_type: VarType::Float,
+                offset: 0,
+                count: 1,
+                name: "Speed".into(),
+                desc: "GPS vehicle speed".into(),
+                unit: "m/s".into(),
+            },
+            VarHeader {
+                var_type: VarType::Int,
+                offset: 4,
+                count: 2,
+                name: "Gear".into(),
+                desc: String::new(),
+                unit: String::new(),
+            },
+        ];
+        for (i, var) in vars.iter().enumerate() {
+            var.write(&mut mapping[256 + i * VAR_HEADER_SIZE..]);
+        }
+
+        let mut var_bufs = [VarBuf::default(); MAX_BUFS];
+        var_bufs[0] = VarBuf {
+            tick_count: 10,
+            buf_offset: 1024,
+        };
+        var_bufs[1] = VarBuf {
+            tick_count: 11,
+            buf_offset: 2048,
+        };
+        Header {
+            ver: IRSDK_VER,
+            num_vars: 2,
+            var_header_offset: 256,
+            num_buf: 2,
+            buf_len: 16,
+            var_bufs,
+            ..Default::default()
+        }
+        .write(&mut mapping);
+
+        mapping[2048..2052].copy_from_slice(&42.5f32.to_le_bytes());
+        mapping[2052..2056].copy_from_slice(&3i32.to_le_bytes());
+        mapping[2056..2060].copy_from_slice(&(-1i32).to_le_bytes());
+
+        let header = Header::parse(&mapping).unwrap();
+        assert_eq!(header.var_headers(&mapping), vars);
+
+        // The buffer with the highest tick count holds the latest values
+        let (tick, buf) = header.latest_var_buf(&mapping).unwrap();
+        assert_eq!(tick, 11);
+        assert_eq!(vars[0].value(buf, 0), Some(Value::Float(42.5)));
+        assert_eq!(vars[1].value(buf, 0), Some(Value::Int(3)));
+        assert_eq!(vars[1].value(buf, 1), Some(Value::Int(-1)));
+        assert_eq!(vars[1].value(buf, 2), None);
+    }
+}
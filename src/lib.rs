@@ -0,0 +1,28 @@
+pub mod api;
+pub mod bridge;
+pub mod codec;
+pub mod config;
+pub mod dictionary;
+pub mod dump;
+pub mod echo;
+pub mod error;
+pub mod export;
+pub mod health;
+pub mod http;
+pub mod interfaces;
+pub mod interpolation;
+pub mod irsdk;
+pub mod logging;
+pub mod monitor;
+pub mod net;
+pub mod protocol;
+pub mod relay;
+pub mod scheduling;
+pub mod selftest;
+pub mod shutdown;
+pub mod source;
+pub mod stats;
+pub mod target;
+pub mod telemetry;
+pub mod timer;
+pub mod wallclock;
@@ -0,0 +1,388 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Size a --log-file grows to before it is rotated, in MB
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 10;
+
+// Rotated log files kept next to a --log-file
+pub const DEFAULT_LOG_KEEP: u32 = 5;
+
+enum Sink {
+    // Info and below go to stdout, warnings and errors to stderr
+    Console,
+    // Everything goes to a file, with --log-file or when there is no console (e.g. as a
+    // service). Warnings and errors also go to stderr if it is a terminal.
+    File(RotatingFile),
+}
+
+/// A log file that is renamed to `<path>.1` once it grows past `max_size` bytes, moving
+/// older ones on to `<path>.2` and so on, up to `<path>.<keep>`
+struct RotatingFile {
+    path: PathBuf,
+    // Closed while rotating, and until the file can be opened again if that fails
+    file: Option<File>,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+impl RotatingFile {
+    /// Opens the log at `path`, appending to any existing content. A `max_size` of 0
+    /// never rotates.
+    fn open(path: &Path, max_size: u64, keep: u32) -> io::Result<Self> {
+        let file = open_append(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file: Some(file),
+            max_size,
+            keep,
+        })
+    }
+
+    /// Writes a line, rotating first if it doesn't fit. Flushed right away, the process
+    /// may be killed without a chance to flush.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate();
+        }
+        if self.file.is_none() {
+            self.file = Some(open_append(&self.path)?);
+        }
+
+        let file = self.file.as_mut().expect("opened");
+        writeln!(file, "{}", line)?;
+        file.flush()?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Moves the full log out of the way. Best-effort, if a rename fails the log keeps
+    /// growing in place rather than losing records, and rotating is tried again once it
+    /// has grown by another `max_size`.
+    fn rotate(&mut self) {
+        let suffixed = |suffix: &str| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            PathBuf::from(path)
+        };
+        let rotated = |n: u32| suffixed(&format!(".{}", n));
+        let rotating = suffixed(".rotating");
+
+        // Windows can't rename an open file
+        self.file = None;
+        self.size = 0;
+        if self.keep == 0 {
+            self.file = File::create(&self.path).ok();
+            return;
+        }
+
+        // Only shift the older files once the full log is known to move, e.g. not while
+        // another process holds it open on Windows
+        if fs::rename(&self.path, &rotating).is_err() {
+            return;
+        }
+        let _ = fs::remove_file(rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(rotated(n), rotated(n + 1));
+        }
+        if fs::rename(&rotating, rotated(1)).is_err() {
+            let _ = fs::rename(&rotating, &self.path);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+struct Logger {
+    sink: Mutex<Sink>,
+    // The --status-line currently shown at the bottom of the console, empty if none
+    status: Mutex<String>,
+}
+
+static LOGGER: Logger = Logger {
+    sink: Mutex::new(Sink::Console),
+    status: Mutex::new(String::new()),
+};
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let Ok(mut sink) = self.sink.lock() else {
+            return;
+        };
+
+        match &mut *sink {
+            Sink::Console => {
+                // Events go on their own line above the status line, which is drawn again
+                let status = self.status.lock().map(|status| status.clone());
+                let status = status.as_deref().unwrap_or_default();
+                if !status.is_empty() {
+                    clear_line(status);
+                }
+                match record.level() {
+                    Level::Error | Level::Warn => eprintln!("{}", record.args()),
+                    _ => println!("{}", record.args()),
+                }
+                if !status.is_empty() {
+                    draw_line(status, "");
+                }
+            }
+            Sink::File(file) => {
+                let _ = file.write_line(&format!("[{}] {}", record.level(), record.args()));
+                if matches!(record.level(), Level::Error | Level::Warn)
+                    && io::stderr().is_terminal()
+                {
+                    eprintln!("{}", record.args());
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let Ok(mut sink) = self.sink.lock() else {
+            return;
+        };
+
+        match &mut *sink {
+            Sink::Console => {
+                let _ = io::stdout().flush();
+            }
+            Sink::File(file) => {
+                if let Some(file) = &mut file.file {
+                    let _ = file.flush();
+                }
+            }
+        }
+    }
+}
+
+/// Logs to stdout/stderr
+pub fn init_console() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Logs debug details too from now on
+pub fn set_verbose() {
+    log::set_max_level(LevelFilter::Debug);
+}
+
+/// Where to log to instead of the console, and when to rotate the log
+pub struct LogFile {
+    pub path: PathBuf,
+    // Bytes the log grows to before it is rotated, 0 never rotates
+    pub max_size: u64,
+    // Rotated files kept
+    pub keep: u32,
+}
+
+impl LogFile {
+    /// Logs to `path` with the default rotation
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_size: DEFAULT_LOG_MAX_SIZE_MB * 1024 * 1024,
+            keep: DEFAULT_LOG_KEEP,
+        }
+    }
+}
+
+/// Redirects all further output to the given file, appending to any existing content
+pub fn init_file(log_file: &LogFile) -> io::Result<()> {
+    let file = RotatingFile::open(&log_file.path, log_file.max_size, log_file.keep)?;
+    init_console();
+    if let Ok(mut sink) = LOGGER.sink.lock() {
+        *sink = Sink::File(file);
+    }
+    Ok(())
+}
+
+/// Returns whether a --status-line can be refreshed in place, which needs the console
+/// to be a terminal rather than a pipe or a file
+pub fn status_line_available() -> bool {
+    let console = LOGGER
+        .sink
+        .lock()
+        .is_ok_and(|sink| matches!(*sink, Sink::Console));
+    console && io::stdout().is_terminal()
+}
+
+/// Shows `line` as the status line, overwriting the one shown before
+pub fn set_status(line: String) {
+    let Ok(sink) = LOGGER.sink.lock() else {
+        return;
+    };
+    if !matches!(*sink, Sink::Console) {
+        return;
+    }
+    if let Ok(mut status) = LOGGER.status.lock() {
+        draw_line(&line, &status);
+        *status = line;
+    }
+}
+
+/// Removes the status line, e.g. before printing the summary on the way out
+pub fn clear_status() {
+    let Ok(_sink) = LOGGER.sink.lock() else {
+        return;
+    };
+    if let Ok(mut status) = LOGGER.status.lock()
+        && !status.is_empty()
+    {
+        clear_line(&status);
+        status.clear();
+    }
+}
+
+// Returns to the start of the line and overwrites it, padding with spaces where the line
+// shown before was longer. Plain carriage returns work on every console, escape codes
+// don't on older Windows ones.
+fn draw_line(line: &str, previous: &str) {
+    let padding = previous
+        .chars()
+        .count()
+        .saturating_sub(line.chars().count());
+    let mut stdout = io::stdout().lock();
+    let _ = write!(stdout, "\r{}{:padding$}", line, "", padding = padding);
+    let _ = stdout.flush();
+}
+
+fn clear_line(line: &str) {
+    let mut stdout = io::stdout().lock();
+    let _ = write!(stdout, "\r{:width$}\r", "", width = line.chars().count());
+    let _ = stdout.flush();
+}
+
+/// Formats the line printed by --ready-signal, e.g. `READY role=target bind=0.0.0.0:5000`
+pub fn ready_line(role: &str, fields: &[(&str, String)]) -> String {
+    let mut line = format!("READY role={}", role);
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line
+}
+
+/// Prints the --ready-signal line straight to stdout, even when logging to a file, so
+/// a launching application can wait for it
+pub fn signal_ready(role: &str, fields: &[(&str, String)]) {
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", ready_line(role, fields));
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_line_lists_the_fields() {
+        let fields = [
+            ("bind", "0.0.0.0:5000".to_string()),
+            ("group", "239.255.0.1".to_string()),
+        ];
+        assert_eq!(
+            ready_line("target", &fields),
+            "READY role=target bind=0.0.0.0:5000 group=239.255.0.1"
+        );
+        assert_eq!(ready_line("source", &[]), "READY role=source");
+    }
+
+    #[test]
+    fn test_log_file_rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("teleport-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("teleport.log");
+        let rotated = |n: u32| dir.join(format!("teleport.log.{}", n));
+
+        // Lines of 10 bytes with the newline, two fit
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for line in [
+            "line 0001",
+            "line 0002",
+            "line 0003",
+            "line 0004",
+            "line 0005",
+        ] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 0005\n");
+        assert_eq!(
+            fs::read_to_string(rotated(1)).unwrap(),
+            "line 0003\nline 0004\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated(2)).unwrap(),
+            "line 0001\nline 0002\n"
+        );
+
+        // The oldest file is dropped once there are more than `keep`
+        for line in ["line 0006", "line 0007"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(rotated(2)).unwrap(),
+            "line 0003\nline 0004\n"
+        );
+        assert!(!rotated(3).exists());
+
+        // Reopened, it carries on with the size already written
+        drop(file);
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for line in ["line 0008", "line 0009"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 0009\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_file_that_cant_be_moved_keeps_the_rotated_ones() {
+        let dir = std::env::temp_dir().join(format!("teleport-log-stuck-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("teleport.log");
+        let rotated = |n: u32| dir.join(format!("teleport.log.{}", n));
+        fs::write(rotated(1), "older\n").unwrap();
+        fs::write(rotated(2), "oldest\n").unwrap();
+        // A directory in the way makes moving the full log fail
+        fs::create_dir_all(dir.join("teleport.log.rotating").join("blocked")).unwrap();
+
+        let mut file = RotatingFile::open(&path, 20, 2).unwrap();
+        for line in ["line 0001", "line 0002", "line 0003", "line 0004"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "line 0001\nline 0002\nline 0003\nline 0004\n"
+        );
+        assert_eq!(fs::read_to_string(rotated(1)).unwrap(), "older\n");
+        assert_eq!(fs::read_to_string(rotated(2)).unwrap(), "oldest\n");
+        // Tried once on the third line, not again on the fourth
+        assert_eq!(file.size, 20);
+
+        // Rotates as usual once it can
+        fs::remove_dir_all(dir.join("teleport.log.rotating")).unwrap();
+        file.write_line("line 0005").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 0005\n");
+        assert_eq!(
+            fs::read_to_string(rotated(1)).unwrap(),
+            "line 0001\nline 0002\nline 0003\nline 0004\n"
+        );
+        assert_eq!(fs::read_to_string(rotated(2)).unwrap(), "older\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
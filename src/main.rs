@@ -1,12 +1,28 @@
-use clap::{Parser, Subcommand};
-use std::io;
-use std::sync::mpsc;
+use clap::error::ErrorKind;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{error, info};
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+use std::{env, io};
 
-mod protocol;
-mod source;
-mod stats;
-mod target;
-mod telemetry;
+use iracing_teleport::config::{self, BridgeConfig, RelayConfig, SourceConfig, TargetConfig};
+use iracing_teleport::dictionary::{self, Dictionary};
+use iracing_teleport::error::TeleportError;
+use iracing_teleport::logging::LogFile;
+use iracing_teleport::protocol::MAX_DATAGRAM_SIZE;
+use iracing_teleport::telemetry::TelemetryError;
+use iracing_teleport::timer::TimerResolution;
+use iracing_teleport::{
+    bridge, echo, interfaces, logging, monitor, relay, selftest, shutdown, source, target,
+    wallclock,
+};
+
+#[cfg(windows)]
+mod service;
 
 /// UDP LZ4 Source/Target application with unicast and multicast support
 #[derive(Parser)]
@@ -15,72 +31,555 @@ mod telemetry;
     version = env!("CARGO_PKG_VERSION"),
     author = env!("CARGO_PKG_AUTHORS"),
     about = env!("CARGO_PKG_DESCRIPTION"),
-    after_help = "Visit https://github.com/sklose/iracing-teleport for more information."
+    after_help = "Exit codes: 0 when stopped or done, 2 for telemetry errors (e.g. no access to the mapping), 3 for network errors (e.g. the port is in use), 4 for invalid options or config files, 5 for protocol errors, 6 when no update arrived within --startup-timeout, 7 when a file can't be written (e.g. train-dict --out).\n\nVisit https://github.com/sklose/iracing-teleport for more information."
 )]
 struct Cli {
+    /// Load settings from a TOML config file (defaults to iracing-teleport.toml next to the executable)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Use the settings of this profile from the config file, e.g. `lan` for `[profile.lan]`
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Write the log to this file instead of the console, warnings and errors still show on a console
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the --log-file once it grows past this many MB, 0 to never rotate
+    #[arg(long, global = true, value_name = "MB", default_value_t = logging::DEFAULT_LOG_MAX_SIZE_MB)]
+    log_max_size: u64,
+
+    /// Number of rotated --log-file files to keep, as <path>.1 (newest) to <path>.<N>
+    #[arg(long, global = true, value_name = "N", default_value_t = logging::DEFAULT_LOG_KEEP)]
+    log_keep: u32,
+
+    /// Also log debug details, like the sequences lost on the way or multicast rejoins
+    #[arg(long, short, global = true)]
+    verbose: bool,
+
+    /// Stamp stats lines and dumped frames with the time in UTC (the default)
+    #[arg(long, global = true, conflicts_with = "local")]
+    utc: bool,
+
+    /// Stamp stats lines with the local time and its offset from UTC instead
+    #[arg(long, global = true)]
+    local: bool,
+
+    /// Run the source or target on the tokio-based async implementation
+    #[cfg(feature = "async")]
+    #[arg(long = "async", global = true)]
+    run_async: bool,
+
     #[command(subcommand)]
-    mode: Mode,
+    command: Option<Command>,
 }
 
+// Parsed once at startup, boxing the options wouldn't save anything
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
-enum Mode {
-    /// Run as the source (sends compressed data at 60Hz)
-    Source {
-        /// Local bind address (e.g., 127.0.0.1:5000)
-        #[arg(long, default_value = "0.0.0.0:0")]
-        bind: String,
+enum Command {
+    #[command(flatten)]
+    Run(Mode),
+
+    /// Write a commented config file template
+    InitConfig {
+        /// Path of the config file to create
+        #[arg(default_value = config::DEFAULT_CONFIG_FILE)]
+        path: PathBuf,
+    },
+
+    /// List the local network interfaces and their addresses
+    Interfaces,
+
+    /// Send test data from a source to a target over 127.0.0.1 to check that teleport works
+    #[command(name = "selftest")]
+    SelfTest {
+        /// How long to run the test for
+        #[arg(long, value_name = "SECS", default_value_t = 3)]
+        duration: u64,
+    },
 
-        /// Target address to send data to (e.g., 127.0.0.1:5000)
-        #[arg(long, default_value = "239.255.0.1:5000")]
+    /// Print the stats a source or target sends with --stats-export
+    Monitor {
+        /// Address to listen on (e.g., 0.0.0.0:5100)
+        addr: String,
+    },
+
+    /// Send every datagram received back to its sender, for `ping` to test the network
+    Echo {
+        /// Address to listen on (e.g., 0.0.0.0:5000)
+        addr: String,
+    },
+
+    /// Time the round trip of datagrams to an `echo` and count the ones lost, without any
+    /// telemetry involved
+    Ping {
+        /// Address of the echo (e.g., 192.168.1.20:5000)
         target: String,
 
-        /// Use unicast mode instead of multicast
-        #[arg(long)]
-        unicast: bool,
+        /// Number of datagrams to send
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 10,
+            value_parser = clap::value_parser!(u32).range(1..=echo::MAX_PING_COUNT as i64)
+        )]
+        count: u32,
+
+        /// Milliseconds between datagrams
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        interval_ms: u64,
+
+        /// Size of each datagram in bytes, by default the largest teleport sends
+        #[arg(long, value_name = "BYTES", default_value_t = MAX_DATAGRAM_SIZE)]
+        size: usize,
     },
 
-    /// Run as the target (receives compressed data)
-    Target {
-        /// Address to bind to for receiving (e.g., 127.0.0.1:5000)
-        #[arg(long, default_value = "0.0.0.0:5000")]
-        bind: String,
-
-        /// Multicast group to join
-        #[arg(long, default_value = "239.255.0.1")]
-        group: String,
-
-        /// Use unicast mode instead of multicast
-        #[arg(long)]
-        unicast: bool,
+    /// Train a compression dictionary for --dict on the telemetry recorded in an .ibt file
+    TrainDict {
+        /// The .ibt file to train on
+        #[arg(long, value_name = "PATH")]
+        from: PathBuf,
+
+        /// Path of the dictionary to write
+        #[arg(long, value_name = "PATH")]
+        out: PathBuf,
+
+        /// Largest size of the dictionary in bytes, LZ4 uses at most 64 KiB
+        #[arg(long, value_name = "BYTES", default_value_t = dictionary::MAX_DICTIONARY_SIZE)]
+        size: usize,
+
+        /// Train on the updates of a source sending only these variables with --vars
+        #[arg(long, value_delimiter = ',', value_name = "VARS")]
+        vars: Vec<String>,
+    },
+
+    /// Install, uninstall or run as a Windows service
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        action: service::Action,
     },
 }
 
-fn main() -> io::Result<()> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum Mode {
+    /// Run as the source (sends compressed data at 60Hz)
+    Source(SourceConfig),
+
+    /// Run as the target (receives compressed data)
+    Target(TargetConfig),
+
+    /// Run as a relay (receives compressed data and forwards it without decompressing)
+    Relay(RelayConfig),
+
+    /// Run as a bridge (passes the datagrams on to another network without touching them)
+    Bridge(BridgeConfig),
+}
+
+impl Mode {
+    const NAMES: [&str; 4] = ["source", "target", "relay", "bridge"];
+
+    fn run(self, shutdown: Receiver<()>) -> Result<(), TeleportError> {
+        let _timer_resolution = TimerResolution::raise();
+
+        match self {
+            Mode::Source(config) => source::run(&config, shutdown).inspect_err(|e| {
+                error!("Error in source: {}", e);
+            }),
+
+            Mode::Target(config) => target::run(&config, shutdown).inspect_err(|e| {
+                error!("Error in target: {}", e);
+            }),
+
+            Mode::Relay(config) => Ok(relay::run(&config, shutdown).inspect_err(|e| {
+                error!("Error in relay: {}", e);
+            })?),
+
+            Mode::Bridge(config) => Ok(bridge::run(&config, shutdown).inspect_err(|e| {
+                error!("Error in bridge: {}", e);
+            })?),
+        }
+    }
+
+    /// How long to run before shutting down, if limited
+    fn duration(&self) -> Option<Duration> {
+        let secs = match self {
+            Mode::Source(config) => config.duration,
+            Mode::Target(config) => config.duration,
+            Mode::Relay(_) | Mode::Bridge(_) => None,
+        };
+        secs.map(Duration::from_secs)
+    }
+
+    #[cfg(feature = "async")]
+    fn run_async(self, shutdown: Receiver<()>) -> Result<(), TeleportError> {
+        let _timer_resolution = TimerResolution::raise();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+
+        let result = runtime.block_on(async {
+            // The shutdown channel can only be waited on by blocking
+            let shutdown = tokio::task::spawn_blocking(move || {
+                let _ = shutdown.recv();
+            });
+            let shutdown = async {
+                let _ = shutdown.await;
+            };
+
+            match self {
+                Mode::Source(config) => source::run_async(&config, shutdown)
+                    .await
+                    .inspect_err(|e| error!("Error in source: {}", e)),
+
+                Mode::Target(config) => target::run_async(&config, shutdown)
+                    .await
+                    .inspect_err(|e| error!("Error in target: {}", e)),
+
+                Mode::Relay(_) => Err(TeleportError::Config(
+                    "The relay has no async implementation, run it without --async".to_string(),
+                ))
+                .inspect_err(|e| error!("{}", e)),
 
-    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+                Mode::Bridge(_) => Err(TeleportError::Config(
+                    "The bridge has no async implementation, run it without --async".to_string(),
+                ))
+                .inspect_err(|e| error!("{}", e)),
+            }
+        });
 
-    ctrlc::set_handler(move || {
-        println!("Received Ctrl+C, shutting down...");
-        let _ = shutdown_tx.send(());
+        // Don't wait for the shutdown bridge when stopping because of an error
+        runtime.shutdown_background();
+        result
+    }
+
+    /// Checks the addresses for common mistakes before any socket is opened
+    fn validate(&self) -> io::Result<()> {
+        match self {
+            Mode::Source(config) => config.validate(),
+            Mode::Target(config) => config.validate(),
+            Mode::Relay(config) => config.validate(),
+            Mode::Bridge(config) => config.validate(),
+        }
+    }
+
+    /// Applies the config file on top of the command line arguments in `matches`
+    fn configure(self, matches: &ArgMatches, config: &Option<Config>) -> io::Result<Self> {
+        let Some((path, file)) = config else {
+            return Ok(self);
+        };
+
+        let (name, matches) = matches.subcommand().expect("mode subcommand");
+        match config::mode(path, file)? {
+            Some(mode) if mode != name => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Config file {} is for {} mode, but {} mode was requested",
+                        path.display(),
+                        mode,
+                        name
+                    ),
+                ));
+            }
+            _ => {}
+        }
+
+        Ok(match self {
+            Mode::Source(args) => Mode::Source(config::merge(args, matches, path, file)?),
+            Mode::Target(args) => Mode::Target(config::merge(args, matches, path, file)?),
+            Mode::Relay(args) => Mode::Relay(config::merge(args, matches, path, file)?),
+            Mode::Bridge(args) => Mode::Bridge(config::merge(args, matches, path, file)?),
+        })
+    }
+}
+
+// Path and contents of the loaded config file
+type Config = (PathBuf, toml::Table);
+
+/// Returns the matches one level above the source/target subcommand
+fn mode_parent(matches: &ArgMatches) -> Option<&ArgMatches> {
+    let (name, sub) = matches.subcommand()?;
+    if Mode::NAMES.contains(&name) {
+        Some(matches)
+    } else {
+        mode_parent(sub)
+    }
+}
+
+/// Parses the command line, loading the config file and taking the mode from it
+/// when none was given on the command line
+fn parse() -> io::Result<(Cli, ArgMatches, Option<Config>)> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    let mut matches = Cli::command().get_matches_from(&args);
+
+    let path = config::resolve_path(matches.get_one::<PathBuf>("config").cloned());
+    let profile = matches.get_one::<String>("profile").map(String::as_str);
+    let config = match path {
+        Some(path) => {
+            let file = config::profile(&path, config::load(&path)?, profile)?;
+            Some((path, file))
+        }
+        None if profile.is_some() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--profile needs a config file, pass --config or put {} next to the executable",
+                    config::DEFAULT_CONFIG_FILE
+                ),
+            ));
+        }
+        None => None,
+    };
+
+    // `iracing-teleport` and `iracing-teleport service install|run` may omit the mode
+    let needs_mode = match matches.subcommand() {
+        None => true,
+        Some(("service", sub)) => {
+            matches!(sub.subcommand(), Some(("install" | "run", sub)) if sub.subcommand().is_none())
+        }
+        Some(_) => false,
+    };
+
+    if needs_mode {
+        let mode = match &config {
+            Some((path, file)) => config::mode(path, file)?,
+            None => None,
+        };
+
+        match mode {
+            Some(mode) if Mode::NAMES.contains(&mode.as_str()) => {
+                args.push(mode.into());
+                matches = Cli::command().get_matches_from(&args);
+            }
+            Some(mode) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Unknown mode '{}' in config file, expected one of {:?}",
+                        mode,
+                        Mode::NAMES
+                    ),
+                ));
+            }
+            None => Cli::command()
+                .error(
+                    ErrorKind::MissingSubcommand,
+                    "no mode given; run `source`, `target`, `relay` or `bridge`, or set `mode` in a config file",
+                )
+                .exit(),
+        }
+    }
+
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    Ok((cli, matches, config))
+}
+
+// Exit codes for the errors that stop the program, so scripts and service managers can
+// tell them apart. Stopping on Ctrl+C, --duration or --count exits with 0.
+const EXIT_TELEMETRY: u8 = 2;
+const EXIT_NETWORK: u8 = 3;
+const EXIT_CONFIG: u8 = 4;
+const EXIT_PROTOCOL: u8 = 5;
+const EXIT_NO_DATA: u8 = 6;
+const EXIT_FILE: u8 = 7;
+
+/// Exit code for an error that stopped the program, the error itself has been logged
+fn exit_code(e: &TeleportError) -> u8 {
+    match e {
+        TeleportError::Telemetry { .. } => EXIT_TELEMETRY,
+        TeleportError::Transport(_) => EXIT_NETWORK,
+        TeleportError::Config(_) => EXIT_CONFIG,
+        TeleportError::Protocol(_) => EXIT_PROTOCOL,
+        TeleportError::NoData(_) => EXIT_NO_DATA,
+        TeleportError::File(_) => EXIT_FILE,
+    }
+}
+
+/// Sends the log to the --log-file from now on
+fn open_log_file(log_file: &LogFile) -> io::Result<()> {
+    logging::init_file(log_file).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to open --log-file {}: {}",
+                log_file.path.display(),
+                e
+            ),
+        )
     })
-    .expect("Error setting Ctrl+C handler");
+}
 
-    match cli.mode {
-        Mode::Source {
-            bind,
+/// Reports options or a config file that can't be run
+fn config_error(e: io::Error) -> TeleportError {
+    error!("{}", e);
+    TeleportError::Config(e.to_string())
+}
+
+fn main() -> ExitCode {
+    logging::init_console();
+    match run() {
+        Ok(code) => code,
+        Err(e) => ExitCode::from(exit_code(&e)),
+    }
+}
+
+fn run() -> Result<ExitCode, TeleportError> {
+    let (cli, matches, config) = parse().map_err(config_error)?;
+    wallclock::set_local(cli.local && !cli.utc);
+    if cli.verbose {
+        logging::set_verbose();
+    }
+    let log_file = cli.log_file.map(|path| LogFile {
+        path,
+        max_size: cli.log_max_size.saturating_mul(1024 * 1024),
+        keep: cli.log_keep,
+    });
+
+    #[cfg(feature = "async")]
+    let run_async = cli.run_async;
+
+    match cli.command.expect("command") {
+        Command::Run(mode) => {
+            let mode = mode
+                .configure(mode_parent(&matches).expect("mode"), &config)
+                .map_err(config_error)?;
+            mode.validate().map_err(config_error)?;
+            if let Some(log_file) = &log_file {
+                open_log_file(log_file).map_err(config_error)?;
+            }
+
+            let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+            if let Some(duration) = mode.duration() {
+                shutdown::after(duration, shutdown_tx.clone());
+            }
+
+            let _guard = shutdown::install(shutdown_tx).inspect_err(|e| error!("{}", e))?;
+
+            #[cfg(feature = "async")]
+            if run_async {
+                return mode.run_async(shutdown_rx).map(|()| ExitCode::SUCCESS);
+            }
+
+            mode.run(shutdown_rx).map(|()| ExitCode::SUCCESS)
+        }
+
+        Command::InitConfig { path } => {
+            config::write_template(&path).map_err(config_error)?;
+            info!("Wrote config template to {}", path.display());
+            Ok(ExitCode::SUCCESS)
+        }
+
+        Command::Interfaces => {
+            interfaces::print().inspect_err(|e| error!("{}", e))?;
+            Ok(ExitCode::SUCCESS)
+        }
+
+        // A failed self-test isn't any one kind of error, it exits with the generic code
+        Command::SelfTest { duration } => match selftest::run(Duration::from_secs(duration)) {
+            Ok(()) => Ok(ExitCode::SUCCESS),
+            Err(e) => {
+                error!("{}", e);
+                Ok(ExitCode::FAILURE)
+            }
+        },
+
+        Command::Monitor { addr } => {
+            let (shutdown_tx, shutdown_rx) = mpsc::channel();
+            let _guard = shutdown::install(shutdown_tx).inspect_err(|e| error!("{}", e))?;
+            monitor::run(&addr, shutdown_rx).inspect_err(|e| error!("{}", e))?;
+            Ok(ExitCode::SUCCESS)
+        }
+
+        Command::Echo { addr } => {
+            let (shutdown_tx, shutdown_rx) = mpsc::channel();
+            let _guard = shutdown::install(shutdown_tx).inspect_err(|e| error!("{}", e))?;
+            echo::run(&addr, shutdown_rx).inspect_err(|e| error!("{}", e))?;
+            Ok(ExitCode::SUCCESS)
+        }
+
+        Command::Ping {
             target,
-            unicast,
-        } => source::run(&bind, &target, unicast, shutdown_rx).inspect_err(|e| {
-            eprintln!("Error in source: {}", e);
-        }),
-
-        Mode::Target {
-            bind,
-            group,
-            unicast,
-        } => target::run(&bind, unicast, group, shutdown_rx).inspect_err(|e| {
-            eprintln!("Error in target: {}", e);
-        }),
+            count,
+            interval_ms,
+            size,
+        } => {
+            let (shutdown_tx, shutdown_rx) = mpsc::channel();
+            let _guard = shutdown::install(shutdown_tx).inspect_err(|e| error!("{}", e))?;
+            let interval = Duration::from_millis(interval_ms);
+            echo::ping(&target, count, interval, size, &shutdown_rx)
+                .inspect_err(|e| error!("{}", e))?;
+            Ok(ExitCode::SUCCESS)
+        }
+
+        Command::TrainDict {
+            from,
+            out,
+            size,
+            vars,
+        } => {
+            let train = || {
+                let (data, samples) =
+                    source::train_dictionary(&from, &vars, size).map_err(|e| {
+                        TeleportError::telemetry(
+                            "read the .ibt file",
+                            TelemetryError::Other(e.into()),
+                        )
+                    })?;
+                let dictionary = Dictionary::new(data).map_err(|_| {
+                    TeleportError::Config(format!(
+                        "{} has no content its updates share, train on another recording",
+                        from.display()
+                    ))
+                })?;
+                fs::write(&out, dictionary.as_slice()).map_err(|e| {
+                    TeleportError::File(io::Error::new(
+                        e.kind(),
+                        format!("Failed to write {}: {}", out.display(), e),
+                    ))
+                })?;
+                Ok::<_, TeleportError>((dictionary, samples))
+            };
+            let (dictionary, samples) = train().inspect_err(|e| error!("{}", e))?;
+            info!(
+                "Wrote a {} byte dictionary (id {:08x}) trained on {} updates to {}",
+                dictionary.as_slice().len(),
+                dictionary.id(),
+                samples,
+                out.display()
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+
+        #[cfg(windows)]
+        Command::Service { action } => {
+            service::handle(action, mode_parent(&matches), &config, log_file)
+                .inspect_err(|e| error!("{}", e))?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_match_the_help() {
+        let denied = TeleportError::telemetry("create telemetry", TelemetryError::AccessDenied);
+        assert_eq!(exit_code(&denied), EXIT_TELEMETRY);
+        assert_eq!(exit_code(&TeleportError::Config("bad".into())), EXIT_CONFIG);
+        assert_eq!(
+            exit_code(&TeleportError::NoData("none".into())),
+            EXIT_NO_DATA
+        );
+        assert_eq!(
+            exit_code(&TeleportError::File(io::Error::other("read-only"))),
+            EXIT_FILE
+        );
     }
 }
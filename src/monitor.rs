@@ -0,0 +1,254 @@
+use log::{info, warn};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::wallclock;
+
+// Starts every report, so the monitor can tell them from other traffic on its port
+const MAGIC: [u8; 2] = *b"TS";
+
+// Bumped whenever the layout changes, a monitor only renders the version it knows
+pub const REPORT_VERSION: u8 = 1;
+
+// Magic, version and role, followed by the counters as little-endian u32s
+pub const REPORT_SIZE: usize = 4 + 10 * 4;
+
+// Roles by the number they are sent as
+const ROLES: [&str; 4] = ["source", "target", "relay", "bridge"];
+
+/// The counters of one stats interval, as sent with --stats-export
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsReport {
+    pub role: &'static str,
+    pub interval_ms: u32,
+    pub updates: u32,
+    pub bytes: u32,
+    pub fragments: u32,
+    pub avg_latency_us: u32,
+    pub lost: u32,
+    pub skipped: u32,
+    pub dropped: u32,
+    pub corrupt: u32,
+    pub overrun: u32,
+}
+
+impl StatsReport {
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let role = ROLES
+            .iter()
+            .position(|&role| role == self.role)
+            .unwrap_or(u8::MAX as usize);
+
+        let mut report = [0u8; REPORT_SIZE];
+        report[..2].copy_from_slice(&MAGIC);
+        report[2] = REPORT_VERSION;
+        report[3] = role as u8;
+        let counters = [
+            self.interval_ms,
+            self.updates,
+            self.bytes,
+            self.fragments,
+            self.avg_latency_us,
+            self.lost,
+            self.skipped,
+            self.dropped,
+            self.corrupt,
+            self.overrun,
+        ];
+        for (chunk, counter) in report[4..].chunks_exact_mut(4).zip(counters) {
+            chunk.copy_from_slice(&counter.to_le_bytes());
+        }
+        report
+    }
+
+    /// Returns None for anything but a report of the current version
+    pub fn decode(report: &[u8]) -> Option<Self> {
+        if report.len() != REPORT_SIZE || report[..2] != MAGIC || report[2] != REPORT_VERSION {
+            return None;
+        }
+        let role = ROLES.get(report[3] as usize).copied().unwrap_or("unknown");
+        let mut counters = report[4..]
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().expect("4 bytes")));
+        let mut next = || counters.next().expect("counter");
+
+        Some(Self {
+            role,
+            interval_ms: next(),
+            updates: next(),
+            bytes: next(),
+            fragments: next(),
+            avg_latency_us: next(),
+            lost: next(),
+            skipped: next(),
+            dropped: next(),
+            corrupt: next(),
+            overrun: next(),
+        })
+    }
+
+    /// Renders the report like the stats line of the role that sent it
+    pub fn render(&self, from: SocketAddr) -> String {
+        let elapsed = self.interval_ms.max(1) as f64 / 1000.0;
+        let avg_fragments = if self.updates > 0 {
+            self.fragments as f64 / self.updates as f64
+        } else {
+            0.0
+        };
+
+        let mut line = format!(
+            "[{} {}] {:.2} msgs/s | Bandwidth: {:.2} Mbps | Avg fragments: {:.1} | Avg latency: {} µs",
+            self.role,
+            from,
+            self.updates as f64 / elapsed,
+            (self.bytes as f64 * 8.0) / (elapsed * 1_000_000.0),
+            avg_fragments,
+            self.avg_latency_us
+        );
+        for (name, count) in [
+            ("Skipped", self.skipped),
+            ("Lost", self.lost),
+            ("Dropped for bandwidth", self.dropped),
+            ("Corrupt", self.corrupt),
+            ("Overrun", self.overrun),
+        ] {
+            if count > 0 {
+                line.push_str(&format!(" | {}: {}", name, count));
+            }
+        }
+        line
+    }
+}
+
+/// Sends a report per stats interval to a monitor
+pub struct StatsExport {
+    socket: UdpSocket,
+}
+
+impl StatsExport {
+    pub fn new(addr: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect(addr).map(|_| socket))
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to set up stats export to {}: {}", addr, e),
+                )
+            })?;
+        info!("Exporting stats to {}", addr);
+
+        Ok(Self { socket })
+    }
+
+    pub fn send(&self, report: &StatsReport) {
+        // Nobody may be listening, which must not interrupt the telemetry
+        if let Err(e) = self.socket.send(&report.encode()) {
+            warn!("Failed to send stats export: {}", e);
+        }
+    }
+}
+
+/// Listens on `addr` for the reports sent with --stats-export and prints them until
+/// shut down
+pub fn run(addr: &str, shutdown: Receiver<()>) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind to {}: {}", addr, e)))?;
+    info!("Listening for stats on {}", addr);
+
+    // Set a short timeout on UDP receive to stay responsive to shutdown
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set socket timeout: {}", e)))?;
+
+    let mut buf = [0u8; 1500];
+    let mut warned = false;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            return Ok(());
+        }
+
+        match socket.recv_from(&mut buf) {
+            Ok((amt, from)) => match StatsReport::decode(&buf[..amt]) {
+                Some(report) => info!("{} {}", wallclock::timestamp(), report.render(from)),
+                None if !warned => {
+                    warn!(
+                        "Ignoring a datagram from {} that isn't a version {} stats report",
+                        from, REPORT_VERSION
+                    );
+                    warned = true;
+                }
+                None => {}
+            },
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_are_exported() {
+        let monitor = UdpSocket::bind("127.0.0.1:0").unwrap();
+        monitor
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let export = StatsExport::new(&monitor.local_addr().unwrap().to_string()).unwrap();
+
+        let report = StatsReport {
+            role: "target",
+            interval_ms: 5000,
+            updates: 300,
+            bytes: 1_500_000,
+            fragments: 900,
+            avg_latency_us: 120,
+            lost: 2,
+            ..Default::default()
+        };
+        export.send(&report);
+
+        let mut buf = [0u8; 1500];
+        let (amt, from) = monitor.recv_from(&mut buf).unwrap();
+        assert_eq!(amt, REPORT_SIZE);
+        let received = StatsReport::decode(&buf[..amt]).unwrap();
+        assert_eq!(received, report);
+        let line = received.render(from);
+        assert!(line.starts_with("[target "), "{}", line);
+        assert!(line.contains("60.00 msgs/s"), "{}", line);
+        assert!(line.contains("Bandwidth: 2.40 Mbps"), "{}", line);
+        assert!(
+            line.contains("Lost: 2") && !line.contains("Corrupt"),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn test_other_datagrams_are_not_reports() {
+        let report = StatsReport {
+            role: "source",
+            ..Default::default()
+        }
+        .encode();
+        assert!(StatsReport::decode(&report).is_some());
+        assert!(StatsReport::decode(&report[..REPORT_SIZE - 1]).is_none());
+
+        let mut newer = report;
+        newer[2] = REPORT_VERSION + 1;
+        assert!(StatsReport::decode(&newer).is_none());
+
+        let mut other = report;
+        other[0] = b'{';
+        assert!(StatsReport::decode(&other).is_none());
+    }
+}
@@ -0,0 +1,577 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+
+/// Resolves an address given on the command line to a single socket address
+pub fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()
+        .and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable))
+        })
+        .map_err(|e| io::Error::new(e.kind(), format!("Invalid address {}: {}", addr, e)))
+}
+
+/// Binds a UDP socket to `addr`. With `reuse_address` set, sockets that set it too can
+/// share the port, like several multicast receivers on one computer.
+pub fn bind_udp(addr: &str, reuse_address: bool) -> io::Result<UdpSocket> {
+    let addr = resolve(addr)?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Takes over a UDP socket inherited from the parent process with --fd, a file
+/// descriptor on Unix (e.g. 3 for the first one systemd passes on socket activation) or
+/// a socket handle on Windows. It has to be a bound UDP socket.
+pub fn inherited_udp(fd: u64) -> io::Result<UdpSocket> {
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--fd {} {}", fd, reason),
+        )
+    };
+
+    // Checked before taking ownership, so a wrong number doesn't close someone else's file
+    {
+        #[cfg(unix)]
+        let borrowed = unsafe {
+            std::os::fd::BorrowedFd::borrow_raw(
+                i32::try_from(fd).map_err(|_| invalid("is not a file descriptor".to_string()))?,
+            )
+        };
+        #[cfg(windows)]
+        let borrowed = unsafe {
+            std::os::windows::io::BorrowedSocket::borrow_raw(fd as std::os::windows::io::RawSocket)
+        };
+        let socket = SockRef::from(&borrowed);
+        match socket.r#type() {
+            Ok(Type::DGRAM) => {}
+            Ok(_) => return Err(invalid("is not a datagram socket".to_string())),
+            Err(e) => return Err(invalid(format!("is not an inherited socket: {}", e))),
+        }
+        match socket.local_addr().map(|addr| addr.as_socket()) {
+            Ok(Some(addr)) if addr.port() != 0 => {}
+            Ok(_) => return Err(invalid("is not bound to an IP address".to_string())),
+            Err(e) => return Err(invalid(format!("is not usable: {}", e))),
+        }
+    }
+
+    #[cfg(unix)]
+    let socket = unsafe { <UdpSocket as std::os::fd::FromRawFd>::from_raw_fd(fd as i32) };
+    #[cfg(windows)]
+    let socket = unsafe {
+        <UdpSocket as std::os::windows::io::FromRawSocket>::from_raw_socket(
+            fd as std::os::windows::io::RawSocket,
+        )
+    };
+
+    // The parent may have left it in either mode, the caller sets it up like its own
+    socket.set_nonblocking(false)?;
+    info!(
+        "Using the inherited socket {} bound to {}",
+        fd,
+        socket.local_addr()?
+    );
+    Ok(socket)
+}
+
+/// A DSCP class to mark outgoing datagrams with, so QoS-aware routers can prioritize them.
+/// Given by name (EF, CS5, AF41, ...) or as a number from 0 to 63.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Dscp(u8);
+
+// Per-hop behaviors with names of their own, class selectors (CSn) and assured forwarding
+// (AFxy) classes are computed
+const DSCP_NAMES: [(&str, u8); 3] = [("EF", 46), ("VA", 44), ("LE", 1)];
+
+impl Dscp {
+    /// The value for the IPv4 ToS byte, the DSCP takes its upper six bits
+    pub fn tos(self) -> u32 {
+        (self.0 as u32) << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_uppercase();
+        let value = if let Ok(value) = name.parse::<u8>() {
+            Some(value).filter(|&value| value < 64)
+        } else if let Some(class) = name.strip_prefix("CS") {
+            class
+                .parse::<u8>()
+                .ok()
+                .filter(|&class| class < 8)
+                .map(|class| class * 8)
+        } else if let Some(class) = name.strip_prefix("AF") {
+            match class.as_bytes() {
+                &[class @ b'1'..=b'4', drop @ b'1'..=b'3'] => {
+                    Some((class - b'0') * 8 + (drop - b'0') * 2)
+                }
+                _ => None,
+            }
+        } else {
+            DSCP_NAMES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|&(_, value)| value)
+        };
+
+        value.map(Dscp).ok_or_else(|| {
+            format!(
+                "invalid DSCP class '{}', expected a name like EF, CS5 or AF41, or a number from 0 to 63",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = self.0;
+        if let Some((name, _)) = DSCP_NAMES.iter().find(|&&(_, known)| known == value) {
+            write!(f, "{}", name)
+        } else if value.is_multiple_of(8) {
+            write!(f, "CS{}", value / 8)
+        } else if matches!(value / 8, 1..=4) && matches!(value % 8, 2 | 4 | 6) {
+            write!(f, "AF{}{}", value / 8, value % 8 / 2)
+        } else {
+            write!(f, "{}", value)
+        }
+    }
+}
+
+impl TryFrom<String> for Dscp {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Dscp> for String {
+    fn from(dscp: Dscp) -> Self {
+        dscp.to_string()
+    }
+}
+
+/// Marks the datagrams sent from `socket` with the DSCP class. Failures are reported but
+/// not fatal, the stream just isn't prioritized.
+pub fn set_dscp<'s, S>(socket: &'s S, dscp: Dscp)
+where
+    SockRef<'s>: From<&'s S>,
+{
+    match SockRef::from(socket).set_tos_v4(dscp.tos()) {
+        Ok(()) => info!(
+            "Marking datagrams with DSCP {} (ToS 0x{:02x}), routers on the way must honor DSCP for this to help",
+            dscp,
+            dscp.tos()
+        ),
+        Err(e) => warn!("Failed to set DSCP {}: {}", dscp, e),
+    }
+}
+
+/// Sends the multicast datagrams from `socket` out of the interface with the given address
+/// instead of the one the OS picks
+pub fn set_multicast_interface<'s, S>(socket: &'s S, interface: Ipv4Addr) -> io::Result<()>
+where
+    SockRef<'s>: From<&'s S>,
+{
+    SockRef::from(socket)
+        .set_multicast_if_v4(&interface)
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to send multicast from {}: {}", interface, e),
+            )
+        })
+}
+
+/// Enables or disables receiving the multicast datagrams sent from `socket` on this
+/// computer, keeping the OS default for `None`. Returns whether loopback is enabled.
+pub fn set_multicast_loop<'s, S>(socket: &'s S, enabled: Option<bool>) -> io::Result<bool>
+where
+    SockRef<'s>: From<&'s S>,
+{
+    let socket = SockRef::from(socket);
+    if let Some(enabled) = enabled {
+        socket.set_multicast_loop_v4(enabled).map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to set multicast loopback: {}", e))
+        })?;
+    }
+    socket.multicast_loop_v4()
+}
+
+/// Sends the datagrams in order to `destination`, or to the connected peer if `None`.
+/// On Linux they are handed to the kernel with a single `sendmmsg` call.
+#[cfg(target_os = "linux")]
+pub fn send_batch(
+    socket: &UdpSocket,
+    destination: Option<SocketAddr>,
+    datagrams: &[&[u8]],
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (mut addr, addr_len) = match destination {
+        Some(destination) => sockaddr(destination),
+        None => (unsafe { std::mem::zeroed() }, 0),
+    };
+
+    let mut iovecs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|datagram| libc::iovec {
+            iov_base: datagram.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.len(),
+        })
+        .collect();
+
+    let mut messages: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            message.msg_hdr.msg_iov = iovec;
+            message.msg_hdr.msg_iovlen = 1;
+            if addr_len > 0 {
+                message.msg_hdr.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+                message.msg_hdr.msg_namelen = addr_len;
+            }
+            message
+        })
+        .collect();
+
+    // The kernel may accept only part of the batch, keep going with the rest
+    let mut sent = 0;
+    while sent < messages.len() {
+        let result = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                messages[sent..].as_mut_ptr(),
+                (messages.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        sent += result as usize;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
+
+/// Sends the datagrams in order to `destination`, or to the connected peer if `None`
+#[cfg(not(target_os = "linux"))]
+pub fn send_batch(
+    socket: &UdpSocket,
+    destination: Option<SocketAddr>,
+    datagrams: &[&[u8]],
+) -> io::Result<()> {
+    for datagram in datagrams {
+        match destination {
+            Some(destination) => socket.send_to(datagram, destination)?,
+            None => socket.send(datagram)?,
+        };
+    }
+    Ok(())
+}
+
+/// Sends the datagrams in order without waiting, failing with `WouldBlock` if the socket
+/// isn't ready for all of them
+#[cfg(feature = "async")]
+pub fn try_send_batch(
+    socket: &tokio::net::UdpSocket,
+    destination: Option<SocketAddr>,
+    datagrams: &[&[u8]],
+) -> io::Result<()> {
+    for datagram in datagrams {
+        match destination {
+            Some(destination) => socket.try_send_to(datagram, destination)?,
+            None => socket.try_send(datagram)?,
+        };
+    }
+    Ok(())
+}
+
+/// Buffers for receiving several datagrams at once
+pub struct RecvBatch {
+    buffer: Vec<u8>,
+    datagram_size: usize,
+    lens: Vec<usize>,
+    sources: Vec<SocketAddr>,
+    received: usize,
+}
+
+impl RecvBatch {
+    pub fn new(count: usize, datagram_size: usize) -> Self {
+        Self {
+            buffer: vec![0; count * datagram_size],
+            datagram_size,
+            lens: vec![0; count],
+            sources: vec![SocketAddr::from(([0, 0, 0, 0], 0)); count],
+            received: 0,
+        }
+    }
+
+    /// Waits for datagrams like `UdpSocket::recv` (honoring the read timeout and
+    /// non-blocking mode) and returns how many were received. On Linux all datagrams that
+    /// are already queued are taken with a single `recvmmsg` call, elsewhere one at a time.
+    #[cfg(target_os = "linux")]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        self.received = 0;
+        let mut iovecs: Vec<libc::iovec> = self
+            .buffer
+            .chunks_exact_mut(self.datagram_size)
+            .map(|chunk| libc::iovec {
+                iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+                iov_len: chunk.len(),
+            })
+            .collect();
+
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            vec![unsafe { std::mem::zeroed() }; iovecs.len()];
+
+        let mut messages: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                message.msg_hdr.msg_iov = iovec;
+                message.msg_hdr.msg_iovlen = 1;
+                message.msg_hdr.msg_name = addr as *mut _ as *mut libc::c_void;
+                message.msg_hdr.msg_namelen =
+                    std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                message
+            })
+            .collect();
+
+        // Block for the first datagram only, then take whatever else is queued
+        let result = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                std::ptr::null_mut(),
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.received = result as usize;
+        for (i, message) in messages[..self.received].iter().enumerate() {
+            self.lens[i] = message.msg_len as usize;
+            self.sources[i] = socket_addr(&addrs[i]).unwrap_or(self.sources[i]);
+        }
+        Ok(self.received)
+    }
+
+    /// Waits for datagrams like `UdpSocket::recv` (honoring the read timeout and
+    /// non-blocking mode) and returns how many were received
+    #[cfg(not(target_os = "linux"))]
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        self.received = 0;
+        let (len, source) = socket.recv_from(&mut self.buffer[..self.datagram_size])?;
+        self.lens[0] = len;
+        self.sources[0] = source;
+        self.received = 1;
+        Ok(self.received)
+    }
+
+    /// The datagrams received by the last call to `recv` along with their senders, in order
+    pub fn datagrams(&self) -> impl Iterator<Item = (SocketAddr, &[u8])> {
+        self.buffer
+            .chunks_exact(self.datagram_size)
+            .zip(self.lens.iter().zip(&self.sources))
+            .take(self.received)
+            .map(|(chunk, (&len, &source))| (source, &chunk[..len]))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+            Some(SocketAddr::from((ip, u16::from_be(sin.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(std::net::SocketAddrV6::new(
+                sin6.sin6_addr.s6_addr.into(),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_dscp_names() {
+        for (name, value) in [
+            ("EF", 46),
+            ("cs5", 40),
+            ("CS0", 0),
+            ("AF41", 34),
+            ("46", 46),
+        ] {
+            assert_eq!(name.parse::<Dscp>(), Ok(Dscp(value)), "{}", name);
+        }
+        for name in ["64", "CS8", "AF51", "AF14", "XX", ""] {
+            assert!(name.parse::<Dscp>().is_err(), "{}", name);
+        }
+
+        // Known values are shown by name
+        assert_eq!(Dscp(34).to_string(), "AF41");
+        assert_eq!(Dscp(40).to_string(), "CS5");
+        assert_eq!(Dscp(7).to_string(), "7");
+        assert_eq!(Dscp(46).tos(), 0xb8);
+    }
+
+    #[test]
+    fn test_set_dscp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_dscp(&socket, Dscp(46));
+        assert_eq!(SockRef::from(&socket).tos_v4().unwrap(), 0xb8);
+    }
+
+    fn receive_all(socket: &UdpSocket, count: usize) -> Vec<Vec<u8>> {
+        let mut buf = [0u8; 64];
+        (0..count)
+            .map(|_| {
+                let len = socket.recv(&mut buf).unwrap();
+                buf[..len].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_inherited_socket_is_checked() {
+        use std::os::fd::IntoRawFd;
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let inherited = inherited_udp(socket.into_raw_fd() as u64).unwrap();
+        assert_eq!(inherited.local_addr().unwrap(), addr);
+
+        // Files and stream sockets are left alone
+        let file = std::fs::File::open(std::env::current_exe().unwrap()).unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&file);
+        assert!(inherited_udp(fd as u64).is_err());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&listener);
+        let e = inherited_udp(fd as u64).unwrap_err();
+        assert!(e.to_string().contains("not a datagram socket"), "{}", e);
+    }
+
+    #[test]
+    fn test_send_batch() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let datagrams: [&[u8]; 3] = [b"first", b"second", b"third"];
+
+        // To an explicit destination
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_batch(&socket, Some(receiver.local_addr().unwrap()), &datagrams).unwrap();
+        assert_eq!(receive_all(&receiver, 3), datagrams);
+
+        // To the connected peer
+        socket.connect(receiver.local_addr().unwrap()).unwrap();
+        send_batch(&socket, None, &datagrams).unwrap();
+        assert_eq!(receive_all(&receiver, 3), datagrams);
+    }
+
+    #[test]
+    fn test_recv_batch() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let datagrams: [&[u8]; 3] = [b"first", b"second", b"third"];
+        send_batch(&socket, Some(receiver.local_addr().unwrap()), &datagrams).unwrap();
+
+        // Smaller than the number of datagrams sent, so it takes more than one call
+        let mut batch = RecvBatch::new(2, 64);
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            batch.recv(&receiver).unwrap();
+            for (source, datagram) in batch.datagrams() {
+                assert_eq!(source, socket.local_addr().unwrap());
+                received.push(datagram.to_vec());
+            }
+        }
+        assert_eq!(received, datagrams);
+
+        // Times out like a regular receive
+        receiver
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let e = batch.recv(&receiver).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        assert_eq!(batch.datagrams().count(), 0);
+    }
+}
@@ -1,410 +1,1737 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use reed_solomon_erasure::galois_8::ReedSolomon;
 use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use xxhash_rust::xxh3::xxh3_64;
 
 // Maximum UDP multicast payload size (leaving some headroom for IP/UDP headers)
 pub const MAX_DATAGRAM_SIZE: usize = 9_000;
 
 // Maximum payload size per datagram (header + data)
-pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - std::mem::size_of::<DatagramHeader>();
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE;
 
-#[repr(C, packed)]
+// Frame flags
+pub const FLAG_SESSION_INFO: u16 = 1 << 0; // Payload is the session info region of the mapping
+pub const FLAG_SESSION_INFO_OMITTED: u16 = 1 << 1; // Payload is the mapping minus the session info
+pub const FLAG_HEARTBEAT: u16 = 1 << 2; // No payload, the source is alive but has no new data
+pub const FLAG_KEYFRAME_REQUEST: u16 = 1 << 3; // Sent back by a target missing the session info
+pub const FLAG_UNCOMPRESSED: u16 = 1 << 4; // Payload is sent as-is, it didn't compress well
+pub const FLAG_PROBE: u16 = 1 << 5; // Sent back by a target to measure the network delay
+pub const FLAG_PROBE_REPLY: u16 = 1 << 6; // A source's answer to a probe, with its clock
+pub const FLAG_LOSS_REPORT: u16 = 1 << 7; // Sent back by a target with the updates it lost
+pub const FLAG_DICTIONARY: u16 = 1 << 14; // Compressed with the dictionary whose id ends the payload
+pub const FLAG_VERIFY: u16 = 1 << 15; // Payload ends with the hash of the decoded frame
+
+// Sent by a source shutting down, so targets close the telemetry right away. There is no
+// bit left for it: a heartbeat has no payload, so one that also claims to be the session
+// info is a goodbye. Targets that don't know it take it for a heartbeat.
+pub const FLAG_GOODBYE: u16 = FLAG_HEARTBEAT | FLAG_SESSION_INFO;
+
+// An update holding only the iRSDK header and the --region of the mapping, after the
+// offset of the region. Neither the session info nor the mapping without it, so it is
+// both. Targets that don't know it take it for the session info.
+pub const FLAG_REGION: u16 = FLAG_SESSION_INFO | FLAG_SESSION_INFO_OMITTED;
+
+// A source describing its stream, and a target's answer to it. Combinations of the bits
+// of other control datagrams, with sizes of their own: peers that don't know them drop
+// them like any control datagram they can't parse.
+pub const FLAG_HELLO: u16 = FLAG_PROBE_REPLY | FLAG_LOSS_REPORT;
+pub const FLAG_HELLO_REPLY: u16 = FLAG_PROBE | FLAG_LOSS_REPORT;
+
+// Version of the protocol described by a hello. Hellos of later versions append fields,
+// so the fields known here are read from the start of any version.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Codecs a hello can announce
+pub const CODEC_LZ4: u8 = 0;
+pub const CODEC_LZ4_FRAME: u8 = 1; // --lz4-format frame
+
+// Features a hello can announce
+pub const FEATURE_VERIFY: u16 = 1 << 0; // Payloads end with a hash, --verify
+pub const FEATURE_DICTIONARY: u16 = 1 << 1; // Compressed with the dictionary of the given id
+pub const FEATURE_REGION: u16 = 1 << 2; // Updates between keyframes only hold a --region
+
+// Size of a hello: the header, version, codec, features, mapping size and dictionary id
+pub const HELLO_SIZE: usize = HEADER_SIZE + 12;
+
+// Size of the reply to a hello: the header, version, and the reason for a rejection
+pub const HELLO_REPLY_SIZE: usize = HEADER_SIZE + 4;
+
+// Size of the header preceding the payload of each datagram
+pub const HEADER_SIZE: usize = 30;
+
+// Bits 8 to 13 of the flags hold the number of parity fragments sent after the data
+// fragments, from which a receiver can rebuild up to that many lost fragments
+const PARITY_SHIFT: u16 = 8;
+const PARITY_MASK: u16 = 0x3f << PARITY_SHIFT;
+
+// Most parity fragments the flags can announce
+pub const MAX_PARITY_FRAGMENTS: u8 = 0x3f;
+
+// Size of the hash a source running with --verify appends to each payload
+pub const VERIFY_HASH_SIZE: usize = 8;
+
+// Size of the dictionary id a source running with --dict appends to compressed payloads,
+// ahead of the hash
+pub const DICTIONARY_ID_SIZE: usize = 4;
+
+// Reed-Solomon over GF(2^8) works with at most this many data and parity fragments
+const MAX_FEC_FRAGMENTS: usize = 256;
+
+// Size of a probe reply, the header followed by the source's receive and reply times
+pub const PROBE_REPLY_SIZE: usize = HEADER_SIZE + 16;
+
+// Size of a loss report, the header followed by the updates received and lost
+pub const LOSS_REPORT_SIZE: usize = HEADER_SIZE + 8;
+
+// Time between the probes a target sends to measure the network delay
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Weight of a new probe round trip in the smoothed estimate
+const PROBE_SMOOTHING: f64 = 1.0 / 8.0;
+
+// Updates a source remembers the send times of, for the echoes in loss reports. A few
+// seconds at 60 Hz, longer than a report takes to come back.
+const SEND_LOG_SIZE: usize = 256;
+
+// Larger jumps in the sequence number are taken as a restarted source rather than loss
+pub const MAX_SEQUENCE_GAP: u32 = 1000;
+
+// Fragments of the sequences this far before the last one are stragglers the network
+// reordered, larger steps back are taken as a restarted source
+const STALE_SEQUENCES: u32 = 8;
+
+// Most sequences a receiver reassembles at a time, older ones would be stale
+pub const MAX_REASSEMBLY_DEPTH: usize = STALE_SEQUENCES as usize;
+
+// Most later datagrams --reorder sends ahead of a datagram it holds back
+const MAX_REORDER_DISTANCE: u32 = 3;
+
+// Gaps between paced fragments shorter than this are waited out by spinning, the OS timer
+// resolution is too coarse for them
+const PACING_SPIN: Duration = Duration::from_millis(1);
+
+/// The header preceding the payload of each datagram, its fields packed in this order
+/// and little-endian on the wire
+#[derive(Clone, Copy)]
 struct DatagramHeader {
     sequence: u32,       // Monotonically increasing sequence number
+    source_id: u32,      // Identifies the sender when several share a multicast group
     fragment: u16,       // Fragment index within this sequence
     fragments: u16,      // Total number of fragments in this sequence
+    flags: u16,          // FLAG_* bits describing the payload
     payload_size: u32,   // Size of the compressed payload across all fragments
+    mapping_size: u32,   // Size of the source's telemetry mapping (0 if unknown)
     source_time_us: u64, // Source processing time in microseconds
 }
 
+impl DatagramHeader {
+    /// Reads the header at the start of `data` field by field, so neither its length nor
+    /// its alignment is assumed. Returns `None` if `data` is too short to hold one.
+    fn parse(data: &[u8]) -> Option<Self> {
+        let data: &[u8; HEADER_SIZE] = data.get(..HEADER_SIZE)?.try_into().ok()?;
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(data[offset..offset + 4].try_into().expect("4 bytes"))
+        };
+        Some(Self {
+            sequence: u32_at(0),
+            source_id: u32_at(4),
+            fragment: u16_at(8),
+            fragments: u16_at(10),
+            flags: u16_at(12),
+            payload_size: u32_at(14),
+            mapping_size: u32_at(18),
+            source_time_us: u64::from_le_bytes(data[22..30].try_into().expect("8 bytes")),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.source_id.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.fragment.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.fragments.to_le_bytes());
+        bytes[12..14].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[14..18].copy_from_slice(&self.payload_size.to_le_bytes());
+        bytes[18..22].copy_from_slice(&self.mapping_size.to_le_bytes());
+        bytes[22..30].copy_from_slice(&self.source_time_us.to_le_bytes());
+        bytes
+    }
+}
+
+/// Randomly drops or reorders datagrams to simulate a bad link, for testing the recovery
+/// features and the reassembly with --drop-rate and --reorder
+pub struct FaultInjector {
+    drop_rate: f64,
+    reorder_rate: f64,
+    rng: StdRng,
+    // Datagrams held back, with the number of datagrams still to go out before them
+    held: Vec<(u32, Vec<u8>)>,
+}
+
+impl FaultInjector {
+    /// Drops each datagram with probability `drop_rate` and holds it back behind up to
+    /// `MAX_REORDER_DISTANCE` later ones with probability `reorder_rate`, or returns `None`
+    /// if neither ever happens. The same `seed` picks the same datagrams.
+    pub fn new(drop_rate: f64, reorder_rate: f64, seed: Option<u64>) -> Option<Self> {
+        (drop_rate > 0.0 || reorder_rate > 0.0).then(|| Self {
+            drop_rate: drop_rate.min(1.0),
+            reorder_rate: reorder_rate.min(1.0),
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_os_rng(),
+            },
+            held: Vec::new(),
+        })
+    }
+
+    /// Returns whether to drop the next datagram
+    pub fn drop_next(&mut self) -> bool {
+        self.rng.random_bool(self.drop_rate)
+    }
+
+    /// Hands `datagram` to `send_fn` unless it is dropped or held back, followed by the
+    /// datagrams held back that are due after it
+    pub fn send<F>(&mut self, datagram: &[u8], send_fn: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        if self.drop_next() {
+            return Ok(());
+        }
+
+        if self.rng.random_bool(self.reorder_rate) {
+            let distance = self.rng.random_range(1..=MAX_REORDER_DISTANCE);
+            self.held.push((distance, datagram.to_vec()));
+            return Ok(());
+        }
+
+        send_fn(datagram)?;
+        for (remaining, _) in &mut self.held {
+            *remaining -= 1;
+        }
+        while let Some(i) = self.held.iter().position(|(remaining, _)| *remaining == 0) {
+            let (_, datagram) = self.held.remove(i);
+            send_fn(&datagram)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Sender {
+    source_id: u32,
     sequence: u32,
+    mapping_size: u32,
     buffer: Vec<u8>,
+    batch: Vec<u8>,
+    batch_lens: Vec<usize>,
+    pacing: Option<Duration>,
+    parity: u8,
+    copies: u8,
+    sent_bytes: u64,
+    encoder: Option<ReedSolomon>,
+    last_shard: Vec<u8>,
+    parity_buf: Vec<u8>,
+    faults: Option<FaultInjector>,
 }
 
 impl Sender {
-    pub fn new() -> Self {
+    pub fn new(source_id: u32) -> Self {
         Self {
+            source_id,
             sequence: 0,
+            mapping_size: 0,
             buffer: vec![0; MAX_DATAGRAM_SIZE],
+            batch: Vec::new(),
+            batch_lens: Vec::new(),
+            pacing: None,
+            parity: 0,
+            copies: 1,
+            sent_bytes: 0,
+            encoder: None,
+            last_shard: Vec::new(),
+            parity_buf: Vec::new(),
+            faults: None,
         }
     }
 
-    pub fn send<F>(&mut self, data: &[u8], source_time_us: u64, mut send_fn: F) -> io::Result<u16>
+    /// Drops or reorders datagrams at random, see `FaultInjector`. Dropped datagrams still
+    /// count as sent.
+    pub fn set_faults(&mut self, faults: Option<FaultInjector>) {
+        self.faults = faults;
+    }
+
+    /// Sends `parity` Reed-Solomon parity fragments after the data fragments of each
+    /// payload, so receivers can rebuild up to that many lost fragments without asking
+    /// for them again
+    pub fn set_fec(&mut self, parity: u8) {
+        self.parity = parity.min(MAX_PARITY_FRAGMENTS);
+    }
+
+    /// Sends every datagram `copies` times, so a fragment only goes missing if all its
+    /// copies are lost. Receivers keep the first copy that arrives.
+    pub fn set_redundancy(&mut self, copies: u8) {
+        self.copies = copies.max(1);
+    }
+
+    /// Returns the number of bytes handed to the network since the last call, headers,
+    /// parity and copies included
+    pub fn take_sent_bytes(&mut self) -> u64 {
+        std::mem::take(&mut self.sent_bytes)
+    }
+
+    /// Spreads the fragments of each payload evenly over `window` instead of sending them
+    /// back to back, so they don't overflow small switch buffers. Adds up to `window` of
+    /// latency.
+    pub fn set_pacing(&mut self, window: Option<Duration>) {
+        self.pacing = window;
+    }
+
+    /// Returns the sequence number the next payload goes out with
+    pub fn next_sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// Sets the telemetry mapping size advertised to receivers, so they can create their
+    /// mapping to match
+    pub fn set_mapping_size(&mut self, mapping_size: u32) {
+        self.mapping_size = mapping_size;
+    }
+
+    pub fn send<F>(
+        &mut self,
+        data: &[u8],
+        source_time_us: u64,
+        flags: u16,
+        mut send_fn: F,
+    ) -> io::Result<u16>
     where
         F: FnMut(&[u8]) -> io::Result<()>,
     {
         let len = data.len();
-        let fragments = len.div_ceil(MAX_PAYLOAD_SIZE);
-        if fragments > u16::MAX as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Compressed data too large",
-            ));
-        }
+        let (payload_size, fragments) = payload_layout(len)?;
+        let fragments = fragments as usize;
+
+        let parity = self.parity_fragments(fragments);
 
         // Prepare header
         let mut header = DatagramHeader {
             sequence: self.sequence,
+            source_id: self.source_id,
             fragments: fragments as u16,
             fragment: 0,
-            payload_size: len as u32,
+            flags: flags & !PARITY_MASK | (parity as u16) << PARITY_SHIFT,
+            payload_size,
+            mapping_size: self.mapping_size,
             source_time_us,
         };
 
-        let header_size = std::mem::size_of::<DatagramHeader>();
+        // Parity is computed up front, so every copy of the payload can include it
+        let shard_size = len.min(MAX_PAYLOAD_SIZE);
+        if parity > 0 {
+            self.encode_parity(data, fragments, parity, shard_size)?;
+        }
+
+        let per_copy = fragments + parity;
+        let datagrams = per_copy * self.copies as usize;
+        let start = Instant::now();
+        let spacing = self.pacing.map(|window| window / datagrams.max(1) as u32);
+
+        // Send each fragment followed by the parity, once per copy, so the copies of a
+        // fragment are spread apart instead of getting lost in the same burst
+        for n in 0..datagrams {
+            if let Some(spacing) = spacing {
+                wait_until(start + spacing * n as u32);
+            }
 
-        // Send each fragment
-        let mut offset = 0;
-        for i in 0..fragments {
             // Update fragment number
+            let i = n % per_copy;
             header.fragment = i as u16;
 
             // Copy header to buffer
-            let header_bytes = unsafe {
-                std::slice::from_raw_parts(&header as *const _ as *const u8, header_size)
-            };
-            self.buffer[..header_size].copy_from_slice(header_bytes);
-
-            // Calculate fragment size
-            let remaining = len - offset;
-            let fragment_size = remaining.min(MAX_PAYLOAD_SIZE);
+            self.buffer[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
 
             // Copy fragment data
-            let start = offset;
-            let end = start + fragment_size;
-            self.buffer[header_size..header_size + fragment_size]
-                .copy_from_slice(&data[start..end]);
+            let fragment = if i < fragments {
+                &data[i * MAX_PAYLOAD_SIZE..len.min((i + 1) * MAX_PAYLOAD_SIZE)]
+            } else {
+                let offset = (i - fragments) * shard_size;
+                &self.parity_buf[offset..offset + shard_size]
+            };
+            let datagram_len = HEADER_SIZE + fragment.len();
+            self.buffer[HEADER_SIZE..datagram_len].copy_from_slice(fragment);
 
             // Send datagram
-            send_fn(&self.buffer[..header_size + fragment_size])?;
-            offset += fragment_size;
+            match &mut self.faults {
+                Some(faults) => faults.send(&self.buffer[..datagram_len], &mut send_fn)?,
+                None => send_fn(&self.buffer[..datagram_len])?,
+            }
+            self.sent_bytes += datagram_len as u64;
         }
 
         // Increment sequence number
         self.sequence = self.sequence.wrapping_add(1);
-        Ok(fragments as u16)
+        Ok((fragments + parity) as u16)
     }
-}
-
-pub struct Receiver {
-    buffer: Vec<u8>,
-    fragments: Vec<bool>,
-    current_sequence: Option<u32>,
-    total_fragments: u16,
-    received_fragments: u16,
-    payload_size: u32,
-    last_source_time_us: u64,
-}
 
-impl Receiver {
-    pub fn new(max_payload_size: usize) -> Self {
-        Self {
-            buffer: Vec::with_capacity(max_payload_size),
-            fragments: Vec::new(),
-            current_sequence: None,
-            total_fragments: 0,
-            received_fragments: 0,
-            payload_size: 0,
-            last_source_time_us: 0,
+    /// Returns how many parity fragments go with a payload of `fragments` data fragments.
+    /// Payloads with too many fragments for the code go without parity.
+    fn parity_fragments(&self, fragments: usize) -> usize {
+        if fragments > 0 && fragments + self.parity as usize <= MAX_FEC_FRAGMENTS {
+            self.parity as usize
+        } else {
+            0
         }
     }
 
-    pub fn last_source_time_us(&self) -> u64 {
-        self.last_source_time_us
+    /// Returns how many bytes `send` hands to the network for a payload of `len` bytes,
+    /// headers, parity and copies included
+    pub fn wire_size(&self, len: usize) -> u64 {
+        let fragments = len.div_ceil(MAX_PAYLOAD_SIZE);
+        let parity = self.parity_fragments(fragments);
+        let shard_size = len.min(MAX_PAYLOAD_SIZE);
+        let per_copy = len + fragments * HEADER_SIZE + parity * (HEADER_SIZE + shard_size);
+        (per_copy * self.copies as usize) as u64
     }
 
-    pub fn total_fragments(&self) -> u16 {
-        self.total_fragments
+    /// Computes the parity shards of `data` into the parity buffer
+    fn encode_parity(
+        &mut self,
+        data: &[u8],
+        fragments: usize,
+        parity: usize,
+        shard_size: usize,
+    ) -> io::Result<()> {
+        // Shards have the size of the first fragment, the last one is padded with zeros
+        let last = &data[(fragments - 1) * MAX_PAYLOAD_SIZE..];
+        self.last_shard.clear();
+        self.last_shard.extend_from_slice(last);
+        self.last_shard.resize(shard_size, 0);
+
+        let shards: Vec<&[u8]> = data
+            .chunks(MAX_PAYLOAD_SIZE)
+            .take(fragments - 1)
+            .chain([self.last_shard.as_slice()])
+            .collect();
+
+        self.parity_buf.clear();
+        self.parity_buf.resize(parity * shard_size, 0);
+        let mut parity_shards: Vec<&mut [u8]> = self.parity_buf.chunks_mut(shard_size).collect();
+
+        let encoder = match self.encoder.take() {
+            Some(encoder)
+                if encoder.data_shard_count() == fragments
+                    && encoder.parity_shard_count() == parity =>
+            {
+                encoder
+            }
+            _ => ReedSolomon::new(fragments, parity).map_err(fec_error)?,
+        };
+        let result = encoder
+            .encode_sep(&shards, &mut parity_shards)
+            .map_err(fec_error);
+        self.encoder = Some(encoder);
+        result
     }
 
-    pub fn process_datagram(&mut self, data: &[u8]) -> (Option<&[u8]>, bool) {
-        // Ensure we have enough data for the header
-        let header_size = std::mem::size_of::<DatagramHeader>();
-        if data.len() < header_size {
-            return (None, false);
+    /// Like `send`, but hands all datagrams of the payload to `send_fn` at once, so they
+    /// can be sent with a single system call. With pacing they are handed over one by one.
+    pub fn send_batch<F>(
+        &mut self,
+        data: &[u8],
+        source_time_us: u64,
+        flags: u16,
+        mut send_fn: F,
+    ) -> io::Result<u16>
+    where
+        F: FnMut(&[&[u8]]) -> io::Result<()>,
+    {
+        if self.pacing.is_some() {
+            return self.send(data, source_time_us, flags, |datagram| send_fn(&[datagram]));
         }
 
-        // Parse header
-        let header = unsafe { &*(data.as_ptr() as *const DatagramHeader) };
-
-        // Store the source processing time from fragment 0
-        if header.fragment == 0 {
-            self.last_source_time_us = header.source_time_us;
-        }
+        let mut batch = std::mem::take(&mut self.batch);
+        let mut batch_lens = std::mem::take(&mut self.batch_lens);
+        batch.clear();
+        batch_lens.clear();
 
-        // Check if this fragment belongs to a different sequence
-        let is_different_sequence = match self.current_sequence {
-            Some(current_seq) => header.sequence != current_seq,
-            None => true,
-        };
+        let result = self
+            .send(data, source_time_us, flags, |datagram| {
+                batch.extend_from_slice(datagram);
+                batch_lens.push(datagram.len());
+                Ok(())
+            })
+            .and_then(|fragments| {
+                let mut rest = batch.as_slice();
+                let datagrams: Vec<&[u8]> = batch_lens
+                    .iter()
+                    .map(|&len| {
+                        let (datagram, tail) = rest.split_at(len);
+                        rest = tail;
+                        datagram
+                    })
+                    .collect();
+                send_fn(&datagrams).map(|_| fragments)
+            });
+
+        self.batch = batch;
+        self.batch_lens = batch_lens;
+        result
+    }
 
-        // A sequence change is indicated when we receive fragment 0,
-        // regardless of whether we've seen other fragments of this sequence before
-        let sequence_changed = header.fragment == 0;
+    /// Sends a datagram without payload to signal that the source is still alive. It doesn't
+    /// use up a sequence number, so it never interferes with the reassembly of real frames.
+    pub fn send_heartbeat<F>(&mut self, send_fn: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        self.send_without_payload(FLAG_HEARTBEAT, send_fn)
+    }
 
-        // Initialize or update sequence state
-        if is_different_sequence {
-            self.start_new_sequence(header);
-        }
+    /// Sends a heartbeat marked as the last one, to signal that the source is shutting down
+    pub fn send_goodbye<F>(&mut self, send_fn: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        self.send_without_payload(FLAG_GOODBYE, send_fn)
+    }
 
-        // Validate fragment
-        if header.fragment >= header.fragments || header.fragments == 0 {
-            return (None, sequence_changed);
-        }
+    fn send_without_payload<F>(&mut self, flags: u16, mut send_fn: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        let header = DatagramHeader {
+            sequence: self.sequence,
+            source_id: self.source_id,
+            fragment: 0,
+            fragments: 0,
+            flags,
+            payload_size: 0,
+            mapping_size: self.mapping_size,
+            source_time_us: 0,
+        };
 
-        // Check if we already received this fragment
-        if self.fragments[header.fragment as usize] {
-            return (None, sequence_changed);
+        let header_bytes = header.to_bytes();
+        match &mut self.faults {
+            Some(faults) => faults.send(&header_bytes, &mut send_fn),
+            None => send_fn(&header_bytes),
         }
+    }
+}
 
-        // Copy fragment data
-        let fragment_size = data.len() - header_size;
-        let buffer_offset = header.fragment as usize * MAX_PAYLOAD_SIZE;
+fn fec_error(e: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::other(format!("Forward error correction failed: {}", e))
+}
 
-        if buffer_offset + fragment_size > self.buffer.len() {
-            return (None, sequence_changed);
+/// Waits until `deadline`, sleeping while it's far off and spinning for the rest
+fn wait_until(deadline: Instant) {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return;
         }
-
-        self.buffer[buffer_offset..buffer_offset + fragment_size]
-            .copy_from_slice(&data[header_size..]);
-
-        // Mark fragment as received
-        self.fragments[header.fragment as usize] = true;
-        self.received_fragments += 1;
-
-        // Check if we have all fragments
-        if self.received_fragments == self.total_fragments {
-            let result = &self.buffer[..self.payload_size as usize];
-            self.current_sequence = None;
-            (Some(result), sequence_changed)
+        if remaining > PACING_SPIN {
+            thread::sleep(remaining - PACING_SPIN);
         } else {
-            (None, sequence_changed)
+            std::hint::spin_loop();
         }
     }
+}
 
-    fn start_new_sequence(&mut self, header: &DatagramHeader) {
-        self.current_sequence = Some(header.sequence);
-        self.total_fragments = header.fragments;
-        self.received_fragments = 0;
-        self.payload_size = header.payload_size;
+/// Returns the largest payload a source sends for telemetry of up to `max_size` bytes:
+/// the telemetry as it is when it doesn't compress, followed by a dictionary id and a hash
+pub fn max_payload_size(max_size: usize) -> usize {
+    max_size + DICTIONARY_ID_SIZE + VERIFY_HASH_SIZE
+}
 
-        // Reset fragment tracking
-        self.fragments.clear();
-        self.fragments.resize(header.fragments as usize, false);
+/// Builds the datagram a target sends back to ask the source with the given id for a
+/// keyframe, an update that includes the session info, so it doesn't have to wait for the
+/// periodic one
+pub fn keyframe_request(source_id: u32) -> [u8; HEADER_SIZE] {
+    control_datagram(source_id, FLAG_KEYFRAME_REQUEST, 0, 0)
+}
 
-        // Ensure buffer has enough capacity and is properly sized
-        self.buffer.clear();
-        self.buffer.resize(header.payload_size as usize, 0);
+/// Builds a datagram without fragments, for the messages exchanged outside of updates
+fn control_datagram(
+    source_id: u32,
+    flags: u16,
+    payload_size: u32,
+    time_us: u64,
+) -> [u8; HEADER_SIZE] {
+    DatagramHeader {
+        sequence: 0,
+        source_id,
+        fragment: 0,
+        fragments: 0,
+        flags,
+        payload_size,
+        mapping_size: 0,
+        source_time_us: time_us,
     }
+    .to_bytes()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Returns the `payload_size` and number of fragments the header gives a payload of `len`
+/// bytes, or an error if it doesn't fit their fields rather than letting them wrap around
+fn payload_layout(len: usize) -> io::Result<(u32, u16)> {
+    let Ok(payload_size) = u32::try_from(len) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Compressed data of {} bytes is larger than the {} bytes a payload can have",
+                len,
+                u32::MAX
+            ),
+        ));
+    };
+    let Ok(fragments) = u16::try_from(len.div_ceil(MAX_PAYLOAD_SIZE)) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Compressed data too large",
+        ));
+    };
+    Ok((payload_size, fragments))
+}
 
-    // Helper function to create test data
-    fn create_test_data(size: usize) -> Vec<u8> {
-        (0..size).map(|i| (i % 256) as u8).collect()
-    }
+/// Returns whether the flags of a frame mark the goodbye of a source shutting down
+pub fn is_goodbye(flags: u16) -> bool {
+    flags & FLAG_GOODBYE == FLAG_GOODBYE
+}
 
-    #[test]
-    fn test_single_fragment_send_receive() {
-        let data = create_test_data(1000);
-        let mut sent_datagrams = Vec::new();
-        let mut sender = Sender::new();
+/// Returns whether the flags of a frame mark an update holding only a region of the mapping
+pub fn is_region(flags: u16) -> bool {
+    flags & (FLAG_REGION | FLAG_HEARTBEAT) == FLAG_REGION
+}
 
-        // Send the data
-        sender
-            .send(&data, 0, |datagram| {
-                sent_datagrams.push(datagram.to_vec());
-                Ok(())
-            })
-            .unwrap();
+/// Returns whether the flags of a frame mark the session info, rather than a region or a
+/// goodbye
+pub fn is_session_info(flags: u16) -> bool {
+    flags & FLAG_SESSION_INFO != 0 && flags & (FLAG_SESSION_INFO_OMITTED | FLAG_HEARTBEAT) == 0
+}
 
-        // Verify we got exactly one datagram
-        assert_eq!(sent_datagrams.len(), 1);
+/// Hashes a frame before compression, for the target to check what it decodes against
+pub fn verify_hash(frame: &[u8]) -> u64 {
+    xxh3_64(frame)
+}
 
-        // Receive the data
-        let mut receiver = Receiver::new(MAX_DATAGRAM_SIZE);
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
-        assert!(
-            sequence_changed,
-            "First datagram should indicate sequence change"
-        );
-        let received = received.unwrap();
+/// Splits the hash appended by a source running with --verify off a payload, returning
+/// the payload without it and the hash if there is one. Returns `None` if the payload is
+/// too short to hold the hash its flags announce.
+pub fn split_verify_hash(payload: &[u8], flags: u16) -> Option<(&[u8], Option<u64>)> {
+    if flags & FLAG_VERIFY == 0 {
+        return Some((payload, None));
+    }
+    let (payload, hash) = payload.split_at_checked(payload.len().checked_sub(VERIFY_HASH_SIZE)?)?;
+    Some((payload, Some(u64::from_le_bytes(hash.try_into().ok()?))))
+}
 
-        // Verify the received data matches the original
-        assert_eq!(received, data);
+/// Splits the id of the dictionary a payload was compressed with off its end, returning
+/// the payload without it and the id if there is one. Returns `None` if the payload is
+/// too short to hold the id its flags announce.
+pub fn split_dictionary_id(payload: &[u8], flags: u16) -> Option<(&[u8], Option<u32>)> {
+    if flags & FLAG_DICTIONARY == 0 {
+        return Some((payload, None));
     }
+    let (payload, id) = payload.split_at_checked(payload.len().checked_sub(DICTIONARY_ID_SIZE)?)?;
+    Some((payload, Some(u32::from_le_bytes(id.try_into().ok()?))))
+}
 
-    #[test]
-    fn test_multi_fragment_send_receive() {
-        let data = create_test_data(MAX_PAYLOAD_SIZE * 3 + 1000); // Will require 4 fragments
-        let mut sent_datagrams = Vec::new();
-        let mut sender = Sender::new();
+/// Returns the source id a keyframe request is meant for, or `None` if `data` isn't one
+pub fn parse_keyframe_request(data: &[u8]) -> Option<u32> {
+    if data.len() != HEADER_SIZE {
+        return None;
+    }
 
-        // Send the data
-        sender
-            .send(&data, 0, |datagram| {
-                sent_datagrams.push(datagram.to_vec());
-                Ok(())
-            })
-            .unwrap();
+    let header = DatagramHeader::parse(data)?;
+    (header.flags & FLAG_KEYFRAME_REQUEST != 0).then_some(header.source_id)
+}
 
-        // Verify we got 4 datagrams
-        assert_eq!(sent_datagrams.len(), 4);
+/// Returns the wall clock time in microseconds since the Unix epoch, as carried by probes
+pub fn wall_clock_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_micros() as u64)
+}
 
-        // Receive the data in order
-        let mut receiver = Receiver::new(data.len());
-        for (i, datagram) in sent_datagrams.iter().take(3).enumerate() {
-            let (received, sequence_changed) = receiver.process_datagram(datagram);
-            assert!(
-                received.is_none(),
-                "Fragment {} should not complete sequence",
-                i
-            );
-            assert_eq!(
-                sequence_changed,
-                i == 0,
-                "Only first fragment should indicate sequence change"
-            );
-        }
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[3]);
-        assert!(
-            !sequence_changed,
-            "Last fragment should not indicate sequence change"
-        );
-        let received = received.unwrap();
+/// Builds the datagram a target sends back to the source with the given id to measure
+/// the network delay, stamped with the target's clock
+pub fn probe(source_id: u32, sent_us: u64) -> [u8; HEADER_SIZE] {
+    control_datagram(source_id, FLAG_PROBE, 0, sent_us)
+}
 
-        // Verify the received data matches the original
-        assert_eq!(received, data);
+/// Returns the source id a probe is meant for and the target's time of sending it, or
+/// `None` if `data` isn't one
+pub fn parse_probe(data: &[u8]) -> Option<(u32, u64)> {
+    if data.len() != HEADER_SIZE {
+        return None;
     }
 
-    #[test]
-    fn test_out_of_order_fragments() {
-        let data = create_test_data(MAX_PAYLOAD_SIZE * 2 + 1000); // Will require 3 fragments
-        let mut sent_datagrams = Vec::new();
-        let mut sender = Sender::new();
-
-        // Send the data
-        sender
-            .send(&data, 0, |datagram| {
-                sent_datagrams.push(datagram.to_vec());
-                Ok(())
-            })
-            .unwrap();
+    let header = DatagramHeader::parse(data)?;
+    (header.flags & FLAG_PROBE != 0).then_some((header.source_id, header.source_time_us))
+}
 
-        // Receive the data out of order
-        let mut receiver = Receiver::new(data.len());
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[2]);
-        assert!(received.is_none());
-        assert!(
-            !sequence_changed,
-            "Last fragment should not indicate sequence change"
-        );
+/// Timestamps of a probe's round trip in microseconds, each on the clock of the host
+/// taking it
+pub struct ProbeTimes {
+    pub source_id: u32,
+    pub sent_us: u64,     // Target sent the probe
+    pub received_us: u64, // Source received the probe
+    pub replied_us: u64,  // Source sent the reply
+}
 
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
-        assert!(received.is_none());
-        assert!(
-            sequence_changed,
-            "First fragment should indicate sequence change"
-        );
+/// Builds a source's reply to a probe, echoing the target's time of sending it
+pub fn probe_reply(times: &ProbeTimes) -> [u8; PROBE_REPLY_SIZE] {
+    let mut datagram = [0u8; PROBE_REPLY_SIZE];
+    datagram[..HEADER_SIZE].copy_from_slice(&control_datagram(
+        times.source_id,
+        FLAG_PROBE_REPLY,
+        16,
+        times.sent_us,
+    ));
+    datagram[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&times.received_us.to_le_bytes());
+    datagram[HEADER_SIZE + 8..].copy_from_slice(&times.replied_us.to_le_bytes());
+    datagram
+}
 
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[1]);
-        assert!(
-            !sequence_changed,
-            "Middle fragment should not indicate sequence change"
-        );
-        let received = received.unwrap();
+/// Returns the times carried by a probe reply, or `None` if `data` isn't one
+pub fn parse_probe_reply(data: &[u8]) -> Option<ProbeTimes> {
+    if data.len() != PROBE_REPLY_SIZE {
+        return None;
+    }
 
-        // Verify the received data matches the original
-        assert_eq!(received, data);
+    let header = DatagramHeader::parse(data)?;
+    if header.flags & FLAG_PROBE_REPLY == 0 {
+        return None;
     }
 
-    #[test]
-    fn test_sequence_numbers() {
-        let data = create_test_data(1000);
-        let mut sender = Sender::new();
-        let mut last_sequence: Option<u32> = None;
+    let time =
+        |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().expect("8 bytes"));
+    Some(ProbeTimes {
+        source_id: header.source_id,
+        sent_us: header.source_time_us,
+        received_us: time(HEADER_SIZE),
+        replied_us: time(HEADER_SIZE + 8),
+    })
+}
 
-        // Send the data multiple times
-        for _ in 0..3 {
-            let mut current_sequence = None;
-            sender
-                .send(&data, 0, |datagram| {
-                    // Extract sequence number from header
-                    let header = unsafe { &*(datagram.as_ptr() as *const DatagramHeader) };
-                    current_sequence = Some(header.sequence);
-                    Ok(())
-                })
-                .unwrap();
+/// What a source tells targets about its stream before they decode it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub source_id: u32,
+    pub version: u8,
+    pub codec: u8,
+    pub features: u16,
+    pub mapping_size: u32,
+    pub dictionary_id: u32,
+}
 
-            if let Some(last) = last_sequence {
-                assert_eq!(current_sequence.unwrap(), last.wrapping_add(1));
+/// Builds the hello a source sends at the start of a stream, and periodically on
+/// multicast
+pub fn hello(hello: &Hello) -> [u8; HELLO_SIZE] {
+    let mut datagram = [0u8; HELLO_SIZE];
+    datagram[..HEADER_SIZE].copy_from_slice(&control_datagram(
+        hello.source_id,
+        FLAG_HELLO,
+        (HELLO_SIZE - HEADER_SIZE) as u32,
+        0,
+    ));
+    let body = &mut datagram[HEADER_SIZE..];
+    body[0] = hello.version;
+    body[1] = hello.codec;
+    body[2..4].copy_from_slice(&hello.features.to_le_bytes());
+    body[4..8].copy_from_slice(&hello.mapping_size.to_le_bytes());
+    body[8..12].copy_from_slice(&hello.dictionary_id.to_le_bytes());
+    datagram
+}
+
+/// Returns the hello in `data`, or `None` if it isn't one. Hellos of later versions may
+/// be longer.
+pub fn parse_hello(data: &[u8]) -> Option<Hello> {
+    if data.len() < HELLO_SIZE {
+        return None;
+    }
+
+    let header = DatagramHeader::parse(data)?;
+    if header.flags != FLAG_HELLO {
+        return None;
+    }
+
+    let body = &data[HEADER_SIZE..];
+    let u32_at =
+        |offset: usize| u32::from_le_bytes(body[offset..offset + 4].try_into().expect("4 bytes"));
+    Some(Hello {
+        source_id: header.source_id,
+        version: body[0],
+        codec: body[1],
+        features: u16::from_le_bytes([body[2], body[3]]),
+        mapping_size: u32_at(4),
+        dictionary_id: u32_at(8),
+    })
+}
+
+/// Why a target turned down a source's stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    Version = 1,
+    Codec = 2,
+    Dictionary = 3,
+    MappingSize = 4,
+    Other = 255,
+}
+
+impl Rejection {
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => Rejection::Version,
+            2 => Rejection::Codec,
+            3 => Rejection::Dictionary,
+            4 => Rejection::MappingSize,
+            _ => Rejection::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Rejection::Version => "it doesn't speak this protocol version",
+            Rejection::Codec => "it can't decode this codec or has another --lz4-format",
+            Rejection::Dictionary => "it has another --dict",
+            Rejection::MappingSize => "the mapping is larger than its --max-size",
+            Rejection::Other => "of a reason this version doesn't know",
+        })
+    }
+}
+
+/// A target's answer to a hello
+#[derive(Clone, Debug, PartialEq)]
+pub struct HelloReply {
+    pub source_id: u32,
+    pub version: u8,
+    pub rejection: Option<Rejection>,
+}
+
+/// Builds the datagram a unicast target answers a hello with
+pub fn hello_reply(reply: &HelloReply) -> [u8; HELLO_REPLY_SIZE] {
+    let mut datagram = [0u8; HELLO_REPLY_SIZE];
+    datagram[..HEADER_SIZE].copy_from_slice(&control_datagram(
+        reply.source_id,
+        FLAG_HELLO_REPLY,
+        (HELLO_REPLY_SIZE - HEADER_SIZE) as u32,
+        0,
+    ));
+    datagram[HEADER_SIZE] = reply.version;
+    datagram[HEADER_SIZE + 1] = reply.rejection.map_or(0, |rejection| rejection as u8);
+    datagram
+}
+
+/// Returns the reply to a hello in `data`, or `None` if it isn't one
+pub fn parse_hello_reply(data: &[u8]) -> Option<HelloReply> {
+    if data.len() < HELLO_REPLY_SIZE {
+        return None;
+    }
+
+    let header = DatagramHeader::parse(data)?;
+    if header.flags != FLAG_HELLO_REPLY {
+        return None;
+    }
+
+    let code = data[HEADER_SIZE + 1];
+    Some(HelloReply {
+        source_id: header.source_id,
+        version: data[HEADER_SIZE],
+        rejection: (code != 0).then(|| Rejection::from_code(code)),
+    })
+}
+
+/// Updates a target received from a source and lost on the way since its last report
+pub struct LossReport {
+    pub source_id: u32,
+    pub received: u32,
+    pub lost: u32,
+    pub echo: Option<Echo>,
+}
+
+/// The newest update a target completed, echoed in its loss report so the source can
+/// time the round trip of an actual update rather than a probe
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Echo {
+    pub sequence: u32,
+    pub held_us: u64, // Between completing the update and sending the report
+}
+
+/// Builds the datagram a target sends back to tell a source how many of its updates
+/// arrived, so the source can adapt its rate to the link
+pub fn loss_report(report: &LossReport) -> [u8; LOSS_REPORT_SIZE] {
+    let mut datagram = [0u8; LOSS_REPORT_SIZE];
+    // The echo goes in header fields a control datagram leaves empty, so sources that
+    // don't know it still read the counts. A fragment count of 1 marks it present.
+    let echo = report.echo.unwrap_or(Echo {
+        sequence: 0,
+        held_us: 0,
+    });
+    let header = DatagramHeader {
+        sequence: echo.sequence,
+        source_id: report.source_id,
+        fragment: 0,
+        fragments: report.echo.is_some() as u16,
+        flags: FLAG_LOSS_REPORT,
+        payload_size: 8,
+        mapping_size: 0,
+        source_time_us: echo.held_us,
+    };
+    datagram[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    datagram[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&report.received.to_le_bytes());
+    datagram[HEADER_SIZE + 4..].copy_from_slice(&report.lost.to_le_bytes());
+    datagram
+}
+
+/// Returns the counts carried by a loss report, or `None` if `data` isn't one
+pub fn parse_loss_report(data: &[u8]) -> Option<LossReport> {
+    if data.len() != LOSS_REPORT_SIZE {
+        return None;
+    }
+
+    let header = DatagramHeader::parse(data)?;
+    if header.flags & FLAG_LOSS_REPORT == 0 {
+        return None;
+    }
+
+    let count =
+        |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().expect("4 bytes"));
+    Some(LossReport {
+        source_id: header.source_id,
+        received: count(HEADER_SIZE),
+        lost: count(HEADER_SIZE + 4),
+        echo: (header.fragments == 1).then_some(Echo {
+            sequence: header.sequence,
+            held_us: header.source_time_us,
+        }),
+    })
+}
+
+/// Remembers when a source read and sent its recent updates, to time the ones targets
+/// echo back. Unlike probes, the echoes follow the path and the size of real updates.
+pub struct SendLog {
+    // Sequence, when the update was read and when its last fragment was sent
+    entries: Vec<(u32, Instant, Instant)>,
+    next: usize,
+}
+
+impl Default for SendLog {
+    fn default() -> Self {
+        Self {
+            entries: Vec::with_capacity(SEND_LOG_SIZE),
+            next: 0,
+        }
+    }
+}
+
+impl SendLog {
+    /// Notes that update `sequence`, read at `read`, finished sending at `sent`
+    pub fn record(&mut self, sequence: u32, read: Instant, sent: Instant) {
+        if self.entries.len() < SEND_LOG_SIZE {
+            self.entries.push((sequence, read, sent));
+        } else {
+            self.entries[self.next] = (sequence, read, sent);
+        }
+        self.next = (self.next + 1) % SEND_LOG_SIZE;
+    }
+
+    /// Returns the one-way latency of the echoed update received at `received`: the time
+    /// from reading it to sending it, plus half the round trip after the time the target
+    /// held it. `None` if the update is too old to be remembered or the times don't add up.
+    pub fn latency(&self, echo: &Echo, received: Instant) -> Option<Duration> {
+        let &(_, read, sent) = self
+            .entries
+            .iter()
+            .find(|(sequence, _, _)| *sequence == echo.sequence)?;
+        let round_trip = received
+            .checked_duration_since(sent)?
+            .checked_sub(Duration::from_micros(echo.held_us))?;
+        Some(sent.duration_since(read) + round_trip / 2)
+    }
+}
+
+/// One-way network delay and how far the source's clock is ahead of the target's
+#[derive(Clone, Copy)]
+pub struct ClockEstimate {
+    pub delay_us: f64,
+    pub offset_us: f64,
+}
+
+/// Estimates the network delay between a source and a target from probe round trips,
+/// the way NTP does. Assumes the delay is the same in both directions. The round trip
+/// is measured on each host's own clock, so unsynchronized clocks don't skew the delay,
+/// they only show up in the offset.
+#[derive(Default)]
+pub struct ClockSync {
+    source_id: Option<u32>,
+    last_probe: Option<Instant>,
+    estimate: Option<ClockEstimate>,
+}
+
+impl ClockSync {
+    /// Returns a probe for the given source if the last one is long enough ago
+    pub fn probe(&mut self, source_id: u32) -> Option<[u8; HEADER_SIZE]> {
+        // Another source is another network path
+        if self.source_id != Some(source_id) {
+            self.source_id = Some(source_id);
+            self.estimate = None;
+        } else if self
+            .last_probe
+            .is_some_and(|last| last.elapsed() < PROBE_INTERVAL)
+        {
+            return None;
+        }
+
+        self.last_probe = Some(Instant::now());
+        Some(probe(source_id, wall_clock_us()))
+    }
+
+    /// Folds a reply received at `received_us` on the target's clock into the estimate,
+    /// returning the updated one. Replies from other sources and round trips that come
+    /// out negative because a clock was adjusted in between are dropped.
+    pub fn add_reply(&mut self, times: &ProbeTimes, received_us: u64) -> Option<ClockEstimate> {
+        if self.source_id != Some(times.source_id) {
+            return self.estimate;
+        }
+
+        let (t1, t2, t3, t4) = (
+            times.sent_us as i64,
+            times.received_us as i64,
+            times.replied_us as i64,
+            received_us as i64,
+        );
+        let round_trip = (t4 - t1) - (t3 - t2);
+        if round_trip < 0 {
+            return self.estimate;
+        }
+
+        let sample = ClockEstimate {
+            delay_us: round_trip as f64 / 2.0,
+            offset_us: ((t2 - t1) + (t3 - t4)) as f64 / 2.0,
+        };
+        let estimate = match self.estimate {
+            Some(estimate) => ClockEstimate {
+                delay_us: estimate.delay_us
+                    + (sample.delay_us - estimate.delay_us) * PROBE_SMOOTHING,
+                offset_us: estimate.offset_us
+                    + (sample.offset_us - estimate.offset_us) * PROBE_SMOOTHING,
+            },
+            None => sample,
+        };
+        self.estimate = Some(estimate);
+        self.estimate
+    }
+}
+
+/// Header fields of a single datagram, for forwarding it without reassembly
+pub struct DatagramInfo {
+    pub sequence: u32,
+    pub source_id: u32,
+    pub fragment: u16,
+    pub fragments: u16,
+    pub flags: u16,
+    pub source_time_us: u64,
+}
+
+/// Reads the header of a datagram, or returns `None` if `data` is too short to have one
+pub fn datagram_info(data: &[u8]) -> Option<DatagramInfo> {
+    let header = DatagramHeader::parse(data)?;
+    Some(DatagramInfo {
+        sequence: header.sequence,
+        source_id: header.source_id,
+        fragment: header.fragment,
+        fragments: header.fragments,
+        flags: header.flags,
+        source_time_us: header.source_time_us,
+    })
+}
+
+/// A completely reassembled payload along with its header metadata
+pub struct Frame<'a> {
+    pub payload: &'a [u8],
+    pub source_id: u32,
+    pub sequence: u32,
+    pub flags: u16,
+    pub fragments: u16,
+    pub mapping_size: u32,
+    pub source_time_us: u64,
+}
+
+/// A sequence being reassembled
+#[derive(Default)]
+struct Slot {
+    sequence: Option<u32>, // None while the slot is free
+    buffer: Vec<u8>,
+    fragments: Vec<bool>,
+    total_fragments: u16,
+    received_fragments: u16,
+    payload_size: u32,
+    mapping_size: u32,
+    flags: u16,
+    source_time_us: u64,
+    parity_fragments: u16,
+    received_parity: u16,
+    parity_buf: Vec<u8>,
+}
+
+impl Slot {
+    fn start(&mut self, header: &DatagramHeader) {
+        self.sequence = Some(header.sequence);
+        self.total_fragments = header.fragments;
+        self.received_fragments = 0;
+        self.payload_size = header.payload_size;
+        self.mapping_size = header.mapping_size;
+        self.flags = header.flags & !PARITY_MASK;
+        self.source_time_us = 0;
+        self.parity_fragments = parity_count(header);
+        self.received_parity = 0;
+
+        // Reset fragment tracking, parity fragments come after the data fragments
+        let fragments = (header.fragments + self.parity_fragments) as usize;
+        if self.fragments.len() == fragments {
+            self.fragments.fill(false);
+        } else {
+            self.fragments.clear();
+            self.fragments.resize(fragments, false);
+        }
+
+        // Size the buffers for the sequence. With parity the buffer holds whole shards, the
+        // last one padded with zeros like the sender did.
+        let (buffer_len, parity_len) = if self.parity_fragments > 0 {
+            let shard_size = self.shard_size();
+            (
+                header.fragments as usize * shard_size,
+                self.parity_fragments as usize * shard_size,
+            )
+        } else {
+            (header.payload_size as usize, 0)
+        };
+
+        // A steady stream of same-size updates overwrites the buffers in place rather than
+        // filling them with zeros first, only the padding has to be zeroed again
+        if self.buffer.len() == buffer_len {
+            self.buffer[header.payload_size as usize..].fill(0);
+        } else {
+            self.buffer.clear();
+            self.buffer.resize(buffer_len, 0);
+        }
+        if self.parity_buf.len() != parity_len {
+            self.parity_buf.clear();
+            self.parity_buf.resize(parity_len, 0);
+        }
+    }
+
+    /// Size of the data and parity shards of the sequence, that of its first fragment
+    fn shard_size(&self) -> usize {
+        (self.payload_size as usize).min(MAX_PAYLOAD_SIZE)
+    }
+
+    /// Rebuilds the missing data fragments of the sequence from the parity fragments,
+    /// reusing `decoder` if it fits. Returns false if they can't be rebuilt.
+    fn reconstruct(&mut self, decoder: &mut Option<ReedSolomon>) -> bool {
+        let data = self.total_fragments as usize;
+        let parity = self.parity_fragments as usize;
+        let shard_size = self.shard_size();
+
+        let reused = match decoder.take() {
+            Some(decoder)
+                if decoder.data_shard_count() == data && decoder.parity_shard_count() == parity =>
+            {
+                decoder
+            }
+            _ => match ReedSolomon::new(data, parity) {
+                Ok(decoder) => decoder,
+                Err(_) => return false,
+            },
+        };
+
+        let mut shards: Vec<(&mut [u8], bool)> = self
+            .buffer
+            .chunks_mut(shard_size)
+            .chain(self.parity_buf.chunks_mut(shard_size))
+            .zip(self.fragments.iter().copied())
+            .collect();
+        let result = reused.reconstruct_data(&mut shards);
+
+        *decoder = Some(reused);
+        result.is_ok()
+    }
+}
+
+/// Reassembles the fragments of the sequences within a window of the newest ones, one
+/// slot each. With a depth of 1 a new sequence replaces the one in progress. A deeper
+/// window lets sequences the network interleaves complete independently, at the cost of
+/// a buffer per slot.
+pub struct Receiver {
+    max_payload_size: usize,
+    source_id: Option<u32>,
+    source_filtered: bool,
+    slots: Vec<Slot>,
+    // The newest sequence seen and the oldest one still in the window
+    newest_sequence: Option<u32>,
+    oldest_sequence: u32,
+    // Sequences in the window that were completed, their leftovers are ignored
+    completed: Vec<u32>,
+    lost: u32,
+    decoder: Option<ReedSolomon>,
+}
+
+impl Receiver {
+    pub fn new(max_payload_size: usize) -> Self {
+        Self {
+            max_payload_size,
+            source_id: None,
+            source_filtered: false,
+            slots: vec![Slot::default()],
+            newest_sequence: None,
+            oldest_sequence: 0,
+            completed: Vec::new(),
+            lost: 0,
+            decoder: None,
+        }
+    }
+
+    /// Reassembles up to `depth` sequences at a time, clamped to 1 to
+    /// `MAX_REASSEMBLY_DEPTH`
+    pub fn set_depth(&mut self, depth: usize) {
+        let depth = depth.clamp(1, MAX_REASSEMBLY_DEPTH);
+        self.slots.resize_with(depth, Slot::default);
+    }
+
+    /// Only accepts datagrams from the given source id. Without a filter, the receiver
+    /// locks onto the first source id it sees.
+    pub fn filter_source(&mut self, source_id: u32) {
+        self.source_id = Some(source_id);
+        self.source_filtered = true;
+    }
+
+    /// Returns the id of the source whose datagrams are accepted, if locked onto one
+    pub fn source_id(&self) -> Option<u32> {
+        self.source_id
+    }
+
+    /// Forgets the source id locked onto (if not filtered), so the next source seen is accepted
+    pub fn release_source(&mut self) {
+        if !self.source_filtered {
+            self.source_id = None;
+            self.newest_sequence = None;
+            self.completed.clear();
+            for slot in &mut self.slots {
+                slot.sequence = None;
+            }
+        }
+    }
+
+    /// Returns how many updates were lost since the last call, either because fragments
+    /// went missing or because no datagram of them arrived at all
+    pub fn take_lost(&mut self) -> u32 {
+        std::mem::take(&mut self.lost)
+    }
+
+    /// Returns the newest sequence being reassembled, if any. Like the other progress
+    /// getters this is for diagnostics, not part of the protocol, and may change.
+    pub fn current_sequence(&self) -> Option<u32> {
+        self.current_slot().and_then(|slot| slot.sequence)
+    }
+
+    /// Returns how many data fragments of the current sequence arrived so far, not
+    /// counting parity. For diagnostics.
+    pub fn received_fragments(&self) -> u16 {
+        self.current_slot()
+            .map_or(0, |slot| slot.received_fragments)
+    }
+
+    /// Returns how many data fragments the current sequence has. For diagnostics.
+    pub fn total_fragments(&self) -> u16 {
+        self.current_slot().map_or(0, |slot| slot.total_fragments)
+    }
+
+    fn current_slot(&self) -> Option<&Slot> {
+        let oldest = self.oldest_sequence;
+        self.slots
+            .iter()
+            .filter_map(|slot| Some((slot.sequence?.wrapping_sub(oldest), slot)))
+            .max_by_key(|&(age, _)| age)
+            .map(|(_, slot)| slot)
+    }
+
+    pub fn process_datagram(&mut self, data: &[u8]) -> (Option<Frame<'_>>, bool) {
+        // Parse header, ignoring anything too short to have one
+        let Some(header) = DatagramHeader::parse(data) else {
+            return (None, false);
+        };
+
+        // Ignore other sources sharing the group so their fragments don't interleave
+        if self
+            .source_id
+            .is_some_and(|source_id| header.source_id != source_id)
+        {
+            return (None, false);
+        }
+
+        // Keyframe requests, probes and loss reports travel between targets and sources and
+        // are no frames
+        if header.flags & (FLAG_KEYFRAME_REQUEST | FLAG_PROBE | FLAG_PROBE_REPLY | FLAG_LOSS_REPORT)
+            != 0
+        {
+            return (None, false);
+        }
+
+        // Heartbeats carry no payload and leave the reassembly state untouched
+        if header.flags & FLAG_HEARTBEAT != 0 {
+            let frame = Frame {
+                payload: &[],
+                source_id: header.source_id,
+                sequence: header.sequence,
+                flags: header.flags,
+                fragments: 0,
+                mapping_size: header.mapping_size,
+                source_time_us: 0,
+            };
+            return (Some(frame), false);
+        }
+
+        // Reject sizes that can't be right, rather than allocating whatever the header says
+        if header.payload_size as usize > self.max_payload_size
+            || header.fragments as usize
+                != (header.payload_size as usize).div_ceil(MAX_PAYLOAD_SIZE)
+        {
+            return (None, false);
+        }
+
+        // Left over from a sequence completed already, like parity that wasn't needed
+        let sequence = header.sequence;
+        if self.completed.contains(&sequence) {
+            return (None, false);
+        }
+
+        match self.newest_sequence {
+            // Within the window
+            Some(newest)
+                if sequence.wrapping_sub(self.oldest_sequence)
+                    <= newest.wrapping_sub(self.oldest_sequence) => {}
+            // Stragglers of an earlier sequence would abort the ones being reassembled
+            Some(newest) if (1..=STALE_SEQUENCES).contains(&newest.wrapping_sub(sequence)) => {
+                return (None, false);
+            }
+            _ => self.advance(sequence),
+        }
+
+        // A sequence change is indicated when we receive fragment 0,
+        // regardless of whether we've seen other fragments of this sequence before
+        let sequence_changed = header.fragment == 0;
+
+        // Initialize the slot of a sequence seen for the first time. The window holds no
+        // more sequences than there are slots, so one is free.
+        let index = match self
+            .slots
+            .iter()
+            .position(|slot| slot.sequence == Some(sequence))
+        {
+            Some(index) => index,
+            None => {
+                let index = self
+                    .slots
+                    .iter()
+                    .position(|slot| slot.sequence.is_none())
+                    .expect("free slot");
+                self.slots[index].start(&header);
+                index
+            }
+        };
+        let slot = &mut self.slots[index];
+
+        // Validate fragment against the sequence it belongs to
+        if header.fragment >= slot.total_fragments + slot.parity_fragments
+            || header.fragments != slot.total_fragments
+            || parity_count(&header) != slot.parity_fragments
+        {
+            return (None, sequence_changed);
+        }
+
+        // Check if we already received this fragment
+        if slot.fragments[header.fragment as usize] {
+            return (None, sequence_changed);
+        }
+
+        // Lock onto the first source that sends a valid fragment
+        self.source_id.get_or_insert(header.source_id);
+
+        // Store the source processing time from fragment 0
+        if header.fragment == 0 {
+            slot.source_time_us = header.source_time_us;
+        }
+
+        // Copy fragment data
+        let fragment_size = data.len() - HEADER_SIZE;
+        if header.fragment < slot.total_fragments {
+            let buffer_offset = header.fragment as usize * MAX_PAYLOAD_SIZE;
+
+            if buffer_offset + fragment_size > slot.payload_size as usize {
+                return (None, sequence_changed);
+            }
+
+            slot.buffer[buffer_offset..buffer_offset + fragment_size]
+                .copy_from_slice(&data[HEADER_SIZE..]);
+            slot.received_fragments += 1;
+        } else {
+            let shard_size = slot.shard_size();
+            if fragment_size != shard_size {
+                return (None, sequence_changed);
+            }
+
+            let parity_offset = (header.fragment - slot.total_fragments) as usize * shard_size;
+            slot.parity_buf[parity_offset..parity_offset + shard_size]
+                .copy_from_slice(&data[HEADER_SIZE..]);
+            slot.received_parity += 1;
+        }
+
+        // Mark fragment as received
+        slot.fragments[header.fragment as usize] = true;
+
+        // Check if we have all fragments, or enough parity to rebuild the missing ones
+        let complete = slot.received_fragments == slot.total_fragments
+            || (slot.received_fragments + slot.received_parity >= slot.total_fragments
+                && slot.reconstruct(&mut self.decoder));
+        if !complete {
+            return (None, sequence_changed);
+        }
+
+        slot.sequence = None;
+        self.completed.push(sequence);
+        let slot = &self.slots[index];
+        let frame = Frame {
+            payload: &slot.buffer[..slot.payload_size as usize],
+            source_id: header.source_id,
+            sequence,
+            flags: slot.flags,
+            fragments: slot.total_fragments,
+            mapping_size: slot.mapping_size,
+            source_time_us: slot.source_time_us,
+        };
+        (Some(frame), sequence_changed)
+    }
+
+    /// Moves the window on to end at `sequence`, a newer sequence than any seen before.
+    /// Sequences that leave the window without completing are counted as lost, and their
+    /// slots freed.
+    fn advance(&mut self, sequence: u32) {
+        let depth = self.slots.len() as u32;
+        let oldest = sequence.wrapping_sub(depth - 1);
+
+        match self
+            .newest_sequence
+            .map(|newest| sequence.wrapping_sub(newest))
+        {
+            Some(gap) if gap < MAX_SEQUENCE_GAP => {
+                // Until the window first fills up, nothing leaves it
+                let leaving = oldest.wrapping_sub(self.oldest_sequence);
+                if leaving < MAX_SEQUENCE_GAP {
+                    let completed = self
+                        .completed
+                        .iter()
+                        .filter(|&&completed| {
+                            completed.wrapping_sub(self.oldest_sequence) < leaving
+                        })
+                        .count() as u32;
+                    self.lost += leaving - completed;
+                    self.oldest_sequence = oldest;
+                }
+            }
+            // A restarted source, only the sequences in progress are lost
+            _ => {
+                self.lost += self
+                    .slots
+                    .iter()
+                    .filter(|slot| slot.sequence.is_some())
+                    .count() as u32;
+                self.oldest_sequence = sequence;
+            }
+        }
+        self.newest_sequence = Some(sequence);
+
+        let oldest = self.oldest_sequence;
+        let in_window = |candidate: u32| candidate.wrapping_sub(oldest) < depth;
+        for slot in &mut self.slots {
+            if slot.sequence.is_some_and(|candidate| !in_window(candidate)) {
+                slot.sequence = None;
             }
-            last_sequence = current_sequence;
         }
+        self.completed.retain(|&completed| in_window(completed));
+    }
+}
+
+/// Number of parity fragments announced in a datagram's flags, or 0 if the payload has too
+/// many fragments for forward error correction
+fn parity_count(header: &DatagramHeader) -> u16 {
+    let parity = (header.flags & PARITY_MASK) >> PARITY_SHIFT;
+    if header.fragments > 0 && header.fragments as usize + parity as usize <= MAX_FEC_FRAGMENTS {
+        parity
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function to create test data
+    fn create_test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 256) as u8).collect()
     }
 
     #[test]
-    fn test_duplicate_fragment_handling() {
-        let data = create_test_data(MAX_PAYLOAD_SIZE * 2); // Will require 2 fragments
+    fn test_single_fragment_send_receive() {
+        let data = create_test_data(1000);
         let mut sent_datagrams = Vec::new();
-        let mut sender = Sender::new();
+        let mut sender = Sender::new(0);
 
         // Send the data
         sender
-            .send(&data, 0, |datagram| {
+            .send(&data, 0, 0, |datagram| {
                 sent_datagrams.push(datagram.to_vec());
                 Ok(())
             })
             .unwrap();
 
-        // Receive with duplicate fragments
-        let mut receiver = Receiver::new(data.len());
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
-        assert!(received.is_none());
-        assert!(
-            sequence_changed,
-            "First fragment should indicate sequence change"
-        );
+        // Verify we got exactly one datagram
+        assert_eq!(sent_datagrams.len(), 1);
 
+        // Receive the data
+        let mut receiver = Receiver::new(MAX_DATAGRAM_SIZE);
         let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
-        assert!(received.is_none());
         assert!(
             sequence_changed,
-            "Duplicate fragment may indicate sequence change if we get the first fragment twice"
-        );
-
-        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[1]);
-        assert!(
-            !sequence_changed,
-            "Second fragment should not indicate sequence change"
+            "First datagram should indicate sequence change"
         );
-        let received = received.unwrap();
+        let received = received.unwrap().payload;
 
         // Verify the received data matches the original
         assert_eq!(received, data);
     }
 
     #[test]
-    fn test_invalid_fragment_number() {
-        let data = create_test_data(1000);
+    fn test_multi_fragment_send_receive() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 3 + 1000); // Will require 4 fragments
         let mut sent_datagrams = Vec::new();
-        let mut sender = Sender::new();
+        let mut sender = Sender::new(0);
 
         // Send the data
         sender
-            .send(&data, 0, |datagram| {
+            .send(&data, 0, 0, |datagram| {
                 sent_datagrams.push(datagram.to_vec());
                 Ok(())
             })
             .unwrap();
 
-        // Corrupt the fragment number in the header
-        let mut corrupted = sent_datagrams[0].clone();
-        let header = unsafe { &mut *(corrupted.as_mut_ptr() as *mut DatagramHeader) };
-        header.fragment = 99; // Invalid fragment number
-
+        // Verify we got 4 datagrams
+        assert_eq!(sent_datagrams.len(), 4);
+
+        // Receive the data in order
+        let mut receiver = Receiver::new(data.len());
+        for (i, datagram) in sent_datagrams.iter().take(3).enumerate() {
+            let (received, sequence_changed) = receiver.process_datagram(datagram);
+            assert!(
+                received.is_none(),
+                "Fragment {} should not complete sequence",
+                i
+            );
+            assert_eq!(
+                sequence_changed,
+                i == 0,
+                "Only first fragment should indicate sequence change"
+            );
+        }
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[3]);
+        assert!(
+            !sequence_changed,
+            "Last fragment should not indicate sequence change"
+        );
+        let received = received.unwrap().payload;
+
+        // Verify the received data matches the original
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_out_of_order_fragments() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 2 + 1000); // Will require 3 fragments
+        let mut sent_datagrams = Vec::new();
+        let mut sender = Sender::new(0);
+
+        // Send the data
+        sender
+            .send(&data, 0, 0, |datagram| {
+                sent_datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        // Receive the data out of order
+        let mut receiver = Receiver::new(data.len());
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[2]);
+        assert!(received.is_none());
+        assert!(
+            !sequence_changed,
+            "Last fragment should not indicate sequence change"
+        );
+
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
+        assert!(received.is_none());
+        assert!(
+            sequence_changed,
+            "First fragment should indicate sequence change"
+        );
+
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[1]);
+        assert!(
+            !sequence_changed,
+            "Middle fragment should not indicate sequence change"
+        );
+        let received = received.unwrap().payload;
+
+        // Verify the received data matches the original
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_stale_fragment_does_not_abort_sequence() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 2); // Will require 2 fragments
+        let mut sent_datagrams = Vec::new();
+        let mut sender = Sender::new(0);
+        for _ in 0..2 {
+            sender
+                .send(&data, 0, 0, |datagram| {
+                    sent_datagrams.push(datagram.to_vec());
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        // A fragment of the first update arrives in the middle of the second one
+        let mut receiver = Receiver::new(data.len());
+        let (received, _) = receiver.process_datagram(&sent_datagrams[2]);
+        assert!(received.is_none());
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[1]);
+        assert!(received.is_none());
+        assert!(!sequence_changed, "Stale fragment should be ignored");
+
+        let (received, _) = receiver.process_datagram(&sent_datagrams[3]);
+        assert_eq!(received.unwrap().payload, data);
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let data = create_test_data(1000);
+        let mut sender = Sender::new(0);
+        let mut last_sequence: Option<u32> = None;
+
+        // Send the data multiple times
+        for _ in 0..3 {
+            let mut current_sequence = None;
+            sender
+                .send(&data, 0, 0, |datagram| {
+                    // Extract sequence number from header
+                    let header = DatagramHeader::parse(datagram).unwrap();
+                    current_sequence = Some(header.sequence);
+                    Ok(())
+                })
+                .unwrap();
+
+            if let Some(last) = last_sequence {
+                assert_eq!(current_sequence.unwrap(), last.wrapping_add(1));
+            }
+            last_sequence = current_sequence;
+        }
+    }
+
+    #[test]
+    fn test_duplicate_fragment_handling() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 2); // Will require 2 fragments
+        let mut sent_datagrams = Vec::new();
+        let mut sender = Sender::new(0);
+
+        // Send the data
+        sender
+            .send(&data, 0, 0, |datagram| {
+                sent_datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        // Receive with duplicate fragments
+        let mut receiver = Receiver::new(data.len());
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
+        assert!(received.is_none());
+        assert!(
+            sequence_changed,
+            "First fragment should indicate sequence change"
+        );
+
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[0]);
+        assert!(received.is_none());
+        assert!(
+            sequence_changed,
+            "Duplicate fragment may indicate sequence change if we get the first fragment twice"
+        );
+
+        let (received, sequence_changed) = receiver.process_datagram(&sent_datagrams[1]);
+        assert!(
+            !sequence_changed,
+            "Second fragment should not indicate sequence change"
+        );
+        let received = received.unwrap().payload;
+
+        // Verify the received data matches the original
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_payload_layout_is_checked() {
+        assert_eq!(payload_layout(0).unwrap(), (0, 0));
+        assert_eq!(
+            payload_layout(MAX_PAYLOAD_SIZE + 1).unwrap(),
+            (MAX_PAYLOAD_SIZE as u32 + 1, 2)
+        );
+
+        let max_fragments = u16::MAX as usize * MAX_PAYLOAD_SIZE;
+        assert_eq!(payload_layout(max_fragments).unwrap().1, u16::MAX);
+        let e = payload_layout(max_fragments + 1).unwrap_err();
+        assert_eq!(e.to_string(), "Compressed data too large");
+
+        // Too large for payload_size itself, which would otherwise wrap around
+        let e = payload_layout(u32::MAX as usize + 1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("4294967295 bytes"), "{}", e);
+    }
+
+    #[test]
+    fn test_invalid_fragment_number() {
+        let data = create_test_data(1000);
+        let mut sent_datagrams = Vec::new();
+        let mut sender = Sender::new(0);
+
+        // Send the data
+        sender
+            .send(&data, 0, 0, |datagram| {
+                sent_datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        // Corrupt the fragment number in the header
+        let mut corrupted = sent_datagrams[0].clone();
+        let mut header = DatagramHeader::parse(&corrupted).unwrap();
+        header.fragment = 99; // Invalid fragment number
+        corrupted[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
         // Attempt to receive corrupted datagram
         let mut receiver = Receiver::new(data.len());
         let (received, sequence_changed) = receiver.process_datagram(&corrupted);
@@ -414,4 +1741,849 @@ mod tests {
             "we can't make sense of a random fragment number showing up"
         );
     }
+
+    #[test]
+    fn test_multiple_sources_do_not_interleave() {
+        let data_a = create_test_data(MAX_PAYLOAD_SIZE * 2 + 100);
+        let data_b: Vec<u8> = create_test_data(MAX_PAYLOAD_SIZE * 2 + 100)
+            .iter()
+            .map(|b| b.wrapping_add(1))
+            .collect();
+
+        let mut datagrams_a = Vec::new();
+        let mut datagrams_b = Vec::new();
+        let mut sender_a = Sender::new(1);
+        let mut sender_b = Sender::new(2);
+
+        sender_a
+            .send(&data_a, 0, 0, |datagram| {
+                datagrams_a.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        sender_b
+            .send(&data_b, 0, 0, |datagram| {
+                datagrams_b.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        // Both senders start at sequence 0, interleave their fragments
+        let interleaved: Vec<_> = datagrams_a
+            .iter()
+            .zip(datagrams_b.iter())
+            .flat_map(|(a, b)| [a, b])
+            .collect();
+
+        // Without a filter, the receiver locks onto the first source it sees
+        let mut receiver = Receiver::new(data_a.len());
+        let mut completed = Vec::new();
+        for datagram in &interleaved {
+            if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                assert_eq!(frame.source_id, 1);
+                completed.push(frame.payload.to_vec());
+            }
+        }
+        assert_eq!(completed, vec![data_a.clone()]);
+
+        // With a filter, only the selected source is reassembled
+        let mut receiver = Receiver::new(data_b.len());
+        receiver.filter_source(2);
+        let mut completed = Vec::new();
+        for datagram in &interleaved {
+            if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                assert_eq!(frame.source_id, 2);
+                completed.push(frame.payload.to_vec());
+            }
+        }
+        assert_eq!(completed, vec![data_b]);
+    }
+
+    #[test]
+    fn test_flags_are_preserved() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE + 10);
+        let mut sent_datagrams = Vec::new();
+        let mut sender = Sender::new(0);
+
+        sender
+            .send(&data, 42, FLAG_SESSION_INFO, |datagram| {
+                sent_datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        let mut receiver = Receiver::new(data.len());
+        assert!(receiver.process_datagram(&sent_datagrams[0]).0.is_none());
+        let frame = receiver.process_datagram(&sent_datagrams[1]).0.unwrap();
+        assert_eq!(frame.flags, FLAG_SESSION_INFO);
+        assert_eq!(frame.fragments, 2);
+        assert_eq!(frame.source_time_us, 42);
+        assert_eq!(frame.payload, data);
+    }
+
+    #[test]
+    fn test_goodbye_is_a_marked_heartbeat() {
+        let mut sender = Sender::new(7);
+        let mut goodbye = Vec::new();
+        sender
+            .send_goodbye(|datagram| {
+                goodbye = datagram.to_vec();
+                Ok(())
+            })
+            .unwrap();
+
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE);
+        let (frame, _) = receiver.process_datagram(&goodbye);
+        let frame = frame.unwrap();
+        assert!(is_goodbye(frame.flags));
+        assert!(frame.flags & FLAG_HEARTBEAT != 0);
+        assert!(frame.payload.is_empty());
+        assert_eq!(frame.source_id, 7);
+
+        assert!(!is_goodbye(FLAG_HEARTBEAT));
+        assert!(!is_goodbye(FLAG_SESSION_INFO));
+
+        // Neither is a region or the session info
+        assert!(!is_region(frame.flags));
+        assert!(!is_session_info(frame.flags));
+        assert!(is_region(FLAG_REGION | FLAG_VERIFY));
+        assert!(!is_session_info(FLAG_REGION));
+        assert!(is_session_info(FLAG_SESSION_INFO | FLAG_UNCOMPRESSED));
+    }
+
+    #[test]
+    fn test_heartbeats_do_not_disturb_frames() {
+        let mut sender = Sender::new(0);
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE * 3);
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 2);
+
+        let mut datagrams = Vec::new();
+        sender
+            .send(&data, 0, 0, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        let mut heartbeat = Vec::new();
+        sender
+            .send_heartbeat(|datagram| {
+                heartbeat = datagram.to_vec();
+                Ok(())
+            })
+            .unwrap();
+
+        // A heartbeat between two fragments is reported without resetting the reassembly
+        assert!(receiver.process_datagram(&datagrams[0]).0.is_none());
+        let (frame, sequence_changed) = receiver.process_datagram(&heartbeat);
+        let frame = frame.unwrap();
+        assert_eq!(frame.flags, FLAG_HEARTBEAT);
+        assert!(frame.payload.is_empty());
+        assert!(!sequence_changed);
+
+        let (frame, _) = receiver.process_datagram(&datagrams[1]);
+        assert_eq!(frame.unwrap().payload, &data[..]);
+
+        // The heartbeat didn't use up a sequence number
+        let mut sequences = Vec::new();
+        for _ in 0..2 {
+            sender
+                .send(&[1, 2, 3], 0, 0, |datagram| {
+                    let header = DatagramHeader::parse(datagram).unwrap();
+                    sequences.push(header.sequence);
+                    Ok(())
+                })
+                .unwrap();
+            sender.send_heartbeat(|_| Ok(())).unwrap();
+        }
+        assert_eq!(sequences, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_keyframe_requests() {
+        let request = keyframe_request(7);
+        assert_eq!(parse_keyframe_request(&request), Some(7));
+
+        // Frames and heartbeats are no requests
+        let mut sender = Sender::new(7);
+        let mut datagrams = Vec::new();
+        sender
+            .send(&[1, 2, 3], 0, 0, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        sender
+            .send_heartbeat(|datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        for datagram in &datagrams {
+            assert_eq!(parse_keyframe_request(datagram), None);
+        }
+
+        // A request that ends up at a receiver is ignored
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE);
+        assert!(receiver.process_datagram(&request).0.is_none());
+        let (frame, _) = receiver.process_datagram(&datagrams[0]);
+        assert_eq!(frame.unwrap().payload, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_probes() {
+        let request = probe(7, 1_000);
+        assert_eq!(parse_probe(&request), Some((7, 1_000)));
+        assert_eq!(parse_keyframe_request(&request), None);
+        assert_eq!(parse_probe(&keyframe_request(7)), None);
+
+        let reply = probe_reply(&ProbeTimes {
+            source_id: 7,
+            sent_us: 1_000,
+            received_us: 2_000,
+            replied_us: 2_010,
+        });
+        let times = parse_probe_reply(&reply).unwrap();
+        assert_eq!(times.source_id, 7);
+        assert_eq!(times.sent_us, 1_000);
+        assert_eq!(times.received_us, 2_000);
+        assert_eq!(times.replied_us, 2_010);
+        assert!(parse_probe_reply(&request).is_none());
+
+        // Neither direction ends up as a frame
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE);
+        assert!(receiver.process_datagram(&request).0.is_none());
+        assert!(receiver.process_datagram(&reply).0.is_none());
+    }
+
+    #[test]
+    fn test_send_log_times_echoed_updates() {
+        let mut log = SendLog::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        for sequence in 0..SEND_LOG_SIZE as u32 + 10 {
+            let read = start + ms(sequence as u64);
+            log.record(sequence, read, read + ms(1));
+        }
+
+        // Read at 200 ms, sent at 201 ms, back at 209 ms after the target held it 2 ms
+        let echo = |sequence, held_us| Echo { sequence, held_us };
+        let received = start + ms(209);
+        assert_eq!(log.latency(&echo(200, 2_000), received), Some(ms(4)));
+
+        // Forgotten, not sent yet, and held longer than the round trip took
+        assert_eq!(log.latency(&echo(5, 0), received), None);
+        assert_eq!(log.latency(&echo(1_000, 0), received), None);
+        assert_eq!(log.latency(&echo(200, 9_000), received), None);
+    }
+
+    #[test]
+    fn test_loss_reports() {
+        let report = loss_report(&LossReport {
+            source_id: 7,
+            received: 290,
+            lost: 10,
+            echo: None,
+        });
+        let parsed = parse_loss_report(&report).unwrap();
+        assert_eq!(parsed.source_id, 7);
+        assert_eq!(parsed.received, 290);
+        assert_eq!(parsed.lost, 10);
+        assert_eq!(parsed.echo, None);
+
+        let echo = Echo {
+            sequence: 0,
+            held_us: 35,
+        };
+        let report = loss_report(&LossReport {
+            source_id: 7,
+            received: 60,
+            lost: 0,
+            echo: Some(echo),
+        });
+        let parsed = parse_loss_report(&report).unwrap();
+        assert_eq!((parsed.received, parsed.echo), (60, Some(echo)));
+        assert!(parse_loss_report(&probe(7, 1_000)).is_none());
+        assert_eq!(parse_probe(&report), None);
+
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE);
+        assert!(receiver.process_datagram(&report).0.is_none());
+    }
+
+    #[test]
+    fn test_hellos() {
+        let announced = Hello {
+            source_id: 7,
+            version: PROTOCOL_VERSION,
+            codec: CODEC_LZ4,
+            features: FEATURE_VERIFY | FEATURE_DICTIONARY,
+            mapping_size: 1_164_909,
+            dictionary_id: 0xdead_beef,
+        };
+        let datagram = hello(&announced);
+        assert_eq!(parse_hello(&datagram), Some(announced.clone()));
+
+        // A later version with more fields still reads
+        let mut longer = datagram.to_vec();
+        longer[HEADER_SIZE] = PROTOCOL_VERSION + 1;
+        longer.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(parse_hello(&longer).unwrap().version, PROTOCOL_VERSION + 1);
+
+        let rejected = HelloReply {
+            source_id: 7,
+            version: PROTOCOL_VERSION,
+            rejection: Some(Rejection::Dictionary),
+        };
+        let reply = hello_reply(&rejected);
+        assert_eq!(parse_hello_reply(&reply), Some(rejected));
+        assert!(parse_hello(&reply).is_none());
+        assert!(parse_hello_reply(&datagram).is_none());
+
+        // Peers that don't know them parse neither as anything else
+        assert!(parse_probe_reply(&datagram).is_none());
+        assert!(parse_probe(&reply).is_none());
+        assert!(parse_keyframe_request(&reply).is_none());
+        assert!(parse_loss_report(&reply).is_none());
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE);
+        assert!(receiver.process_datagram(&datagram).0.is_none());
+        assert!(receiver.process_datagram(&reply).0.is_none());
+    }
+
+    #[test]
+    fn test_clock_sync_estimates_delay_and_offset() {
+        let mut clock_sync = ClockSync::default();
+        assert!(clock_sync.probe(7).is_some());
+        assert!(clock_sync.probe(7).is_none(), "probes are rate limited");
+
+        // The source's clock is 5 s ahead, the network takes 300 µs each way and the
+        // source takes 20 µs to reply
+        let reply = |sent_us: u64| ProbeTimes {
+            source_id: 7,
+            sent_us,
+            received_us: sent_us + 5_000_300,
+            replied_us: sent_us + 5_000_320,
+        };
+        let estimate = clock_sync.add_reply(&reply(1_000), 1_620).unwrap();
+        assert_eq!(estimate.delay_us, 300.0);
+        assert_eq!(estimate.offset_us, 5_000_000.0);
+
+        // A clock stepped back mid round trip is dropped, as are replies of other sources
+        let estimate = clock_sync.add_reply(&reply(10_000), 9_000).unwrap();
+        assert_eq!(estimate.delay_us, 300.0);
+        let other = ProbeTimes {
+            source_id: 8,
+            ..reply(20_000)
+        };
+        let estimate = clock_sync.add_reply(&other, 30_000).unwrap();
+        assert_eq!(estimate.delay_us, 300.0);
+
+        // Later round trips are smoothed in
+        let estimate = clock_sync.add_reply(&reply(40_000), 41_420).unwrap();
+        assert!(estimate.delay_us > 300.0 && estimate.delay_us < 700.0);
+
+        // Probing another source starts over
+        assert!(clock_sync.probe(8).is_some());
+        assert!(clock_sync.add_reply(&reply(50_000), 50_620).is_none());
+    }
+
+    #[test]
+    fn test_pacing_spreads_fragments() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 3 + 10); // Will require 4 fragments
+        let mut sender = Sender::new(0);
+        sender.set_pacing(Some(Duration::from_millis(40)));
+
+        let start = Instant::now();
+        let mut sent_at = Vec::new();
+        sender
+            .send_batch(&data, 0, 0, |datagrams| {
+                assert_eq!(datagrams.len(), 1);
+                sent_at.push(start.elapsed());
+                Ok(())
+            })
+            .unwrap();
+
+        // 10ms apart, the last one leaves before the window is over
+        assert_eq!(sent_at.len(), 4);
+        for (i, at) in sent_at.iter().enumerate() {
+            assert!(*at >= Duration::from_millis(10) * i as u32, "{:?}", sent_at);
+        }
+        assert!(sent_at[3] < Duration::from_millis(40), "{:?}", sent_at);
+    }
+
+    #[test]
+    fn test_lost_updates_are_counted() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE + 10); // Will require 2 fragments
+        let mut sender = Sender::new(0);
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE * 2);
+        let mut sequences = Vec::new();
+        for _ in 0..5 {
+            let mut datagrams = Vec::new();
+            sender
+                .send(&data, 0, 0, |datagram| {
+                    datagrams.push(datagram.to_vec());
+                    Ok(())
+                })
+                .unwrap();
+            sequences.push(datagrams);
+        }
+
+        // The first arrives complete, the second misses a fragment and the third is gone
+        for datagram in &sequences[0] {
+            receiver.process_datagram(datagram);
+        }
+        receiver.process_datagram(&sequences[1][0]);
+        for datagram in sequences[3].iter().chain(&sequences[4]) {
+            receiver.process_datagram(datagram);
+        }
+        assert_eq!(receiver.take_lost(), 2);
+        assert_eq!(receiver.take_lost(), 0);
+
+        // A restarted source is no loss
+        let mut sender = Sender::new(0);
+        sender
+            .send(&data, 0, 0, |datagram| {
+                receiver.process_datagram(datagram);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(receiver.take_lost(), 0);
+    }
+
+    fn send_with_fec(data: &[u8], parity: u8) -> Vec<Vec<u8>> {
+        let mut sender = Sender::new(0);
+        sender.set_fec(parity);
+        let mut datagrams = Vec::new();
+        let sent = sender
+            .send(data, 0, FLAG_SESSION_INFO, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(sent as usize, datagrams.len());
+        let wire_bytes: usize = datagrams.iter().map(Vec::len).sum();
+        assert_eq!(sender.wire_size(data.len()), wire_bytes as u64);
+        datagrams
+    }
+
+    #[test]
+    fn test_fec_recovers_lost_fragments() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 3 + 1000); // Will require 4 fragments
+        let datagrams = send_with_fec(&data, 2);
+        assert_eq!(datagrams.len(), 6);
+
+        // Any one or two lost fragments, including the short last one, are rebuilt
+        for lost in [vec![0], vec![3], vec![1, 2], vec![0, 5]] {
+            let mut receiver = Receiver::new(data.len());
+            let mut frames = Vec::new();
+            for (i, datagram) in datagrams.iter().enumerate() {
+                if lost.contains(&i) {
+                    continue;
+                }
+                if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                    assert_eq!(frame.flags, FLAG_SESSION_INFO);
+                    assert_eq!(frame.fragments, 4);
+                    frames.push(frame.payload.to_vec());
+                }
+            }
+            assert_eq!(frames, std::slice::from_ref(&data), "lost {:?}", lost);
+        }
+
+        // More lost fragments than parity can't be rebuilt
+        let mut receiver = Receiver::new(data.len());
+        for datagram in &datagrams[3..] {
+            assert!(receiver.process_datagram(datagram).0.is_none());
+        }
+    }
+
+    #[test]
+    fn test_same_size_sequences_reuse_the_buffers() {
+        let mut sender = Sender::new(0);
+        sender.set_fec(1);
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE * 4);
+
+        // Same size but different content each time, losing a different fragment of each
+        // so the reused buffers get rebuilt in every position, the padded last one included
+        for lost in 0..4 {
+            let data: Vec<u8> = (0..MAX_PAYLOAD_SIZE * 2 + 10)
+                .map(|i| (i * (lost + 1) % 251) as u8)
+                .collect();
+            let mut datagrams = Vec::new();
+            sender
+                .send(&data, 0, 0, |datagram| {
+                    datagrams.push(datagram.to_vec());
+                    Ok(())
+                })
+                .unwrap();
+
+            let mut frames = Vec::new();
+            for (i, datagram) in datagrams.iter().enumerate() {
+                if i == lost {
+                    continue;
+                }
+                if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                    frames.push(frame.payload.to_vec());
+                }
+            }
+            assert_eq!(frames, std::slice::from_ref(&data), "lost {}", lost);
+        }
+    }
+
+    #[test]
+    fn test_fec_single_fragment() {
+        let data = create_test_data(100);
+        let datagrams = send_with_fec(&data, 1);
+        assert_eq!(datagrams.len(), 2);
+
+        // Only the parity fragment arrives
+        let mut receiver = Receiver::new(data.len());
+        let (frame, _) = receiver.process_datagram(&datagrams[1]);
+        assert_eq!(frame.unwrap().payload, &data[..]);
+
+        // Parity arriving after the data completed the frame is ignored
+        let mut receiver = Receiver::new(data.len());
+        let (frame, _) = receiver.process_datagram(&datagrams[0]);
+        assert_eq!(frame.unwrap().payload, &data[..]);
+        assert!(receiver.process_datagram(&datagrams[1]).0.is_none());
+    }
+
+    #[test]
+    fn test_redundant_copies() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 2 + 10); // Will require 3 fragments
+        let mut sender = Sender::new(0);
+        sender.set_redundancy(2);
+        let mut datagrams = Vec::new();
+        sender
+            .send(&data, 0, 0, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        // Each round carries every fragment once
+        assert_eq!(datagrams.len(), 6);
+        assert_eq!(datagrams[..3], datagrams[3..]);
+        let wire_bytes: usize = datagrams.iter().map(Vec::len).sum();
+        assert_eq!(sender.wire_size(data.len()), wire_bytes as u64);
+        assert_eq!(sender.take_sent_bytes(), wire_bytes as u64);
+        assert_eq!(sender.take_sent_bytes(), 0);
+
+        // A fragment lost in one round is taken from the other, and the frame comes out once
+        let mut receiver = Receiver::new(data.len());
+        let mut frames = Vec::new();
+        for (i, datagram) in datagrams.iter().enumerate() {
+            if i == 1 || i == 5 {
+                continue;
+            }
+            if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                frames.push(frame.payload.to_vec());
+            }
+        }
+        assert_eq!(frames, std::slice::from_ref(&data));
+        assert_eq!(receiver.take_lost(), 0);
+    }
+
+    #[test]
+    fn test_datagram_info() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE + 10);
+        let mut sender = Sender::new(3);
+        let mut datagrams = Vec::new();
+        sender
+            .send(&data, 1234, FLAG_SESSION_INFO, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        for (i, datagram) in datagrams.iter().enumerate() {
+            let info = datagram_info(datagram).unwrap();
+            assert_eq!(info.sequence, 0);
+            assert_eq!(info.source_id, 3);
+            assert_eq!(info.fragment, i as u16);
+            assert_eq!(info.fragments, 2);
+            assert_eq!(info.flags, FLAG_SESSION_INFO);
+            assert_eq!(info.source_time_us, 1234);
+        }
+
+        assert!(datagram_info(&datagrams[0][..HEADER_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn test_interleaved_sequences_complete_independently() {
+        let first = create_test_data(MAX_PAYLOAD_SIZE * 2 + 10);
+        let second: Vec<u8> = first.iter().map(|b| b.wrapping_add(1)).collect();
+        let mut sender = Sender::new(1);
+        let mut datagrams = Vec::new();
+        for data in [&first, &second] {
+            sender
+                .send(data, 0, 0, |datagram| {
+                    datagrams.push(datagram.to_vec());
+                    Ok(())
+                })
+                .unwrap();
+        }
+        let (a, b) = datagrams.split_at(3);
+        let interleaved = [&a[0], &b[0], &a[1], &b[1], &b[2], &a[2]];
+
+        // One slot: the second sequence replaces the first
+        let mut receiver = Receiver::new(first.len());
+        let mut frames = Vec::new();
+        for datagram in interleaved {
+            if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                frames.push((frame.sequence, frame.payload.to_vec()));
+            }
+        }
+        assert_eq!(frames, vec![(1, second.clone())]);
+        assert_eq!(receiver.take_lost(), 1);
+
+        // Two slots: both complete, in the order they did
+        let mut receiver = Receiver::new(first.len());
+        receiver.set_depth(2);
+        let mut frames = Vec::new();
+        for datagram in interleaved {
+            if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                frames.push((frame.sequence, frame.payload.to_vec()));
+            }
+        }
+        assert_eq!(frames, vec![(1, second), (0, first)]);
+        assert_eq!(receiver.take_lost(), 0);
+    }
+
+    #[test]
+    fn test_progress_of_the_current_sequence() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 3);
+        let mut sender = Sender::new(1);
+        let mut datagrams = Vec::new();
+        sender
+            .send(&data, 0, 0, |datagram| {
+                datagrams.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        let mut receiver = Receiver::new(data.len());
+        assert_eq!(receiver.current_sequence(), None);
+        assert_eq!(receiver.total_fragments(), 0);
+
+        receiver.process_datagram(&datagrams[0]);
+        receiver.process_datagram(&datagrams[2]);
+        assert_eq!(receiver.current_sequence(), Some(0));
+        assert_eq!(receiver.received_fragments(), 2);
+        assert_eq!(receiver.total_fragments(), 3);
+
+        // Nothing is in progress once it completes
+        assert!(receiver.process_datagram(&datagrams[1]).0.is_some());
+        assert_eq!(receiver.current_sequence(), None);
+        assert_eq!(receiver.received_fragments(), 0);
+    }
+
+    #[test]
+    fn test_sequences_leaving_the_window_incomplete_are_lost() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE + 10);
+        let mut sender = Sender::new(1);
+        let mut datagrams = Vec::new();
+        for _ in 0..4 {
+            sender
+                .send(&data, 0, 0, |datagram| {
+                    datagrams.push(datagram.to_vec());
+                    Ok(())
+                })
+                .unwrap();
+        }
+
+        // Sequence 0 misses its last fragment and leaves the window as 2 arrives
+        let mut receiver = Receiver::new(data.len());
+        receiver.set_depth(2);
+        assert!(receiver.process_datagram(&datagrams[0]).0.is_none());
+        assert!(receiver.process_datagram(&datagrams[4]).0.is_none());
+        assert_eq!(receiver.take_lost(), 1);
+        let (frame, _) = receiver.process_datagram(&datagrams[5]);
+        assert_eq!(frame.unwrap().sequence, 2);
+
+        // Sequence 1 never arrived when 3 pushes it out, its fragments are too late then
+        assert!(receiver.process_datagram(&datagrams[6]).0.is_none());
+        assert_eq!(receiver.take_lost(), 1);
+        assert!(receiver.process_datagram(&datagrams[2]).0.is_none());
+        let (frame, _) = receiver.process_datagram(&datagrams[7]);
+        assert_eq!(frame.unwrap().sequence, 3);
+        assert_eq!(receiver.take_lost(), 0);
+    }
+
+    #[test]
+    fn test_headers_are_parsed_from_any_alignment() {
+        let data = create_test_data(100);
+        let mut sender = Sender::new(0x0102_0304);
+        let mut datagram = Vec::new();
+        sender
+            .send(&data, 0, 0, |sent| {
+                datagram = sent.to_vec();
+                Ok(())
+            })
+            .unwrap();
+
+        // The fields are little-endian regardless of the platform
+        assert_eq!(&datagram[4..8], &[4, 3, 2, 1]);
+        assert_eq!(&keyframe_request(0x0102_0304)[4..8], &[4, 3, 2, 1]);
+
+        // Starting at an odd address
+        let mut shifted = vec![0u8; datagram.len() + 1];
+        shifted[1..].copy_from_slice(&datagram);
+        let mut receiver = Receiver::new(data.len());
+        let (frame, _) = receiver.process_datagram(&shifted[1..]);
+        assert_eq!(frame.unwrap().payload, &data[..]);
+        assert_eq!(datagram_info(&shifted[1..]).unwrap().source_id, 0x0102_0304);
+
+        // Runts of every length short of a header are ignored
+        for len in 0..HEADER_SIZE {
+            assert!(receiver.process_datagram(&shifted[1..1 + len]).0.is_none());
+            assert!(datagram_info(&shifted[1..1 + len]).is_none());
+        }
+    }
+
+    #[test]
+    fn test_mapping_size_is_advertised() {
+        let mut sender = Sender::new(0);
+        let mut receiver = Receiver::new(MAX_PAYLOAD_SIZE);
+        sender.set_mapping_size(1234);
+
+        let mut datagram = Vec::new();
+        sender
+            .send(&[1, 2, 3], 0, 0, |data| {
+                datagram = data.to_vec();
+                Ok(())
+            })
+            .unwrap();
+        let (frame, _) = receiver.process_datagram(&datagram);
+        assert_eq!(frame.unwrap().mapping_size, 1234);
+
+        sender
+            .send_heartbeat(|data| {
+                datagram = data.to_vec();
+                Ok(())
+            })
+            .unwrap();
+        let (frame, _) = receiver.process_datagram(&datagram);
+        assert_eq!(frame.unwrap().mapping_size, 1234);
+    }
+
+    #[test]
+    fn test_batch_matches_individual_sends() {
+        let data = create_test_data(MAX_PAYLOAD_SIZE * 2 + 10);
+
+        let mut individual = Vec::new();
+        Sender::new(0)
+            .send(&data, 5, FLAG_SESSION_INFO, |datagram| {
+                individual.push(datagram.to_vec());
+                Ok(())
+            })
+            .unwrap();
+
+        let mut batched = Vec::new();
+        let fragments = Sender::new(0)
+            .send_batch(&data, 5, FLAG_SESSION_INFO, |datagrams| {
+                batched = datagrams.iter().map(|datagram| datagram.to_vec()).collect();
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(fragments, 3);
+        assert_eq!(batched, individual);
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        const MAX_SIZE: usize = MAX_PAYLOAD_SIZE * 4;
+
+        fn datagram(
+            sequence: u32,
+            fragment: u16,
+            fragments: u16,
+            flags: u16,
+            payload_size: u32,
+            len: usize,
+        ) -> Vec<u8> {
+            let header = DatagramHeader {
+                sequence,
+                source_id: 0,
+                fragment,
+                fragments,
+                flags,
+                payload_size,
+                mapping_size: 0,
+                source_time_us: 0,
+            };
+            let mut datagram = header.to_bytes().to_vec();
+            datagram.resize(HEADER_SIZE + len, 0xAB);
+            datagram
+        }
+
+        proptest! {
+            #[test]
+            fn arbitrary_bytes_never_panic(
+                datagrams in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..128), 0..64)
+            ) {
+                let mut receiver = Receiver::new(MAX_SIZE);
+                for datagram in &datagrams {
+                    if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                        prop_assert!(frame.payload.len() <= MAX_SIZE);
+                    }
+                }
+            }
+
+            #[test]
+            fn malformed_headers_never_panic(
+                datagrams in prop::collection::vec(
+                    (
+                        0u32..4,
+                        0u16..6,
+                        0u16..6,
+                        prop_oneof![Just(0u16), any::<u16>()],
+                        prop_oneof![0u32..(MAX_SIZE as u32 * 2), any::<u32>()],
+                        0usize..MAX_PAYLOAD_SIZE + 16,
+                    ),
+                    0..32,
+                )
+            ) {
+                let mut receiver = Receiver::new(MAX_SIZE);
+                for &(sequence, fragment, fragments, flags, payload_size, len) in &datagrams {
+                    let datagram = datagram(sequence, fragment, fragments, flags, payload_size, len);
+                    if let (Some(frame), _) = receiver.process_datagram(&datagram) {
+                        prop_assert!(frame.payload.len() <= MAX_SIZE);
+                        prop_assert!(frame.payload.len() <= frame.fragments as usize * MAX_PAYLOAD_SIZE);
+                    }
+                }
+            }
+
+            #[test]
+            fn shuffled_and_duplicated_fragments_reassemble(
+                payload in prop::collection::vec(any::<u8>(), 1..MAX_SIZE),
+                order in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+            ) {
+                let mut datagrams = Vec::new();
+                Sender::new(0)
+                    .send(&payload, 0, 0, |datagram| {
+                        datagrams.push(datagram.to_vec());
+                        Ok(())
+                    })
+                    .unwrap();
+
+                // Random fragments first (possibly duplicated), then all of them to complete it
+                let mut receiver = Receiver::new(MAX_SIZE);
+                let mut complete = None;
+                for index in &order {
+                    if let (Some(frame), _) = receiver.process_datagram(index.get::<Vec<u8>>(&datagrams)) {
+                        complete = Some(frame.payload.to_vec());
+                    }
+                }
+                for datagram in &datagrams {
+                    if let (Some(frame), _) = receiver.process_datagram(datagram) {
+                        complete = Some(frame.payload.to_vec());
+                    }
+                }
+
+                prop_assert_eq!(complete, Some(payload));
+            }
+        }
+    }
 }
@@ -0,0 +1,142 @@
+use log::info;
+use std::net::UdpSocket;
+use std::sync::mpsc::Receiver;
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use crate::config::RelayConfig;
+use crate::protocol::{
+    self, FLAG_HEARTBEAT, MAX_DATAGRAM_SIZE, Receiver as ProtocolReceiver, Sender,
+};
+use crate::stats::StatisticsPrinter;
+use crate::target::setup_multicast;
+
+pub fn run(config: &RelayConfig, shutdown: Receiver<()>) -> io::Result<()> {
+    let Some(target) = config.target.as_deref() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The relay requires a --target address to forward to",
+        ));
+    };
+
+    let in_socket = UdpSocket::bind(&config.bind).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to bind to {}: {}", config.bind, e),
+        )
+    })?;
+    info!("Relay bound to {}", config.bind);
+
+    if !config.unicast_in {
+        setup_multicast(&in_socket, &config.bind, &config.group)?;
+    }
+
+    let out_socket = UdpSocket::bind(&config.out_bind)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind UDP socket: {}", e)))?;
+
+    if config.unicast_out {
+        out_socket.connect(target).map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to connect to {}: {}", target, e))
+        })?;
+    }
+    info!("Forwarding to {}", target);
+
+    let mut rcv_buf = [0u8; MAX_DATAGRAM_SIZE];
+    let mut protocol_receiver = ProtocolReceiver::new(protocol::max_payload_size(config.max_size));
+    if let Some(source_id) = config.filter_id {
+        protocol_receiver.filter_source(source_id);
+    }
+
+    // Created on the first frame to forward under the upstream source id
+    let mut sender: Option<Sender> = None;
+    let mut stats = StatisticsPrinter::new("relay");
+    let mut sequence_start_time: Option<Instant> = None;
+
+    // Set a short timeout on UDP receive to stay responsive to shutdown
+    in_socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set socket timeout: {}", e)))?;
+
+    loop {
+        // Check for shutdown signal
+        if shutdown.try_recv().is_ok() {
+            return Ok(());
+        }
+
+        match in_socket.recv_from(&mut rcv_buf) {
+            Ok((amt, _)) => {
+                let (frame, sequence_changed) = protocol_receiver.process_datagram(&rcv_buf[..amt]);
+
+                if sequence_changed {
+                    sequence_start_time = Some(Instant::now());
+                }
+
+                if let Some(frame) = frame {
+                    let sender = sender.get_or_insert_with(|| Sender::new(frame.source_id));
+                    sender.set_mapping_size(frame.mapping_size);
+
+                    // Goodbyes are passed on like heartbeats, for the targets downstream
+                    if frame.flags & FLAG_HEARTBEAT != 0 {
+                        let send_fn = |data: &[u8]| {
+                            if config.unicast_out {
+                                out_socket.send(data).map(|_| ())
+                            } else {
+                                out_socket.send_to(data, target).map(|_| ())
+                            }
+                        };
+                        let _ = if protocol::is_goodbye(frame.flags) {
+                            sender.send_goodbye(send_fn)
+                        } else {
+                            sender.send_heartbeat(send_fn)
+                        };
+                        continue;
+                    }
+
+                    // Carry the upstream latency forward and add the time spent in the relay,
+                    // so targets downstream still see the end-to-end figure
+                    let relay_time = sequence_start_time
+                        .take()
+                        .map(|start| start.elapsed().as_micros() as u64)
+                        .unwrap_or(0);
+                    let source_time = frame.source_time_us + relay_time;
+
+                    // Forward the compressed payload as-is
+                    let send_result = if !config.unicast_out {
+                        sender.send(frame.payload, source_time, frame.flags, |data| {
+                            out_socket.send_to(data, target).map(|_| ())
+                        })
+                    } else {
+                        sender.send(frame.payload, source_time, frame.flags, |data| {
+                            out_socket.send(data).map(|_| ())
+                        })
+                    };
+
+                    if let Ok(fragments) = send_result {
+                        stats.add_fragments(fragments);
+                    }
+
+                    stats.add_bytes(frame.payload.len());
+                    stats.add_latency(source_time);
+                    stats.add_update();
+
+                    if stats.should_print() {
+                        stats.print_and_reset();
+                    }
+                }
+            }
+            // Interrupted when a termination signal arrives, the shutdown check comes next
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("UDP receive error: {}", e),
+                ));
+            }
+        }
+    }
+}
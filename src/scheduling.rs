@@ -0,0 +1,99 @@
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Scheduling priority of the thread running the source or target loop
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    High,
+}
+
+/// Pins the current thread to the given CPU core and/or raises its priority. Failures
+/// (usually missing permissions) are reported but not fatal.
+pub fn apply(cpu: Option<usize>, priority: Priority) {
+    if let Some(cpu) = cpu {
+        match platform::set_affinity(cpu) {
+            Ok(()) => info!("Pinned to CPU core {}", cpu),
+            Err(e) => warn!("Failed to pin to CPU core {}: {}", cpu, e),
+        }
+    }
+
+    if priority == Priority::High {
+        match platform::raise_priority() {
+            Ok(()) => info!("Raised thread priority"),
+            Err(e) => warn!("Failed to raise thread priority: {}", e),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use std::io;
+    use windows::Win32::System::Threading::{
+        GetCurrentThread, SetThreadAffinityMask, SetThreadPriority, THREAD_PRIORITY_HIGHEST,
+    };
+
+    pub fn set_affinity(cpu: usize) -> io::Result<()> {
+        if cpu >= usize::BITS as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such core"));
+        }
+
+        match unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << cpu) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn raise_priority() -> io::Result<()> {
+        unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_HIGHEST) }
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::io;
+
+    // Nice value for high priority, lowering it below 0 needs CAP_SYS_NICE
+    const HIGH_PRIORITY_NICE: libc::c_int = -10;
+
+    pub fn set_affinity(cpu: usize) -> io::Result<()> {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such core"));
+        }
+
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            match libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+    }
+
+    pub fn raise_priority() -> io::Result<()> {
+        // On Linux the nice value of a thread id only applies to that thread
+        let tid = unsafe { libc::gettid() } as libc::id_t;
+        match unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, HIGH_PRIORITY_NICE) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+mod platform {
+    use std::io;
+
+    pub fn set_affinity(_: usize) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    pub fn raise_priority() -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
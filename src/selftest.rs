@@ -0,0 +1,171 @@
+use clap::{Args, Command, FromArgMatches};
+use log::{error, info};
+use rand::{Rng, rng};
+use std::io;
+use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{SourceConfig, TargetConfig};
+use crate::protocol::MAX_PAYLOAD_SIZE;
+use crate::telemetry::{SharedMemoryNames, TelemetryError, TelemetryProvider};
+use crate::{source, target};
+
+// Spans several datagrams to exercise fragmentation, random data doesn't compress
+const TELEMETRY_SIZE: usize = MAX_PAYLOAD_SIZE * 4;
+const FRAME_TIME: Duration = Duration::from_nanos(16_666_667);
+
+// What the source publishes, and how many updates the target received intact or corrupted
+static PUBLISHED: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+static RECEIVED: AtomicU64 = AtomicU64::new(0);
+static CORRUPTED: AtomicU64 = AtomicU64::new(0);
+
+/// In-process telemetry that checks what the target receives against what the source sent
+struct SelfTestTelemetry {
+    buffer: Vec<u8>,
+    last_update: Option<Instant>,
+}
+
+impl TelemetryProvider for SelfTestTelemetry {
+    fn open(_: &SharedMemoryNames) -> Result<Self, TelemetryError> {
+        Ok(Self {
+            buffer: PUBLISHED.lock().unwrap().clone(),
+            last_update: None,
+        })
+    }
+
+    fn create(_: &SharedMemoryNames, size: usize) -> Result<Self, TelemetryError> {
+        Ok(Self {
+            buffer: vec![0; size],
+            last_update: None,
+        })
+    }
+
+    fn wait_for_data(&mut self, _: u32) -> bool {
+        if let Some(last_update) = self.last_update {
+            thread::sleep(FRAME_TIME.saturating_sub(last_update.elapsed()));
+        }
+        self.last_update = Some(Instant::now());
+        true
+    }
+
+    fn signal_data_ready(&mut self) -> Result<(), TelemetryError> {
+        if *PUBLISHED.lock().unwrap() == self.buffer {
+            RECEIVED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            CORRUPTED.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn as_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// Parses the options for a mode like they were given on the command line, so any option
+/// not given keeps its default
+fn parse<T: Args + FromArgMatches>(args: &[&str]) -> io::Result<T> {
+    let matches = T::augment_args(Command::new("selftest"))
+        .try_get_matches_from(args)
+        .map_err(io::Error::other)?;
+    T::from_arg_matches(&matches).map_err(io::Error::other)
+}
+
+/// Runs a source and a target in this process over 127.0.0.1 for `duration` and reports
+/// whether the telemetry made it through intact
+pub fn run(duration: Duration) -> io::Result<()> {
+    let mut published = vec![0u8; TELEMETRY_SIZE];
+    rng().fill(&mut published[..]);
+    *PUBLISHED.lock().unwrap() = published;
+    RECEIVED.store(0, Ordering::Relaxed);
+    CORRUPTED.store(0, Ordering::Relaxed);
+
+    let port = UdpSocket::bind("127.0.0.1:0")?.local_addr()?.port();
+    let address = format!("127.0.0.1:{}", port);
+    let target_config: TargetConfig = parse(&["target", "--bind", &address, "--unicast"])?;
+    let source_config: SourceConfig = parse(&[
+        "source",
+        "--bind",
+        "127.0.0.1:0",
+        "--target",
+        &address,
+        "--unicast",
+    ])?;
+
+    info!(
+        "Running self-test over {} for {} seconds...",
+        address,
+        duration.as_secs()
+    );
+
+    let (target_tx, target_rx) = mpsc::channel();
+    let target =
+        thread::spawn(move || target::run_with::<SelfTestTelemetry>(&target_config, target_rx));
+
+    let (source_tx, source_rx) = mpsc::channel();
+    let source =
+        thread::spawn(move || source::run_with::<SelfTestTelemetry>(&source_config, source_rx));
+
+    thread::sleep(duration);
+    let _ = source_tx.send(());
+    let _ = target_tx.send(());
+
+    let mut errors = Vec::new();
+    for (name, handle) in [("source", source), ("target", target)] {
+        match handle.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => errors.push(format!("{} failed: {}", name, e)),
+            Err(_) => errors.push(format!("{} panicked", name)),
+        }
+    }
+
+    let received = RECEIVED.load(Ordering::Relaxed);
+    let corrupted = CORRUPTED.load(Ordering::Relaxed);
+    let rate = received as f64 / duration.as_secs_f64();
+
+    if received == 0 {
+        errors.push("no telemetry made it from the source to the target".to_string());
+    }
+    if corrupted > 0 {
+        errors.push(format!("{} updates arrived corrupted", corrupted));
+    }
+
+    if errors.is_empty() {
+        info!(
+            "PASS: {} updates of {} bytes received intact ({:.2} msgs/s)",
+            received, TELEMETRY_SIZE, rate
+        );
+        Ok(())
+    } else {
+        for e in &errors {
+            error!("{}", e);
+        }
+        error!(
+            "FAIL: {} updates received intact ({:.2} msgs/s)",
+            received, rate
+        );
+        Err(io::Error::other("Self-test failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        run(Duration::from_secs(1)).unwrap();
+    }
+}
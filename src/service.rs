@@ -0,0 +1,205 @@
+use clap::{ArgMatches, Subcommand};
+use log::{error, info};
+use std::ffi::OsString;
+use std::sync::{Mutex, mpsc};
+use std::time::Duration;
+use std::{env, io};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::{Config, Mode, logging};
+
+const SERVICE_NAME: &str = "iracing-teleport";
+const SERVICE_DISPLAY_NAME: &str = "iRacing Telemetry Teleport";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+const LOG_FILE_NAME: &str = "iracing-teleport.log";
+
+// Mode handed from the command line to the service entry point
+static SERVICE_MODE: Mutex<Option<Mode>> = Mutex::new(None);
+
+#[derive(Subcommand)]
+pub enum Action {
+    /// Register the given source/target command as an auto-starting Windows service
+    Install {
+        #[command(subcommand)]
+        mode: Option<Mode>,
+    },
+
+    /// Run as a Windows service (invoked by the service control manager)
+    #[command(hide = true)]
+    Run {
+        #[command(subcommand)]
+        mode: Option<Mode>,
+    },
+
+    /// Stop and remove the Windows service
+    Uninstall,
+}
+
+pub fn handle(
+    action: Action,
+    matches: Option<&ArgMatches>,
+    config: &Option<Config>,
+    log_file: Option<logging::LogFile>,
+) -> io::Result<()> {
+    match action {
+        Action::Install { .. } => install(log_file).map_err(io::Error::other),
+        Action::Run { mode } => {
+            let log_file = match log_file {
+                Some(log_file) => log_file,
+                None => logging::LogFile::new(log_path().map_err(io::Error::other)?),
+            };
+            logging::init_file(&log_file)?;
+            let mode = mode
+                .expect("mode")
+                .configure(matches.expect("mode"), config)
+                .inspect_err(|e| error!("Invalid configuration: {}", e))?;
+            mode.validate()
+                .inspect_err(|e| error!("Invalid configuration: {}", e))?;
+            run(mode)
+        }
+        Action::Uninstall => uninstall().map_err(io::Error::other),
+    }
+}
+
+fn install(log_file: Option<logging::LogFile>) -> windows_service::Result<()> {
+    // Everything following `install` is stored as the service's launch arguments,
+    // so the service starts with exactly the command line that was validated here.
+    // A config file is re-read each time the service starts.
+    let mode_args = env::args_os().skip_while(|arg| arg != "install").skip(1);
+    let launch_arguments = [OsString::from("service"), OsString::from("run")]
+        .into_iter()
+        .chain(mode_args)
+        .collect();
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None, // run as LocalSystem
+        account_password: None,
+    };
+
+    let service = manager.create_service(
+        &service_info,
+        ServiceAccess::CHANGE_CONFIG | ServiceAccess::START,
+    )?;
+    service.set_description(env!("CARGO_PKG_DESCRIPTION"))?;
+    service.start::<&str>(&[])?;
+
+    info!("Service '{}' installed and started", SERVICE_NAME);
+    // A --log-file is passed on with the launch arguments
+    let log_path = match log_file {
+        Some(log_file) => log_file.path,
+        None => log_path()?,
+    };
+    info!("Logs are written to {}", log_path.display());
+    Ok(())
+}
+
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    // The service is removed once it is stopped and all handles to it are closed
+    service.delete()?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+
+    info!("Service '{}' uninstalled", SERVICE_NAME);
+    Ok(())
+}
+
+fn log_path() -> windows_service::Result<std::path::PathBuf> {
+    // Services start in the system directory, so keep the log next to the executable
+    let exe = env::current_exe().map_err(windows_service::Error::Winapi)?;
+    Ok(exe.with_file_name(LOG_FILE_NAME))
+}
+
+fn run(mode: Mode) -> io::Result<()> {
+    *SERVICE_MODE.lock().unwrap() = Some(mode);
+
+    // Blocks until the service has stopped
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(io::Error::other)
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("Service error: {}", e);
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                info!("Received service stop request, shutting down...");
+                let _ = shutdown_tx.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+    status_handle.set_service_status(service_status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        0,
+    ))?;
+
+    let exit_code = match SERVICE_MODE.lock().unwrap().take() {
+        Some(mode) => match mode.run(shutdown_rx) {
+            Ok(()) => 0,
+            Err(e) => crate::exit_code(&e).into(),
+        },
+        None => 1,
+    };
+
+    status_handle.set_service_status(service_status(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    ))?;
+    Ok(())
+}
+
+fn service_status(
+    current_state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+    exit_code: u32,
+) -> ServiceStatus {
+    ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
@@ -0,0 +1,131 @@
+use log::info;
+use std::io;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+/// Keeps the process alive for cleanup after a termination request; drop it once the
+/// mode has finished running
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(windows)]
+        console::finished();
+    }
+}
+
+/// Sends on `shutdown_tx` when the process is asked to terminate: Ctrl+C, SIGTERM and
+/// SIGHUP on Unix, and Ctrl+C, Ctrl+Break, closing the console window, logging off and
+/// shutting down on Windows
+pub fn install(shutdown_tx: Sender<()>) -> io::Result<Guard> {
+    #[cfg(windows)]
+    let console_tx = shutdown_tx.clone();
+
+    ctrlc::set_handler(move || {
+        info!("Received termination signal, shutting down...");
+        let _ = shutdown_tx.send(());
+    })
+    .map_err(|e| io::Error::other(format!("Failed to set termination handler: {}", e)))?;
+
+    // Registered after the Ctrl+C handler so it is called first
+    #[cfg(windows)]
+    console::install(console_tx)?;
+
+    Ok(Guard)
+}
+
+/// Sends on `shutdown_tx` once `duration` has passed, to run for a fixed time
+pub fn after(duration: Duration, shutdown_tx: Sender<()>) {
+    thread::spawn(move || {
+        thread::sleep(duration);
+        info!("Ran for {} seconds, shutting down...", duration.as_secs());
+        let _ = shutdown_tx.send(());
+    });
+}
+
+#[cfg(windows)]
+mod console {
+    use log::info;
+    use std::io;
+    use std::sync::mpsc::Sender;
+    use std::sync::{Condvar, Mutex};
+    use std::time::Duration;
+    use windows::Win32::Foundation::{FALSE, TRUE};
+    use windows::Win32::System::Console::{
+        CTRL_CLOSE_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT, SetConsoleCtrlHandler,
+    };
+    use windows::core::BOOL;
+
+    // Windows terminates the process 5 seconds after a close event at the latest
+    const CLEANUP_TIMEOUT: Duration = Duration::from_millis(4500);
+
+    static SHUTDOWN: Mutex<Option<Sender<()>>> = Mutex::new(None);
+    static FINISHED: Mutex<bool> = Mutex::new(false);
+    static FINISHED_CHANGED: Condvar = Condvar::new();
+
+    unsafe extern "system" fn handler(ctrl_type: u32) -> BOOL {
+        // Ctrl+C and Ctrl+Break are left to the next handler
+        if !matches!(
+            ctrl_type,
+            CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT
+        ) {
+            return FALSE;
+        }
+
+        info!("Console is closing, shutting down...");
+        if let Some(shutdown_tx) = SHUTDOWN.lock().unwrap().as_ref() {
+            let _ = shutdown_tx.send(());
+        }
+
+        // The process is terminated as soon as this returns, so wait for the cleanup
+        let finished = FINISHED.lock().unwrap();
+        let _ = FINISHED_CHANGED.wait_timeout_while(finished, CLEANUP_TIMEOUT, |done| !*done);
+        TRUE
+    }
+
+    pub fn install(shutdown_tx: Sender<()>) -> io::Result<()> {
+        *SHUTDOWN.lock().unwrap() = Some(shutdown_tx);
+        unsafe { SetConsoleCtrlHandler(Some(handler), true) }
+            .map_err(|e| io::Error::other(format!("Failed to set console close handler: {}", e)))
+    }
+
+    pub fn finished() {
+        *FINISHED.lock().unwrap() = true;
+        FINISHED_CHANGED.notify_all();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_signals_trigger_shutdown() {
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        let _guard = install(shutdown_tx).unwrap();
+
+        for signal in ["INT", "TERM", "HUP"] {
+            let status = Command::new("kill")
+                .args(["-s", signal, &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            shutdown_rx
+                .recv_timeout(Duration::from_secs(5))
+                .unwrap_or_else(|_| panic!("SIG{} did not trigger a shutdown", signal));
+        }
+    }
+
+    #[test]
+    fn test_shutdown_after_duration() {
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        after(Duration::from_millis(10), shutdown_tx);
+        shutdown_rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("duration elapsed without a shutdown");
+    }
+}
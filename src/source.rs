@@ -1,48 +1,1385 @@
-use lz4::block::compress_to_buffer;
-use std::net::UdpSocket;
+use log::{debug, error, info, warn};
+use lz4::block::{compress_bound, compress_to_buffer};
+use socket2::SockRef;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::{
     io,
     time::{Duration, Instant},
 };
 
-use crate::protocol::Sender;
+use crate::codec::{self, FRAME_OVERHEAD, Lz4Format, Quality};
+use crate::config::SourceConfig;
+use crate::dictionary::{Compressor, Dictionary, Trainer};
+use crate::error::TeleportError;
+use crate::health::{DEFAULT_HEALTH_WINDOW, HealthCheck};
+use crate::interfaces;
+use crate::irsdk::{self, Region};
+use crate::logging;
+use crate::monitor::StatsExport;
+use crate::net;
+use crate::protocol::{
+    self, DICTIONARY_ID_SIZE, Echo, FEATURE_DICTIONARY, FEATURE_REGION, FEATURE_VERIFY,
+    FLAG_DICTIONARY, FLAG_REGION, FLAG_SESSION_INFO, FLAG_SESSION_INFO_OMITTED, FLAG_UNCOMPRESSED,
+    FLAG_VERIFY, FaultInjector, Hello, HelloReply, LOSS_REPORT_SIZE, PROBE_REPLY_SIZE,
+    PROTOCOL_VERSION, ProbeTimes, SendLog, Sender, VERIFY_HASH_SIZE,
+};
+use crate::scheduling;
 use crate::stats::StatisticsPrinter;
-use crate::telemetry::{MAX_TELEMETRY_SIZE, Telemetry, TelemetryError, TelemetryProvider};
+use crate::telemetry::ibt::IbtReplay;
+use crate::telemetry::mock::{self, MockTelemetry};
+use crate::telemetry::{SharedMemoryNames, Telemetry, TelemetryError, TelemetryProvider};
+
+// Timeout before considering the connection lost
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Interval between attempts to open the telemetry
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// Individual wait interval to maintain shutdown responsiveness
+const WAIT_INTERVAL_MS: u32 = 200;
+
+// Window over which --max-bandwidth is enforced
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+
+// Reported loss above which --adaptive halves the share of updates sent
+const ADAPTIVE_LOSS_THRESHOLD: f64 = 0.02;
+
+// Share of the updates --adaptive adds back for every report below the threshold
+const ADAPTIVE_STEP: f64 = 0.05;
+
+// Lowest share of the updates --adaptive goes down to, about 2 Hz of iRacing's 60
+const ADAPTIVE_MIN_SHARE: f64 = 1.0 / 32.0;
+
+// Telemetry that didn't change is still sent this often with --skip-unchanged, so targets
+// that join while the sim is idle get a mapping
+const UNCHANGED_REFRESH: Duration = Duration::from_secs(1);
+
+// How often the listener for requests from targets checks whether the source has stopped
+const REQUEST_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// How often a --target host name is resolved again in unicast mode, to follow a target
+// behind dynamic DNS whose address changes
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Shortest time between resolving the host name again because sending keeps failing
+const RESOLVE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often a unicast source repeats its hello until the target answers it
+const HELLO_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often a multicast source repeats its hello, for targets that join later. Targets
+// on a group don't answer.
+const HELLO_INTERVAL: Duration = Duration::from_secs(5);
+
+// Most updates of a recording a dictionary is trained on, spread over its length
+const TRAINING_SAMPLES: u64 = 500;
+
+// Times the goodbye is sent when shutting down, it may be lost like any datagram
+const GOODBYE_COPIES: usize = 3;
+
+/// Logs the iRSDK header of a newly opened mapping, for --print-header
+fn print_header(data: &[u8]) {
+    match irsdk::Header::parse(data) {
+        Some(header) => {
+            info!("iRSDK header:");
+            for line in header.describe().lines() {
+                info!("  {}", line);
+            }
+        }
+        None => info!("No iRSDK header found, the mapping doesn't look like iRacing's"),
+    }
+}
+
+fn open_telemetry<T: TelemetryProvider>(
+    names: &SharedMemoryNames,
+    show_header: bool,
+) -> Result<Option<T>, TeleportError> {
+    match T::open(names) {
+        Ok(telemetry) => {
+            info!("Connected to racing session");
+            info!("Memory region size: {} bytes", telemetry.size());
+            if show_header {
+                print_header(telemetry.as_slice());
+            }
+            Ok(Some(telemetry))
+        }
+        Err(TelemetryError::Unavailable) => Ok(None),
+        Err(e) => Err(TeleportError::telemetry("open telemetry", e)),
+    }
+}
+
+fn try_connect_telemetry<T: TelemetryProvider>(
+    names: &SharedMemoryNames,
+    show_header: bool,
+    shutdown: &Receiver<()>,
+) -> Result<Option<T>, TeleportError> {
+    let result = open_telemetry(names, show_header)?;
+
+    if result.is_none() {
+        // Wait for either a shutdown signal or timeout
+        match shutdown.recv_timeout(RETRY_INTERVAL) {
+            Ok(_) => return Ok(None),                   // Shutdown requested
+            Err(mpsc::RecvTimeoutError::Timeout) => (), // Continue trying
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None), // Shutdown
+        }
+    }
+    Ok(result)
+}
+
+/// Compresses `data` into `buffer` in the given LZ4 format and quality and returns the
+/// payload to send along with its flags. If compressing saves too little, `data` itself
+/// is sent with `FLAG_UNCOMPRESSED`, so the target doesn't spend time decompressing it
+/// and the wire size never exceeds it.
+fn compress<'a>(
+    data: &'a [u8],
+    buffer: &'a mut [u8],
+    threshold: f64,
+    format: Lz4Format,
+    quality: Quality,
+    compressor: Option<&mut Compressor>,
+) -> (&'a [u8], u16) {
+    let (result, encoding) = match (compressor, format) {
+        (Some(compressor), _) => (compressor.compress_to_buffer(data, buffer), FLAG_DICTIONARY),
+        (None, Lz4Format::Block) => (
+            compress_to_buffer(data, Some(quality.block_mode()), true, buffer),
+            0,
+        ),
+        (None, Lz4Format::Frame) => (
+            codec::compress_frame(data, buffer, quality.frame_level()),
+            0,
+        ),
+    };
+    match result {
+        Ok(len) if len as f64 <= data.len() as f64 * threshold => (&buffer[..len], encoding),
+        Ok(_) => (data, FLAG_UNCOMPRESSED),
+        Err(e) => {
+            warn!(
+                "LZ4 compression failed: {}. Sending this update uncompressed.",
+                e
+            );
+            (data, FLAG_UNCOMPRESSED)
+        }
+    }
+}
+
+/// Compresses `data` like `compress`, appending the id of the dictionary it was compressed
+/// with and, with --verify, the hash of `data` so targets can check what they decode end
+/// to end. `buffer` needs room for both.
+fn encode<'a>(
+    data: &'a [u8],
+    buffer: &'a mut [u8],
+    threshold: f64,
+    verify: bool,
+    format: Lz4Format,
+    quality: Quality,
+    compressor: Option<&mut Compressor>,
+) -> (&'a [u8], u16) {
+    let dictionary_id = compressor
+        .as_ref()
+        .map(|compressor| compressor.dictionary().id());
+    if !verify && dictionary_id.is_none() {
+        return compress(data, buffer, threshold, format, quality, None);
+    }
+
+    let trailer =
+        dictionary_id.map_or(0, |_| DICTIONARY_ID_SIZE) + if verify { VERIFY_HASH_SIZE } else { 0 };
+    let offset = buffer.len() - trailer;
+    let (payload, encoding) = compress(
+        data,
+        &mut buffer[..offset],
+        threshold,
+        format,
+        quality,
+        compressor,
+    );
+    let mut len = payload.len();
+    if let Some(id) = dictionary_id
+        && encoding & FLAG_DICTIONARY != 0
+    {
+        buffer[len..len + DICTIONARY_ID_SIZE].copy_from_slice(&id.to_le_bytes());
+        len += DICTIONARY_ID_SIZE;
+    }
+
+    if !verify {
+        return match encoding & FLAG_UNCOMPRESSED {
+            0 => (&buffer[..len], encoding),
+            _ => (data, encoding),
+        };
+    }
+
+    // Uncompressed data is sent from the buffer too, so the hash can follow it
+    if encoding & FLAG_UNCOMPRESSED != 0 {
+        let Some(payload) = buffer.get_mut(..len) else {
+            return (data, encoding);
+        };
+        payload.copy_from_slice(data);
+    }
+    buffer[len..len + VERIFY_HASH_SIZE].copy_from_slice(&protocol::verify_hash(data).to_le_bytes());
+    (&buffer[..len + VERIFY_HASH_SIZE], encoding | FLAG_VERIFY)
+}
+
+/// Returns the size of the buffer `encode` needs for `size` bytes of telemetry. LZ4
+/// expands data that doesn't compress slightly, the buffer has room for that, a frame
+/// around it, the dictionary id and the hash.
+fn encode_buffer_len(size: usize) -> usize {
+    compress_bound(size).unwrap_or(size).max(size)
+        + FRAME_OVERHEAD
+        + DICTIONARY_ID_SIZE
+        + VERIFY_HASH_SIZE
+}
+
+/// Warns if the --region doesn't fit the telemetry mapping, whole updates are sent then
+fn check_region(region: &Region, mapping_size: usize) {
+    if region.end().is_none_or(|end| end > mapping_size) {
+        warn!(
+            "--region {}:{} reaches past the {} bytes of the telemetry mapping, sending whole updates",
+            region.offset, region.len, mapping_size
+        );
+    }
+}
+
+/// Warns if the telemetry mapping is larger than targets with the same --max-size accept
+fn check_mapping_size(mapping_size: usize, max_size: usize) {
+    if mapping_size > max_size {
+        warn!(
+            "The telemetry mapping is {} bytes, more than the {} bytes of --max-size. Raise --max-size on both sides to teleport it.",
+            mapping_size, max_size
+        );
+    }
+}
+
+/// Keeps the bytes sent within a budget per sliding window, for --max-bandwidth
+struct BandwidthLimit {
+    budget: u64,
+    sent: VecDeque<(Instant, u64)>,
+    total: u64,
+}
+
+impl BandwidthLimit {
+    fn new(mbps: f64) -> Self {
+        Self {
+            budget: (mbps * 1_000_000.0 / 8.0 * BANDWIDTH_WINDOW.as_secs_f64()) as u64,
+            sent: VecDeque::new(),
+            total: 0,
+        }
+    }
+
+    /// Returns whether `bytes` more fit into the budget at `now` and counts them if so.
+    /// Anything fits while nothing else was sent within the window, so a payload larger
+    /// than the budget goes out at a lower rate instead of never.
+    fn try_send(&mut self, bytes: u64, now: Instant) -> bool {
+        while let Some(&(time, size)) = self.sent.front()
+            && now.duration_since(time) >= BANDWIDTH_WINDOW
+        {
+            self.total -= size;
+            self.sent.pop_front();
+        }
+
+        if !self.sent.is_empty() && self.total + bytes > self.budget {
+            return false;
+        }
+
+        self.sent.push_back((now, bytes));
+        self.total += bytes;
+        true
+    }
+}
+
+/// Sends a share of the updates that follows the loss reported by the target, for
+/// --adaptive. The share is halved while the loss is above the threshold and grows by a
+/// fixed step with every report below it, like TCP's congestion control.
+struct AdaptiveRate {
+    share: f64,
+    credit: f64,
+}
+
+impl AdaptiveRate {
+    fn new() -> Self {
+        Self {
+            share: 1.0,
+            credit: 0.0,
+        }
+    }
+
+    /// Adjusts the share of updates sent to a report of `received` and `lost` updates,
+    /// returning the loss it measured if the report counted any updates
+    fn add_report(&mut self, received: u32, lost: u32) -> Option<f64> {
+        let total = received as u64 + lost as u64;
+        if total == 0 {
+            return None;
+        }
+
+        let loss = lost as f64 / total as f64;
+        self.share = if loss > ADAPTIVE_LOSS_THRESHOLD {
+            (self.share / 2.0).max(ADAPTIVE_MIN_SHARE)
+        } else {
+            (self.share + ADAPTIVE_STEP).min(1.0)
+        };
+        Some(loss)
+    }
+
+    /// Returns whether to send the next update, spreading the ones sent evenly
+    fn admit(&mut self) -> bool {
+        self.credit += self.share;
+        if self.credit < 1.0 {
+            return false;
+        }
+        self.credit -= 1.0;
+        true
+    }
+}
+
+/// Remembers what the last update sent held, for --skip-unchanged. The header's tick
+/// counts change with every update, so only the latest variable buffer and the session
+/// info version are compared for iRSDK mappings. Other mappings are compared in full.
+#[derive(Default)]
+struct ChangeFilter {
+    session_info_update: Option<i32>,
+    variables: Vec<u8>,
+    last_sent: Option<Instant>,
+}
+
+impl ChangeFilter {
+    /// Returns the session info version and the part of `data` that is compared
+    fn compared(data: &[u8]) -> (Option<i32>, &[u8]) {
+        match irsdk::Header::parse(data) {
+            Some(header) => (
+                Some(header.session_info_update),
+                header.latest_var_buf(data).map_or(data, |(_, buf)| buf),
+            ),
+            None => (None, data),
+        }
+    }
+
+    /// Returns whether `data` holds the same as the last update sent, recently enough
+    /// that it doesn't have to be sent again
+    fn unchanged(&self, data: &[u8], now: Instant) -> bool {
+        let recent = self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < UNCHANGED_REFRESH);
+        let (session_info_update, variables) = Self::compared(data);
+        recent && session_info_update == self.session_info_update && variables == self.variables
+    }
+
+    /// Makes sure the next update is sent, changed or not
+    fn forget(&mut self) {
+        self.last_sent = None;
+    }
+
+    /// Remembers `data` as the last update sent
+    fn sent(&mut self, data: &[u8], now: Instant) {
+        let (session_info_update, variables) = Self::compared(data);
+        self.session_info_update = session_info_update;
+        self.variables.clear();
+        self.variables.extend_from_slice(variables);
+        self.last_sent = Some(now);
+    }
+}
+
+/// Watches the tick count of the latest variable buffer, which can stop advancing while
+/// iRacing keeps signaling new data (seen after switching windows). Only connected iRSDK
+/// mappings are watched, other mappings have no tick count.
+struct StallWatchdog {
+    timeout: Option<Duration>,
+    tick: Option<i32>,
+    since: Instant,
+}
+
+impl StallWatchdog {
+    fn new(timeout_secs: u64) -> Self {
+        Self {
+            timeout: (timeout_secs > 0).then(|| Duration::from_secs(timeout_secs)),
+            tick: None,
+            since: Instant::now(),
+        }
+    }
+
+    /// Returns the tick count `data` is stuck at, once it hasn't advanced for the timeout
+    fn stalled(&mut self, data: &[u8], now: Instant) -> Option<i32> {
+        let timeout = self.timeout?;
+        let tick = irsdk::Header::parse(data)
+            .filter(|header| header.status & irsdk::STATUS_CONNECTED != 0)
+            .and_then(|header| header.latest_var_buf(data))
+            .map(|(tick, _)| tick);
+
+        match tick {
+            Some(tick) if self.tick == Some(tick) => {
+                (now.duration_since(self.since) >= timeout).then_some(tick)
+            }
+            _ => {
+                self.tick = tick;
+                self.since = now;
+                None
+            }
+        }
+    }
+
+    /// Starts over on a reopened telemetry
+    fn reset(&mut self) {
+        self.tick = None;
+    }
+}
+
+/// Zeroes the variables not selected with --vars in updates without the session info,
+/// so only the selected ones carry data and the rest compresses away
+struct VarFilter {
+    names: Vec<String>,
+    // Ranges to zero in the stripped update, resolved once per layout of the mapping
+    layout: Option<(irsdk::Header, Vec<Range<usize>>)>,
+}
+
+impl VarFilter {
+    fn new(names: Vec<String>) -> Self {
+        Self {
+            names,
+            layout: None,
+        }
+    }
+
+    /// Zeroes the unselected variables in `stripped`, which is `data` without the
+    /// `session_info` region
+    fn apply(&mut self, data: &[u8], session_info: Range<usize>, stripped: &mut [u8]) {
+        let Some(header) = irsdk::Header::parse(data) else {
+            return;
+        };
+
+        // Only the offsets and sizes make up the layout, not the counters
+        let mut key = header;
+        key.status = 0;
+        key.session_info_update = 0;
+        for var_buf in &mut key.var_bufs {
+            var_buf.tick_count = 0;
+        }
+
+        if self
+            .layout
+            .as_ref()
+            .is_none_or(|(layout, _)| *layout != key)
+        {
+            let ranges = self.resolve(&header, data, &session_info);
+            self.layout = Some((key, ranges));
+        }
+
+        let Some((_, ranges)) = &self.layout else {
+            return;
+        };
+        for range in ranges {
+            if let Some(bytes) = stripped.get_mut(range.clone()) {
+                bytes.fill(0);
+            }
+        }
+    }
+
+    /// Returns the ranges of the unselected variables, moved to where they end up once
+    /// the session info is left out
+    fn resolve(
+        &self,
+        header: &irsdk::Header,
+        data: &[u8],
+        session_info: &Range<usize>,
+    ) -> Vec<Range<usize>> {
+        let available = header.var_headers(data);
+        let mut selected = Vec::with_capacity(self.names.len());
+        for name in &self.names {
+            match available.iter().find(|var| &var.name == name) {
+                Some(var) => selected.push(var.clone()),
+                None => warn!("Telemetry variable '{}' not found, it won't be sent", name),
+            }
+        }
+
+        header
+            .unselected_ranges(&selected)
+            .into_iter()
+            .filter_map(|range| {
+                if range.end <= session_info.start {
+                    Some(range)
+                } else if range.start >= session_info.end {
+                    Some(range.start - session_info.len()..range.end - session_info.len())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns whether `wire_size` more bytes fit into the --max-bandwidth budget, if any,
+/// counting the update as dropped if not
+fn within_bandwidth(
+    limit: &mut Option<BandwidthLimit>,
+    wire_size: u64,
+    stats: &mut StatisticsPrinter,
+) -> bool {
+    let fits = limit
+        .as_mut()
+        .is_none_or(|limit| limit.try_send(wire_size, Instant::now()));
+    if !fits {
+        stats.add_dropped();
+    }
+    fits
+}
+
+/// Errors that are expected while the network is temporarily unavailable, as opposed to
+/// misconfiguration like an invalid target address
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Returns the number of fragments sent, or `None` if the update was skipped because of a
+/// transient network error. Any other error is fatal.
+fn check_send(
+    result: io::Result<u16>,
+    send_failing: &mut bool,
+    stats: &mut StatisticsPrinter,
+) -> io::Result<Option<u16>> {
+    match result {
+        Ok(fragments) => {
+            if *send_failing {
+                info!("Sending recovered");
+                *send_failing = false;
+            }
+            Ok(Some(fragments))
+        }
+        Err(e) if is_transient(&e) => {
+            // Only report the first failure to avoid flooding the log
+            if !*send_failing {
+                warn!(
+                    "Failed to send update: {}. Skipping updates until it recovers.",
+                    e
+                );
+                *send_failing = true;
+            }
+            stats.add_skipped();
+            Ok(None)
+        }
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("Failed to send update: {}", e),
+        )),
+    }
+}
+
+/// Turns telemetry updates into datagrams for the transport, leaving the session info out
+/// of the regular updates and sending heartbeats while there is no new data
+struct Publisher {
+    sender: Sender,
+    stats: StatisticsPrinter,
+    compression_buf: Vec<u8>,
+    compression_threshold: f64,
+    verify: bool,
+    lz4_format: Lz4Format,
+    quality: Quality,
+    compressor: Option<Compressor>,
+    stripped_buf: Vec<u8>,
+    max_size: usize,
+    last_session_info: Option<(i32, Instant)>,
+    keyframe_interval: Option<Duration>,
+    send_failing: bool,
+    heartbeat_interval: Option<Duration>,
+    last_send_time: Instant,
+    count: Option<u64>,
+    published: u64,
+    bandwidth_limit: Option<BandwidthLimit>,
+    adaptive: Option<AdaptiveRate>,
+    change_filter: Option<ChangeFilter>,
+    var_filter: Option<VarFilter>,
+    region: Option<Region>,
+    hello: Hello,
+    unicast: bool,
+    last_hello: Option<Instant>,
+    hello_answered: bool,
+    send_log: SendLog,
+}
+
+impl Publisher {
+    fn new(
+        config: &SourceConfig,
+        mapping_size: usize,
+        compressor: Option<Compressor>,
+    ) -> io::Result<Self> {
+        let mut sender = Sender::new(config.id);
+        sender.set_mapping_size(mapping_size as u32);
+        check_mapping_size(mapping_size, config.max_size);
+        if let Some(pace) = config.pace {
+            info!("Spreading the fragments of each update over {} µs", pace);
+            sender.set_pacing(Some(Duration::from_micros(pace)));
+        }
+        if config.fec > 0 {
+            info!(
+                "Adding {} parity fragments to each update for forward error correction",
+                config.fec
+            );
+            sender.set_fec(config.fec);
+        }
+
+        if config.verify {
+            info!("Appending a hash to every update for the targets to verify");
+        }
+        if let Some(faults) = FaultInjector::new(config.drop_rate, config.reorder, config.drop_seed)
+        {
+            warn!(
+                "Dropping {:.1}% and reordering {:.1}% of the datagrams sent on purpose (--drop-rate, --reorder)",
+                config.drop_rate * 100.0,
+                config.reorder * 100.0
+            );
+            sender.set_faults(Some(faults));
+        }
+
+        let mut stats = StatisticsPrinter::new("source");
+        if config.simulate {
+            stats.set_simulated();
+        }
+        if config.redundancy > 1 {
+            info!("Sending every datagram {} times", config.redundancy);
+            sender.set_redundancy(config.redundancy);
+            stats.set_redundancy(config.redundancy);
+        }
+        if let Some(addr) = &config.stats_export {
+            stats.set_export(StatsExport::new(addr)?);
+        }
+        if let Some(addr) = &config.health_addr {
+            stats.set_health(HealthCheck::start(addr, DEFAULT_HEALTH_WINDOW)?);
+        }
+        if config.status_line {
+            stats.set_status_line();
+        }
+
+        info!(
+            "Compressing the updates as {}",
+            config
+                .quality
+                .describe(config.lz4_format, compressor.is_some())
+        );
+
+        let mut features = 0;
+        if config.verify {
+            features |= FEATURE_VERIFY;
+        }
+        if compressor.is_some() {
+            features |= FEATURE_DICTIONARY;
+        }
+        let region = config.region.as_deref().map(Region::parse).transpose()?;
+        if let Some(region) = &region {
+            info!(
+                "Only sending {} bytes at offset {} between keyframes",
+                region.len, region.offset
+            );
+            check_region(region, mapping_size);
+            features |= FEATURE_REGION;
+        }
+        let hello = Hello {
+            source_id: config.id,
+            version: PROTOCOL_VERSION,
+            codec: config.lz4_format.codec(),
+            features,
+            mapping_size: mapping_size as u32,
+            dictionary_id: compressor
+                .as_ref()
+                .map_or(0, |compressor| compressor.dictionary().id()),
+        };
+
+        Ok(Self {
+            sender,
+            stats,
+            compression_buf: vec![0u8; encode_buffer_len(config.max_size)],
+            compression_threshold: config.compression_threshold,
+            verify: config.verify,
+            lz4_format: config.lz4_format,
+            quality: config.quality,
+            compressor,
+            stripped_buf: Vec::with_capacity(config.max_size),
+            max_size: config.max_size,
+            last_session_info: None,
+            keyframe_interval: (config.keyframe_ms > 0)
+                .then(|| Duration::from_millis(config.keyframe_ms)),
+            send_failing: false,
+            heartbeat_interval: (config.heartbeat_ms > 0)
+                .then(|| Duration::from_millis(config.heartbeat_ms)),
+            last_send_time: Instant::now(),
+            count: config.count,
+            published: 0,
+            bandwidth_limit: config.max_bandwidth.map(|mbps| {
+                info!(
+                    "Limiting the bandwidth to {} Mbps, dropping updates that don't fit",
+                    mbps
+                );
+                BandwidthLimit::new(mbps)
+            }),
+            adaptive: config.adaptive.then(|| {
+                info!("Adapting the update rate to the loss reported by the target");
+                AdaptiveRate::new()
+            }),
+            change_filter: config.skip_unchanged.then(|| {
+                info!("Skipping updates while the telemetry doesn't change");
+                ChangeFilter::default()
+            }),
+            var_filter: (!config.vars.is_empty()).then(|| {
+                info!("Only sending variables: {}", config.vars.join(", "));
+                VarFilter::new(config.vars.clone())
+            }),
+            region,
+            hello,
+            unicast: config.unicast,
+            last_hello: None,
+            hello_answered: false,
+            send_log: SendLog::default(),
+        })
+    }
+
+    /// Starts over with a newly opened telemetry mapping
+    fn reconnected(&mut self, mapping_size: usize) {
+        self.sender.set_mapping_size(mapping_size as u32);
+        check_mapping_size(mapping_size, self.max_size);
+        if let Some(region) = &self.region {
+            check_region(region, mapping_size);
+        }
+        self.last_session_info = None;
+        if let Some(filter) = &mut self.change_filter {
+            filter.forget();
+        }
+        self.hello.mapping_size = mapping_size as u32;
+        self.last_hello = None;
+        self.hello_answered = false;
+    }
+
+    /// Describes the stream to targets before its first update: until the target answers
+    /// when sending unicast, and periodically for targets joining a multicast group
+    fn announce<F>(&mut self, mut send: F)
+    where
+        F: FnMut(&[&[u8]]) -> io::Result<()>,
+    {
+        let interval = match (self.unicast, self.hello_answered) {
+            (true, true) => return,
+            (true, false) => HELLO_RETRY_INTERVAL,
+            (false, _) => HELLO_INTERVAL,
+        };
+        if self
+            .last_hello
+            .is_some_and(|last| last.elapsed() < interval)
+        {
+            return;
+        }
+
+        // Best-effort, it goes out again until answered
+        if let Err(e) = send(&[&protocol::hello(&self.hello)]) {
+            debug!("Failed to send hello: {}", e);
+        }
+        self.last_hello = Some(Instant::now());
+    }
+
+    /// Handles a target's answer to the hello
+    fn hello_answered(&mut self, reply: &HelloReply, target: SocketAddr) {
+        let was_answered = std::mem::replace(&mut self.hello_answered, true);
+        match reply.rejection {
+            Some(rejection) => error!(
+                "{} (protocol version {}) can't decode this stream, because {}",
+                target, reply.version, rejection
+            ),
+            None if !was_answered => info!(
+                "{} (protocol version {}) accepted the stream",
+                target, reply.version
+            ),
+            None => {}
+        }
+    }
+
+    /// Includes the session info in the next update, for a target that joined late
+    fn request_keyframe(&mut self) {
+        self.last_session_info = None;
+        if let Some(filter) = &mut self.change_filter {
+            filter.forget();
+        }
+    }
+
+    /// Adapts the update rate to the updates a target received and lost, with --adaptive
+    fn report_loss(&mut self, received: u32, lost: u32) {
+        if let Some(adaptive) = &mut self.adaptive
+            && let Some(loss) = adaptive.add_report(received, lost)
+        {
+            self.stats.set_adaptive(adaptive.share, loss);
+        }
+    }
+
+    /// Times the round trip of an update a target echoed in a loss report received at
+    /// `received`. On multicast every target echoes, so the latency averages all of them.
+    fn echoed(&mut self, echo: &Echo, received: Instant) {
+        if let Some(latency) = self.send_log.latency(echo, received) {
+            self.stats.add_echo_latency(latency.as_micros() as u64);
+        }
+    }
+
+    /// Lets targets know the source is shutting down, so they close the telemetry right
+    /// away instead of waiting for --idle-timeout. Best-effort, and sent a few times since
+    /// a single datagram is easily lost.
+    fn goodbye<F>(&mut self, mut send: F)
+    where
+        F: FnMut(&[&[u8]]) -> io::Result<()>,
+    {
+        for _ in 0..GOODBYE_COPIES {
+            if let Err(e) = self.sender.send_goodbye(|datagram| send(&[datagram])) {
+                debug!("Failed to send goodbye: {}", e);
+                return;
+            }
+        }
+    }
+
+    /// Lets targets know we're still here while there is no new data
+    fn heartbeat<F>(&mut self, mut send: F) -> io::Result<()>
+    where
+        F: FnMut(&[&[u8]]) -> io::Result<()>,
+    {
+        self.stats.refresh_status();
+        self.announce(&mut send);
+        let due = self
+            .heartbeat_interval
+            .is_some_and(|interval| self.last_send_time.elapsed() >= interval);
+        if !due {
+            return Ok(());
+        }
+
+        let result = self
+            .sender
+            .send_heartbeat(|datagram| send(&[datagram]))
+            .map(|_| 0);
+        check_send(result, &mut self.send_failing, &mut self.stats)?;
+        self.last_send_time = Instant::now();
+        self.stats.add_heartbeat();
+        Ok(())
+    }
+
+    /// Sends the telemetry `data` that became available at `data_time`
+    fn publish<F>(&mut self, data: &[u8], data_time: Instant, mut send: F) -> io::Result<()>
+    where
+        F: FnMut(&[&[u8]]) -> io::Result<()>,
+    {
+        self.announce(&mut send);
+
+        // Only heartbeats go out while nothing changes
+        if self
+            .change_filter
+            .as_ref()
+            .is_some_and(|filter| filter.unchanged(data, data_time))
+        {
+            self.stats.add_unchanged();
+            self.heartbeat(&mut send)?;
+            return self.skipped();
+        }
+
+        if self
+            .adaptive
+            .as_mut()
+            .is_some_and(|adaptive| !adaptive.admit())
+        {
+            return self.skipped();
+        }
+
+        // A mapping larger than --max-size, which was warned about, still fits
+        let needed = encode_buffer_len(data.len());
+        if self.compression_buf.len() < needed {
+            self.compression_buf.resize(needed, 0);
+        }
+
+        // Send the session info only when it changes (or as a keyframe for late joiners),
+        // and leave it out of the regular updates
+        let session_info = irsdk::Header::parse(data).and_then(|header| {
+            let range = header.session_info_range(data.len())?;
+            Some((header.session_info_update, range))
+        });
+
+        let (payload, flags) = match session_info {
+            Some((update, range)) => {
+                let due = match self.last_session_info {
+                    Some((last_update, sent)) => {
+                        last_update != update
+                            || self
+                                .keyframe_interval
+                                .is_some_and(|interval| sent.elapsed() >= interval)
+                    }
+                    None => true,
+                };
+
+                if due {
+                    let (payload, encoding) = encode(
+                        &data[range.clone()],
+                        &mut self.compression_buf,
+                        self.compression_threshold,
+                        self.verify,
+                        self.lz4_format,
+                        self.quality,
+                        // The dictionary is trained on the updates, not the session info
+                        None,
+                    );
+                    // Over the budget it stays due and goes out with a later update
+                    let wire_size = self.sender.wire_size(payload.len());
+                    if !within_bandwidth(&mut self.bandwidth_limit, wire_size, &mut self.stats) {
+                        return self.skipped();
+                    }
+                    if encoding & FLAG_UNCOMPRESSED != 0 {
+                        self.stats.add_uncompressed();
+                    }
+
+                    let processing_time = data_time.elapsed().as_micros() as u64;
+                    let send_result = self.sender.send_batch(
+                        payload,
+                        processing_time,
+                        FLAG_SESSION_INFO | encoding,
+                        &mut send,
+                    );
+                    if let Some(fragments) =
+                        check_send(send_result, &mut self.send_failing, &mut self.stats)?
+                    {
+                        self.stats.add_bytes(payload.len());
+                        self.stats.add_fragments(fragments);
+                        self.last_session_info = Some((update, Instant::now()));
+                    }
+                }
+
+                // Keyframes carry the whole mapping for targets that join, only the
+                // region goes out in between
+                if !due
+                    && let Some(region) = &self.region
+                    && irsdk::extract_region(data, region, &mut self.stripped_buf)
+                {
+                    (self.stripped_buf.as_slice(), FLAG_REGION)
+                } else {
+                    irsdk::strip_session_info(data, range.clone(), &mut self.stripped_buf);
+                    if let Some(filter) = &mut self.var_filter {
+                        filter.apply(data, range, &mut self.stripped_buf);
+                    }
+                    (self.stripped_buf.as_slice(), FLAG_SESSION_INFO_OMITTED)
+                }
+            }
+            None => (data, 0),
+        };
+
+        // Compress the memory content
+        let (payload, encoding) = encode(
+            payload,
+            &mut self.compression_buf,
+            self.compression_threshold,
+            self.verify,
+            self.lz4_format,
+            self.quality,
+            self.compressor.as_mut(),
+        );
+
+        // Drop whole updates over the budget, which lowers the rate instead of sending
+        // updates that can't be completed
+        let wire_size = self.sender.wire_size(payload.len());
+        if !within_bandwidth(&mut self.bandwidth_limit, wire_size, &mut self.stats) {
+            return self.skipped();
+        }
+        if encoding & FLAG_UNCOMPRESSED != 0 {
+            self.stats.add_uncompressed();
+        }
+
+        // Calculate processing time in microseconds
+        let processing_time = data_time.elapsed().as_micros() as u64;
+
+        // Send the compressed data in fragments
+        let sequence = self.sender.next_sequence();
+        let send_result =
+            self.sender
+                .send_batch(payload, processing_time, flags | encoding, &mut send);
+
+        if let Some(fragments) = check_send(send_result, &mut self.send_failing, &mut self.stats)? {
+            self.stats.add_bytes(payload.len());
+            self.stats.add_fragments(fragments);
+            self.last_send_time = Instant::now();
+            self.send_log
+                .record(sequence, data_time, self.last_send_time);
+            if let Some(filter) = &mut self.change_filter {
+                filter.sent(data, data_time);
+            }
+        }
+
+        self.stats.add_update();
+        self.stats.add_latency(processing_time);
+        self.stats.add_wire_bytes(self.sender.take_sent_bytes());
+        self.published += 1;
+
+        if self.stats.should_print() {
+            self.stats.print_and_reset();
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether sending has been failing since the last update that went out
+    fn send_failing(&self) -> bool {
+        self.send_failing
+    }
+
+    /// Finishes an update that wasn't sent
+    fn skipped(&mut self) -> io::Result<()> {
+        self.published += 1;
+        if self.stats.should_print() {
+            self.stats.print_and_reset();
+        }
+        Ok(())
+    }
+
+    /// Returns whether the number of updates given by `--count` has been published
+    fn finished(&self) -> bool {
+        let finished = self.count.is_some_and(|count| self.published >= count);
+        if finished {
+            info!("Published {} updates, shutting down...", self.published);
+        }
+        finished
+    }
+}
+
+/// Returns the reply to `request` if it is a probe for this source
+fn probe_reply(request: &[u8], source_id: u32) -> Option<[u8; PROBE_REPLY_SIZE]> {
+    let received_us = protocol::wall_clock_us();
+    match protocol::parse_probe(request) {
+        Some((id, sent_us)) if id == source_id => Some(protocol::probe_reply(&ProbeTimes {
+            source_id,
+            sent_us,
+            received_us,
+            replied_us: protocol::wall_clock_us(),
+        })),
+        _ => None,
+    }
+}
+
+/// Listens for keyframe requests, probes, loss reports and answers to the hello from
+/// targets on a clone of the sending socket. Probes are answered right away so the reply
+/// measures the network and not the source.
+struct TargetRequests {
+    requested: Arc<AtomicBool>,
+    received: Arc<AtomicU32>,
+    lost: Arc<AtomicU32>,
+    hello_reply: Arc<Mutex<Option<(HelloReply, SocketAddr)>>>,
+    echo: Arc<Mutex<Option<(Echo, Instant)>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl TargetRequests {
+    fn listen(socket: &UdpSocket, source_id: u32, unicast: bool) -> io::Result<Self> {
+        let socket = socket.try_clone()?;
+        socket.set_read_timeout(Some(REQUEST_POLL_INTERVAL))?;
+
+        let requested = Arc::new(AtomicBool::new(false));
+        let received = Arc::new(AtomicU32::new(0));
+        let lost = Arc::new(AtomicU32::new(0));
+        let hello_reply = Arc::new(Mutex::new(None));
+        let echo = Arc::new(Mutex::new(None));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (requested_tx, received_tx, lost_tx, hello_reply_tx, echo_tx, stopped_rx) = (
+            requested.clone(),
+            received.clone(),
+            lost.clone(),
+            hello_reply.clone(),
+            echo.clone(),
+            stopped.clone(),
+        );
+
+        thread::spawn(move || {
+            // Anything longer than the largest request is truncated and fails the length checks
+            let mut buf = [0u8; LOSS_REPORT_SIZE + 1];
+            while !stopped_rx.load(Ordering::Relaxed) {
+                // Errors are timeouts or ICMP responses to the updates sent
+                let Ok((len, target)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                let request = &buf[..len];
+
+                if protocol::parse_keyframe_request(request) == Some(source_id) {
+                    info!("Keyframe requested by {}", target);
+                    requested_tx.store(true, Ordering::Relaxed);
+                } else if let Some(reply) = probe_reply(request, source_id) {
+                    // A connected socket only hears from its peer and can't send elsewhere
+                    let destination = (!unicast).then_some(target);
+                    let _ = net::send_batch(&socket, destination, &[&reply]);
+                } else if let Some(report) = protocol::parse_loss_report(request)
+                    && report.source_id == source_id
+                {
+                    received_tx.fetch_add(report.received, Ordering::Relaxed);
+                    lost_tx.fetch_add(report.lost, Ordering::Relaxed);
+                    // Timed on arrival, the publisher only gets to it with the next update
+                    if let Some(echo) = report.echo {
+                        *echo_tx.lock().unwrap() = Some((echo, Instant::now()));
+                    }
+                } else if let Some(reply) = protocol::parse_hello_reply(request)
+                    && reply.source_id == source_id
+                {
+                    *hello_reply_tx.lock().unwrap() = Some((reply, target));
+                }
+            }
+        });
+
+        Ok(Self {
+            requested,
+            received,
+            lost,
+            hello_reply,
+            echo,
+            stopped,
+        })
+    }
+
+    /// Returns whether a keyframe was requested since the last call
+    fn take(&self) -> bool {
+        self.requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns the updates targets reported as received and lost since the last call
+    fn take_loss(&self) -> (u32, u32) {
+        (
+            self.received.swap(0, Ordering::Relaxed),
+            self.lost.swap(0, Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the latest answer to the hello since the last call
+    fn take_hello_reply(&self) -> Option<(HelloReply, SocketAddr)> {
+        self.hello_reply.lock().unwrap().take()
+    }
+
+    /// Returns the latest update a target echoed since the last call, with when the echo
+    /// arrived
+    fn take_echo(&self) -> Option<(Echo, Instant)> {
+        self.echo.lock().unwrap().take()
+    }
+}
+
+impl Drop for TargetRequests {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Resolves the --target host name again in the background, so a source sending unicast
+/// to a dynamic DNS name follows the target when its address changes
+struct TargetResolver {
+    host: String,
+    changed: Arc<Mutex<Option<SocketAddr>>>,
+    resolve_now: mpsc::Sender<()>,
+    last_request: Option<Instant>,
+}
+
+impl TargetResolver {
+    /// Starts resolving the `target` host name again every `RESOLVE_INTERVAL`, starting
+    /// from the `current` address. Returns `None` if there is nothing to follow, when
+    /// sending multicast or to an IP address.
+    fn start(target: &str, unicast: bool, current: SocketAddr) -> Option<Self> {
+        if !unicast || target.parse::<SocketAddr>().is_ok() {
+            return None;
+        }
 
-// Timeout before considering the connection lost
-const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+        let host = target.to_string();
+        let changed = Arc::new(Mutex::new(None));
+        let (resolve_now, requests) = mpsc::channel();
+        let (thread_host, changed_tx) = (host.clone(), changed.clone());
 
-// Individual wait interval to maintain shutdown responsiveness
-const WAIT_INTERVAL_MS: u32 = 200;
+        thread::spawn(move || {
+            let mut current = current;
+            let mut failing = false;
+            // Stops once the resolver is dropped
+            while let Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) =
+                requests.recv_timeout(RESOLVE_INTERVAL)
+            {
+                match net::resolve(&thread_host) {
+                    Ok(address) => {
+                        failing = false;
+                        if address != current {
+                            current = address;
+                            *changed_tx.lock().unwrap_or_else(|e| e.into_inner()) = Some(address);
+                        }
+                    }
+                    Err(e) => {
+                        // Only report the first failure, the last good address is kept
+                        if !failing {
+                            warn!("{}. Sending to {} until it resolves again.", e, current);
+                            failing = true;
+                        }
+                    }
+                }
+            }
+        });
 
-fn try_connect_telemetry(shutdown: &Receiver<()>) -> io::Result<Option<Telemetry>> {
-    let result = match Telemetry::open() {
-        Ok(telemetry) => {
-            println!("Connected to racing session");
-            println!("Memory region size: {} bytes", telemetry.size());
-            Ok(Some(telemetry))
+        info!(
+            "Resolving {} again every {} seconds to follow changes of its address",
+            host,
+            RESOLVE_INTERVAL.as_secs()
+        );
+        Some(Self {
+            host,
+            changed,
+            resolve_now,
+            last_request: None,
+        })
+    }
+
+    /// Returns the new address of the target if it changed. While sending fails, the
+    /// host name is resolved again sooner.
+    fn take_change(&mut self, send_failing: bool) -> Option<SocketAddr> {
+        if send_failing
+            && self
+                .last_request
+                .is_none_or(|last| last.elapsed() >= RESOLVE_RETRY_INTERVAL)
+        {
+            let _ = self.resolve_now.send(());
+            self.last_request = Some(Instant::now());
         }
-        Err(TelemetryError::Unavailable) => Ok(None),
-        Err(TelemetryError::Other(e)) => Err(io::Error::other(e.to_string())),
-    }?;
 
-    if result.is_none() {
-        // Wait for either a shutdown signal or timeout
-        match shutdown.recv_timeout(Duration::from_secs(10)) {
-            Ok(_) => return Ok(None),                   // Shutdown requested
-            Err(mpsc::RecvTimeoutError::Timeout) => (), // Continue trying
-            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None), // Shutdown
+        let address = self
+            .changed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()?;
+        info!("{} now resolves to {}, sending there", self.host, address);
+        Some(address)
+    }
+}
+
+/// Points a unicast socket at the target's new address, keeping the old one on failure
+fn follow_target(socket: &UdpSocket, address: SocketAddr) {
+    if let Err(e) = socket.connect(address) {
+        warn!("Failed to connect to {}: {}", address, e);
+    }
+}
+
+pub fn run(config: &SourceConfig, shutdown: Receiver<()>) -> Result<(), TeleportError> {
+    match &config.ibt {
+        Some(path) => run_ibt(config, path, shutdown),
+        None if config.simulate => {
+            simulate(config);
+            run_with::<MockTelemetry>(config, shutdown)
         }
+        None => run_with::<Telemetry>(config, shutdown),
     }
-    Ok(result)
 }
 
-pub fn run(bind: &str, target: &str, unicast: bool, shutdown: Receiver<()>) -> io::Result<()> {
-    let socket = UdpSocket::bind(bind)
-        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind UDP socket: {}", e)))?;
+/// Prepares the synthetic telemetry of --simulate, which the source reads instead of the sim
+fn simulate(config: &SourceConfig) {
+    warn!(
+        "SIMULATED: sending synthetic telemetry of {} bytes at 60 Hz, not data from the sim",
+        config.simulate_size
+    );
+    mock::set_size(config.simulate_size);
+}
 
-    if unicast {
+/// Picks the interface to send multicast from if one was given with --out-interface
+fn select_interface<'s, S>(socket: &'s S, config: &SourceConfig) -> io::Result<()>
+where
+    SockRef<'s>: From<&'s S>,
+{
+    let Some(interface) = &config.out_interface else {
+        return Ok(());
+    };
+
+    if config.unicast {
+        warn!(
+            "--out-interface only applies to multicast, bind to the interface's address with --bind instead"
+        );
+        return Ok(());
+    }
+
+    let ip = interfaces::ipv4_address(&interfaces::list(), interface)?;
+    net::set_multicast_interface(socket, ip)?;
+    info!("Sending multicast out of interface {} ({})", interface, ip);
+    Ok(())
+}
+
+/// Applies --multicast-loop and logs whether the source's multicast is delivered locally
+fn select_multicast_loop<'s, S>(socket: &'s S, config: &SourceConfig) -> io::Result<()>
+where
+    SockRef<'s>: From<&'s S>,
+{
+    if config.unicast {
+        if config.multicast_loop.is_some() {
+            warn!("--multicast-loop only applies to multicast");
+        }
+        return Ok(());
+    }
+
+    let enabled = net::set_multicast_loop(socket, config.multicast_loop)?;
+    info!(
+        "Multicast loopback {}{}, targets on this computer {} receive the telemetry",
+        if enabled { "enabled" } else { "disabled" },
+        if config.multicast_loop.is_none() {
+            " (OS default)"
+        } else {
+            ""
+        },
+        if enabled { "can" } else { "won't" }
+    );
+    Ok(())
+}
+
+/// Binds the socket to send from to --bind, which gives the datagrams a fixed source port
+/// unless its port is 0
+fn bind(bind: &str) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(bind).map_err(|e| bind_error(bind, e))?;
+    log_fixed_port(bind);
+    Ok(socket)
+}
+
+/// Explains why binding to `bind` failed, in particular when its port is taken
+fn bind_error(bind: &str, e: io::Error) -> io::Error {
+    if e.kind() == io::ErrorKind::AddrInUse {
+        let port = bind.rsplit_once(':').map_or("", |(_, port)| port);
+        return io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to bind UDP socket to {}: port {} is already in use, e.g. by a target \
+                 on this computer or another source. Pick another port with --bind, or port 0 \
+                 to send from any free one.",
+                bind, port
+            ),
+        );
+    }
+    io::Error::new(
+        e.kind(),
+        format!("Failed to bind UDP socket to {}: {}", bind, e),
+    )
+}
+
+/// Tells that the datagrams go out from a fixed port, for firewall rules and NAT
+fn log_fixed_port(bind: &str) {
+    if let Ok(addr) = bind.parse::<SocketAddr>()
+        && addr.port() != 0
+    {
+        info!("Sending from the fixed source port {}", addr.port());
+    }
+}
+
+/// Opens the socket to send from, along with the address to send each datagram to
+fn open_socket(config: &SourceConfig) -> io::Result<(UdpSocket, Option<SocketAddr>)> {
+    let target = config.target.as_str();
+
+    // Multicast datagrams are addressed individually, unicast goes to the connected peer
+    let destination = if config.unicast {
+        None
+    } else {
+        Some(net::resolve(target)?)
+    };
+
+    let socket = match config.fd {
+        Some(fd) => net::inherited_udp(fd)?,
+        None => bind(&config.bind)?,
+    };
+
+    if config.unicast {
         socket.connect(target).map_err(|e| {
             io::Error::new(
                 e.kind(),
@@ -51,10 +1388,173 @@ pub fn run(bind: &str, target: &str, unicast: bool, shutdown: Receiver<()>) -> i
         })?;
     }
 
+    if let Some(dscp) = config.dscp {
+        net::set_dscp(&socket, dscp);
+    }
+    select_interface(&socket, config)?;
+    select_multicast_loop(&socket, config)?;
+
+    Ok((socket, destination))
+}
+
+/// Prints the --ready-signal line once the telemetry is open and the socket set up
+fn signal_ready(config: &SourceConfig, bind: Option<SocketAddr>) {
+    if config.ready_signal {
+        let bind = bind.map_or_else(|| "-".to_string(), |bind| bind.to_string());
+        logging::signal_ready(
+            "source",
+            &[
+                ("bind", bind),
+                ("target", config.target.clone()),
+                ("id", config.id.to_string()),
+            ],
+        );
+    }
+}
+
+/// Trains a dictionary for --dict on the updates a source would send while playing back
+/// the .ibt file at `path`, with all but `vars` zeroed like --vars does. Returns the
+/// dictionary of up to `size` bytes and the number of updates it was trained on.
+pub fn train_dictionary(path: &Path, vars: &[String], size: usize) -> io::Result<(Vec<u8>, u32)> {
+    let mut replay = IbtReplay::open(path, 1.0, 0.0)?;
+    let (records, _) = replay.length();
+    let step = records.div_ceil(TRAINING_SAMPLES).max(1);
+    let mut var_filter = (!vars.is_empty()).then(|| VarFilter::new(vars.to_vec()));
+    let mut trainer = Trainer::new();
+    let mut stripped = Vec::new();
+
+    let mut record = 0;
+    while replay.read_next()? {
+        record += 1;
+        if record % step != 0 {
+            continue;
+        }
+
+        let data = replay.as_slice();
+        match irsdk::Header::parse(data).and_then(|header| header.session_info_range(data.len())) {
+            Some(range) => {
+                irsdk::strip_session_info(data, range.clone(), &mut stripped);
+                if let Some(filter) = &mut var_filter {
+                    filter.apply(data, range, &mut stripped);
+                }
+                trainer.add(&stripped);
+            }
+            None => trainer.add(data),
+        }
+    }
+
+    let samples = trainer.samples();
+    Ok((trainer.finish(size), samples))
+}
+
+/// Loads the dictionary given with --dict to compress the updates with
+fn load_compressor(config: &SourceConfig) -> Result<Option<Compressor>, TeleportError> {
+    let Some(path) = &config.dict else {
+        return Ok(None);
+    };
+    let dictionary = Dictionary::load(path).map_err(|e| TeleportError::Config(e.to_string()))?;
+    info!(
+        "Compressing with the dictionary {} (id {:08x}), targets need the same --dict",
+        path.display(),
+        dictionary.id()
+    );
+    let mut compressor = Compressor::new(dictionary)?;
+    compressor.set_acceleration(config.quality.acceleration());
+    Ok(Some(compressor))
+}
+
+/// Where a running source sends to: the socket, none on a dry run, the requests targets
+/// send back on it and the --target host it follows to new addresses
+struct Link {
+    socket: Option<(UdpSocket, Option<SocketAddr>)>,
+    // Loaded with the socket so a bad --dict fails right away, used once publishing starts
+    compressor: Option<Compressor>,
+    requests: Option<TargetRequests>,
+    resolver: Option<TargetResolver>,
+}
+
+impl Link {
+    /// Opens the socket and loads the dictionary, before waiting for the telemetry
+    fn open(config: &SourceConfig) -> Result<Self, TeleportError> {
+        // A dry run reads and compresses the telemetry as usual, without touching the network
+        let socket = if config.dry_run {
+            warn!("DRY RUN: not sending, the telemetry is only read and compressed");
+            None
+        } else {
+            Some(open_socket(config)?)
+        };
+        Ok(Self {
+            socket,
+            compressor: load_compressor(config)?,
+            requests: None,
+            resolver: None,
+        })
+    }
+
+    /// Returns the publisher for telemetry of `size` bytes, and starts listening to the
+    /// targets and following --target. Signals ready once done.
+    fn start(&mut self, config: &SourceConfig, size: usize) -> Result<Publisher, TeleportError> {
+        let publisher = Publisher::new(config, size, self.compressor.take())?;
+        self.requests = self
+            .socket
+            .as_ref()
+            .map(|(socket, _)| TargetRequests::listen(socket, config.id, config.unicast))
+            .transpose()?;
+        self.resolver = self.socket.as_ref().and_then(|(socket, _)| {
+            TargetResolver::start(&config.target, config.unicast, socket.peer_addr().ok()?)
+        });
+        signal_ready(
+            config,
+            self.socket
+                .as_ref()
+                .and_then(|(socket, _)| socket.local_addr().ok()),
+        );
+
+        scheduling::apply(config.cpu_affinity, config.priority);
+        Ok(publisher)
+    }
+
+    /// Sends the datagrams the publisher hands it, or drops them on a dry run
+    fn sender(&self) -> impl FnMut(&[&[u8]]) -> io::Result<()> + '_ {
+        |datagrams| match &self.socket {
+            Some((socket, destination)) => net::send_batch(socket, *destination, datagrams),
+            None => Ok(()),
+        }
+    }
+
+    /// Connects to the address --target resolves to now, if it changed
+    fn follow_target(&mut self, publisher: &Publisher) {
+        if let (Some(resolver), Some((socket, _))) = (&mut self.resolver, &self.socket)
+            && let Some(address) = resolver.take_change(publisher.send_failing())
+        {
+            follow_target(socket, address);
+        }
+    }
+
+    /// Hands what the targets sent since the last update to the publisher
+    fn forward_requests(&self, publisher: &mut Publisher) {
+        if let Some(requests) = &self.requests {
+            forward_requests(requests, publisher);
+        }
+    }
+}
+
+/// Runs the source on top of the given telemetry provider
+pub fn run_with<T: TelemetryProvider>(
+    config: &SourceConfig,
+    shutdown: Receiver<()>,
+) -> Result<(), TeleportError> {
+    let mut link = Link::open(config)?;
+
+    let names = SharedMemoryNames {
+        mapping: config.mmap_name.clone(),
+        event: config.event_name.clone(),
+    };
+
     // Keep trying to open telemetry until successful or interrupted
-    println!("Waiting for racing session to start...");
+    info!("Waiting for racing session to start...");
     let mut telemetry = loop {
-        match try_connect_telemetry(&shutdown)? {
+        match try_connect_telemetry::<T>(&names, config.print_header, &shutdown)? {
             Some(telemetry) => break telemetry,
             None => {
                 // Check if we were asked to shut down
@@ -65,85 +1565,626 @@ pub fn run(bind: &str, target: &str, unicast: bool, shutdown: Receiver<()>) -> i
         }
     };
 
-    let mut compression_buf = vec![0u8; MAX_TELEMETRY_SIZE];
-    let mut sender = Sender::new();
-    let mut stats = StatisticsPrinter::new("source");
+    let mut publisher = link.start(config, telemetry.size())?;
     let mut last_data_time = Instant::now();
+    let mut watchdog = StallWatchdog::new(config.stall_timeout);
 
     loop {
         // Check for shutdown signal
         if shutdown.try_recv().is_ok() {
-            return Ok(());
+            break;
         }
 
+        link.follow_target(&publisher);
+
         if !telemetry.wait_for_data(WAIT_INTERVAL_MS) {
+            publisher.heartbeat(link.sender())?;
+
             // Check if we've been waiting too long
             if last_data_time.elapsed() >= DISCONNECT_TIMEOUT {
-                println!("Lost connection, attempting to reconnect...");
+                info!("Lost connection, attempting to reconnect...");
                 // Drop the current telemetry instance
                 drop(telemetry);
 
-                // Try to establish a new connection
-                loop {
-                    match try_connect_telemetry(&shutdown)? {
-                        Some(new_telemetry) => {
-                            telemetry = new_telemetry;
-                            last_data_time = Instant::now();
-                            println!("Successfully reconnected to racing session");
-                            break;
-                        }
-                        None => {
-                            if shutdown.try_recv().is_ok() {
-                                return Ok(());
-                            }
+                let Some(new_telemetry) = reconnect_telemetry::<T>(config, &names, &shutdown)?
+                else {
+                    break;
+                };
+                telemetry = new_telemetry;
+                publisher.reconnected(telemetry.size());
+                watchdog.reset();
+                last_data_time = Instant::now();
+                info!("Successfully reconnected to racing session");
+            }
+            // No data yet but haven't timed out, try again
+            continue;
+        }
+
+        // Got data, reset the timeout
+        last_data_time = Instant::now();
+
+        // The event fires but the data is frozen, reopening the telemetry recovers it
+        if let Some(tick) = watchdog.stalled(telemetry.as_slice(), last_data_time) {
+            warn!(
+                "Telemetry stalled at tick {} for {} seconds while new data kept being signaled, reconnecting...",
+                tick, config.stall_timeout
+            );
+            drop(telemetry);
+
+            let Some(new_telemetry) = reconnect_telemetry::<T>(config, &names, &shutdown)? else {
+                break;
+            };
+            telemetry = new_telemetry;
+            publisher.reconnected(telemetry.size());
+            watchdog.reset();
+            last_data_time = Instant::now();
+            info!("Reconnected after the telemetry stalled");
+            continue;
+        }
+
+        link.forward_requests(&mut publisher);
+
+        publisher.publish(telemetry.as_slice(), last_data_time, link.sender())?;
+        if publisher.finished() {
+            break;
+        }
+    }
+
+    publisher.goodbye(link.sender());
+    Ok(())
+}
+
+/// Keeps trying to open the telemetry again until successful, or returns `None` if
+/// shutdown was requested in the meantime
+fn reconnect_telemetry<T: TelemetryProvider>(
+    config: &SourceConfig,
+    names: &SharedMemoryNames,
+    shutdown: &Receiver<()>,
+) -> Result<Option<T>, TeleportError> {
+    loop {
+        match try_connect_telemetry::<T>(names, config.print_header, shutdown)? {
+            Some(telemetry) => return Ok(Some(telemetry)),
+            None => {
+                if shutdown.try_recv().is_ok() {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// Hands the keyframe requests, loss reports, echoes and answers to the hello targets sent
+/// since the last update to the publisher
+fn forward_requests(target_requests: &TargetRequests, publisher: &mut Publisher) {
+    if target_requests.take() {
+        publisher.request_keyframe();
+    }
+    if let Some((reply, target)) = target_requests.take_hello_reply() {
+        publisher.hello_answered(&reply, target);
+    }
+    let (received, lost) = target_requests.take_loss();
+    publisher.report_loss(received, lost);
+    if let Some((echo, received)) = target_requests.take_echo() {
+        publisher.echoed(&echo, received);
+    }
+}
+
+/// Runs the source on the telemetry recorded in an .ibt file, stopping at its end
+fn run_ibt(
+    config: &SourceConfig,
+    path: &Path,
+    shutdown: Receiver<()>,
+) -> Result<(), TeleportError> {
+    let mut replay = IbtReplay::open(path, config.ibt_speed, config.ibt_start).map_err(|e| {
+        TeleportError::telemetry("play back telemetry", TelemetryError::Other(e.into()))
+    })?;
+    let (records, tick_rate) = replay.length();
+    info!(
+        "Playing back {} ({:.1} seconds at {} Hz) at {}x speed",
+        path.display(),
+        records as f64 / tick_rate as f64,
+        tick_rate,
+        config.ibt_speed
+    );
+    if config.print_header {
+        print_header(replay.as_slice());
+    }
+
+    let mut link = Link::open(config)?;
+    let mut publisher = link.start(config, replay.size())?;
+
+    loop {
+        if shutdown.try_recv().is_ok() {
+            break;
+        }
+
+        if replay.finished() {
+            info!("Reached the end of {}", path.display());
+            break;
+        }
+
+        link.follow_target(&publisher);
+
+        let ready = replay.wait_for_data(WAIT_INTERVAL_MS).map_err(|e| {
+            TeleportError::telemetry("play back telemetry", TelemetryError::Other(e.into()))
+        })?;
+        if !ready {
+            publisher.heartbeat(link.sender())?;
+            continue;
+        }
+
+        link.forward_requests(&mut publisher);
+
+        publisher.publish(replay.as_slice(), Instant::now(), link.sender())?;
+        if publisher.finished() {
+            break;
+        }
+    }
+
+    publisher.goodbye(link.sender());
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+pub async fn run_async(
+    config: &SourceConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), TeleportError> {
+    if config.simulate {
+        simulate(config);
+        return run_async_with::<MockTelemetry>(config, shutdown).await;
+    }
+    run_async_with::<Telemetry>(config, shutdown).await
+}
+
+/// Runs the source on tokio until `shutdown` completes, for embedding it alongside other
+/// async services. Waiting for telemetry blocks, so it is done on tokio's blocking pool.
+/// CPU affinity and priority are left to the embedding application.
+#[cfg(feature = "async")]
+pub async fn run_async_with<T: TelemetryProvider + Send + 'static>(
+    config: &SourceConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), TeleportError> {
+    if config.dry_run {
+        return Err(TeleportError::Config(
+            "The async source has no dry run, run it without --async".to_string(),
+        ));
+    }
+
+    if config.ibt.is_some() {
+        return Err(TeleportError::Config(
+            "The async source can't play back .ibt files, run it without --async".to_string(),
+        ));
+    }
+
+    let compressor = load_compressor(config)?;
+    let target = config.target.as_str();
+    let unicast = config.unicast;
+
+    // Multicast datagrams are addressed individually, unicast goes to the connected peer
+    let destination = if unicast {
+        None
+    } else {
+        Some(net::resolve(target)?)
+    };
+
+    let socket = match config.fd {
+        Some(fd) => {
+            let socket = net::inherited_udp(fd)?;
+            socket.set_nonblocking(true)?;
+            tokio::net::UdpSocket::from_std(socket)?
+        }
+        None => {
+            let socket = tokio::net::UdpSocket::bind(&config.bind)
+                .await
+                .map_err(|e| bind_error(&config.bind, e))?;
+            log_fixed_port(&config.bind);
+            socket
+        }
+    };
+
+    if unicast {
+        socket.connect(target).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to connect to racing session: {}", e),
+            )
+        })?;
+    }
+
+    if let Some(dscp) = config.dscp {
+        net::set_dscp(&socket, dscp);
+    }
+    select_interface(&socket, config)?;
+    select_multicast_loop(&socket, config)?;
+
+    let names = SharedMemoryNames {
+        mapping: config.mmap_name.clone(),
+        event: config.event_name.clone(),
+    };
+
+    tokio::pin!(shutdown);
+
+    info!("Waiting for racing session to start...");
+    let mut telemetry = tokio::select! {
+        _ = &mut shutdown => return Ok(()),
+        telemetry = connect_telemetry_async::<T>(&names, config.print_header) => telemetry?,
+    };
+
+    let mut publisher = Publisher::new(config, telemetry.size(), compressor)?;
+    let mut last_data_time = Instant::now();
+    let mut watchdog = StallWatchdog::new(config.stall_timeout);
+    let mut request_buf = [0u8; LOSS_REPORT_SIZE + 1];
+    let mut resolver = socket
+        .peer_addr()
+        .ok()
+        .and_then(|current| TargetResolver::start(&config.target, config.unicast, current));
+    signal_ready(config, socket.local_addr().ok());
+
+    'run: loop {
+        if let Some(resolver) = &mut resolver
+            && let Some(address) = resolver.take_change(publisher.send_failing())
+            && let Err(e) = socket.connect(address).await
+        {
+            warn!("Failed to connect to {}: {}", address, e);
+        }
+
+        let mut wait = tokio::task::spawn_blocking(move || {
+            let ready = telemetry.wait_for_data(WAIT_INTERVAL_MS);
+            (telemetry, ready)
+        });
+
+        // Take keyframe requests and loss reports and answer probes from targets while waiting
+        let ready;
+        (telemetry, ready) = loop {
+            tokio::select! {
+                _ = &mut shutdown => break 'run,
+                result = &mut wait => break result.map_err(io::Error::other)?,
+                Ok((len, target)) = socket.recv_from(&mut request_buf) => {
+                    let request = &request_buf[..len];
+                    if protocol::parse_keyframe_request(request) == Some(config.id) {
+                        info!("Keyframe requested by {}", target);
+                        publisher.request_keyframe();
+                    } else if let Some(reply) = probe_reply(request, config.id) {
+                        let destination = (!unicast).then_some(target);
+                        let _ = net::try_send_batch(&socket, destination, &[&reply]);
+                    } else if let Some(report) = protocol::parse_loss_report(request)
+                        && report.source_id == config.id
+                    {
+                        publisher.report_loss(report.received, report.lost);
+                        if let Some(echo) = &report.echo {
+                            publisher.echoed(echo, Instant::now());
                         }
+                    } else if let Some(reply) = protocol::parse_hello_reply(request)
+                        && reply.source_id == config.id
+                    {
+                        publisher.hello_answered(&reply, target);
                     }
                 }
-                continue;
             }
-            // No data yet but haven't timed out, try again
+        };
+
+        // Datagrams are sent without waiting, an update that doesn't fit is skipped
+        tokio::select! {
+            _ = &mut shutdown => break 'run,
+            result = socket.writable() => result?,
+        }
+        let send = |datagrams: &[&[u8]]| net::try_send_batch(&socket, destination, datagrams);
+
+        if !ready {
+            publisher.heartbeat(send)?;
+
+            // Check if we've been waiting too long
+            if last_data_time.elapsed() >= DISCONNECT_TIMEOUT {
+                info!("Lost connection, attempting to reconnect...");
+                drop(telemetry);
+
+                telemetry = tokio::select! {
+                    _ = &mut shutdown => break 'run,
+                    telemetry = connect_telemetry_async::<T>(&names, config.print_header) => telemetry?,
+                };
+                publisher.reconnected(telemetry.size());
+                watchdog.reset();
+                last_data_time = Instant::now();
+                info!("Successfully reconnected to racing session");
+            }
             continue;
         }
 
         // Got data, reset the timeout
         last_data_time = Instant::now();
 
-        let data = telemetry.as_slice();
+        // The event fires but the data is frozen, reopening the telemetry recovers it
+        if let Some(tick) = watchdog.stalled(telemetry.as_slice(), last_data_time) {
+            warn!(
+                "Telemetry stalled at tick {} for {} seconds while new data kept being signaled, reconnecting...",
+                tick, config.stall_timeout
+            );
+            drop(telemetry);
 
-        // Compress the memory content
-        let len = match compress_to_buffer(data, None, true, &mut compression_buf) {
-            Ok(len) => len,
-            Err(e) => {
-                println!("LZ4 compression failed: {}. Skipping this update.", e);
-                continue;
+            telemetry = tokio::select! {
+                _ = &mut shutdown => break 'run,
+                telemetry = connect_telemetry_async::<T>(&names, config.print_header) => telemetry?,
+            };
+            publisher.reconnected(telemetry.size());
+            watchdog.reset();
+            last_data_time = Instant::now();
+            info!("Reconnected after the telemetry stalled");
+            continue;
+        }
+
+        publisher.publish(telemetry.as_slice(), last_data_time, send)?;
+        if publisher.finished() {
+            break;
+        }
+    }
+
+    // Sent without waiting, a full socket buffer only loses some of the copies
+    publisher.goodbye(|datagrams: &[&[u8]]| net::try_send_batch(&socket, destination, datagrams));
+    Ok(())
+}
+
+/// Keeps trying to open telemetry until successful
+#[cfg(feature = "async")]
+async fn connect_telemetry_async<T: TelemetryProvider>(
+    names: &SharedMemoryNames,
+    show_header: bool,
+) -> Result<T, TeleportError> {
+    loop {
+        match open_telemetry(names, show_header)? {
+            Some(telemetry) => return Ok(telemetry),
+            None => tokio::time::sleep(RETRY_INTERVAL).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolver_follows_a_changed_address() {
+        let stale: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        assert!(TargetResolver::start("192.0.2.1:5000", true, stale).is_none());
+        assert!(TargetResolver::start("localhost:5000", false, stale).is_none());
+
+        // Failing sends resolve the name again right away
+        let mut resolver = TargetResolver::start("localhost:5000", true, stale).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let address = loop {
+            if let Some(address) = resolver.take_change(true) {
+                break address;
             }
+            assert!(Instant::now() < deadline, "localhost didn't resolve");
+            thread::sleep(Duration::from_millis(10));
         };
+        assert!(address.ip().is_loopback());
+        assert_eq!(address.port(), 5000);
+        assert_eq!(resolver.take_change(false), None);
+    }
 
-        stats.add_bytes(len);
+    #[test]
+    fn test_fixed_source_port_must_be_free() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
 
-        // Calculate processing time in microseconds
-        let processing_time = last_data_time.elapsed().as_micros() as u64;
+        let e = bind(&addr).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        let message = e.to_string();
+        assert!(message.contains("already in use"), "{}", message);
+        assert!(message.contains("--bind"), "{}", message);
 
-        // Send the compressed data in fragments
-        let send_result = if !unicast {
-            sender.send(&compression_buf[..len], processing_time, |data| {
-                socket.send_to(data, target).map(|_| ())
-            })
-        } else {
-            sender.send(&compression_buf[..len], processing_time, |data| {
-                socket.send(data).map(|_| ())
-            })
-        };
+        // The datagrams go out from the port given
+        drop(taken);
+        let socket = bind(&addr).unwrap();
+        assert_eq!(socket.local_addr().unwrap().to_string(), addr);
+    }
+
+    #[test]
+    fn test_incompressible_data_is_sent_as_it_is() {
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
 
-        if let Ok(fragments) = send_result {
-            stats.add_fragments(fragments);
+        let mut data = vec![0u8; 64 * 1024];
+        StdRng::seed_from_u64(7).fill_bytes(&mut data);
+
+        // LZ4 has room to expand it, and the result is sent raw since it grew
+        let mut buffer = vec![0u8; encode_buffer_len(data.len())];
+        let len = compress_to_buffer(&data, None, true, &mut buffer).unwrap();
+        assert!(len > data.len());
+        let (payload, flags) = encode(
+            &data,
+            &mut buffer,
+            1.0,
+            true,
+            Lz4Format::Block,
+            Quality::Balanced,
+            None,
+        );
+        assert_eq!(flags, FLAG_UNCOMPRESSED | FLAG_VERIFY);
+        assert_eq!(&payload[..data.len()], &data[..]);
+        assert_eq!(payload.len(), data.len() + VERIFY_HASH_SIZE);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_drops_what_doesnt_fit() {
+        // 8 Mbps is a budget of 1 MB per second
+        let mut limit = BandwidthLimit::new(8.0);
+        let start = Instant::now();
+        assert!(limit.try_send(600_000, start));
+        assert!(limit.try_send(400_000, start + Duration::from_millis(100)));
+        assert!(!limit.try_send(1, start + Duration::from_millis(200)));
+
+        // Room frees up as the window slides past what was sent
+        assert!(limit.try_send(500_000, start + Duration::from_millis(1000)));
+        assert!(!limit.try_send(500_000, start + Duration::from_millis(1050)));
+        assert!(limit.try_send(100_000, start + Duration::from_millis(1100)));
+    }
+
+    #[test]
+    fn test_adaptive_rate_backs_off_and_recovers() {
+        let mut adaptive = AdaptiveRate::new();
+        let sent = |adaptive: &mut AdaptiveRate| (0..60).filter(|_| adaptive.admit()).count();
+        assert_eq!(sent(&mut adaptive), 60);
+
+        // 10% loss halves the share, every report without loss adds a step back
+        assert_eq!(adaptive.add_report(54, 6), Some(0.1));
+        assert_eq!(sent(&mut adaptive), 30);
+        assert_eq!(adaptive.add_report(30, 0), Some(0.0));
+        assert_eq!(sent(&mut adaptive), 33);
+
+        // It never stops sending, and a report without updates changes nothing
+        for _ in 0..20 {
+            adaptive.add_report(0, 10);
         }
+        assert_eq!(adaptive.share, ADAPTIVE_MIN_SHARE);
+        assert_eq!(adaptive.add_report(0, 0), None);
+        assert_eq!(adaptive.share, ADAPTIVE_MIN_SHARE);
+
+        for _ in 0..20 {
+            adaptive.add_report(100, 1);
+        }
+        assert_eq!(adaptive.share, 1.0);
+    }
+
+    #[test]
+    fn test_change_filter_ignores_tick_counts() {
+        let mut mapping = vec![0u8; 4096];
+        let mut header = irsdk::Header {
+            ver: irsdk::IRSDK_VER,
+            session_info_update: 1,
+            num_buf: 1,
+            buf_len: 256,
+            ..Default::default()
+        };
+        header.var_bufs[0] = irsdk::VarBuf {
+            tick_count: 100,
+            buf_offset: 1024,
+        };
+        header.write(&mut mapping);
+
+        let mut filter = ChangeFilter::default();
+        let start = Instant::now();
+        assert!(!filter.unchanged(&mapping, start));
+        filter.sent(&mapping, start);
+
+        // A new tick with the same variables and data outside of them don't count
+        header.var_bufs[0].tick_count = 101;
+        header.write(&mut mapping);
+        mapping[2048] = 1;
+        assert!(filter.unchanged(&mapping, start + Duration::from_millis(100)));
 
-        stats.add_update();
-        stats.add_latency(processing_time);
+        // It's sent again once the variables or the session info change, or for a refresh
+        assert!(!filter.unchanged(&mapping, start + UNCHANGED_REFRESH));
+        mapping[1024] = 1;
+        assert!(!filter.unchanged(&mapping, start));
+        mapping[1024] = 0;
+        header.session_info_update = 2;
+        header.write(&mut mapping);
+        assert!(!filter.unchanged(&mapping, start));
 
-        if stats.should_print() {
-            stats.print_and_reset();
+        // Other mappings are compared in full
+        let other = vec![7u8; 512];
+        filter.sent(&other, start);
+        assert!(filter.unchanged(&other, start));
+        assert!(!filter.unchanged(&[8u8; 512], start));
+    }
+
+    #[test]
+    fn test_watchdog_catches_a_stuck_tick_count() {
+        let mut mapping = vec![0u8; 4096];
+        let mut header = irsdk::Header {
+            ver: irsdk::IRSDK_VER,
+            status: irsdk::STATUS_CONNECTED,
+            num_buf: 1,
+            buf_len: 256,
+            ..Default::default()
+        };
+        header.var_bufs[0] = irsdk::VarBuf {
+            tick_count: 100,
+            buf_offset: 1024,
+        };
+        header.write(&mut mapping);
+
+        let mut watchdog = StallWatchdog::new(5);
+        let start = Instant::now();
+        assert_eq!(watchdog.stalled(&mapping, start), None);
+        assert_eq!(
+            watchdog.stalled(&mapping, start + Duration::from_secs(4)),
+            None
+        );
+
+        // An advancing tick count restarts the timeout
+        header.var_bufs[0].tick_count = 101;
+        header.write(&mut mapping);
+        let later = start + Duration::from_secs(4);
+        assert_eq!(watchdog.stalled(&mapping, later), None);
+        assert_eq!(
+            watchdog.stalled(&mapping, later + Duration::from_secs(5)),
+            Some(101)
+        );
+
+        // Disconnected sims and other mappings aren't watched
+        header.status = 0;
+        header.write(&mut mapping);
+        assert_eq!(
+            watchdog.stalled(&mapping, later + Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(watchdog.stalled(&[7u8; 512], start), None);
+        assert_eq!(StallWatchdog::new(0).stalled(&mapping, start), None);
+    }
+
+    #[test]
+    fn test_var_filter_zeroes_unselected_variables() {
+        // Header, two variable headers, session info at 512..1024 and a buffer at 1024
+        let mut mapping: Vec<u8> = (0..2048).map(|i| (i % 251) as u8 | 1).collect();
+        let mut header = irsdk::Header {
+            ver: irsdk::IRSDK_VER,
+            session_info_offset: 512,
+            session_info_len: 512,
+            num_vars: 2,
+            var_header_offset: irsdk::HEADER_SIZE as i32,
+            num_buf: 1,
+            buf_len: 64,
+            ..Default::default()
+        };
+        header.var_bufs[0] = irsdk::VarBuf {
+            tick_count: 1,
+            buf_offset: 1024,
+        };
+        header.write(&mut mapping);
+        for (i, name) in ["Throttle", "Speed"].iter().enumerate() {
+            let start = irsdk::HEADER_SIZE + i * irsdk::VAR_HEADER_SIZE;
+            irsdk::VarHeader {
+                var_type: irsdk::VarType::Float,
+                offset: i * 4,
+                count: 1,
+                name: name.to_string(),
+                desc: String::new(),
+                unit: String::new(),
+            }
+            .write(&mut mapping[start..]);
         }
+
+        let mut stripped = Vec::new();
+        irsdk::strip_session_info(&mapping, 512..1024, &mut stripped);
+        let mut filter = VarFilter::new(vec!["Throttle".to_string()]);
+        filter.apply(&mapping, 512..1024, &mut stripped);
+
+        // The buffer starts at 512 once the session info is left out
+        assert_eq!(stripped[..512], mapping[..512]);
+        assert_eq!(stripped[512..516], mapping[1024..1028]);
+        assert!(stripped[516..576].iter().all(|&b| b == 0));
+        assert_eq!(stripped[576..], mapping[1088..]);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_sends_oversized_updates_eventually() {
+        let mut limit = BandwidthLimit::new(0.1);
+        let start = Instant::now();
+        assert!(limit.try_send(50_000, start));
+        assert!(!limit.try_send(50_000, start + Duration::from_millis(500)));
+        assert!(limit.try_send(50_000, start + Duration::from_millis(1000)));
     }
 }
@@ -1,35 +1,148 @@
+use log::info;
+use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 
+use crate::api::{Interval, StatusApi};
+use crate::health::HealthCheck;
+use crate::logging;
+use crate::monitor::{StatsExport, StatsReport};
+use crate::protocol::{
+    DatagramInfo, FLAG_HEARTBEAT, FLAG_KEYFRAME_REQUEST, FLAG_LOSS_REPORT, FLAG_PROBE,
+    FLAG_PROBE_REPLY, MAX_SEQUENCE_GAP,
+};
+use crate::wallclock;
+
 // Statistics print interval
 const STATS_INTERVAL: Duration = Duration::from_secs(5);
 
+// How often the --status-line is refreshed
+const STATUS_INTERVAL: Duration = Duration::from_millis(250);
+
+// Time without updates after which the status line shows the link as idle
+const STATUS_IDLE: Duration = Duration::from_secs(1);
+
+// Upper bounds of the gap sizes told apart in the stats, larger gaps are counted together
+const GAP_SIZES: [u32; 3] = [1, 3, 7];
+
+// Most source ids counted per interval, datagrams with further ids (e.g. junk) are ignored
+const MAX_TRACKED_SOURCES: usize = 32;
+
+/// What the target's --jitter-buffer went through in an interval
+#[derive(Default)]
+struct JitterCounters {
+    releases: u32,
+    held: u64,
+    underruns: u32,
+    overruns: u32,
+}
+
+/// Prints statistics periodically, and a summary of the whole run when dropped
 pub struct StatisticsPrinter {
     name: &'static str,
+    // Shown in the lines printed, the name unless the telemetry is --simulate'd
+    label: String,
+    run_start_time: Instant,
+    run_updates: u64,
+    run_bytes: u64,
     start_time: Instant,
     updates: u32,
     total_bytes: u64,
     total_fragments: u64,
     total_latency_us: u64,
+    skipped: u32,
+    lost: u32,
+    // Gaps of lost updates by size: 1, 2-3, 4-7 and 8 or more
+    gaps: [u32; GAP_SIZES.len() + 1],
+    dropped: u32,
+    unchanged: u32,
+    size_mismatch: u32,
+    create_retries: u32,
+    corrupt: u32,
+    verified: u32,
+    hash_mismatch: u32,
+    overrun: u32,
+    stale: u32,
+    jitter: Option<JitterCounters>,
+    uncompressed: u32,
+    redundancy: u8,
+    wire_bytes: u64,
+    network_delay: Option<(f64, f64)>,
+    // Sum and count of the one-way latencies of updates targets echoed
+    echo_latency_us: (u64, u32),
+    stage_times_us: Option<(u64, u64, u64)>,
+    adaptive: Option<(f64, f64)>,
+    export: Option<StatsExport>,
+    health: Option<HealthCheck>,
+    api: Option<StatusApi>,
+    status_line: bool,
+    last_status: Instant,
+    last_update: Option<Instant>,
 }
 
 impl StatisticsPrinter {
     pub fn new(name: &'static str) -> Self {
         Self {
             name,
+            label: name.to_string(),
+            run_start_time: Instant::now(),
+            run_updates: 0,
+            run_bytes: 0,
             start_time: Instant::now(),
             updates: 0,
             total_bytes: 0,
             total_fragments: 0,
             total_latency_us: 0,
+            skipped: 0,
+            lost: 0,
+            gaps: [0; GAP_SIZES.len() + 1],
+            dropped: 0,
+            unchanged: 0,
+            size_mismatch: 0,
+            create_retries: 0,
+            corrupt: 0,
+            verified: 0,
+            hash_mismatch: 0,
+            overrun: 0,
+            stale: 0,
+            jitter: None,
+            uncompressed: 0,
+            redundancy: 1,
+            wire_bytes: 0,
+            network_delay: None,
+            echo_latency_us: (0, 0),
+            stage_times_us: None,
+            adaptive: None,
+            export: None,
+            health: None,
+            api: None,
+            status_line: false,
+            last_status: Instant::now(),
+            last_update: None,
         }
     }
 
     pub fn add_update(&mut self) {
         self.updates += 1;
+        self.run_updates += 1;
+        self.last_update = Some(Instant::now());
+        if let Some(health) = &self.health {
+            health.mark();
+        }
+        if let Some(api) = &self.api {
+            api.add_update();
+        }
+    }
+
+    /// Counts toward --health-addr like an update, the link is alive without new data
+    pub fn add_heartbeat(&mut self) {
+        if let Some(health) = &self.health {
+            health.mark();
+        }
     }
 
     pub fn add_bytes(&mut self, count: usize) {
         self.total_bytes += count as u64;
+        self.run_bytes += count as u64;
     }
 
     pub fn add_fragments(&mut self, count: u16) {
@@ -40,7 +153,207 @@ impl StatisticsPrinter {
         self.total_latency_us += latency_us;
     }
 
+    pub fn add_skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Counts a gap of `count` updates lost in a row, if there was one
+    pub fn add_lost(&mut self, count: u32) {
+        if count == 0 {
+            return;
+        }
+        self.lost += count;
+        let bucket = GAP_SIZES
+            .iter()
+            .position(|&size| count <= size)
+            .unwrap_or(GAP_SIZES.len());
+        self.gaps[bucket] += 1;
+    }
+
+    /// Counts an update the source didn't send to stay within --max-bandwidth
+    pub fn add_dropped(&mut self) {
+        self.dropped += 1;
+    }
+
+    /// Counts an update the source didn't send because it held the same as the last one
+    pub fn add_unchanged(&mut self) {
+        self.unchanged += 1;
+    }
+
+    /// Counts an update the target didn't write because its size doesn't fit the mapping
+    pub fn add_size_mismatch(&mut self) {
+        self.size_mismatch += 1;
+    }
+
+    /// Counts a failure to create the telemetry mapping the target tries again after
+    pub fn add_create_retry(&mut self) {
+        self.create_retries += 1;
+    }
+
+    /// Counts an update the target didn't write because it decoded wrong
+    pub fn add_corrupt(&mut self) {
+        self.corrupt += 1;
+    }
+
+    /// Counts an update that decoded to what the source hashed with --verify
+    pub fn add_verified(&mut self) {
+        self.verified += 1;
+    }
+
+    /// Counts an update the target didn't write because it didn't match the source's hash
+    pub fn add_hash_mismatch(&mut self) {
+        self.hash_mismatch += 1;
+    }
+
+    /// Counts updates the target dropped because writing them fell behind receiving
+    pub fn add_overrun(&mut self, count: u32) {
+        self.overrun += count;
+    }
+
+    /// Counts an update the target didn't write because it was older than --max-age
+    pub fn add_stale(&mut self) {
+        self.stale += 1;
+    }
+
+    /// Counts an update released by the target's jitter buffer, which held `held` updates
+    /// before releasing it
+    pub fn add_jitter_occupancy(&mut self, held: usize) {
+        let jitter = self.jitter.get_or_insert_default();
+        jitter.releases += 1;
+        jitter.held += held as u64;
+    }
+
+    /// Counts a release of the jitter buffer that found it empty
+    pub fn add_jitter_underrun(&mut self) {
+        self.jitter.get_or_insert_default().underruns += 1;
+    }
+
+    /// Counts an update the jitter buffer dropped because it was full
+    pub fn add_jitter_overrun(&mut self) {
+        self.jitter.get_or_insert_default().overruns += 1;
+    }
+
+    /// Adds the time an update took to be reassembled, to wait for the writer thread and
+    /// to be written, reported as averages per update
+    pub fn add_stage_times(&mut self, receive_us: u64, queue_us: u64, write_us: u64) {
+        let (receive, queue, write) = self.stage_times_us.get_or_insert((0, 0, 0));
+        *receive += receive_us;
+        *queue += queue_us;
+        *write += write_us;
+    }
+
+    pub fn add_uncompressed(&mut self) {
+        self.uncompressed += 1;
+    }
+
+    /// Sets how many copies of each datagram are sent, to report along with the overhead
+    /// Labels the printed stats as coming from the synthetic telemetry of --simulate, so
+    /// they aren't mistaken for a real session
+    pub fn set_simulated(&mut self) {
+        self.label = format!("{} SIMULATED", self.name);
+    }
+
+    pub fn set_redundancy(&mut self, copies: u8) {
+        self.redundancy = copies;
+    }
+
+    pub fn add_wire_bytes(&mut self, count: u64) {
+        self.wire_bytes += count;
+    }
+
+    /// Sets the one-way network delay and the source's clock offset measured by probes,
+    /// reported separately from the processing time in the latency
+    pub fn set_network_delay(&mut self, delay_us: f64, clock_offset_us: f64) {
+        self.network_delay = Some((delay_us, clock_offset_us));
+    }
+
+    /// Adds the one-way latency of an update a target echoed: the processing time plus
+    /// half its round trip
+    pub fn add_echo_latency(&mut self, latency_us: u64) {
+        self.echo_latency_us.0 += latency_us;
+        self.echo_latency_us.1 += 1;
+    }
+
+    /// Sets the share of updates --adaptive sends and the loss the target last reported,
+    /// kept until the next report
+    pub fn set_adaptive(&mut self, share: f64, loss: f64) {
+        self.adaptive = Some((share, loss));
+    }
+
+    /// Also sends the counters of every interval to a monitor
+    pub fn set_export(&mut self, export: StatsExport) {
+        self.export = Some(export);
+    }
+
+    pub fn set_health(&mut self, health: HealthCheck) {
+        self.health = Some(health);
+    }
+
+    pub fn set_api(&mut self, api: StatusApi) {
+        self.api = Some(api);
+    }
+
+    /// Refreshes a single status line in place instead of printing a line per interval,
+    /// unless the output isn't a terminal
+    pub fn set_status_line(&mut self) {
+        if logging::status_line_available() {
+            self.status_line = true;
+        } else {
+            info!("Output isn't a terminal, printing periodic stats instead of --status-line");
+        }
+    }
+
+    /// Refreshes the status line while no updates come in to do it, so it shows the
+    /// link going idle
+    pub fn refresh_status(&mut self) {
+        if self.status_line && self.should_print() {
+            self.print_and_reset();
+        }
+    }
+
+    /// The status line: whether updates are coming in, and the rate, bandwidth, loss
+    /// and latency of the current interval
+    fn status(&self, now: Instant) -> String {
+        let elapsed = now.duration_since(self.start_time).as_secs_f64().max(0.001);
+        let state = match self.last_update {
+            None => "waiting".to_string(),
+            Some(last) if now.duration_since(last) < STATUS_IDLE => "live".to_string(),
+            Some(last) => format!("idle {}s", now.duration_since(last).as_secs()),
+        };
+        let sent = self.updates + self.lost;
+        let loss = if sent > 0 {
+            self.lost as f64 * 100.0 / sent as f64
+        } else {
+            0.0
+        };
+        let latency = if self.updates > 0 {
+            self.total_latency_us as f64 / self.updates as f64
+        } else {
+            0.0
+        };
+        format!(
+            "[{}] {} | {:.1} msgs/s | {:.2} Mbps | Loss: {:.1}% | Latency: {:.0} µs",
+            self.label,
+            state,
+            self.updates as f64 / elapsed,
+            (self.total_bytes as f64 * 8.0) / (elapsed * 1_000_000.0),
+            loss,
+            latency
+        )
+    }
+
     pub fn print_and_reset(&mut self) {
+        // The status line is refreshed more often, the counters are still reset and
+        // exported per interval
+        if self.status_line {
+            let now = Instant::now();
+            logging::set_status(self.status(now));
+            self.last_status = now;
+            if now.duration_since(self.start_time) < STATS_INTERVAL {
+                return;
+            }
+        }
+
         let elapsed = self.start_time.elapsed().as_secs_f64();
         let rate = self.updates as f64 / elapsed;
         let mbps = (self.total_bytes as f64 * 8.0) / (elapsed * 1_000_000.0);
@@ -55,19 +368,426 @@ impl StatisticsPrinter {
             0.0
         };
 
-        println!(
-            "[{}] {:.2} msgs/s | Bandwidth: {:.2} Mbps | Avg fragments: {:.1} | Avg latency: {:.1} µs",
-            self.name, rate, mbps, avg_fragments, avg_latency
-        );
+        let skipped = if self.skipped > 0 {
+            format!(" | Skipped: {}", self.skipped)
+        } else {
+            String::new()
+        };
+        let lost = if self.lost > 0 {
+            format!(
+                " | Lost: {} in {} gaps (1: {}, 2-3: {}, 4-7: {}, 8+: {})",
+                self.lost,
+                self.gaps.iter().sum::<u32>(),
+                self.gaps[0],
+                self.gaps[1],
+                self.gaps[2],
+                self.gaps[3]
+            )
+        } else {
+            String::new()
+        };
+        let dropped = if self.dropped > 0 {
+            format!(" | Dropped for bandwidth: {}", self.dropped)
+        } else {
+            String::new()
+        };
+        let unchanged = if self.unchanged > 0 {
+            format!(" | Unchanged: {}", self.unchanged)
+        } else {
+            String::new()
+        };
+        let size_mismatch = if self.size_mismatch > 0 {
+            format!(" | Size mismatch: {}", self.size_mismatch)
+        } else {
+            String::new()
+        };
+        let create_retries = if self.create_retries > 0 {
+            format!(" | Create retries: {}", self.create_retries)
+        } else {
+            String::new()
+        };
+        let corrupt = if self.corrupt > 0 {
+            format!(" | Corrupt: {}", self.corrupt)
+        } else {
+            String::new()
+        };
+        let verified = if self.verified > 0 || self.hash_mismatch > 0 {
+            format!(
+                " | Verified: {} | Hash mismatch: {}",
+                self.verified, self.hash_mismatch
+            )
+        } else {
+            String::new()
+        };
+        let overrun = if self.overrun > 0 {
+            format!(" | Overrun: {}", self.overrun)
+        } else {
+            String::new()
+        };
+        let stale = if self.stale > 0 {
+            format!(" | Stale: {}", self.stale)
+        } else {
+            String::new()
+        };
+        let jitter = match &self.jitter {
+            Some(jitter) => format!(
+                " | Jitter buffer: {:.1} | Underruns: {} | Jitter overruns: {}",
+                jitter.held as f64 / jitter.releases.max(1) as f64,
+                jitter.underruns,
+                jitter.overruns
+            ),
+            None => String::new(),
+        };
+        let uncompressed = if self.uncompressed > 0 {
+            format!(" | Uncompressed: {}", self.uncompressed)
+        } else {
+            String::new()
+        };
+        let redundancy = if self.redundancy > 1 && self.total_bytes > 0 {
+            let overhead = (self.wire_bytes as f64 / self.total_bytes as f64 - 1.0) * 100.0;
+            format!(
+                " | Redundancy: {}x ({:+.0}% on the wire)",
+                self.redundancy, overhead
+            )
+        } else {
+            String::new()
+        };
+        let network = match self.network_delay {
+            Some((delay_us, clock_offset_us)) => format!(
+                " | Network: {:.1} µs | Clock offset: {:+.1} ms",
+                delay_us,
+                clock_offset_us / 1000.0
+            ),
+            None => String::new(),
+        };
+        let echoed = match self.echo_latency_us {
+            (_, 0) => String::new(),
+            (total_us, echoes) => {
+                format!(" | End-to-end: {:.1} µs", total_us as f64 / echoes as f64)
+            }
+        };
+        let stages = match self.stage_times_us {
+            Some((receive_us, queue_us, write_us)) if self.updates > 0 => {
+                let updates = self.updates as f64;
+                format!(
+                    " | Receive: {:.1} µs | Queue: {:.1} µs | Write: {:.1} µs",
+                    receive_us as f64 / updates,
+                    queue_us as f64 / updates,
+                    write_us as f64 / updates
+                )
+            }
+            _ => String::new(),
+        };
+        let adaptive = match self.adaptive {
+            Some((share, loss)) => format!(
+                " | Adaptive: {:.0}% of updates at {:.1}% loss",
+                share * 100.0,
+                loss * 100.0
+            ),
+            None => String::new(),
+        };
+
+        if !self.status_line {
+            info!(
+                "{} [{}] {:.2} msgs/s | Bandwidth: {:.2} Mbps | Avg fragments: {:.1} | Avg latency: {:.1} µs{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}",
+                wallclock::timestamp(),
+                self.label,
+                rate,
+                mbps,
+                avg_fragments,
+                avg_latency,
+                stages,
+                network,
+                echoed,
+                skipped,
+                lost,
+                dropped,
+                unchanged,
+                size_mismatch,
+                create_retries,
+                corrupt,
+                verified,
+                overrun,
+                stale,
+                jitter,
+                uncompressed,
+                redundancy,
+                adaptive
+            );
+        }
+
+        if let Some(export) = &self.export {
+            let saturate = |count: u64| u32::try_from(count).unwrap_or(u32::MAX);
+            export.send(&StatsReport {
+                role: self.name,
+                interval_ms: saturate(self.start_time.elapsed().as_millis() as u64),
+                updates: self.updates,
+                bytes: saturate(self.total_bytes),
+                fragments: saturate(self.total_fragments),
+                avg_latency_us: avg_latency as u32,
+                lost: self.lost,
+                skipped: self.skipped,
+                dropped: self.dropped,
+                corrupt: self.corrupt,
+                overrun: self.overrun,
+            });
+        }
+
+        if let Some(api) = &self.api {
+            let sent = self.updates + self.lost;
+            api.set_interval(
+                Interval {
+                    rate,
+                    bandwidth_mbps: mbps,
+                    loss_percent: if sent > 0 {
+                        self.lost as f64 * 100.0 / sent as f64
+                    } else {
+                        0.0
+                    },
+                    avg_latency_us: avg_latency,
+                },
+                self.lost,
+            );
+        }
 
         self.updates = 0;
         self.total_bytes = 0;
         self.total_fragments = 0;
         self.total_latency_us = 0;
+        self.echo_latency_us = (0, 0);
+        self.skipped = 0;
+        self.lost = 0;
+        self.gaps = [0; GAP_SIZES.len() + 1];
+        self.dropped = 0;
+        self.unchanged = 0;
+        self.size_mismatch = 0;
+        self.create_retries = 0;
+        self.corrupt = 0;
+        self.verified = 0;
+        self.hash_mismatch = 0;
+        self.overrun = 0;
+        self.stale = 0;
+        self.jitter = None;
+        self.uncompressed = 0;
+        self.wire_bytes = 0;
+        self.network_delay = None;
+        self.stage_times_us = None;
         self.start_time = Instant::now();
     }
 
     pub fn should_print(&self) -> bool {
+        if self.status_line {
+            return self.last_status.elapsed() >= STATUS_INTERVAL;
+        }
         self.start_time.elapsed() >= STATS_INTERVAL
     }
 }
+
+#[derive(Default)]
+struct SourceCounters {
+    last_sequence: Option<u32>,
+    updates: u32,
+    lost: u32,
+    total_latency_us: u64,
+}
+
+/// Counts the updates of every source sending to a shared multicast group from the
+/// headers of their datagrams, so a target that receives from one of them can tell
+/// which of the streams are healthy. Prints a line per source, but only once more than
+/// one is sending.
+pub struct SourceStatistics {
+    name: &'static str,
+    start_time: Instant,
+    sources: BTreeMap<u32, SourceCounters>,
+}
+
+impl SourceStatistics {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            start_time: Instant::now(),
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Counts a datagram, each sequence number once as an update of its source
+    pub fn add_datagram(&mut self, info: &DatagramInfo) {
+        // Heartbeats repeat the sequence number of the next update
+        if info.flags
+            & (FLAG_HEARTBEAT
+                | FLAG_KEYFRAME_REQUEST
+                | FLAG_PROBE
+                | FLAG_PROBE_REPLY
+                | FLAG_LOSS_REPORT)
+            != 0
+        {
+            return;
+        }
+
+        if !self.sources.contains_key(&info.source_id) && self.sources.len() >= MAX_TRACKED_SOURCES
+        {
+            return;
+        }
+        let counters = self.sources.entry(info.source_id).or_default();
+
+        // Further fragments, copies and stragglers of updates already counted
+        let gap = counters
+            .last_sequence
+            .map(|last| info.sequence.wrapping_sub(last));
+        match gap {
+            Some(0) => return,
+            Some(gap) if gap > u32::MAX / 2 => return,
+            Some(gap) if gap < MAX_SEQUENCE_GAP => counters.lost += gap - 1,
+            _ => {}
+        }
+        counters.last_sequence = Some(info.sequence);
+        counters.updates += 1;
+        counters.total_latency_us += info.source_time_us;
+    }
+
+    /// Prints a line per source if more than one sent since the last call, marking the
+    /// one `receiving` from, and starts over. Sources that went quiet are forgotten.
+    pub fn print_and_reset(&mut self, receiving: Option<u32>) {
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        self.sources.retain(|_, counters| counters.updates > 0);
+
+        if self.sources.len() > 1 {
+            let timestamp = wallclock::timestamp();
+            for (source_id, counters) in &self.sources {
+                let sent = counters.updates + counters.lost;
+                info!(
+                    "{} [{}] Source {}{}: {:.2} msgs/s | Lost: {} ({:.1}%) | Avg latency: {:.1} µs",
+                    timestamp,
+                    self.name,
+                    source_id,
+                    if receiving == Some(*source_id) {
+                        " (receiving)"
+                    } else {
+                        ""
+                    },
+                    counters.updates as f64 / elapsed,
+                    counters.lost,
+                    counters.lost as f64 * 100.0 / sent as f64,
+                    counters.total_latency_us as f64 / counters.updates as f64
+                );
+            }
+        }
+
+        for counters in self.sources.values_mut() {
+            counters.updates = 0;
+            counters.lost = 0;
+            counters.total_latency_us = 0;
+        }
+        self.start_time = Instant::now();
+    }
+
+    pub fn should_print(&self) -> bool {
+        self.start_time.elapsed() >= STATS_INTERVAL
+    }
+}
+
+impl Drop for StatisticsPrinter {
+    fn drop(&mut self) {
+        if self.status_line {
+            logging::clear_status();
+        }
+        if self.run_updates == 0 {
+            return;
+        }
+
+        let elapsed = self.run_start_time.elapsed().as_secs_f64();
+        info!(
+            "[{}] Summary: {} updates in {:.1} s ({:.2} msgs/s) | {:.2} MB transferred",
+            self.label,
+            self.run_updates,
+            elapsed,
+            self.run_updates as f64 / elapsed,
+            self.run_bytes as f64 / 1_000_000.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(source_id: u32, sequence: u32, flags: u16) -> DatagramInfo {
+        DatagramInfo {
+            sequence,
+            source_id,
+            fragment: 0,
+            fragments: 1,
+            flags,
+            source_time_us: 100,
+        }
+    }
+
+    #[test]
+    fn test_updates_and_loss_are_counted_per_source() {
+        let mut stats = SourceStatistics::new("target");
+        for sequence in [0, 1, 1, 2, 5] {
+            stats.add_datagram(&datagram(1, sequence, 0));
+        }
+        stats.add_datagram(&datagram(1, 6, FLAG_HEARTBEAT));
+        stats.add_datagram(&datagram(2, 10, 0));
+        stats.add_datagram(&datagram(2, 9, 0));
+
+        let counted = |stats: &SourceStatistics, id| {
+            let counters = &stats.sources[&id];
+            (counters.updates, counters.lost)
+        };
+        assert_eq!(counted(&stats, 1), (4, 2));
+        assert_eq!(counted(&stats, 2), (1, 0));
+
+        // Junk ids beyond the cap aren't tracked
+        for id in 100..200 {
+            stats.add_datagram(&datagram(id, 0, 0));
+        }
+        assert_eq!(stats.sources.len(), MAX_TRACKED_SOURCES);
+
+        // Only sources that sent in the last interval are kept
+        stats.print_and_reset(Some(1));
+        stats.add_datagram(&datagram(2, 11, 0));
+        stats.print_and_reset(Some(1));
+        assert_eq!(stats.sources.keys().copied().collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn test_gaps_are_counted_by_size() {
+        let mut stats = StatisticsPrinter::new("target");
+        for count in [0, 1, 1, 2, 3, 4, 7, 8, 100] {
+            stats.add_lost(count);
+        }
+        assert_eq!(stats.lost, 126);
+        assert_eq!(stats.gaps, [2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_status_line_shows_the_link_state() {
+        let mut stats = StatisticsPrinter::new("target");
+        let start = stats.start_time;
+        assert!(
+            stats
+                .status(start)
+                .starts_with("[target] waiting | 0.0 msgs/s")
+        );
+
+        for _ in 0..30 {
+            stats.add_update();
+            stats.add_bytes(125_000);
+            stats.add_latency(200);
+        }
+        stats.add_lost(10);
+        let now = stats.last_update.unwrap();
+        let status = stats.status(start + Duration::from_millis(500));
+        assert_eq!(
+            status,
+            "[target] live | 60.0 msgs/s | 60.00 Mbps | Loss: 25.0% | Latency: 200 µs"
+        );
+        assert!(
+            stats
+                .status(now + Duration::from_secs(3))
+                .starts_with("[target] idle 3s |")
+        );
+    }
+}
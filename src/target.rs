@@ -1,145 +1,3168 @@
-use lz4::block::decompress_to_buffer;
+use log::{Level, debug, error, info, log_enabled, warn};
+use lz4::block::{decompress, decompress_to_buffer};
+use socket2::SockRef;
+use std::collections::VecDeque;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::mpsc::Receiver;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Condvar, Mutex, MutexGuard};
 use std::{
-    io,
+    io, thread,
     time::{Duration, Instant},
 };
 
-use crate::protocol::{MAX_DATAGRAM_SIZE, Receiver as ProtocolReceiver};
-use crate::stats::StatisticsPrinter;
-use crate::telemetry::{MAX_TELEMETRY_SIZE, Telemetry, TelemetryProvider};
+use crate::api::StatusApi;
+use crate::codec::{self, Lz4Format};
+use crate::config::TargetConfig;
+use crate::dictionary::Dictionary;
+use crate::dump::{DUMP_DIR, DumpedFrame, FrameDump, Outcome};
+use crate::error::TeleportError;
+use crate::export::{JsonExporter, UdpExport, WsBroadcast};
+use crate::health::{DEFAULT_HEALTH_WINDOW, HealthCheck};
+use crate::interfaces;
+use crate::interpolation::Interpolator;
+use crate::irsdk;
+use crate::logging;
+use crate::monitor::StatsExport;
+use crate::net::{self, RecvBatch};
+use crate::protocol::{
+    self, ClockEstimate, ClockSync, Echo, FEATURE_DICTIONARY, FEATURE_VERIFY, FLAG_HEARTBEAT,
+    FLAG_SESSION_INFO_OMITTED, FLAG_UNCOMPRESSED, FaultInjector, HEADER_SIZE, HELLO_REPLY_SIZE,
+    Hello, HelloReply, LOSS_REPORT_SIZE, LossReport, MAX_DATAGRAM_SIZE, MAX_REASSEMBLY_DEPTH,
+    PROTOCOL_VERSION, Receiver as ProtocolReceiver, Rejection,
+};
+use crate::scheduling;
+use crate::stats::{SourceStatistics, StatisticsPrinter};
+use crate::telemetry::pipe::PipeTelemetry;
+use crate::telemetry::{self, SharedMemoryNames, Telemetry, TelemetryError, TelemetryProvider};
+
+// Minimum time between keyframe requests while the session info is missing
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+// Maximum number of datagrams to pull from the socket at once
+const RECV_BATCH_SIZE: usize = 32;
+
+// Complete payloads the receive thread queues up for the writer thread. Beyond that the
+// oldest update is dropped, a few keep a hiccup in writing from losing any.
+const QUEUE_CAPACITY: usize = 4;
+
+// How often the writer thread wakes up without updates to check --idle-timeout
+const WRITER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Time without datagrams after which an update missing fragments is reported as stuck
+const STUCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Time between the debug reports of the sequences lost on the way
+const GAP_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// Gaps listed in a report of lost sequences, further ones are only counted
+const GAP_LOG_LIST: usize = 8;
+
+// Steady rate the --jitter-buffer releases updates at, iRacing's 60 Hz
+const JITTER_RELEASE_INTERVAL: Duration = Duration::from_nanos(16_666_667);
+
+// Time to wait before trying again to create a mapping held by another application or
+// that the OS failed to create, doubled after every attempt up to the maximum
+const CREATE_RETRY_MIN: Duration = Duration::from_secs(1);
+const CREATE_RETRY_MAX: Duration = Duration::from_secs(30);
+
+// Time to wait before binding again while the port is in use with --bind-retry, doubled
+// after every attempt up to the maximum
+const BIND_RETRY_MIN: Duration = Duration::from_millis(250);
+const BIND_RETRY_MAX: Duration = Duration::from_secs(5);
+
+// Time to wait before rebinding the socket after a receive error, doubled after every
+// failed attempt up to the maximum
+const REBIND_RETRY_MIN: Duration = Duration::from_secs(1);
+const REBIND_RETRY_MAX: Duration = Duration::from_secs(10);
+
+/// Returns how long to wait before the next attempt, given the wait before the last one
+fn backoff(last: Option<Duration>, min: Duration, max: Duration) -> Duration {
+    match last {
+        Some(last) => (last * 2).min(max),
+        None => min,
+    }
+}
+
+/// Where the target writes the telemetry, given with --sink
+#[derive(Debug, PartialEq)]
+pub enum Sink {
+    /// A shared memory mapping by this name, iRacing's unless --mmap-name or --sink says
+    /// otherwise
+    Mapping(String),
+    /// Length-prefixed frames on the named pipe
+    Pipe(String),
+}
+
+impl Sink {
+    /// Parses `mmap:<name>` or `pipe:<name>`
+    pub fn parse(sink: &str) -> io::Result<Self> {
+        match sink.split_once(':') {
+            Some(("mmap", name)) if !name.is_empty() => Ok(Sink::Mapping(name.to_string())),
+            Some(("pipe", name)) if !name.is_empty() => Ok(Sink::Pipe(name.to_string())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid --sink '{}', expected mmap:<name> or pipe:<name>",
+                    sink
+                ),
+            )),
+        }
+    }
+
+    fn from_config(config: &TargetConfig) -> Result<Self, TeleportError> {
+        match &config.sink {
+            Some(sink) => Sink::parse(sink).map_err(|e| TeleportError::Config(e.to_string())),
+            None => Ok(Sink::Mapping(config.mmap_name.clone())),
+        }
+    }
+}
+
+/// Creates the telemetry mapping, or returns `None` while waiting to try again because
+/// another application holds it or the OS failed to create it, e.g. when it runs low on
+/// memory or handles. `retry` tracks when to try again and the last wait, the failures
+/// are counted in `stats`. Only a mapping this process may not create stops the target.
+fn create_telemetry<T: TelemetryProvider>(
+    names: &SharedMemoryNames,
+    size: usize,
+    force: bool,
+    retry: &mut Option<(Instant, Duration)>,
+    stats: &mut StatisticsPrinter,
+) -> Result<Option<T>, TeleportError> {
+    if retry.is_some_and(|(retry_at, _)| Instant::now() < retry_at) {
+        return Ok(None);
+    }
+
+    let result = if force {
+        T::create_or_reuse(names, size)
+    } else {
+        T::create(names, size)
+    };
+
+    match result {
+        Ok(telemetry) => {
+            info!(
+                "Memory-mapped file ({} bytes) and data-valid event created.",
+                size
+            );
+            *retry = None;
+            Ok(Some(telemetry))
+        }
+        Err(TelemetryError::AlreadyExists) => {
+            let backoff = backoff(
+                retry.map(|(_, backoff)| backoff),
+                CREATE_RETRY_MIN,
+                CREATE_RETRY_MAX,
+            );
+            warn!(
+                "The memory-mapped file {} already exists. Close the application holding it (often iRacing itself when it runs on this computer, or a target that didn't exit cleanly), or run with --force to write to it anyway. Retrying in {} seconds.",
+                names.mapping,
+                backoff.as_secs()
+            );
+            *retry = Some((Instant::now() + backoff, backoff));
+            Ok(None)
+        }
+        Err(TelemetryError::AccessDenied) => {
+            error!(
+                "Access denied creating the memory-mapped file {}. Names under Global\\ need the target to run as administrator, or pick another --mmap-name.",
+                names.mapping
+            );
+            Err(TeleportError::telemetry(
+                "create telemetry",
+                TelemetryError::AccessDenied,
+            ))
+        }
+        Err(e) => {
+            let backoff = backoff(
+                retry.map(|(_, backoff)| backoff),
+                CREATE_RETRY_MIN,
+                CREATE_RETRY_MAX,
+            );
+            warn!(
+                "Failed to create the memory-mapped file {}: {}. Retrying in {} seconds.",
+                names.mapping,
+                e,
+                backoff.as_secs()
+            );
+            stats.add_create_retry();
+            *retry = Some((Instant::now() + backoff, backoff));
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the mapping size to create for the size advertised by the source, or `None`
+/// if it is out of range
+fn mapping_size(advertised: u32, max_size: usize) -> Option<usize> {
+    match advertised as usize {
+        0 => Some(max_size), // Not advertised
+        size if size <= max_size => Some(size),
+        _ => None,
+    }
+}
+
+/// Returns why this target can't decode the stream a source describes in its hello, or
+/// `None` if it can. `codec` is the one of the target's --lz4-format and `dictionary_id`
+/// the id of its --dict.
+fn check_hello(
+    hello: &Hello,
+    max_size: usize,
+    codec: u8,
+    dictionary_id: Option<u32>,
+) -> Option<Rejection> {
+    if hello.version == 0 {
+        Some(Rejection::Version)
+    } else if hello.codec != codec {
+        Some(Rejection::Codec)
+    } else if hello.features & FEATURE_DICTIONARY != 0 && dictionary_id != Some(hello.dictionary_id)
+    {
+        Some(Rejection::Dictionary)
+    } else if mapping_size(hello.mapping_size, max_size).is_none() {
+        Some(Rejection::MappingSize)
+    } else {
+        None
+    }
+}
+
+/// Parses a multicast group to join, rejecting addresses outside of 224.0.0.0/4
+fn parse_group(group: &str) -> io::Result<Ipv4Addr> {
+    let group_ip: Ipv4Addr = group
+        .parse()
+        .map_err(|e| io::Error::other(format!("Invalid multicast group IP: {}", e)))?;
+
+    if !group_ip.is_multicast() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not a multicast group, use an address from 224.0.0.0 to 239.255.255.255",
+                group_ip
+            ),
+        ));
+    }
+
+    Ok(group_ip)
+}
+
+/// Explains why data sent to a valid multicast group might still not arrive
+fn group_warning(group_ip: Ipv4Addr) -> Option<&'static str> {
+    // 224.0.0.0/24 is link-local and used by protocols like mDNS and routing
+    let [a, b, c, _] = group_ip.octets();
+    (a == 224 && b == 0 && c == 0).then_some(
+        "is reserved for local network control traffic and is never forwarded by routers, \
+         consider a group like 239.255.0.1",
+    )
+}
+
+pub fn setup_multicast(socket: &UdpSocket, bind: &str, group: &str) -> io::Result<()> {
+    let group_ip = parse_group(group)?;
+    if let Some(warning) = group_warning(group_ip) {
+        warn!("Multicast group {} {}", group_ip, warning);
+    }
+
+    join_group(socket, group_ip, multicast_interface(bind)?)
+}
+
+/// Joins the multicast group on the interface with address `interface`
+fn join_group(socket: &UdpSocket, group: Ipv4Addr, interface: Ipv4Addr) -> io::Result<()> {
+    socket
+        .join_multicast_v4(&group, &interface)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to join multicast group: {}", e)))?;
+
+    info!("Joined multicast group: {}", group);
+    Ok(())
+}
+
+/// Returns the address of the interface to join the group on, any interface unless the
+/// socket is bound to one
+fn multicast_interface(bind: &str) -> io::Result<Ipv4Addr> {
+    match bind.parse::<SocketAddr>() {
+        Ok(addr) => match addr.ip() {
+            IpAddr::V4(ipv4) => Ok(ipv4),
+            _ => Err(io::Error::other("Only IPv4 is supported for multicast")),
+        },
+        Err(_) => Ok(Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+/// Joins the multicast group on each candidate interface in turn for up to `probe`, and
+/// stays joined on the first one data arrives on. Returns its address.
+fn autoselect_interface(socket: &UdpSocket, group: &str, probe: Duration) -> io::Result<Ipv4Addr> {
+    let group_ip = parse_group(group)?;
+    if let Some(warning) = group_warning(group_ip) {
+        warn!("Multicast group {} {}", group_ip, warning);
+    }
+
+    let interfaces = interfaces::list();
+    let candidates = interfaces::multicast_candidates(&interfaces);
+    let (name, ip) = probe_interfaces(&candidates, group_ip, probe, |ip| {
+        probe_interface(socket, group_ip, ip, probe)
+    })?;
+    // The probes left the socket blocking until data arrives
+    socket.set_read_timeout(None)?;
+
+    info!(
+        "Interface autoselect: {} ({}) receives multicast group {}",
+        name, ip, group_ip
+    );
+    Ok(ip)
+}
+
+/// Returns the first of `candidates` that `probe` finds data arriving on, or an error
+/// listing them if there is none
+fn probe_interfaces<'i>(
+    candidates: &[(&'i str, Ipv4Addr)],
+    group: Ipv4Addr,
+    duration: Duration,
+    mut probe: impl FnMut(Ipv4Addr) -> io::Result<bool>,
+) -> io::Result<(&'i str, Ipv4Addr)> {
+    for &(name, ip) in candidates {
+        info!(
+            "Probing interface {} ({}) for {} ms",
+            name,
+            ip,
+            duration.as_millis()
+        );
+        match probe(ip) {
+            Ok(true) => return Ok((name, ip)),
+            Ok(false) => debug!("No data on interface {} ({})", name, ip),
+            Err(e) => warn!("Failed to probe interface {} ({}): {}", name, ip, e),
+        }
+    }
+
+    let tried: Vec<String> = candidates
+        .iter()
+        .map(|(name, ip)| format!("{} ({})", name, ip))
+        .collect();
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        if tried.is_empty() {
+            "No interface is up and supports multicast, run `iracing-teleport interfaces` \
+             to list them"
+                .to_string()
+        } else {
+            format!(
+                "No data for multicast group {} arrived within {} ms on any interface, \
+                 tried {}. Check that the source is running and sending to this group.",
+                group,
+                duration.as_millis(),
+                tried.join(", ")
+            )
+        },
+    ))
+}
+
+/// Joins the group on the interface with address `ip` and waits up to `duration` for a
+/// datagram. Leaves the group again unless one arrived.
+fn probe_interface(
+    socket: &UdpSocket,
+    group: Ipv4Addr,
+    ip: Ipv4Addr,
+    duration: Duration,
+) -> io::Result<bool> {
+    let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    // Whatever arrived through an interface probed before doesn't count
+    socket.set_nonblocking(true)?;
+    while socket.recv(&mut datagram).is_ok() {}
+    socket.set_nonblocking(false)?;
+
+    socket.join_multicast_v4(&group, &ip)?;
+    let deadline = Instant::now() + duration;
+    let received = loop {
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+            break false;
+        };
+        socket.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        match socket.recv(&mut datagram) {
+            Ok(_) => break true,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            // Errors like ICMP port unreachable reports don't mean anything here
+            Err(e) => debug!("Receive error while probing {}: {}", ip, e),
+        }
+    };
+
+    if !received {
+        let _ = socket.leave_multicast_v4(&group, &ip);
+    }
+    Ok(received)
+}
+
+/// Rejoins the multicast group every --rejoin seconds while no datagrams arrive. Switches
+/// with IGMP snooping forget a membership nobody renewed, and without a querier on the
+/// network nothing asks the kernel to renew it, so the target goes quiet after a pause.
+/// Leaving and joining again makes the kernel report the membership right away.
+struct GroupKeepalive {
+    group: Ipv4Addr,
+    interface: Ipv4Addr,
+    interval: Duration,
+    last_activity: Instant,
+}
+
+impl GroupKeepalive {
+    /// Keeps the membership on `interface` fresh, the one the group was joined on or
+    /// `None` when receiving unicast
+    fn new(config: &TargetConfig, interface: Option<Ipv4Addr>) -> io::Result<Option<Self>> {
+        let Some(interface) = interface.filter(|_| config.rejoin > 0) else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            group: parse_group(&config.group)?,
+            interface,
+            interval: Duration::from_secs(config.rejoin),
+            last_activity: Instant::now(),
+        }))
+    }
+
+    /// Notes that datagrams arrived, which keeps the membership fresh on its own
+    fn received(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Rejoins the group on `socket` if nothing arrived for the interval
+    fn check(&mut self, socket: SockRef) {
+        if self.last_activity.elapsed() < self.interval {
+            return;
+        }
+        self.last_activity = Instant::now();
+
+        // Best-effort, a failed rejoin is retried after the next interval
+        let _ = socket.leave_multicast_v4(&self.group, &self.interface);
+        match socket.join_multicast_v4(&self.group, &self.interface) {
+            Ok(()) => debug!(
+                "Rejoined multicast group {} after {} s without datagrams",
+                self.group,
+                self.interval.as_secs()
+            ),
+            Err(e) => warn!("Failed to rejoin multicast group {}: {}", self.group, e),
+        }
+    }
+}
+
+/// Asks the kernel to busy poll the device queue for the socket, on top of spinning in
+/// userspace. Needs CAP_NET_ADMIN, so failure is only reported.
+#[cfg(target_os = "linux")]
+fn enable_kernel_busy_poll(socket: &UdpSocket) {
+    use std::os::fd::AsRawFd;
+
+    // Microseconds to busy poll for in a blocking receive
+    let busy_poll_us: libc::c_int = 50;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BUSY_POLL,
+            &busy_poll_us as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if result != 0 {
+        warn!(
+            "Failed to enable SO_BUSY_POLL: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_kernel_busy_poll(_: &UdpSocket) {}
+
+/// Decompresses a payload in the given LZ4 format into `target` and returns its length,
+/// or copies it over as-is if the source sent it uncompressed
+fn decode(
+    payload: &[u8],
+    flags: u16,
+    format: Lz4Format,
+    dictionary: Option<&Dictionary>,
+    target: &mut [u8],
+) -> io::Result<usize> {
+    if flags & FLAG_UNCOMPRESSED == 0 {
+        return match (dictionary, format) {
+            (Some(dictionary), _) => dictionary.decompress_to_buffer(payload, target),
+            (None, Lz4Format::Block) => decompress_to_buffer(payload, None, target),
+            (None, Lz4Format::Frame) => codec::decompress_frame(payload, target),
+        };
+    }
+
+    let Some(target) = target.get_mut(..payload.len()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "uncompressed payload is larger than the buffer",
+        ));
+    };
+    target.copy_from_slice(payload);
+    Ok(payload.len())
+}
+
+/// Decompresses the session info, sent compressed in the given LZ4 format without a
+/// dictionary, into a buffer of its own
+fn decompress_session_info(
+    payload: &[u8],
+    format: Lz4Format,
+    max_size: usize,
+) -> io::Result<Vec<u8>> {
+    if format == Lz4Format::Block {
+        return decompress(payload, None);
+    }
+    let Some(size) = codec::frame_content_size(payload).filter(|&size| size <= max_size) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "session info frame has no valid content size",
+        ));
+    };
+    let mut data = vec![0u8; size];
+    let len = codec::decompress_frame(payload, &mut data)?;
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Returns the size `payload` decodes to, read from the size the source puts in front of
+/// compressed blocks or in the header of a frame, or `None` if it doesn't have one
+fn decoded_size(payload: &[u8], flags: u16, format: Lz4Format) -> Option<usize> {
+    if flags & FLAG_UNCOMPRESSED != 0 {
+        return Some(payload.len());
+    }
+    if format == Lz4Format::Frame {
+        return codec::frame_content_size(payload);
+    }
+    let prefix = payload.get(..4)?;
+    usize::try_from(i32::from_le_bytes(prefix.try_into().expect("4 bytes"))).ok()
+}
+
+/// Explains why an update that decodes to `size` bytes can't be written to a mapping of
+/// `mapping_len` bytes, or returns `None` if it fits. Updates without the session info
+/// are smaller than the mapping, full ones have to match it if the size was advertised.
+fn size_mismatch(
+    size: usize,
+    mapping_len: usize,
+    stripped: bool,
+    advertised: bool,
+) -> Option<String> {
+    if size > mapping_len {
+        return Some(format!(
+            "Received an update of {} bytes, larger than the {} bytes of the telemetry mapping. The source may run another version or a larger --max-size.",
+            size, mapping_len
+        ));
+    }
+    if !stripped && advertised && size != mapping_len {
+        return Some(format!(
+            "Received an update of {} bytes for a mapping of {} bytes.",
+            size, mapping_len
+        ));
+    }
+    None
+}
+
+/// Returns whether an update decoded to `len` bytes has the size expected of it. Only
+/// sources that advertise their mapping size tell what to expect.
+fn check_decoded_size(len: usize, expected: usize, advertised: bool) -> bool {
+    if advertised && len != expected {
+        warn!(
+            "Update decompressed to {} bytes instead of {}. Skipping this update.",
+            len, expected
+        );
+        return false;
+    }
+    true
+}
+
+/// Why an update wasn't written to the mapping
+#[derive(Debug, PartialEq)]
+enum Rejected {
+    /// It didn't decode, or not to the expected size
+    Corrupt,
+    /// It decoded to something other than what the source hashed with --verify
+    HashMismatch,
+}
+
+/// How to decode an update: its flags and --verify hash, the target's --lz4-format and
+/// the dictionary the source compressed it with
+#[derive(Clone, Copy)]
+struct Decoding<'d> {
+    flags: u16,
+    hash: Option<u64>,
+    format: Lz4Format,
+    dictionary: Option<&'d Dictionary>,
+}
+
+/// Decodes a payload into `staging` and returns its length, checking it against the hash
+/// if the source sent one
+fn decode_verified(
+    payload: &[u8],
+    decoding: Decoding,
+    staging: &mut [u8],
+) -> Result<usize, Rejected> {
+    let Decoding {
+        flags,
+        hash,
+        format,
+        dictionary,
+    } = decoding;
+    let len = match decode(payload, flags, format, dictionary, staging) {
+        Ok(len) => len,
+        Err(e) => {
+            warn!("LZ4 decompression failed: {}. Skipping this update.", e);
+            return Err(Rejected::Corrupt);
+        }
+    };
+
+    if hash.is_some_and(|hash| protocol::verify_hash(&staging[..len]) != hash) {
+        warn!(
+            "Update decompressed to data that doesn't match the source's hash. Skipping this update."
+        );
+        return Err(Rejected::HashMismatch);
+    }
+    Ok(len)
+}
+
+/// Decodes an update into `staging` and writes it to `mapping` once its size and hash
+/// check out, so a corrupt update never reaches the mapping
+fn try_decompress_data(
+    payload: &[u8],
+    decoding: Decoding,
+    staging: &mut [u8],
+    mapping: &mut [u8],
+    advertised: bool,
+) -> Result<(), Rejected> {
+    let len = decode_verified(payload, decoding, staging)?;
+
+    if !check_decoded_size(len, mapping.len(), advertised) {
+        return Err(Rejected::Corrupt);
+    }
+    let Some(mapping) = mapping.get_mut(..len) else {
+        warn!("Received update does not fit the telemetry mapping. Skipping this update.");
+        return Err(Rejected::Corrupt);
+    };
+    mapping.copy_from_slice(&staging[..len]);
+    Ok(())
+}
+
+/// Writes an update that was sent without the session info region, followed by the
+/// latest session info if it hasn't been written to this mapping yet
+fn write_without_session_info(
+    payload: &[u8],
+    decoding: Decoding,
+    staging: &mut [u8],
+    mapping: &mut [u8],
+    session_info: &[u8],
+    session_info_pending: &mut bool,
+    advertised: bool,
+) -> Result<(), Rejected> {
+    let len = decode_verified(payload, decoding, staging)?;
+
+    // Everything but the session info region is expected
+    let expected = irsdk::Header::parse(&staging[..len])
+        .and_then(|header| header.session_info_range(mapping.len()))
+        .map_or(0, |range| mapping.len() - range.len());
+    if !check_decoded_size(len, expected, advertised) {
+        return Err(Rejected::Corrupt);
+    }
+
+    let Some(range) = irsdk::restore_stripped(&staging[..len], mapping) else {
+        warn!("Received update does not fit the telemetry mapping. Skipping this update.");
+        return Err(Rejected::Corrupt);
+    };
+
+    if *session_info_pending {
+        let len = session_info.len().min(range.len());
+        mapping[range.start..range.start + len].copy_from_slice(&session_info[..len]);
+        *session_info_pending = false;
+    }
+
+    Ok(())
+}
+
+/// Writes an update that only holds the header and a --region of the mapping, leaving
+/// the rest of the mapping as the last whole update wrote it
+fn write_region(
+    payload: &[u8],
+    decoding: Decoding,
+    staging: &mut [u8],
+    mapping: &mut [u8],
+) -> Result<(), Rejected> {
+    let len = decode_verified(payload, decoding, staging)?;
+    if irsdk::restore_region(&staging[..len], mapping).is_none() {
+        warn!("Received region does not fit the telemetry mapping. Skipping this update.");
+        return Err(Rejected::Corrupt);
+    }
+    Ok(())
+}
+
+/// The payload of `received` as --dump-frames writes it
+fn dumped(received: &Received) -> DumpedFrame<'_> {
+    DumpedFrame {
+        flags: received.flags,
+        source_id: received.source_id,
+        mapping_size: received.mapping_size,
+        fragments: received.fragments,
+        payload: &received.payload,
+    }
+}
+
+/// A complete payload on its way from the receive thread to the writer, along with what
+/// the receive side learned since the last one
+struct Received {
+    payload: Vec<u8>,
+    source: SocketAddr,
+    source_id: u32,
+    flags: u16,
+    fragments: u16,
+    mapping_size: u32,
+    source_time_us: u64,
+    // When the first fragment of the sequence and the last one needed arrived
+    started: Option<Instant>,
+    reassembled: Instant,
+    lost: u32,
+    overrun: u32,
+    network_delay: Option<ClockEstimate>,
+}
+
+impl Received {
+    fn is_update(&self) -> bool {
+        self.flags & FLAG_HEARTBEAT == 0 && !protocol::is_session_info(self.flags)
+    }
+
+    /// Returns how long ago the source read the update: its processing time, the network
+    /// delay once probes measured it, and the time since the first fragment arrived
+    fn age(&self, now: Instant) -> Option<Duration> {
+        let network_us = self
+            .network_delay
+            .map_or(0, |estimate| estimate.delay_us.max(0.0) as u64);
+        let started = self.started?;
+        Some(
+            Duration::from_micros(self.source_time_us + network_us)
+                + now.saturating_duration_since(started),
+        )
+    }
+}
+
+/// Collects the gaps of lost sequences for a debug report at most every
+/// GAP_LOG_INTERVAL, rather than a line per gap at 60 Hz
+struct GapLog {
+    last_report: Instant,
+    // The first lost sequence and how many were lost from there, up to GAP_LOG_LIST
+    gaps: Vec<(u32, u32)>,
+    count: u32,
+    lost: u32,
+}
+
+impl GapLog {
+    fn new() -> Self {
+        Self {
+            last_report: Instant::now(),
+            gaps: Vec::new(),
+            count: 0,
+            lost: 0,
+        }
+    }
+
+    /// Records the `lost` sequences before `sequence`, and returns the report once the
+    /// interval since the last one is up
+    fn record(&mut self, sequence: u32, lost: u32, now: Instant) -> Option<String> {
+        if lost == 0 {
+            return None;
+        }
+        self.count += 1;
+        self.lost += lost;
+        if self.gaps.len() < GAP_LOG_LIST {
+            self.gaps.push((sequence.wrapping_sub(lost), lost));
+        }
+        if now.duration_since(self.last_report) < GAP_LOG_INTERVAL {
+            return None;
+        }
+
+        let listed = self
+            .gaps
+            .iter()
+            .map(|&(first, lost)| match lost {
+                1 => first.to_string(),
+                _ => format!("{}-{}", first, first.wrapping_add(lost - 1)),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let more = self.count as usize - self.gaps.len();
+        let report = format!(
+            "Lost {} sequences in {} gaps: {}{}",
+            self.lost,
+            self.count,
+            listed,
+            if more > 0 {
+                format!(" and {} more gaps", more)
+            } else {
+                String::new()
+            }
+        );
+        self.gaps.clear();
+        self.count = 0;
+        self.lost = 0;
+        self.last_report = now;
+        Some(report)
+    }
+}
+
+/// Stops the target with --startup-timeout when no update completes in time after
+/// startup, telling a source that never got through from one that stopped or whose
+/// updates don't complete
+struct StartupWatchdog {
+    timeout: Duration,
+    started: Instant,
+    // Where the source has to send to, for the message
+    destination: String,
+    datagrams: u64,
+    update_datagrams: u64,
+    last_datagram: Option<Instant>,
+}
+
+impl StartupWatchdog {
+    fn new(config: &TargetConfig) -> Option<Self> {
+        if config.startup_timeout == 0 {
+            return None;
+        }
+        let destination = if config.unicast {
+            config.bind.clone()
+        } else {
+            let port = config.bind.rsplit_once(':').map_or("", |(_, port)| port);
+            format!("{}:{}", config.group, port)
+        };
+        Some(Self {
+            timeout: Duration::from_secs(config.startup_timeout),
+            started: Instant::now(),
+            destination,
+            datagrams: 0,
+            update_datagrams: 0,
+            last_datagram: None,
+        })
+    }
+
+    /// Counts a datagram from the source, `update` if it is part of an update rather
+    /// than a hello or heartbeat
+    fn datagram(&mut self, update: bool, now: Instant) {
+        self.datagrams += 1;
+        self.update_datagrams += update as u64;
+        self.last_datagram = Some(now);
+    }
+
+    /// Returns why the target is giving up once the timeout is up
+    fn expired(&self, now: Instant) -> Option<String> {
+        if now.saturating_duration_since(self.started) < self.timeout {
+            return None;
+        }
+
+        let timeout = self.timeout.as_secs();
+        let Some(last_datagram) = self.last_datagram else {
+            return Some(format!(
+                "Nothing arrived within --startup-timeout {} s. Check that the source is \
+                 running and sending to {}, and that no firewall blocks the UDP port.",
+                timeout, self.destination
+            ));
+        };
+
+        let quiet = now.saturating_duration_since(last_datagram).as_secs_f64();
+        if self.update_datagrams == 0 {
+            return Some(format!(
+                "Only hellos and heartbeats arrived within --startup-timeout {} s, the last \
+                 {:.0} s ago. The source is running but has no telemetry to send, check that \
+                 the sim is running on its computer.",
+                timeout, quiet
+            ));
+        }
+        Some(format!(
+            "{} datagrams arrived within --startup-timeout {} s, the last {:.0} s ago, but \
+             no update completed. {}",
+            self.datagrams,
+            timeout,
+            quiet,
+            if quiet >= STUCK_TIMEOUT.as_secs_f64() {
+                "The source stopped sending, or the network stopped passing its datagrams."
+            } else {
+                "Their fragments get lost on the way or they can't be decoded, see the \
+                 warnings above."
+            }
+        ))
+    }
+}
+
+/// Reassembles datagrams into complete payloads, and prepares the probes and loss
+/// reports to send back to the source
+struct Reassembler {
+    protocol_receiver: ProtocolReceiver,
+    sequence_start_time: Option<Instant>,
+    clock_sync: ClockSync,
+    network_delay: Option<ClockEstimate>,
+    probe: Option<[u8; HEADER_SIZE]>,
+    report_interval: Option<Duration>,
+    last_report: Instant,
+    received_since_report: u32,
+    lost_since_report: u32,
+    loss_report: Option<[u8; LOSS_REPORT_SIZE]>,
+    source_stats: SourceStatistics,
+    dropper: Option<FaultInjector>,
+    filter_id: Option<u32>,
+    max_size: usize,
+    codec: u8,
+    dictionary_id: Option<u32>,
+    unicast: bool,
+    last_hello: Option<Hello>,
+    hello_reply: Option<[u8; HELLO_REPLY_SIZE]>,
+    // Sequence of the newest update passed on, with --reassembly-depth older ones can
+    // complete after it
+    newest_update: Option<u32>,
+    last_datagram: Instant,
+    // The sequence last reported as stuck, so it is reported once
+    reported_stuck: Option<u32>,
+    gap_log: GapLog,
+    // Dropped once the first update completes
+    startup: Option<StartupWatchdog>,
+}
+
+impl Reassembler {
+    fn new(config: &TargetConfig) -> Self {
+        let mut protocol_receiver =
+            ProtocolReceiver::new(protocol::max_payload_size(config.max_size));
+        if let Some(source_id) = config.filter_id {
+            protocol_receiver.filter_source(source_id);
+            info!("Receiving from source id {}", source_id);
+        }
+        if config.reassembly_depth > 1 {
+            info!(
+                "Reassembling up to {} updates at a time",
+                config.reassembly_depth
+            );
+            protocol_receiver.set_depth(config.reassembly_depth);
+        }
+        let dropper = FaultInjector::new(config.drop_rate, 0.0, config.drop_seed);
+        if dropper.is_some() {
+            warn!(
+                "Dropping {:.1}% of the datagrams received on purpose (--drop-rate)",
+                config.drop_rate * 100.0
+            );
+        }
+        // Only compared with the source's hello, the writer reports a --dict it can't load
+        let dictionary_id = config
+            .dict
+            .as_deref()
+            .and_then(|path| Dictionary::load(path).ok())
+            .map(|dictionary| dictionary.id());
+
+        Self {
+            protocol_receiver,
+            sequence_start_time: None,
+            clock_sync: ClockSync::default(),
+            network_delay: None,
+            probe: None,
+            report_interval: (config.report_ms > 0)
+                .then(|| Duration::from_millis(config.report_ms)),
+            last_report: Instant::now(),
+            received_since_report: 0,
+            lost_since_report: 0,
+            loss_report: None,
+            source_stats: SourceStatistics::new("target"),
+            dropper,
+            filter_id: config.filter_id,
+            max_size: config.max_size,
+            codec: config.lz4_format.codec(),
+            dictionary_id,
+            unicast: config.unicast,
+            last_hello: None,
+            hello_reply: None,
+            newest_update: None,
+            last_datagram: Instant::now(),
+            reported_stuck: None,
+            gap_log: GapLog::new(),
+            startup: StartupWatchdog::new(config),
+        }
+    }
+
+    /// Processes a datagram from `source`, returning the payload it completes copied into
+    /// the buffer from `buffer`
+    fn process_datagram(
+        &mut self,
+        datagram: &[u8],
+        source: SocketAddr,
+        buffer: impl FnOnce() -> Vec<u8>,
+    ) -> Option<Received> {
+        if self
+            .dropper
+            .as_mut()
+            .is_some_and(|dropper| dropper.drop_next())
+        {
+            return None;
+        }
+
+        if let Some(times) = protocol::parse_probe_reply(datagram) {
+            let received_us = protocol::wall_clock_us();
+            if let Some(estimate) = self.clock_sync.add_reply(&times, received_us) {
+                self.network_delay = Some(estimate);
+            }
+            return None;
+        }
+
+        if let Some(hello) = protocol::parse_hello(datagram) {
+            if let Some(startup) = &mut self.startup {
+                startup.datagram(false, Instant::now());
+            }
+            self.hello(hello);
+            return None;
+        }
+        self.last_datagram = Instant::now();
+
+        if let Some(startup) = &mut self.startup {
+            let update = protocol::datagram_info(datagram)
+                .is_some_and(|info| info.flags & FLAG_HEARTBEAT == 0);
+            startup.datagram(update, self.last_datagram);
+        }
+
+        // Every source on the group is counted, not only the one received from
+        if let Some(info) = protocol::datagram_info(datagram) {
+            self.source_stats.add_datagram(&info);
+        }
+        if self.source_stats.should_print() {
+            self.source_stats
+                .print_and_reset(self.protocol_receiver.source_id());
+        }
+
+        let (frame, sequence_changed) = self.protocol_receiver.process_datagram(datagram);
+
+        if sequence_changed {
+            self.sequence_start_time = Some(Instant::now());
+        }
+
+        let frame = frame?;
+        let reassembled = Instant::now();
+        let sequence = frame.sequence;
+
+        // Only the latest telemetry is written, the session info is taken whenever it
+        // completes. Further steps back are a restarted source.
+        if frame.flags & FLAG_HEARTBEAT == 0 && !protocol::is_session_info(frame.flags) {
+            if self.newest_update.is_some_and(|newest| {
+                (1..=MAX_REASSEMBLY_DEPTH as u32).contains(&newest.wrapping_sub(frame.sequence))
+            }) {
+                debug!(
+                    "Skipping update {}, a newer one completed first",
+                    frame.sequence
+                );
+                return None;
+            }
+            self.newest_update = Some(frame.sequence);
+        }
+
+        // Copied out, the receiver reuses its buffer for the next sequence
+        let mut payload = buffer();
+        payload.clear();
+        payload.extend_from_slice(frame.payload);
+        let mut received = Received {
+            payload,
+            source,
+            source_id: frame.source_id,
+            flags: frame.flags,
+            fragments: frame.fragments,
+            mapping_size: frame.mapping_size,
+            source_time_us: frame.source_time_us,
+            started: self.sequence_start_time.take(),
+            reassembled,
+            lost: 0,
+            overrun: 0,
+            network_delay: self.network_delay.take(),
+        };
+
+        // Keep measuring the network delay, the source answers these right away
+        self.probe = self.clock_sync.probe(received.source_id);
+
+        // Lost sequences are counted against the next update
+        if received.is_update() {
+            self.startup = None;
+            received.lost = self.protocol_receiver.take_lost();
+            self.count_for_report(received.source_id, received.lost, sequence, reassembled);
+            if log_enabled!(Level::Debug)
+                && let Some(report) = self.gap_log.record(sequence, received.lost, reassembled)
+            {
+                debug!("{}", report);
+            }
+        }
+
+        Some(received)
+    }
+
+    /// Checks the stream a source describes against this target, logging the outcome when
+    /// the description changes, and prepares the answer when receiving unicast
+    fn hello(&mut self, hello: Hello) {
+        if self.filter_id.is_some_and(|id| id != hello.source_id) {
+            return;
+        }
+
+        let rejection = check_hello(&hello, self.max_size, self.codec, self.dictionary_id);
+        if self.last_hello.as_ref() != Some(&hello) {
+            match rejection {
+                Some(rejection) => warn!(
+                    "Source {} (protocol version {}) sends a stream this target can't decode, because {}",
+                    hello.source_id, hello.version, rejection
+                ),
+                None => info!(
+                    "Source {} (protocol version {}) sends a {} byte mapping{}{}",
+                    hello.source_id,
+                    hello.version,
+                    hello.mapping_size,
+                    if hello.features & FEATURE_VERIFY != 0 {
+                        ", verified"
+                    } else {
+                        ""
+                    },
+                    if hello.features & FEATURE_DICTIONARY != 0 {
+                        format!(
+                            ", compressed with the dictionary {:08x}",
+                            hello.dictionary_id
+                        )
+                    } else {
+                        String::new()
+                    }
+                ),
+            }
+        }
+
+        // Targets on a group stay quiet, the source repeats its hello for them instead
+        if self.unicast {
+            self.hello_reply = Some(protocol::hello_reply(&HelloReply {
+                source_id: hello.source_id,
+                version: PROTOCOL_VERSION,
+                rejection,
+            }));
+        }
+        self.last_hello = Some(hello);
+    }
+
+    /// Counts a received update along with the ones lost before it, and prepares a loss
+    /// report for the source once the interval is up. The report echoes the update, which
+    /// completed at `reassembled`, for the source to time its round trip.
+    fn count_for_report(&mut self, source_id: u32, lost: u32, sequence: u32, reassembled: Instant) {
+        let Some(interval) = self.report_interval else {
+            return;
+        };
+
+        self.received_since_report += 1;
+        self.lost_since_report += lost;
+        if self.last_report.elapsed() < interval {
+            return;
+        }
+
+        self.loss_report = Some(protocol::loss_report(&LossReport {
+            source_id,
+            received: std::mem::take(&mut self.received_since_report),
+            lost: std::mem::take(&mut self.lost_since_report),
+            echo: Some(Echo {
+                sequence,
+                held_us: reassembled.elapsed().as_micros() as u64,
+            }),
+        }));
+        self.last_report = Instant::now();
+    }
+
+    /// Returns a probe to send back to the source of the last datagram, if one is due
+    fn take_probe(&mut self) -> Option<[u8; HEADER_SIZE]> {
+        self.probe.take()
+    }
+
+    /// Returns the answer to the hello in the last datagram, if it was one
+    fn take_hello_reply(&mut self) -> Option<[u8; HELLO_REPLY_SIZE]> {
+        self.hello_reply.take()
+    }
+
+    /// Returns a loss report to send back to the source of the last datagram, if one is due
+    fn take_loss_report(&mut self) -> Option<[u8; LOSS_REPORT_SIZE]> {
+        self.loss_report.take()
+    }
+
+    /// Fails once --startup-timeout is up without a complete update
+    fn check_startup(&self) -> Result<(), TeleportError> {
+        match self
+            .startup
+            .as_ref()
+            .and_then(|startup| startup.expired(Instant::now()))
+        {
+            Some(message) => Err(TeleportError::NoData(message)),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs the progress of an update that stopped getting fragments halfway, e.g. to
+    /// tell a source that stopped mid-update from fragments the network keeps dropping
+    fn check_stuck(&mut self) {
+        let Some(sequence) = self.protocol_receiver.current_sequence() else {
+            return;
+        };
+        let quiet = self.last_datagram.elapsed();
+        if quiet < STUCK_TIMEOUT || self.reported_stuck == Some(sequence) {
+            return;
+        }
+
+        info!(
+            "Waiting on {}/{} fragments of sequence {}, nothing arrived for {:.1} s",
+            self.protocol_receiver.received_fragments(),
+            self.protocol_receiver.total_fragments(),
+            sequence,
+            quiet.as_secs_f64()
+        );
+        self.reported_stuck = Some(sequence);
+    }
+
+    /// Allows another source to take over after the telemetry was closed
+    fn release_source(&mut self) {
+        self.protocol_receiver.release_source();
+        self.newest_update = None;
+    }
+}
+
+/// Writes complete payloads to the telemetry mapping, which is created on the first
+/// update and closed again once the source goes quiet, or with --precreate created at
+/// startup and zeroed again once the source goes quiet
+struct Writer<T> {
+    names: SharedMemoryNames,
+    telemetry: Option<T>,
+    last_update: Instant,
+    idle_timeout: Option<Duration>,
+    max_age: Option<Duration>,
+    stats: StatisticsPrinter,
+    staging_buf: Vec<u8>,
+    session_info: Vec<u8>,
+    session_info_pending: bool,
+    keyframe_request: Option<(u32, SocketAddr)>,
+    last_keyframe_request: Option<Instant>,
+    released: bool,
+    max_size: usize,
+    rejected_size: Option<u32>,
+    size_mismatch_reported: bool,
+    lz4_format: Lz4Format,
+    dictionary: Option<Dictionary>,
+    rejected_dictionary: Option<u32>,
+    force: bool,
+    precreate: bool,
+    precreated: bool,
+    create_retry: Option<(Instant, Duration)>,
+    count: Option<u64>,
+    written: u64,
+    exporter: Option<JsonExporter>,
+    udp_export: Option<UdpExport>,
+    ws_broadcast: Option<WsBroadcast>,
+    interpolator: Option<Interpolator>,
+    dump: Option<FrameDump>,
+}
+
+impl<T: TelemetryProvider> Writer<T> {
+    fn new(config: &TargetConfig) -> Result<Self, TeleportError> {
+        let dictionary = config
+            .dict
+            .as_deref()
+            .map(|path| {
+                let dictionary =
+                    Dictionary::load(path).map_err(|e| TeleportError::Config(e.to_string()))?;
+                info!(
+                    "Decompressing with the dictionary {} (id {:08x})",
+                    path.display(),
+                    dictionary.id()
+                );
+                Ok::<_, TeleportError>(dictionary)
+            })
+            .transpose()?;
+        let udp_export = config
+            .export_json
+            .as_deref()
+            .map(UdpExport::new)
+            .transpose()?;
+        let ws_broadcast = config.ws.as_deref().map(WsBroadcast::start).transpose()?;
+        let mut stats = StatisticsPrinter::new("target");
+        if let Some(addr) = &config.stats_export {
+            stats.set_export(StatsExport::new(addr)?);
+        }
+        // Updates are expected within --idle-timeout, while the telemetry stays open
+        let window = match config.idle_timeout {
+            0 => DEFAULT_HEALTH_WINDOW,
+            secs => Duration::from_secs(secs),
+        };
+        if let Some(addr) = &config.health_addr {
+            stats.set_health(HealthCheck::start(addr, window)?);
+        }
+        if let Some(addr) = &config.api_addr {
+            stats.set_api(StatusApi::start(addr, "target", config, window)?);
+        }
+        if config.status_line {
+            stats.set_status_line();
+        }
+        let exporter = (udp_export.is_some() || ws_broadcast.is_some()).then(|| {
+            info!("Exporting variables: {}", config.vars.join(", "));
+            JsonExporter::new(config.vars.clone())
+        });
+        if config.interpolate {
+            info!("Interpolating the ticks in between updates");
+        }
+
+        let (mapping, pipe) = match Sink::from_config(config)? {
+            Sink::Mapping(name) => (name, false),
+            Sink::Pipe(name) => (name, true),
+        };
+        let names = SharedMemoryNames {
+            mapping,
+            event: config.event_name.clone(),
+        };
+        // A guardrail only, some setups share the names with a local sim on purpose
+        if let Some(warning) = telemetry::conflict_warning(&names).filter(|_| !pipe) {
+            warn!("{}", warning);
+        }
+
+        let mut writer = Self {
+            names,
+            telemetry: None,
+            last_update: Instant::now(),
+            idle_timeout: (config.idle_timeout > 0)
+                .then(|| Duration::from_secs(config.idle_timeout)),
+            max_age: (config.max_age > 0).then(|| Duration::from_millis(config.max_age)),
+            stats,
+            staging_buf: vec![0u8; config.max_size],
+            session_info: Vec::new(),
+            session_info_pending: false,
+            keyframe_request: None,
+            last_keyframe_request: None,
+            released: false,
+            max_size: config.max_size,
+            rejected_size: None,
+            size_mismatch_reported: false,
+            lz4_format: config.lz4_format,
+            dictionary,
+            rejected_dictionary: None,
+            force: config.force,
+            precreate: config.precreate,
+            precreated: false,
+            create_retry: None,
+            count: config.count,
+            written: 0,
+            exporter,
+            udp_export,
+            ws_broadcast,
+            interpolator: config.interpolate.then(Interpolator::new),
+            dump: (config.dump_frames > 0)
+                .then(|| FrameDump::new(Path::new(DUMP_DIR), config.dump_frames))
+                .transpose()?,
+        };
+        writer.precreate()?;
+        Ok(writer)
+    }
+
+    /// Creates the telemetry ahead of the first update with --precreate, at --max-size so
+    /// that any source fits in it
+    fn precreate(&mut self) -> Result<(), TeleportError> {
+        if !self.precreate || self.telemetry.is_some() {
+            return Ok(());
+        }
+        let Some(mut telemetry) = create_telemetry::<T>(
+            &self.names,
+            self.max_size,
+            self.force,
+            &mut self.create_retry,
+            &mut self.stats,
+        )?
+        else {
+            return Ok(());
+        };
+        // With --force it may be an existing mapping, don't show what it held
+        telemetry.as_slice_mut().fill(0);
+        info!("Waiting for the first update to signal data (--precreate)");
+        self.telemetry = Some(telemetry);
+        self.precreated = true;
+        Ok(())
+    }
+
+    /// Closes the telemetry, or with --precreate zeroes it so applications see the sim as
+    /// disconnected while the mapping stays around for the next source. Returns whether
+    /// there was data to close.
+    fn close(&mut self) -> bool {
+        if self.precreated {
+            return false;
+        }
+        if !self.precreate {
+            return self.telemetry.take().is_some();
+        }
+        let Some(telemetry) = &mut self.telemetry else {
+            return false;
+        };
+        telemetry.as_slice_mut().fill(0);
+        self.precreated = true;
+        true
+    }
+
+    /// Writes a complete payload to the telemetry, or keeps it for later if it is the
+    /// session info
+    fn write(&mut self, received: &Received) -> Result<(), TeleportError> {
+        let write_start = Instant::now();
+
+        self.stats.add_lost(received.lost);
+        self.stats.add_overrun(received.overrun);
+        if let Some(estimate) = received.network_delay {
+            self.stats
+                .set_network_delay(estimate.delay_us, estimate.offset_us);
+        }
+
+        // The source shut down, close the telemetry now rather than at --idle-timeout so
+        // applications don't keep reading its last update
+        if protocol::is_goodbye(received.flags) {
+            if self.close() {
+                info!(
+                    "Source {} is shutting down, closing telemetry",
+                    received.source
+                );
+                self.released = true;
+            }
+            return Ok(());
+        }
+
+        // The source is alive but has no new data, keep the telemetry open
+        if received.flags & FLAG_HEARTBEAT != 0 {
+            self.last_update = Instant::now();
+            self.stats.add_heartbeat();
+            return Ok(());
+        }
+
+        // Too late to be of use, the telemetry is better off showing the last update. The
+        // source is still alive though, so keep the telemetry open.
+        if received.is_update()
+            && self
+                .max_age
+                .is_some_and(|max_age| received.age(write_start).is_some_and(|age| age > max_age))
+        {
+            self.last_update = Instant::now();
+            self.stats.add_stale();
+            if self.stats.should_print() {
+                self.stats.print_and_reset();
+            }
+            return Ok(());
+        }
+
+        // A source running with --verify appends the hash of what the payload decodes to
+        let Some((payload, hash)) = protocol::split_verify_hash(&received.payload, received.flags)
+        else {
+            self.stats.add_corrupt();
+            return Ok(());
+        };
+        // Followed by the id of the dictionary the source compressed it with, if any
+        let Some((payload, dictionary_id)) = protocol::split_dictionary_id(payload, received.flags)
+        else {
+            self.stats.add_corrupt();
+            return Ok(());
+        };
+        let dictionary = match dictionary_id {
+            None => None,
+            Some(id) => match &self.dictionary {
+                Some(dictionary) if dictionary.id() == id => Some(dictionary),
+                dictionary => {
+                    if self.rejected_dictionary != Some(id) {
+                        let ours = match dictionary {
+                            Some(dictionary) => format!("this target has {:08x}", dictionary.id()),
+                            None => "this target has no --dict".to_string(),
+                        };
+                        warn!(
+                            "Source compresses with the dictionary {:08x}, but {}. Skipping its updates, give both the same --dict.",
+                            id, ours
+                        );
+                        self.rejected_dictionary = Some(id);
+                    }
+                    return Ok(());
+                }
+            },
+        };
+
+        // Session info is only sent when it changes, keep it around until it can be
+        // written along with the next update
+        if protocol::is_session_info(received.flags) {
+            let session_info = if received.flags & FLAG_UNCOMPRESSED != 0 {
+                Ok(payload.to_vec())
+            } else {
+                decompress_session_info(payload, self.lz4_format, self.max_size)
+            };
+            if let Some(dump) = &mut self.dump {
+                let decoded = session_info.as_deref().ok();
+                dump.write(&dumped(received), Outcome::SessionInfo, decoded);
+            }
+            match session_info {
+                Ok(data) if hash.is_some_and(|hash| protocol::verify_hash(&data) != hash) => {
+                    warn!("Session info doesn't match the source's hash, waiting for the next");
+                    self.stats.add_hash_mismatch();
+                }
+                Ok(data) => {
+                    if hash.is_some() {
+                        self.stats.add_verified();
+                    }
+                    self.session_info = data;
+                    self.session_info_pending = true;
+                }
+                Err(e) => {
+                    warn!("LZ4 decompression of session info failed: {}", e);
+                }
+            }
+            self.stats.add_bytes(received.payload.len());
+            self.stats.add_fragments(received.fragments);
+            return Ok(());
+        }
+
+        // Match the mapping to the size of the source's mapping
+        let Some(size) = mapping_size(received.mapping_size, self.max_size) else {
+            if self.rejected_size != Some(received.mapping_size) {
+                warn!(
+                    "Source advertised a mapping size of {} bytes, more than the {} bytes allowed by --max-size. Skipping its updates.",
+                    received.mapping_size, self.max_size
+                );
+                self.rejected_size = Some(received.mapping_size);
+            }
+            return Ok(());
+        };
+
+        // A precreated mapping is at --max-size already, any source fits in it
+        if self
+            .telemetry
+            .as_ref()
+            .is_some_and(|t| t.size() < size || (!self.precreate && t.size() != size))
+        {
+            info!("Source mapping size changed, recreating telemetry");
+            // Close the old mapping first, it would be reused under the same name
+            self.telemetry = None;
+        }
+
+        // Create telemetry if it doesn't exist
+        if self.telemetry.is_none() {
+            let create_size = if self.precreate { self.max_size } else { size };
+            let Some(telemetry) = create_telemetry(
+                &self.names,
+                create_size,
+                self.force,
+                &mut self.create_retry,
+                &mut self.stats,
+            )?
+            else {
+                return Ok(());
+            };
+            self.telemetry = Some(telemetry);
+            self.session_info_pending = !self.session_info.is_empty();
+            self.size_mismatch_reported = false;
+            if let Some(interpolator) = &mut self.interpolator {
+                interpolator.reset();
+            }
+        }
+
+        // Joined after the source sent the session info, ask for it instead of waiting
+        if received.flags & FLAG_SESSION_INFO_OMITTED != 0
+            && self.session_info.is_empty()
+            && self
+                .last_keyframe_request
+                .is_none_or(|last| last.elapsed() >= KEYFRAME_REQUEST_INTERVAL)
+        {
+            if self.last_keyframe_request.is_none() {
+                info!("Session info not received yet, requesting a keyframe");
+            }
+            self.keyframe_request = Some((received.source_id, received.source));
+            self.last_keyframe_request = Some(Instant::now());
+        }
+
+        // Process the complete payload
+        let telemetry = self.telemetry.as_mut().unwrap();
+        let advertised = received.mapping_size != 0;
+
+        // Check the size before decompressing, reporting it once per mapping
+        let stripped = received.flags & FLAG_SESSION_INFO_OMITTED != 0;
+        if let Some(mismatch) = decoded_size(payload, received.flags, self.lz4_format)
+            .and_then(|decoded| size_mismatch(decoded, size, stripped, advertised))
+        {
+            if !self.size_mismatch_reported {
+                warn!("{} Skipping such updates.", mismatch);
+                self.size_mismatch_reported = true;
+            }
+            self.stats.add_size_mismatch();
+            return Ok(());
+        }
+
+        let decoding = Decoding {
+            flags: received.flags,
+            hash,
+            format: self.lz4_format,
+            dictionary,
+        };
+        let written = if protocol::is_region(received.flags) {
+            write_region(
+                payload,
+                decoding,
+                &mut self.staging_buf,
+                &mut telemetry.as_slice_mut()[..size],
+            )
+        } else if stripped {
+            write_without_session_info(
+                payload,
+                decoding,
+                &mut self.staging_buf,
+                &mut telemetry.as_slice_mut()[..size],
+                &self.session_info,
+                &mut self.session_info_pending,
+                advertised,
+            )
+        } else {
+            try_decompress_data(
+                payload,
+                decoding,
+                &mut self.staging_buf,
+                &mut telemetry.as_slice_mut()[..size],
+                advertised,
+            )
+        };
+
+        if let Some(dump) = &mut self.dump {
+            let (outcome, decoded) = match written {
+                Ok(()) => (Outcome::Written, Some(&telemetry.as_slice()[..size])),
+                Err(Rejected::Corrupt) => (Outcome::Corrupt, None),
+                Err(Rejected::HashMismatch) => (Outcome::HashMismatch, None),
+            };
+            dump.write(&dumped(received), outcome, decoded);
+        }
+
+        // Rather than signaling data that consumers would misread
+        match written {
+            Ok(()) if hash.is_some() => self.stats.add_verified(),
+            Ok(()) => {}
+            Err(Rejected::Corrupt) => {
+                self.stats.add_corrupt();
+                return Ok(());
+            }
+            Err(Rejected::HashMismatch) => {
+                self.stats.add_hash_mismatch();
+                return Ok(());
+            }
+        }
+
+        // Track total bytes and fragments for the complete message
+        self.stats.add_bytes(received.payload.len());
+        self.stats.add_fragments(received.fragments);
+
+        // Rewinds to the first of the ticks the update skipped, if it skipped any
+        if let Some(interpolator) = &mut self.interpolator {
+            interpolator.update(telemetry.as_slice_mut(), Instant::now());
+        }
+
+        telemetry
+            .signal_data_ready()
+            .map_err(|e| TeleportError::telemetry("signal data ready", e))?;
+        let signaled = Instant::now();
+        self.precreated = false;
+
+        // Export the selected variables alongside the telemetry mapping
+        if let Some(json) = self
+            .exporter
+            .as_mut()
+            .and_then(|exporter| exporter.encode(telemetry.as_slice()))
+        {
+            if let Some(udp_export) = &self.udp_export {
+                udp_export.send(&json);
+            }
+            if let Some(ws_broadcast) = &self.ws_broadcast {
+                ws_broadcast.send(&json);
+            }
+        }
+
+        // Calculate total latency (source processing + target processing), and how it
+        // splits between reassembly, the queue and writing
+        if let Some(started) = received.started {
+            let target_time = signaled.duration_since(started).as_micros() as u64;
+            self.stats
+                .add_latency(received.source_time_us + target_time);
+            self.stats.add_stage_times(
+                received.reassembled.duration_since(started).as_micros() as u64,
+                write_start.duration_since(received.reassembled).as_micros() as u64,
+                signaled.duration_since(write_start).as_micros() as u64,
+            );
+        }
+
+        self.last_update = Instant::now();
+        self.written += 1;
+        self.stats.add_update();
+
+        if self.stats.should_print() {
+            self.stats.print_and_reset();
+        }
+
+        Ok(())
+    }
+
+    /// Returns when the next frame in between updates is due with --interpolate
+    fn next_frame(&self) -> Option<Instant> {
+        self.telemetry.as_ref()?;
+        self.interpolator.as_ref()?.next_frame()
+    }
+
+    /// Writes the next frame in between updates if it is due
+    fn write_interpolated(&mut self) -> Result<(), TeleportError> {
+        let (Some(telemetry), Some(interpolator)) = (&mut self.telemetry, &mut self.interpolator)
+        else {
+            return Ok(());
+        };
+        if interpolator.write_due(telemetry.as_slice_mut(), Instant::now()) {
+            telemetry
+                .signal_data_ready()
+                .map_err(|e| TeleportError::telemetry("signal data ready", e))?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the number of updates given by `--count` has been written
+    fn finished(&self) -> bool {
+        let finished = self.count.is_some_and(|count| self.written >= count);
+        if finished {
+            info!("Wrote {} updates, shutting down...", self.written);
+        }
+        finished
+    }
+
+    /// Returns a keyframe request and the source to send it to, if the target is missing
+    /// the session info
+    fn take_keyframe_request(&mut self) -> Option<([u8; HEADER_SIZE], SocketAddr)> {
+        self.keyframe_request
+            .take()
+            .map(|(source_id, source)| (protocol::keyframe_request(source_id), source))
+    }
+
+    /// Returns whether the telemetry was closed since the last call, so the source it
+    /// came from should be released
+    fn take_released(&mut self) -> bool {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Closes the telemetry if the source has gone quiet for longer than --idle-timeout
+    /// and retries to create it with --precreate
+    fn check_timeout(&mut self) -> Result<(), TeleportError> {
+        self.stats.refresh_status();
+        if let Some(idle_timeout) = self.idle_timeout
+            && self.telemetry.is_some()
+            && self.last_update.elapsed() >= idle_timeout
+            && self.close()
+        {
+            info!(
+                "No updates received for {} seconds (--idle-timeout), closing telemetry",
+                idle_timeout.as_secs()
+            );
+
+            // Allow another source to take over
+            self.released = true;
+        }
+        self.precreate()
+    }
+}
+
+/// What the writer thread asks of the receive thread, which owns the socket and the
+/// reassembly
+enum Reply {
+    KeyframeRequest([u8; HEADER_SIZE], SocketAddr),
+    ReleaseSource,
+}
+
+struct QueueState {
+    updates: VecDeque<Received>,
+    free: Vec<Vec<u8>>,
+    closed: bool,
+}
+
+/// Bounded queue of complete payloads between the receive and the writer thread. When
+/// the writer falls behind, the oldest update is dropped so it catches up on the latest
+/// telemetry instead of writing stale data. Payload buffers are recycled through it.
+struct UpdateQueue {
+    capacity: usize,
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+impl UpdateQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(QueueState {
+                updates: VecDeque::with_capacity(capacity + 1),
+                free: Vec::new(),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns a buffer for the next payload, reusing one the writer is done with
+    fn buffer(&self) -> Vec<u8> {
+        self.lock().free.pop().unwrap_or_default()
+    }
+
+    /// Queues a payload, dropping the oldest update if the queue is full. Session info
+    /// and heartbeats are never dropped, the counts of a dropped update carry over.
+    fn push(&self, mut received: Received) {
+        let mut state = self.lock();
+        if state.updates.len() >= self.capacity
+            && let Some(index) = state.updates.iter().position(Received::is_update)
+        {
+            let dropped = state.updates.remove(index).expect("index in range");
+            received.lost += dropped.lost;
+            received.overrun += dropped.overrun + 1;
+            received.network_delay = received.network_delay.or(dropped.network_delay);
+            state.free.push(dropped.payload);
+        }
+        state.updates.push_back(received);
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    /// Waits up to `timeout` for the next payload. Returns `Err` once the queue is closed.
+    fn pop(&self, timeout: Duration) -> Result<Option<Received>, ()> {
+        let state = self.lock();
+        let (mut state, _) = self
+            .ready
+            .wait_timeout_while(state, timeout, |state| {
+                state.updates.is_empty() && !state.closed
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return Err(());
+        }
+        Ok(state.updates.pop_front())
+    }
+
+    /// Returns a payload buffer for reuse
+    fn recycle(&self, payload: Vec<u8>) {
+        self.lock().free.push(payload);
+    }
+
+    /// Stops both threads, waking up the writer if it is waiting
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+}
 
-const TELEMETRY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Holds complete updates for --jitter-buffer and releases them to the telemetry on a
+/// steady 60 Hz clock, so updates that arrive in bursts are still written at a regular
+/// cadence. Releasing starts once the buffer holds the configured delay's worth of
+/// updates, and waits for that again after the buffer ran empty. When updates come in
+/// faster than they are released, the oldest is dropped at twice that.
+struct JitterBuffer {
+    updates: VecDeque<Received>,
+    depth: usize,
+    capacity: usize,
+    // When the next update is due, None while filling up
+    next_release: Option<Instant>,
+}
 
-fn create_telemetry() -> io::Result<Telemetry> {
-    let telemetry = Telemetry::create(MAX_TELEMETRY_SIZE)
-        .map_err(|e| io::Error::other(format!("Failed to create telemetry: {}", e)))?;
-    println!("Memory-mapped file and data-valid event created.");
-    Ok(telemetry)
+/// What a due release of the jitter buffer came up with
+enum Release {
+    /// The next update, and the number of updates held before releasing it
+    Update(Received, usize),
+    /// The buffer ran empty and fills up again
+    Underrun,
 }
 
-fn setup_multicast(socket: &UdpSocket, bind: &str, group: &str) -> io::Result<()> {
-    let group_ip: Ipv4Addr = group
-        .parse()
-        .map_err(|e| io::Error::other(format!("Invalid multicast group IP: {}", e)))?;
+impl JitterBuffer {
+    fn new(delay: Duration) -> Self {
+        let depth = (delay.as_secs_f64() / JITTER_RELEASE_INTERVAL.as_secs_f64())
+            .round()
+            .max(1.0) as usize;
+        Self {
+            updates: VecDeque::with_capacity(depth * 2 + 1),
+            depth,
+            capacity: depth * 2,
+            next_release: None,
+        }
+    }
 
-    let local_ip = match bind.parse::<SocketAddr>() {
-        Ok(addr) => match addr.ip() {
-            IpAddr::V4(ipv4) => ipv4,
-            _ => return Err(io::Error::other("Only IPv4 is supported for multicast")),
-        },
-        Err(_) => Ipv4Addr::UNSPECIFIED,
+    /// Holds an update, returning the payload of the oldest one if the buffer overflowed.
+    /// The counts of a dropped update carry over to the next.
+    fn push(&mut self, received: Received, now: Instant) -> Option<Vec<u8>> {
+        self.updates.push_back(received);
+        let dropped = (self.updates.len() > self.capacity)
+            .then(|| self.updates.pop_front())
+            .flatten()
+            .map(|dropped| {
+                let next = self.updates.front_mut().expect("more than one update");
+                next.lost += dropped.lost;
+                next.overrun += dropped.overrun;
+                next.network_delay = next.network_delay.or(dropped.network_delay);
+                dropped.payload
+            });
+
+        if self.next_release.is_none() && self.updates.len() >= self.depth {
+            self.next_release = Some(now);
+        }
+        dropped
+    }
+
+    /// Returns when the next update is due, if releasing
+    fn next_release(&self) -> Option<Instant> {
+        self.next_release
+    }
+
+    /// Releases the next update if one is due
+    fn release(&mut self, now: Instant) -> Option<Release> {
+        let due = self.next_release.filter(|&due| due <= now)?;
+
+        let held = self.updates.len();
+        let Some(received) = self.updates.pop_front() else {
+            self.next_release = None;
+            return Some(Release::Underrun);
+        };
+
+        // Keeps the cadence, unless writing fell so far behind that it would catch up in
+        // a burst
+        let next = due + JITTER_RELEASE_INTERVAL;
+        self.next_release = Some(if next > now {
+            next
+        } else {
+            now + JITTER_RELEASE_INTERVAL
+        });
+        Some(Release::Update(received, held))
+    }
+
+    /// Drops the updates held, returning their payloads
+    fn clear(&mut self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.next_release = None;
+        self.updates.drain(..).map(|received| received.payload)
+    }
+}
+
+/// Writes the payloads the receive thread queues up until the queue is closed or --count
+/// updates have been written. Closes the queue when done, so the receive thread stops too.
+fn write_updates<T: TelemetryProvider>(
+    config: &TargetConfig,
+    queue: &UpdateQueue,
+    replies: mpsc::Sender<Reply>,
+) -> Result<(), TeleportError> {
+    let result = write_queued::<T>(config, queue, replies);
+    queue.close();
+    result
+}
+
+fn write_queued<T: TelemetryProvider>(
+    config: &TargetConfig,
+    queue: &UpdateQueue,
+    replies: mpsc::Sender<Reply>,
+) -> Result<(), TeleportError> {
+    let mut writer = Writer::<T>::new(config)?;
+    // The affinity is for the receive thread, which spins when busy polling
+    scheduling::apply(None, config.priority);
+
+    let mut jitter = jitter_buffer(config);
+
+    // Sending fails once the receive thread is gone, which only happens on the way out
+    loop {
+        // Wake up for the next frame in between updates with --interpolate, and the next
+        // release of the --jitter-buffer
+        let due = writer
+            .next_frame()
+            .into_iter()
+            .chain(jitter.as_ref().and_then(JitterBuffer::next_release))
+            .min();
+        let timeout = due.map_or(WRITER_POLL_INTERVAL, |due| {
+            due.saturating_duration_since(Instant::now())
+                .min(WRITER_POLL_INTERVAL)
+        });
+        let Ok(next) = queue.pop(timeout) else {
+            return Ok(());
+        };
+
+        if let Some(received) = next {
+            match &mut jitter {
+                Some(jitter) if received.is_update() => {
+                    if let Some(payload) = jitter.push(received, Instant::now()) {
+                        writer.stats.add_jitter_overrun();
+                        queue.recycle(payload);
+                    }
+                }
+                _ => {
+                    // A source saying goodbye doesn't want the updates still held written
+                    if let Some(jitter) = &mut jitter
+                        && protocol::is_goodbye(received.flags)
+                    {
+                        jitter.clear().for_each(|payload| queue.recycle(payload));
+                    }
+                    if write_dequeued(&mut writer, received, queue, &replies)? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        match jitter
+            .as_mut()
+            .and_then(|jitter| jitter.release(Instant::now()))
+        {
+            Some(Release::Update(received, held)) => {
+                writer.stats.add_jitter_occupancy(held);
+                if write_dequeued(&mut writer, received, queue, &replies)? {
+                    return Ok(());
+                }
+            }
+            Some(Release::Underrun) => writer.stats.add_jitter_underrun(),
+            None => {}
+        }
+
+        writer.write_interpolated()?;
+        writer.check_timeout()?;
+        if writer.take_released() {
+            let _ = replies.send(Reply::ReleaseSource);
+        }
+    }
+}
+
+/// Returns the --jitter-buffer to hold updates in, if enabled
+fn jitter_buffer(config: &TargetConfig) -> Option<JitterBuffer> {
+    (config.jitter_buffer > 0).then(|| {
+        let jitter = JitterBuffer::new(Duration::from_millis(config.jitter_buffer));
+        info!(
+            "Releasing updates at 60 Hz from a jitter buffer of {} updates ({} ms)",
+            jitter.depth, config.jitter_buffer
+        );
+        jitter
+    })
+}
+
+/// Writes an update taken off the queue and hands its buffer back, returning whether
+/// --count updates have been written
+fn write_dequeued<T: TelemetryProvider>(
+    writer: &mut Writer<T>,
+    received: Received,
+    queue: &UpdateQueue,
+    replies: &mpsc::Sender<Reply>,
+) -> Result<bool, TeleportError> {
+    writer.write(&received)?;
+    if let Some((request, source)) = writer.take_keyframe_request() {
+        let _ = replies.send(Reply::KeyframeRequest(request, source));
+    }
+    queue.recycle(received.payload);
+    Ok(writer.finished())
+}
+
+/// Receive errors after which the socket is rebound instead of giving up, like the
+/// network going down and coming back
+fn is_recoverable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NetworkDown
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Binds the socket to receive on and joins the multicast group unless receiving unicast.
+/// Returns the address of the interface the group was joined on, which is `interface`
+/// if given rather than one --interface-autoselect probes for again.
+fn bind_socket(
+    config: &TargetConfig,
+    interface: Option<Ipv4Addr>,
+) -> io::Result<(UdpSocket, Option<Ipv4Addr>)> {
+    let (socket, bind) = match config.fd {
+        Some(fd) => {
+            let socket = net::inherited_udp(fd)?;
+            let bind = socket.local_addr()?.to_string();
+            (socket, bind)
+        }
+        None => {
+            let bind = config.bind.as_str();
+
+            // Other multicast receivers on this computer can share the port
+            let socket = net::bind_udp(bind, !config.unicast).map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to bind to {}: {}", bind, e))
+            })?;
+            info!("Target bound to {}", bind);
+            (socket, bind.to_string())
+        }
     };
 
-    socket
-        .join_multicast_v4(&group_ip, &local_ip)
-        .map_err(|e| io::Error::new(e.kind(), format!("Failed to join multicast group: {}", e)))?;
+    let interface = if config.unicast {
+        None
+    } else if let Some(interface) = interface {
+        join_group(&socket, parse_group(&config.group)?, interface)?;
+        Some(interface)
+    } else if config.interface_autoselect {
+        let probe = Duration::from_millis(config.interface_probe);
+        Some(autoselect_interface(&socket, &config.group, probe)?)
+    } else {
+        setup_multicast(&socket, &bind, &config.group)?;
+        Some(multicast_interface(&bind)?)
+    };
+    Ok((socket, interface))
+}
+
+/// The error to stop with after `error` on an inherited socket, which can't be bound
+/// again. The process that passed it on has to start the target over.
+fn inherited_socket_failed(error: io::Error) -> io::Error {
+    io::Error::new(
+        error.kind(),
+        format!("UDP receive error on the inherited socket: {}", error),
+    )
+}
+
+/// Returns how long to wait before binding again after `error`, or `None` if it isn't
+/// worth retrying: the port isn't in use, or --bind-retry is up by `deadline`
+fn bind_retry_delay(
+    error: &io::Error,
+    wait: &mut Option<Duration>,
+    deadline: Instant,
+) -> Option<Duration> {
+    let remaining = deadline.checked_duration_since(Instant::now())?;
+    if error.kind() != io::ErrorKind::AddrInUse || remaining.is_zero() {
+        return None;
+    }
+
+    let delay = backoff(*wait, BIND_RETRY_MIN, BIND_RETRY_MAX).min(remaining);
+    *wait = Some(delay);
+    info!(
+        "{}. Retrying in {} ms, for up to {:.0} more seconds (--bind-retry).",
+        error,
+        delay.as_millis(),
+        remaining.as_secs_f64()
+    );
+    Some(delay)
+}
+
+/// Binds the socket like `bind_socket`, retrying while the port is in use for up to
+/// --bind-retry seconds. Returns `None` if shutdown was requested in the meantime.
+fn bind_with_retry(
+    config: &TargetConfig,
+    shutdown: &Receiver<()>,
+) -> io::Result<Option<(UdpSocket, Option<Ipv4Addr>)>> {
+    let deadline = Instant::now() + Duration::from_secs(config.bind_retry);
+    let mut wait = None;
+    loop {
+        let error = match bind_socket(config, None) {
+            Ok(bound) => return Ok(Some(bound)),
+            Err(e) => e,
+        };
+        let Some(delay) = bind_retry_delay(&error, &mut wait, deadline) else {
+            return Err(error);
+        };
+        match shutdown.recv_timeout(delay) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None),
+        }
+    }
+}
+
+/// Prints the --ready-signal line once the socket is bound and the multicast group joined
+fn signal_ready(config: &TargetConfig, bind: io::Result<SocketAddr>) {
+    if config.ready_signal {
+        let bind = bind.map_or_else(|_| config.bind.clone(), |bind| bind.to_string());
+        let mut fields = vec![("bind", bind)];
+        if !config.unicast {
+            fields.push(("group", config.group.clone()));
+        }
+        logging::signal_ready("target", &fields);
+    }
+}
 
-    println!("Joined multicast group: {}", group_ip);
+/// Makes receiving on the socket either spin or time out, so the loop gets to check for
+/// shutdown and the telemetry timeout
+fn set_receive_mode(socket: &UdpSocket, busy_poll: bool) -> io::Result<()> {
+    if busy_poll {
+        socket.set_nonblocking(true).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to set socket non-blocking: {}", e),
+            )
+        })?;
+        enable_kernel_busy_poll(socket);
+    } else {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to set socket timeout: {}", e))
+            })?;
+    }
     Ok(())
 }
 
-fn try_decompress_data(compressed: &[u8], target: &mut [u8]) -> bool {
-    match decompress_to_buffer(compressed, None, target) {
-        Ok(_) => true,
-        Err(e) => {
-            eprintln!("LZ4 decompression failed: {}. Skipping this update.", e);
-            false
+/// Rebinds the socket after `error`, waiting longer after every failed attempt, and
+/// joins the group on `interface` again. Returns `None` if shutdown was requested in the
+/// meantime.
+fn rebind(
+    config: &TargetConfig,
+    interface: Option<Ipv4Addr>,
+    error: io::Error,
+    shutdown: &Receiver<()>,
+) -> io::Result<Option<UdpSocket>> {
+    if config.fd.is_some() {
+        return Err(inherited_socket_failed(error));
+    }
+    warn!("UDP receive error: {}. Rebinding the socket...", error);
+
+    let mut wait = None;
+    loop {
+        let delay = backoff(wait, REBIND_RETRY_MIN, REBIND_RETRY_MAX);
+        wait = Some(delay);
+        match shutdown.recv_timeout(delay) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Ok(_) | Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(None),
+        }
+
+        match bind_socket(config, interface)
+            .and_then(|(socket, _)| set_receive_mode(&socket, config.busy_poll).map(|_| socket))
+        {
+            Ok(socket) => {
+                info!("Socket rebound, receiving again");
+                return Ok(Some(socket));
+            }
+            Err(e) => warn!(
+                "Failed to rebind: {}. Trying again in {} seconds.",
+                e,
+                backoff(wait, REBIND_RETRY_MIN, REBIND_RETRY_MAX).as_secs()
+            ),
         }
     }
 }
 
-pub fn run(bind: &str, unicast: bool, group: String, shutdown: Receiver<()>) -> io::Result<()> {
-    let socket = UdpSocket::bind(bind)
-        .map_err(|e| io::Error::new(e.kind(), format!("Failed to bind to {}: {}", bind, e)))?;
-    println!("Target bound to {}", bind);
+pub fn run(config: &TargetConfig, shutdown: Receiver<()>) -> Result<(), TeleportError> {
+    match Sink::from_config(config)? {
+        Sink::Mapping(_) => run_with::<Telemetry>(config, shutdown),
+        Sink::Pipe(_) => run_with::<PipeTelemetry>(config, shutdown),
+    }
+}
+
+/// Runs the target on top of the given telemetry provider. A receive thread keeps the
+/// socket drained and reassembles the payloads, the writer thread decodes and writes them.
+pub fn run_with<T: TelemetryProvider>(
+    config: &TargetConfig,
+    shutdown: Receiver<()>,
+) -> Result<(), TeleportError> {
+    let Some((socket, interface)) = bind_with_retry(config, &shutdown)? else {
+        return Ok(());
+    };
 
-    if !unicast {
-        setup_multicast(&socket, bind, &group)?;
+    set_receive_mode(&socket, config.busy_poll)?;
+    signal_ready(config, socket.local_addr());
+    if config.busy_poll {
+        info!("Busy polling the socket, this keeps a CPU core fully loaded");
     }
 
-    let mut rcv_buf = [0u8; MAX_DATAGRAM_SIZE];
-    let mut protocol_receiver = ProtocolReceiver::new(MAX_TELEMETRY_SIZE);
-    let mut telemetry: Option<Telemetry> = None;
-    let mut last_update = Instant::now();
-    let mut stats = StatisticsPrinter::new("target");
-    let mut sequence_start_time: Option<Instant> = None;
+    let queue = UpdateQueue::new(QUEUE_CAPACITY);
+    let (replies_tx, replies) = mpsc::channel();
 
-    // Set a short timeout on UDP receive to check for telemetry timeout
-    socket
-        .set_read_timeout(Some(Duration::from_secs(1)))
-        .map_err(|e| io::Error::new(e.kind(), format!("Failed to set socket timeout: {}", e)))?;
+    thread::scope(|scope| {
+        // The telemetry is created on the writer thread, providers don't have to be Send
+        let writer = thread::Builder::new()
+            .name("writer".into())
+            .spawn_scoped(scope, || write_updates::<T>(config, &queue, replies_tx))?;
+
+        scheduling::apply(config.cpu_affinity, config.priority);
+        let received = receive_updates(config, socket, interface, &shutdown, &queue, &replies);
+        queue.close();
+
+        let written = writer.join().expect("writer thread panicked");
+        received.and(written)
+    })
+}
+
+/// Receives datagrams and queues the complete payloads until shutdown or the queue is
+/// closed by the writer thread. `interface` is the one the group was joined on.
+fn receive_updates(
+    config: &TargetConfig,
+    mut socket: UdpSocket,
+    interface: Option<Ipv4Addr>,
+    shutdown: &Receiver<()>,
+    queue: &UpdateQueue,
+    replies: &Receiver<Reply>,
+) -> Result<(), TeleportError> {
+    let mut batch = RecvBatch::new(RECV_BATCH_SIZE, MAX_DATAGRAM_SIZE);
+    // Kept across rebinds, so the reassembly carries on where it left off
+    let mut reassembler = Reassembler::new(config);
+    let mut keepalive = GroupKeepalive::new(config, interface)?;
 
     loop {
         // Check for shutdown signal
-        if shutdown.try_recv().is_ok() {
+        if shutdown.try_recv().is_ok() || queue.is_closed() {
             return Ok(());
         }
 
-        match socket.recv_from(&mut rcv_buf) {
-            Ok((amt, _)) => {
-                // Process the received datagram
-                let (data, sequence_changed) = protocol_receiver.process_datagram(&rcv_buf[..amt]);
+        match batch.recv(&socket) {
+            Ok(_) => {
+                if let Some(keepalive) = &mut keepalive {
+                    keepalive.received();
+                }
+                // Process the received datagrams in order
+                for (source, datagram) in batch.datagrams() {
+                    if let Some(received) =
+                        reassembler.process_datagram(datagram, source, || queue.buffer())
+                    {
+                        queue.push(received);
+                    }
+                    if let Some(probe) = reassembler.take_probe() {
+                        let _ = socket.send_to(&probe, source);
+                    }
+                    if let Some(report) = reassembler.take_loss_report() {
+                        let _ = socket.send_to(&report, source);
+                    }
+                    if let Some(reply) = reassembler.take_hello_reply() {
+                        let _ = socket.send_to(&reply, source);
+                    }
+                }
+            }
+            // Interrupted when a termination signal arrives, the shutdown check comes next.
+            // Windows reports a keyframe request that found no source as a reset.
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::ConnectionReset =>
+            {
+                if config.busy_poll {
+                    thread::yield_now();
+                }
+            }
+            Err(e) if is_recoverable(&e) => {
+                // The old socket holds the port until it is closed
+                drop(socket);
+                match rebind(config, interface, e, shutdown)? {
+                    Some(rebound) => socket = rebound,
+                    None => return Ok(()),
+                }
+            }
+            Err(e) => {
+                return Err(io::Error::new(e.kind(), format!("UDP receive error: {}", e)).into());
+            }
+        }
 
-                if sequence_changed {
-                    sequence_start_time = Some(Instant::now());
+        for reply in replies.try_iter() {
+            match reply {
+                // Best-effort, the periodic keyframes cover for a lost request
+                Reply::KeyframeRequest(request, source) => {
+                    let _ = socket.send_to(&request, source);
                 }
+                Reply::ReleaseSource => reassembler.release_source(),
+            }
+        }
 
-                if let Some(data) = data {
-                    // Create telemetry if it doesn't exist
-                    if telemetry.is_none() {
-                        telemetry = Some(create_telemetry()?);
-                    }
+        if let Some(keepalive) = &mut keepalive {
+            keepalive.check(SockRef::from(&socket));
+        }
+        reassembler.check_stuck();
+        reassembler.check_startup()?;
+    }
+}
 
-                    // Process the complete payload
-                    let telemetry = telemetry.as_mut().unwrap();
-                    if !try_decompress_data(data, telemetry.as_slice_mut()) {
-                        // Reset accumulated bytes since we failed to process this message
-                        continue;
-                    }
+#[cfg(feature = "async")]
+pub async fn run_async(
+    config: &TargetConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), TeleportError> {
+    match Sink::from_config(config)? {
+        Sink::Mapping(_) => run_async_with::<Telemetry>(config, shutdown).await,
+        Sink::Pipe(_) => run_async_with::<PipeTelemetry>(config, shutdown).await,
+    }
+}
 
-                    // Track total bytes and fragments for the complete message
-                    stats.add_bytes(data.len());
-                    stats.add_fragments(protocol_receiver.total_fragments());
+/// Runs the target on tokio until `shutdown` completes, for embedding it alongside other
+/// async services. Busy polling, CPU affinity and priority only apply to `run`.
+#[cfg(feature = "async")]
+pub async fn run_async_with<T: TelemetryProvider>(
+    config: &TargetConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), TeleportError> {
+    tokio::pin!(shutdown);
 
-                    telemetry.signal_data_ready().map_err(|e| {
-                        io::Error::other(format!("Failed to signal data ready: {}", e))
-                    })?;
+    let (mut socket, interface) = tokio::select! {
+        _ = &mut shutdown => return Ok(()),
+        bound = bind_async_with_retry(config) => bound?,
+    };
+    signal_ready(config, socket.local_addr());
 
-                    // Calculate total latency (source processing + target processing)
-                    if let Some(start_time) = sequence_start_time.take() {
-                        let source_time = protocol_receiver.last_source_time_us();
-                        let target_time = start_time.elapsed().as_micros() as u64;
-                        stats.add_latency(source_time + target_time);
-                    }
+    if config.busy_poll {
+        warn!("Busy polling is not supported by the async target, ignoring it");
+    }
 
-                    last_update = Instant::now();
-                    stats.add_update();
+    // Reassembled and written inline, the runtime interleaves them with receiving
+    let mut reassembler = Reassembler::new(config);
+    let mut writer = Writer::<T>::new(config)?;
+    let mut payload = Vec::new();
+    let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut timeout_check = tokio::time::interval(Duration::from_secs(1));
+    let mut keepalive = GroupKeepalive::new(config, interface)?;
+    let mut jitter = jitter_buffer(config);
 
-                    if stats.should_print() {
-                        stats.print_and_reset();
+    loop {
+        let mut failed = None;
+        let next_frame = writer.next_frame();
+        let next_release = jitter.as_ref().and_then(JitterBuffer::next_release);
+        tokio::select! {
+            _ = &mut shutdown => return Ok(()),
+            _ = tokio::time::sleep_until(next_frame.unwrap_or_else(Instant::now).into()),
+                if next_frame.is_some() => writer.write_interpolated()?,
+            _ = tokio::time::sleep_until(next_release.unwrap_or_else(Instant::now).into()),
+                if next_release.is_some() => {
+                match jitter.as_mut().and_then(|jitter| jitter.release(Instant::now())) {
+                    Some(Release::Update(received, held)) => {
+                        writer.stats.add_jitter_occupancy(held);
+                        writer.write(&received)?;
+                        if let Some((request, source)) = writer.take_keyframe_request() {
+                            let _ = socket.try_send_to(&request, source);
+                        }
+                        if writer.finished() {
+                            return Ok(());
+                        }
                     }
+                    Some(Release::Underrun) => writer.stats.add_jitter_underrun(),
+                    None => {}
                 }
             }
-            Err(e)
-                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
-            {
-                // Check if we should close telemetry due to timeout
-                if telemetry.is_some() && last_update.elapsed() >= TELEMETRY_TIMEOUT {
-                    println!(
-                        "No updates received for {} seconds, closing telemetry",
-                        TELEMETRY_TIMEOUT.as_secs()
-                    );
-                    telemetry = None;
+            result = socket.recv_from(&mut datagram) => match result {
+                Ok((len, source)) => {
+                    if let Some(keepalive) = &mut keepalive {
+                        keepalive.received();
+                    }
+                    if let Some(received) = reassembler.process_datagram(
+                        &datagram[..len],
+                        source,
+                        || std::mem::take(&mut payload),
+                    ) {
+                        match &mut jitter {
+                            Some(jitter) if received.is_update() => {
+                                if let Some(dropped) = jitter.push(received, Instant::now()) {
+                                    writer.stats.add_jitter_overrun();
+                                    payload = dropped;
+                                }
+                            }
+                            _ => {
+                                if let Some(jitter) = &mut jitter
+                                    && protocol::is_goodbye(received.flags)
+                                {
+                                    jitter.clear().for_each(drop);
+                                }
+                                writer.write(&received)?;
+                                payload = received.payload;
+                            }
+                        }
+                    }
+                    if let Some((request, source)) = writer.take_keyframe_request() {
+                        // Best-effort, the periodic keyframes cover for a lost request
+                        let _ = socket.try_send_to(&request, source);
+                    }
+                    if let Some(probe) = reassembler.take_probe() {
+                        let _ = socket.try_send_to(&probe, source);
+                    }
+                    if let Some(report) = reassembler.take_loss_report() {
+                        let _ = socket.try_send_to(&report, source);
+                    }
+                    if let Some(reply) = reassembler.take_hello_reply() {
+                        let _ = socket.try_send_to(&reply, source);
+                    }
+                    if writer.finished() {
+                        return Ok(());
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        || e.kind() == io::ErrorKind::ConnectionReset => {}
+                Err(e) if is_recoverable(&e) => failed = Some(e),
+                Err(e) => {
+                    return Err(io::Error::new(e.kind(), format!("UDP receive error: {}", e)).into());
+                }
+            },
+            _ = timeout_check.tick() => {
+                writer.check_timeout()?;
+                if writer.take_released() {
+                    reassembler.release_source();
+                }
+                if let Some(keepalive) = &mut keepalive {
+                    keepalive.check(SockRef::from(&socket));
                 }
+                reassembler.check_stuck();
+                reassembler.check_startup()?;
             }
-            Err(e) => {
-                return Err(io::Error::new(
-                    e.kind(),
-                    format!("UDP receive error: {}", e),
-                ));
+        }
+
+        if let Some(error) = failed {
+            if config.fd.is_some() {
+                return Err(inherited_socket_failed(error).into());
+            }
+            // The old socket holds the port until it is closed
+            drop(socket);
+            socket = tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                rebound = rebind_async(config, interface, error) => rebound,
+            };
+        }
+    }
+}
+
+/// Binds the socket to receive on for the async target, like `bind_socket`
+#[cfg(feature = "async")]
+fn bind_async(
+    config: &TargetConfig,
+    interface: Option<Ipv4Addr>,
+) -> io::Result<(tokio::net::UdpSocket, Option<Ipv4Addr>)> {
+    let (socket, interface) = bind_socket(config, interface)?;
+    socket.set_nonblocking(true)?;
+    Ok((tokio::net::UdpSocket::from_std(socket)?, interface))
+}
+
+/// Binds the socket of the async target, retrying like `bind_with_retry`
+#[cfg(feature = "async")]
+async fn bind_async_with_retry(
+    config: &TargetConfig,
+) -> io::Result<(tokio::net::UdpSocket, Option<Ipv4Addr>)> {
+    let deadline = Instant::now() + Duration::from_secs(config.bind_retry);
+    let mut wait = None;
+    loop {
+        let error = match bind_async(config, None) {
+            Ok(bound) => return Ok(bound),
+            Err(e) => e,
+        };
+        let Some(delay) = bind_retry_delay(&error, &mut wait, deadline) else {
+            return Err(error);
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Rebinds the socket of the async target after `error`, like `rebind`
+#[cfg(feature = "async")]
+async fn rebind_async(
+    config: &TargetConfig,
+    interface: Option<Ipv4Addr>,
+    error: io::Error,
+) -> tokio::net::UdpSocket {
+    warn!("UDP receive error: {}. Rebinding the socket...", error);
+
+    let mut wait = None;
+    loop {
+        let delay = backoff(wait, REBIND_RETRY_MIN, REBIND_RETRY_MAX);
+        wait = Some(delay);
+        tokio::time::sleep(delay).await;
+
+        match bind_async(config, interface) {
+            Ok((socket, _)) => {
+                info!("Socket rebound, receiving again");
+                return socket;
+            }
+            Err(e) => warn!(
+                "Failed to rebind: {}. Trying again in {} seconds.",
+                e,
+                backoff(wait, REBIND_RETRY_MIN, REBIND_RETRY_MAX).as_secs()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{CODEC_LZ4, CODEC_LZ4_FRAME, FLAG_SESSION_INFO};
+
+    #[test]
+    fn test_mapping_size_is_limited() {
+        assert_eq!(mapping_size(0, 4096), Some(4096));
+        assert_eq!(mapping_size(1024, 4096), Some(1024));
+        assert_eq!(mapping_size(4096, 4096), Some(4096));
+        assert_eq!(mapping_size(4097, 4096), None);
+    }
+
+    #[test]
+    fn test_hello_is_checked_against_the_target() {
+        let hello = Hello {
+            source_id: 1,
+            version: PROTOCOL_VERSION,
+            codec: CODEC_LZ4,
+            features: FEATURE_VERIFY,
+            mapping_size: 4096,
+            dictionary_id: 0,
+        };
+        assert_eq!(check_hello(&hello, 4096, CODEC_LZ4, None), None);
+        assert_eq!(
+            check_hello(&hello, 1024, CODEC_LZ4, None),
+            Some(Rejection::MappingSize)
+        );
+
+        // Later versions are understood, they only append fields
+        let later = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..hello.clone()
+        };
+        assert_eq!(check_hello(&later, 4096, CODEC_LZ4, None), None);
+        let unknown_codec = Hello {
+            codec: 7,
+            ..hello.clone()
+        };
+        assert_eq!(
+            check_hello(&unknown_codec, 4096, CODEC_LZ4, None),
+            Some(Rejection::Codec)
+        );
+
+        // Frames need a target with --lz4-format frame
+        let frame = Hello {
+            codec: CODEC_LZ4_FRAME,
+            ..hello.clone()
+        };
+        assert_eq!(
+            check_hello(&frame, 4096, CODEC_LZ4, None),
+            Some(Rejection::Codec)
+        );
+        assert_eq!(check_hello(&frame, 4096, CODEC_LZ4_FRAME, None), None);
+
+        let dictionary = Hello {
+            features: FEATURE_DICTIONARY,
+            dictionary_id: 0xabcd,
+            ..hello
+        };
+        assert_eq!(
+            check_hello(&dictionary, 4096, CODEC_LZ4, Some(0xabcd)),
+            None
+        );
+        assert_eq!(
+            check_hello(&dictionary, 4096, CODEC_LZ4, Some(0x1234)),
+            Some(Rejection::Dictionary)
+        );
+        assert_eq!(
+            check_hello(&dictionary, 4096, CODEC_LZ4, None),
+            Some(Rejection::Dictionary)
+        );
+    }
+
+    #[test]
+    fn test_size_mismatch_is_caught_before_decompressing() {
+        let data = vec![3u8; 1000];
+        let payload = lz4::block::compress(&data, None, true).unwrap();
+        assert_eq!(decoded_size(&payload, 0, Lz4Format::Block), Some(1000));
+        assert_eq!(
+            decoded_size(&data[..10], FLAG_UNCOMPRESSED, Lz4Format::Block),
+            Some(10)
+        );
+        assert_eq!(decoded_size(&payload[..3], 0, Lz4Format::Block), None);
+        let mut frame = vec![0u8; 2000];
+        let len = codec::compress_frame(&data, &mut frame, 0).unwrap();
+        assert_eq!(decoded_size(&frame[..len], 0, Lz4Format::Frame), Some(1000));
+        assert_eq!(decoded_size(&payload, 0, Lz4Format::Frame), None);
+
+        assert!(size_mismatch(1000, 1000, false, true).is_none());
+        assert!(size_mismatch(1001, 1000, false, false).is_some());
+        assert!(size_mismatch(900, 1000, false, true).is_some());
+        assert!(size_mismatch(900, 1000, false, false).is_none());
+        assert!(size_mismatch(900, 1000, true, true).is_none());
+        assert!(size_mismatch(1001, 1000, true, true).is_some());
+    }
+
+    const BLOCK: Decoding = Decoding {
+        flags: 0,
+        hash: None,
+        format: Lz4Format::Block,
+        dictionary: None,
+    };
+
+    #[test]
+    fn test_decoded_size_is_checked() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let payload = lz4::block::compress(&data, None, true).unwrap();
+        let mut staging = vec![0u8; 4096];
+
+        let mut mapping = vec![0u8; 1000];
+        assert!(try_decompress_data(&payload, BLOCK, &mut staging, &mut mapping, true).is_ok());
+        assert_eq!(mapping, data);
+
+        // A mapping of the advertised size that the update doesn't fill is left untouched
+        let mut mapping = vec![0xffu8; 1200];
+        assert!(try_decompress_data(&payload, BLOCK, &mut staging, &mut mapping, true).is_err());
+        assert!(mapping.iter().all(|&b| b == 0xff));
+
+        // Without an advertised size any update that fits is written
+        assert!(try_decompress_data(&payload, BLOCK, &mut staging, &mut mapping, false).is_ok());
+        assert_eq!(mapping[..1000], data[..]);
+        let mut mapping = vec![0u8; 500];
+        assert!(try_decompress_data(&payload, BLOCK, &mut staging, &mut mapping, false).is_err());
+    }
+
+    #[test]
+    fn test_verify_hash_catches_corrupt_updates() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut payload = lz4::block::compress(&data, None, true).unwrap();
+        payload.extend_from_slice(&protocol::verify_hash(&data).to_le_bytes());
+        let mut staging = vec![0u8; 4096];
+
+        let (compressed, hash) =
+            protocol::split_verify_hash(&payload, protocol::FLAG_VERIFY).unwrap();
+        let mut mapping = vec![0u8; 1000];
+        assert_eq!(
+            try_decompress_data(
+                compressed,
+                Decoding { hash, ..BLOCK },
+                &mut staging,
+                &mut mapping,
+                true
+            ),
+            Ok(())
+        );
+        assert_eq!(mapping, data);
+
+        // Data that decompresses fine but differs from what the source hashed is skipped
+        let mut mapping = vec![0xffu8; 1000];
+        assert_eq!(
+            try_decompress_data(
+                compressed,
+                Decoding {
+                    hash: hash.map(|hash| hash ^ 1),
+                    ..BLOCK
+                },
+                &mut staging,
+                &mut mapping,
+                true
+            ),
+            Err(Rejected::HashMismatch)
+        );
+        assert!(mapping.iter().all(|&b| b == 0xff));
+        assert!(protocol::split_verify_hash(&payload[..4], protocol::FLAG_VERIFY).is_none());
+    }
+
+    #[test]
+    fn test_sink_names_a_mapping_or_a_pipe() {
+        assert_eq!(
+            Sink::parse(r"mmap:Local\Teleport").unwrap(),
+            Sink::Mapping(r"Local\Teleport".to_string())
+        );
+        assert_eq!(
+            Sink::parse("pipe:/tmp/teleport").unwrap(),
+            Sink::Pipe("/tmp/teleport".to_string())
+        );
+        for invalid in ["teleport", "pipe:", "file:/tmp/teleport"] {
+            assert!(Sink::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_precreated_mapping_outlives_the_source() {
+        use crate::config::tests::target;
+        use crate::telemetry::Telemetry;
+
+        let config = target(&["test", "--precreate"]);
+
+        // Created zeroed at startup, before any update arrived
+        let mut writer = Writer::<Telemetry>::new(&config).unwrap();
+        let telemetry = writer.telemetry.as_ref().unwrap();
+        assert_eq!(telemetry.size(), config.max_size);
+        assert!(telemetry.as_slice().iter().all(|&b| b == 0));
+
+        // A goodbye before the first update has nothing to close
+        let goodbye = Received {
+            payload: Vec::new(),
+            source: "127.0.0.1:5000".parse().unwrap(),
+            source_id: 1,
+            flags: protocol::FLAG_GOODBYE,
+            fragments: 1,
+            mapping_size: 0,
+            source_time_us: 0,
+            started: None,
+            reassembled: Instant::now(),
+            lost: 0,
+            overrun: 0,
+            network_delay: None,
+        };
+        writer.write(&goodbye).unwrap();
+        assert!(writer.telemetry.is_some());
+        assert!(!writer.take_released());
+
+        // After an update the goodbye zeroes the mapping but keeps it
+        writer.precreated = false;
+        writer.telemetry.as_mut().unwrap().as_slice_mut()[0] = 1;
+        writer.write(&goodbye).unwrap();
+        assert!(writer.take_released());
+        let telemetry = writer.telemetry.as_ref().unwrap();
+        assert!(telemetry.as_slice().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_gaps_are_reported_at_most_every_interval() {
+        let mut gaps = GapLog::new();
+        let start = gaps.last_report;
+        assert_eq!(gaps.record(10, 0, start), None);
+        assert_eq!(gaps.record(10, 1, start), None);
+        assert_eq!(gaps.record(20, 3, start), None);
+        assert_eq!(
+            gaps.record(30, 1, start + GAP_LOG_INTERVAL).unwrap(),
+            "Lost 5 sequences in 3 gaps: 9, 17-19, 29"
+        );
+
+        // Only the first few gaps are listed
+        for sequence in 1..=GAP_LOG_LIST as u32 + 2 {
+            gaps.record(sequence * 10, 1, start + GAP_LOG_INTERVAL);
+        }
+        let report = gaps.record(200, 1, start + GAP_LOG_INTERVAL * 2).unwrap();
+        assert!(report.ends_with(", 69, 79 and 3 more gaps"), "{}", report);
+    }
+
+    /// Telemetry the OS never creates, for lack of resources or with `DENIED` of rights
+    struct FailingTelemetry<const DENIED: bool>;
+
+    impl<const DENIED: bool> TelemetryProvider for FailingTelemetry<DENIED> {
+        fn open(_: &SharedMemoryNames) -> Result<Self, TelemetryError> {
+            Err(TelemetryError::Unavailable)
+        }
+
+        fn create(_: &SharedMemoryNames, _: usize) -> Result<Self, TelemetryError> {
+            Err(match DENIED {
+                true => TelemetryError::AccessDenied,
+                false => TelemetryError::Other("Not enough memory resources".into()),
+            })
+        }
+
+        fn wait_for_data(&mut self, _: u32) -> bool {
+            unreachable!()
+        }
+
+        fn signal_data_ready(&mut self) -> Result<(), TelemetryError> {
+            unreachable!()
+        }
+
+        fn as_slice(&self) -> &[u8] {
+            unreachable!()
+        }
+
+        fn as_slice_mut(&mut self) -> &mut [u8] {
+            unreachable!()
+        }
+
+        fn size(&self) -> usize {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn test_transient_create_failures_are_retried() {
+        let names = SharedMemoryNames::default();
+        let mut stats = StatisticsPrinter::new("target");
+        let mut retry = None;
+        let created = create_telemetry::<FailingTelemetry<false>>(
+            &names, 4096, false, &mut retry, &mut stats,
+        );
+        assert!(matches!(created, Ok(None)));
+        let (retry_at, wait) = retry.unwrap();
+        assert_eq!(wait, CREATE_RETRY_MIN);
+
+        // Nothing is tried again until the wait is over
+        let created = create_telemetry::<FailingTelemetry<false>>(
+            &names, 4096, false, &mut retry, &mut stats,
+        );
+        assert!(matches!(created, Ok(None)));
+        assert_eq!(retry.unwrap().0, retry_at);
+
+        // Missing rights stop the target instead
+        let created =
+            create_telemetry::<FailingTelemetry<true>>(&names, 4096, false, &mut None, &mut stats);
+        assert!(matches!(
+            created,
+            Err(TeleportError::Telemetry {
+                error: TelemetryError::AccessDenied,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let next = |last| backoff(last, CREATE_RETRY_MIN, CREATE_RETRY_MAX);
+        let mut wait = next(None);
+        assert_eq!(wait, CREATE_RETRY_MIN);
+        wait = next(Some(wait));
+        assert_eq!(wait, CREATE_RETRY_MIN * 2);
+        for _ in 0..10 {
+            wait = next(Some(wait));
+        }
+        assert_eq!(wait, CREATE_RETRY_MAX);
+    }
+
+    #[test]
+    fn test_bind_retries_while_the_port_is_in_use() {
+        let in_use = io::Error::from(io::ErrorKind::AddrInUse);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut wait = None;
+        assert_eq!(
+            bind_retry_delay(&in_use, &mut wait, deadline),
+            Some(BIND_RETRY_MIN)
+        );
+        assert_eq!(
+            bind_retry_delay(&in_use, &mut wait, deadline),
+            Some(BIND_RETRY_MIN * 2)
+        );
+
+        // Other errors fail right away, as does a port still in use at the deadline
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(bind_retry_delay(&denied, &mut None, deadline), None);
+        assert_eq!(bind_retry_delay(&in_use, &mut None, Instant::now()), None);
+
+        // The last wait ends at the deadline
+        let soon = Instant::now() + Duration::from_millis(100);
+        assert!(bind_retry_delay(&in_use, &mut None, soon).unwrap() <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_receive_errors_that_rebind() {
+        let error = |kind| io::Error::new(kind, "test");
+        assert!(is_recoverable(&error(io::ErrorKind::NetworkDown)));
+        assert!(is_recoverable(&error(io::ErrorKind::AddrNotAvailable)));
+        assert!(is_recoverable(&error(io::ErrorKind::ConnectionAborted)));
+        assert!(!is_recoverable(&error(io::ErrorKind::PermissionDenied)));
+        assert!(!is_recoverable(&error(io::ErrorKind::InvalidInput)));
+    }
+
+    #[test]
+    fn test_group_must_be_multicast() {
+        for group in ["224.0.0.251", "224.0.1.1", "239.255.0.1", "239.255.255.255"] {
+            assert!(parse_group(group).is_ok(), "{} should be accepted", group);
+        }
+        for group in [
+            "192.168.1.10",
+            "223.255.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(parse_group(group).is_err(), "{} should be rejected", group);
+        }
+        assert!(parse_group("239.255.0.1:5000").is_err());
+    }
+
+    #[test]
+    fn test_startup_timeout_tells_why_nothing_arrived() {
+        let start = Instant::now();
+        let mut watchdog = StartupWatchdog {
+            timeout: Duration::from_secs(30),
+            started: start,
+            destination: "239.255.0.1:5000".into(),
+            datagrams: 0,
+            update_datagrams: 0,
+            last_datagram: None,
+        };
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(watchdog.expired(at(29)), None);
+        let message = watchdog.expired(at(30)).unwrap();
+        assert!(message.starts_with("Nothing arrived"), "{}", message);
+        assert!(message.contains("239.255.0.1:5000"), "{}", message);
+
+        watchdog.datagram(false, at(5));
+        let message = watchdog.expired(at(30)).unwrap();
+        assert!(message.contains("the last 25 s ago"), "{}", message);
+        assert!(message.contains("no telemetry"), "{}", message);
+
+        // Connected, then the datagrams stopped
+        watchdog.datagram(true, at(10));
+        let message = watchdog.expired(at(30)).unwrap();
+        assert!(message.starts_with("2 datagrams arrived"), "{}", message);
+        assert!(message.contains("stopped sending"), "{}", message);
+
+        // Still arriving, but nothing completes
+        watchdog.datagram(true, at(30));
+        let message = watchdog.expired(at(30)).unwrap();
+        assert!(message.contains("get lost on the way"), "{}", message);
+    }
+
+    #[test]
+    fn test_link_local_group_warns() {
+        assert!(group_warning(Ipv4Addr::new(224, 0, 0, 251)).is_some());
+        assert!(group_warning(Ipv4Addr::new(224, 0, 1, 1)).is_none());
+        assert!(group_warning(Ipv4Addr::new(239, 255, 0, 1)).is_none());
+    }
+
+    #[test]
+    fn test_idle_group_is_rejoined() {
+        let group = Ipv4Addr::new(239, 255, 0, 99);
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        socket
+            .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+            .unwrap();
+        let mut keepalive = GroupKeepalive {
+            group,
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: Duration::from_secs(60),
+            last_activity: Instant::now(),
+        };
+
+        // Not idle long enough yet
+        let joined = keepalive.last_activity;
+        keepalive.check(SockRef::from(&socket));
+        assert_eq!(keepalive.last_activity, joined);
+
+        keepalive.last_activity -= keepalive.interval;
+        keepalive.check(SockRef::from(&socket));
+        assert!(keepalive.last_activity >= joined);
+        // Still a member, joining twice fails
+        assert!(
+            socket
+                .join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+                .is_err()
+        );
+        assert!(
+            socket
+                .leave_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_first_interface_with_data_is_selected() {
+        let group = Ipv4Addr::new(239, 255, 0, 1);
+        let probe = Duration::from_millis(100);
+        let candidates = [
+            ("Ethernet", Ipv4Addr::new(192, 168, 1, 5)),
+            ("VPN", Ipv4Addr::new(10, 8, 0, 2)),
+            ("Wi-Fi", Ipv4Addr::new(10, 0, 0, 7)),
+        ];
+
+        let mut probed = Vec::new();
+        let selected = probe_interfaces(&candidates, group, probe, |ip| {
+            probed.push(ip);
+            match ip.octets()[1] {
+                8 => Err(io::Error::other("no such device")),
+                _ => Ok(ip.octets()[0] == 10),
             }
+        })
+        .unwrap();
+        assert_eq!(selected, candidates[2]);
+        assert_eq!(probed.len(), 3);
+
+        let e = probe_interfaces(&candidates, group, probe, |_| Ok(false)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        let message = e.to_string();
+        assert!(message.contains("within 100 ms"), "{}", message);
+        assert!(
+            message.contains("Ethernet (192.168.1.5), VPN (10.8.0.2), Wi-Fi (10.0.0.7)"),
+            "{}",
+            message
+        );
+
+        let e = probe_interfaces(&[], group, probe, |_| Ok(true)).unwrap_err();
+        assert!(e.to_string().contains("iracing-teleport interfaces"));
+    }
+
+    #[test]
+    fn test_probe_without_data_leaves_the_group() {
+        let group = Ipv4Addr::new(239, 255, 0, 98);
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        // Arrived before the probe, so it doesn't count
+        sender.send_to(b"stale", ("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        let probe = Duration::from_millis(50);
+        let any = Ipv4Addr::UNSPECIFIED;
+        assert!(!probe_interface(&socket, group, any, probe).unwrap());
+        assert!(socket.join_multicast_v4(&group, &any).is_ok());
+        socket.leave_multicast_v4(&group, &any).unwrap();
+    }
+
+    fn received(flags: u16, payload: &[u8], lost: u32) -> Received {
+        Received {
+            payload: payload.to_vec(),
+            source: "127.0.0.1:5000".parse().unwrap(),
+            source_id: 1,
+            flags,
+            fragments: 1,
+            mapping_size: 0,
+            source_time_us: 0,
+            started: None,
+            reassembled: Instant::now(),
+            lost,
+            overrun: 0,
+            network_delay: None,
         }
     }
+
+    #[test]
+    fn test_age_counts_from_when_the_source_read_the_update() {
+        let now = Instant::now();
+        let mut update = received(0, b"update", 0);
+        assert_eq!(update.age(now), None);
+
+        update.started = Some(now - Duration::from_millis(5));
+        update.source_time_us = 1_000;
+        assert_eq!(update.age(now), Some(Duration::from_millis(6)));
+
+        update.network_delay = Some(ClockEstimate {
+            delay_us: 250_000.0,
+            offset_us: -3_000_000.0,
+        });
+        assert_eq!(update.age(now), Some(Duration::from_millis(256)));
+    }
+
+    #[test]
+    fn test_jitter_buffer_releases_at_a_steady_rate() {
+        // Rounded to two updates
+        let mut jitter = JitterBuffer::new(Duration::from_millis(30));
+        let start = Instant::now();
+        let released = |release: Option<Release>| match release {
+            Some(Release::Update(received, held)) => Some((received.payload, held)),
+            _ => None,
+        };
+
+        // Fills up before releasing anything
+        assert!(jitter.push(received(0, b"1", 0), start).is_none());
+        assert!(jitter.next_release().is_none());
+        assert!(jitter.push(received(0, b"2", 0), start).is_none());
+        assert_eq!(jitter.next_release(), Some(start));
+
+        // A burst beyond twice the depth drops the oldest, its losses carry over
+        assert!(jitter.push(received(0, b"3", 3), start).is_none());
+        assert!(jitter.push(received(0, b"4", 0), start).is_none());
+        assert_eq!(
+            jitter.push(received(0, b"5", 0), start).as_deref(),
+            Some(&b"1"[..])
+        );
+
+        // One update per interval, not when asked early
+        let release = jitter.release(start).unwrap();
+        let Release::Update(second, 4) = release else {
+            panic!("expected an update");
+        };
+        assert_eq!((&second.payload[..], second.lost), (&b"2"[..], 0));
+        let next = start + JITTER_RELEASE_INTERVAL;
+        assert!(jitter.release(next - Duration::from_millis(1)).is_none());
+        assert_eq!(released(jitter.release(next)), Some((b"3".to_vec(), 3)));
+
+        // Running empty is an underrun, after which it fills up again
+        jitter.clear().for_each(drop);
+        assert!(jitter.release(next + JITTER_RELEASE_INTERVAL).is_none());
+        let mut empty = JitterBuffer::new(Duration::from_millis(1));
+        empty.push(received(0, b"1", 0), start);
+        assert!(released(empty.release(start)).is_some());
+        assert!(matches!(
+            empty.release(start + JITTER_RELEASE_INTERVAL),
+            Some(Release::Underrun)
+        ));
+        assert!(empty.next_release().is_none());
+    }
+
+    #[test]
+    fn test_full_queue_drops_the_oldest_update() {
+        let queue = UpdateQueue::new(3);
+        queue.push(received(FLAG_SESSION_INFO, b"session", 0));
+        queue.push(received(0, b"first", 1));
+        queue.push(received(0, b"second", 0));
+        queue.push(received(0, b"third", 2));
+
+        // The session info stays, the dropped update's buffer and losses carry on
+        let pop = || queue.pop(Duration::ZERO).unwrap().unwrap();
+        assert_eq!(pop().payload, b"session");
+        assert_eq!(pop().payload, b"second");
+        let third = pop();
+        assert_eq!(third.payload, b"third");
+        assert_eq!((third.lost, third.overrun), (3, 1));
+        assert_eq!(queue.buffer(), b"first");
+        assert!(queue.pop(Duration::ZERO).unwrap().is_none());
+
+        queue.close();
+        assert!(queue.pop(Duration::from_secs(1)).is_err());
+    }
 }
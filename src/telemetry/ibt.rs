@@ -0,0 +1,304 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::irsdk::{self, HEADER_SIZE, STATUS_CONNECTED, VAR_HEADER_SIZE};
+
+// Size of the header that follows the iRSDK header in an .ibt file (irsdk_diskSubHeader)
+const DISK_SUB_HEADER_SIZE: usize = 32;
+
+// Offset of the number of records within the disk sub header
+const RECORD_COUNT_OFFSET: usize = 28;
+
+// Variable buffers iRacing rotates through in the live mapping
+const MEMORY_BUFS: usize = 3;
+
+// Alignment of the regions in the rebuilt mapping
+const ALIGNMENT: usize = 16;
+
+/// Plays back the telemetry iRacing recorded to an .ibt file as the mapping a live session
+/// would have. The file keeps a single variable buffer per record after its headers and
+/// session info, the mapping gets the same headers and session info, and the records
+/// rotate through the variable buffers with increasing tick counts like they do live.
+pub struct IbtReplay {
+    reader: BufReader<File>,
+    mapping: Vec<u8>,
+    header: irsdk::Header,
+    buf_len: usize,
+    records: u64,
+    next_record: u64,
+    first_record: u64,
+    frame_time: Duration,
+    start: Option<Instant>,
+}
+
+impl IbtReplay {
+    /// Opens the .ibt file at `path` to play it back at `speed` times its tick rate,
+    /// starting `start_secs` seconds into the recording
+    pub fn open(path: &Path, speed: f64, start_secs: f64) -> io::Result<Self> {
+        let file = File::open(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to open {}: {}", path.display(), e),
+            )
+        })?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+
+        let mut headers = [0u8; HEADER_SIZE + DISK_SUB_HEADER_SIZE];
+        reader
+            .read_exact(&mut headers)
+            .map_err(|_| invalid(path, "too short"))?;
+        let disk =
+            irsdk::Header::parse(&headers).ok_or_else(|| invalid(path, "no iRSDK header"))?;
+
+        let size = |value: i32, what: &str| {
+            usize::try_from(value).map_err(|_| invalid(path, &format!("invalid {}", what)))
+        };
+        let buf_len = size(disk.buf_len, "buffer length")?;
+        let num_vars = size(disk.num_vars, "number of variables")?;
+        let session_info_len = size(disk.session_info_len, "session info length")?;
+        let records_offset = size(disk.var_bufs[0].buf_offset, "record offset")? as u64;
+        if buf_len == 0 || disk.tick_rate <= 0 {
+            return Err(invalid(path, "no variable buffers"));
+        }
+
+        // Files of sessions that didn't end cleanly have no record count
+        let recorded = i32::from_le_bytes(
+            headers[HEADER_SIZE + RECORD_COUNT_OFFSET..][..4]
+                .try_into()
+                .expect("4 bytes"),
+        );
+        let records = match u64::try_from(recorded) {
+            Ok(records) if records > 0 => records,
+            _ => file_len.saturating_sub(records_offset) / buf_len as u64,
+        };
+
+        let mut var_headers = vec![0u8; num_vars * VAR_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(
+            size(disk.var_header_offset, "header offset")? as u64,
+        ))?;
+        reader.read_exact(&mut var_headers)?;
+
+        let mut session_info = vec![0u8; session_info_len];
+        reader.seek(SeekFrom::Start(
+            size(disk.session_info_offset, "session info offset")? as u64,
+        ))?;
+        reader.read_exact(&mut session_info)?;
+
+        // Headers, session info with room for its terminator, then the variable buffers
+        let var_header_offset = align(HEADER_SIZE);
+        let session_info_offset = align(var_header_offset + var_headers.len());
+        let session_info_region = align(session_info_len + 1);
+        let buf_offset = session_info_offset + session_info_region;
+        let buf_stride = align(buf_len);
+
+        let mut header = irsdk::Header {
+            ver: disk.ver,
+            status: STATUS_CONNECTED,
+            tick_rate: disk.tick_rate,
+            session_info_update: 1,
+            session_info_len: session_info_region as i32,
+            session_info_offset: session_info_offset as i32,
+            num_vars: disk.num_vars,
+            var_header_offset: var_header_offset as i32,
+            num_buf: MEMORY_BUFS as i32,
+            buf_len: disk.buf_len,
+            var_bufs: Default::default(),
+        };
+        for (i, var_buf) in header.var_bufs.iter_mut().take(MEMORY_BUFS).enumerate() {
+            var_buf.buf_offset = (buf_offset + i * buf_stride) as i32;
+        }
+
+        let mut mapping = vec![0u8; buf_offset + MEMORY_BUFS * buf_stride];
+        header.write(&mut mapping);
+        mapping[var_header_offset..var_header_offset + var_headers.len()]
+            .copy_from_slice(&var_headers);
+        mapping[session_info_offset..session_info_offset + session_info_len]
+            .copy_from_slice(&session_info);
+
+        let first_record = (start_secs * disk.tick_rate as f64) as u64;
+        if first_record >= records {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is only {:.1} seconds long, it can't start at {} seconds",
+                    path.display(),
+                    records as f64 / disk.tick_rate as f64,
+                    start_secs
+                ),
+            ));
+        }
+        reader.seek(SeekFrom::Start(
+            records_offset + first_record * buf_len as u64,
+        ))?;
+
+        Ok(Self {
+            reader,
+            mapping,
+            header,
+            buf_len,
+            records,
+            next_record: first_record,
+            first_record,
+            frame_time: Duration::from_secs_f64(1.0 / (disk.tick_rate as f64 * speed)),
+            start: None,
+        })
+    }
+
+    /// Returns the number of records in the file and its tick rate
+    pub fn length(&self) -> (u64, i32) {
+        (self.records, self.header.tick_rate)
+    }
+
+    /// Returns whether every record has been played back
+    pub fn finished(&self) -> bool {
+        self.next_record >= self.records
+    }
+
+    /// Waits up to `timeout_ms` for the next record to be due and loads it into the
+    /// mapping, returning whether it was
+    pub fn wait_for_data(&mut self, timeout_ms: u32) -> io::Result<bool> {
+        if self.finished() {
+            thread::sleep(Duration::from_millis(timeout_ms as u64));
+            return Ok(false);
+        }
+
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let due = start
+            + self
+                .frame_time
+                .mul_f64((self.next_record - self.first_record) as f64);
+        let remaining = due.saturating_duration_since(Instant::now());
+        let timeout = Duration::from_millis(timeout_ms as u64);
+        if remaining > timeout {
+            thread::sleep(timeout);
+            return Ok(false);
+        }
+        thread::sleep(remaining);
+        self.read_next()
+    }
+
+    /// Loads the next record into the mapping right away, returning whether there was one
+    pub fn read_next(&mut self) -> io::Result<bool> {
+        if self.finished() {
+            return Ok(false);
+        }
+
+        // Rotate through the buffers, each write becoming the one with the latest tick
+        let index = (self.next_record % MEMORY_BUFS as u64) as usize;
+        let offset = self.header.var_bufs[index].buf_offset as usize;
+        self.reader
+            .read_exact(&mut self.mapping[offset..offset + self.buf_len])?;
+        self.next_record += 1;
+        self.header.var_bufs[index].tick_count = self.next_record as i32;
+        self.header.write(&mut self.mapping);
+        Ok(true)
+    }
+
+    /// Gets the mapping as a live session would have it
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mapping
+    }
+
+    pub fn size(&self) -> usize {
+        self.mapping.len()
+    }
+}
+
+fn align(offset: usize) -> usize {
+    offset.next_multiple_of(ALIGNMENT)
+}
+
+fn invalid(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is not a valid .ibt file: {}", path.display(), reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irsdk::{VarHeader, VarType};
+
+    /// Writes an .ibt file with one float variable and a record per value
+    fn write_ibt(path: &Path, values: &[f32]) {
+        let session_info = b"WeekendInfo:\n TrackName: test\n";
+        let var_header_offset = HEADER_SIZE + DISK_SUB_HEADER_SIZE;
+        let session_info_offset = var_header_offset + VAR_HEADER_SIZE;
+        let records_offset = session_info_offset + session_info.len();
+
+        let mut file = vec![0u8; records_offset];
+        let mut header = irsdk::Header {
+            ver: irsdk::IRSDK_VER,
+            tick_rate: 60,
+            session_info_len: session_info.len() as i32,
+            session_info_offset: session_info_offset as i32,
+            num_vars: 1,
+            var_header_offset: var_header_offset as i32,
+            num_buf: 1,
+            buf_len: 4,
+            ..Default::default()
+        };
+        header.var_bufs[0].buf_offset = records_offset as i32;
+        header.write(&mut file);
+        file[HEADER_SIZE + RECORD_COUNT_OFFSET..][..4]
+            .copy_from_slice(&(values.len() as i32).to_le_bytes());
+        VarHeader {
+            var_type: VarType::Float,
+            offset: 0,
+            count: 1,
+            name: "Speed".into(),
+            desc: String::new(),
+            unit: "m/s".into(),
+        }
+        .write(&mut file[var_header_offset..]);
+        file[session_info_offset..records_offset].copy_from_slice(session_info);
+        for value in values {
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(path, file).unwrap();
+    }
+
+    fn speed(mapping: &[u8]) -> (i32, f32) {
+        let header = irsdk::Header::parse(mapping).unwrap();
+        let (tick, buf) = header.latest_var_buf(mapping).unwrap();
+        let var = &header.var_headers(mapping)[0];
+        match var.value(buf, 0) {
+            Some(irsdk::Value::Float(value)) => (tick, value),
+            value => panic!("unexpected value {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_replay_rebuilds_the_live_mapping() {
+        let path = std::env::temp_dir().join(format!("teleport-{}.ibt", std::process::id()));
+        write_ibt(&path, &[1.0, 2.0, 3.0, 4.0]);
+
+        let mut replay = IbtReplay::open(&path, 100.0, 0.0).unwrap();
+        assert_eq!(replay.length(), (4, 60));
+
+        let header = irsdk::Header::parse(replay.as_slice()).unwrap();
+        assert_eq!(header.num_buf, MEMORY_BUFS as i32);
+        let range = header.session_info_range(replay.size()).unwrap();
+        assert!(replay.as_slice()[range].starts_with(b"WeekendInfo:"));
+
+        for expected in 1..=4 {
+            assert!(replay.wait_for_data(1000).unwrap());
+            assert_eq!(speed(replay.as_slice()), (expected, expected as f32));
+        }
+        assert!(replay.finished());
+        assert!(!replay.wait_for_data(0).unwrap());
+
+        // Seeking skips whole seconds of records
+        let mut replay = IbtReplay::open(&path, 100.0, 0.05).unwrap();
+        assert!(replay.wait_for_data(1000).unwrap());
+        assert_eq!(speed(replay.as_slice()).1, 4.0);
+        assert!(IbtReplay::open(&path, 1.0, 1.0).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
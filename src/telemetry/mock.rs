@@ -1,11 +1,20 @@
-use super::{TelemetryError, TelemetryProvider};
+use super::{SharedMemoryNames, TelemetryError, TelemetryProvider};
 use crate::protocol::MAX_PAYLOAD_SIZE;
 use rand::{Rng, rng};
 use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
 // Telemetry can be larger than a single datagram since the protocol handles fragmentation
-const MOCK_TELEMETRY_SIZE: usize = MAX_PAYLOAD_SIZE * 4; // Example: 4 fragments worth of data
+pub const MOCK_TELEMETRY_SIZE: usize = MAX_PAYLOAD_SIZE * 4; // Example: 4 fragments worth of data
+
+// Size of the telemetry opened from now on, --simulate-size
+static OPEN_SIZE: AtomicUsize = AtomicUsize::new(MOCK_TELEMETRY_SIZE);
+
+/// Sets the size of the synthetic telemetry `open` generates from now on
+pub fn set_size(size: usize) {
+    OPEN_SIZE.store(size, Ordering::Relaxed);
+}
 const FRAME_TIME: Duration = Duration::from_nanos(16_666_667); // 1/60th of a second
 
 pub struct MockTelemetry {
@@ -26,15 +35,15 @@ impl MockTelemetry {
 }
 
 impl TelemetryProvider for MockTelemetry {
-    fn open() -> Result<Self, TelemetryError> {
+    fn open(_: &SharedMemoryNames) -> Result<Self, TelemetryError> {
         // When opening as source, create random test data that spans multiple datagrams
         Ok(Self {
-            buffer: UnsafeCell::new(Self::generate_test_data(MOCK_TELEMETRY_SIZE)),
+            buffer: UnsafeCell::new(Self::generate_test_data(OPEN_SIZE.load(Ordering::Relaxed))),
             last_update: None,
         })
     }
 
-    fn create(size: usize) -> Result<Self, TelemetryError> {
+    fn create(_: &SharedMemoryNames, size: usize) -> Result<Self, TelemetryError> {
         // Target just allocates empty buffer of requested size
         Ok(Self {
             buffer: UnsafeCell::new(vec![0; size]),
@@ -77,7 +86,7 @@ mod tests {
     #[test]
     fn test_mock_telemetry() {
         // Create source with random test data
-        let mut source = MockTelemetry::open().unwrap();
+        let mut source = MockTelemetry::open(&SharedMemoryNames::default()).unwrap();
         let source_size = source.size();
         assert!(
             source_size > MAX_PAYLOAD_SIZE,
@@ -87,7 +96,7 @@ mod tests {
         );
 
         // Create target with same size as source
-        let mut target = MockTelemetry::create(source_size).unwrap();
+        let mut target = MockTelemetry::create(&SharedMemoryNames::default(), source_size).unwrap();
 
         // Test writing and reading data
         source.as_slice_mut()[0] = 42;
@@ -2,10 +2,41 @@ use std::fmt;
 
 pub const MAX_TELEMETRY_SIZE: usize = 2 * 1024 * 1024; // 2 MB
 
+// Names iRacing uses for its shared memory and data-valid event
+pub const DEFAULT_MAPPING_NAME: &str = "Local\\IRSDKMemMapFileName";
+pub const DEFAULT_EVENT_NAME: &str = "Local\\IRSDKDataValidEvent";
+
+// Executables of the iRacing simulator
+#[cfg_attr(not(windows), allow(dead_code))]
+const IRACING_EXECUTABLES: [&str; 2] = ["iRacingSim64DX11.exe", "iRacingSim64.exe"];
+
+/// Names of the shared memory mapping and the data-valid event, which differ between sims
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Clone, Debug)]
+pub struct SharedMemoryNames {
+    pub mapping: String,
+    pub event: String,
+}
+
+impl Default for SharedMemoryNames {
+    fn default() -> Self {
+        Self {
+            mapping: DEFAULT_MAPPING_NAME.to_string(),
+            event: DEFAULT_EVENT_NAME.to_string(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum TelemetryError {
     #[allow(dead_code)]
     Unavailable,
+    /// The mapping to create is already there, left behind or held by another application
+    #[allow(dead_code)]
+    AlreadyExists,
+    /// This process isn't allowed to create the mapping, retrying won't change that
+    #[allow(dead_code)]
+    AccessDenied,
     #[allow(dead_code)]
     Other(Box<dyn std::error::Error + Send + Sync>),
 }
@@ -14,6 +45,8 @@ impl fmt::Display for TelemetryError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             TelemetryError::Unavailable => write!(f, "Telemetry not available"),
+            TelemetryError::AlreadyExists => write!(f, "Telemetry mapping already exists"),
+            TelemetryError::AccessDenied => write!(f, "Access to the telemetry mapping denied"),
             TelemetryError::Other(e) => write!(f, "Telemetry error: {}", e),
         }
     }
@@ -24,15 +57,25 @@ impl std::error::Error for TelemetryError {}
 /// Trait defining the interface for telemetry access
 pub trait TelemetryProvider {
     /// Opens an existing telemetry mapping for reading (source mode)
-    fn open() -> Result<Self, TelemetryError>
+    fn open(names: &SharedMemoryNames) -> Result<Self, TelemetryError>
     where
         Self: Sized;
 
-    /// Creates a new telemetry mapping for writing (target mode)
-    fn create(size: usize) -> Result<Self, TelemetryError>
+    /// Creates a new telemetry mapping for writing (target mode), failing with
+    /// `AlreadyExists` if there is one already
+    fn create(names: &SharedMemoryNames, size: usize) -> Result<Self, TelemetryError>
     where
         Self: Sized;
 
+    /// Creates a new telemetry mapping for writing like `create`, but writes to the
+    /// existing one if there is one already
+    fn create_or_reuse(names: &SharedMemoryNames, size: usize) -> Result<Self, TelemetryError>
+    where
+        Self: Sized,
+    {
+        Self::create(names, size)
+    }
+
     /// Waits for the data valid event with a timeout
     fn wait_for_data(&mut self, timeout_ms: u32) -> bool;
 
@@ -58,5 +101,68 @@ pub use mock::MockTelemetry as Telemetry;
 #[cfg(windows)]
 pub mod windows;
 
-#[cfg(not(windows))]
 pub mod mock;
+
+pub mod ibt;
+pub mod pipe;
+
+/// Returns a warning if a target creating the mapping with the given names would conflict
+/// with a sim running on this computer
+pub fn conflict_warning(names: &SharedMemoryNames) -> Option<String> {
+    #[cfg(windows)]
+    {
+        let iracing_running = names.mapping == DEFAULT_MAPPING_NAME
+            && self::windows::process_running(&IRACING_EXECUTABLES);
+        conflict_message(
+            names,
+            self::windows::mapping_exists(&names.mapping),
+            iracing_running,
+        )
+    }
+
+    #[cfg(not(windows))]
+    {
+        let _ = names;
+        None
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn conflict_message(
+    names: &SharedMemoryNames,
+    mapping_exists: bool,
+    iracing_running: bool,
+) -> Option<String> {
+    let owner = if iracing_running {
+        "iRacing is running on this computer and owns"
+    } else if mapping_exists {
+        "Another application, most likely a sim running on this computer, already created"
+    } else {
+        return None;
+    };
+
+    Some(format!(
+        "{} the memory-mapped file {}. The target would conflict with it. Run the target on \
+         a separate computer, or give it other names with --mmap-name and --event-name.",
+        owner, names.mapping
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conflict_message() {
+        let names = SharedMemoryNames::default();
+        assert!(conflict_message(&names, false, false).is_none());
+
+        let message = conflict_message(&names, true, true).unwrap();
+        assert!(message.starts_with("iRacing is running"));
+        assert!(message.contains(DEFAULT_MAPPING_NAME));
+        assert!(message.contains("--mmap-name"));
+
+        let message = conflict_message(&names, true, false).unwrap();
+        assert!(message.starts_with("Another application"));
+    }
+}
@@ -0,0 +1,214 @@
+use super::{SharedMemoryNames, TelemetryError, TelemetryProvider};
+use log::{info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Time between attempts to connect to the pipe while nothing reads it
+const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes the telemetry to a named pipe instead of a shared memory mapping, for
+/// --sink pipe:<name>. Every signal writes the whole buffer as one frame: its length as a
+/// little-endian u32, followed by that many bytes.
+///
+/// On Windows the consumer creates the pipe `\\.\pipe\<name>` and teleport connects to
+/// it. Elsewhere `<name>` is the path of a FIFO, created if it doesn't exist. Frames are
+/// dropped while nothing reads the pipe, and teleport connects again once something does.
+pub struct PipeTelemetry {
+    path: PathBuf,
+    buffer: Vec<u8>,
+    pipe: Option<File>,
+    last_attempt: Option<Instant>,
+}
+
+impl PipeTelemetry {
+    /// Connects to the pipe unless connected or the last attempt was too recent
+    fn connect(&mut self) {
+        if self.pipe.is_some()
+            || self
+                .last_attempt
+                .is_some_and(|last| last.elapsed() < CONNECT_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_attempt = Some(Instant::now());
+        match open(&self.path) {
+            Ok(pipe) => {
+                info!("Connected to pipe {}", self.path.display());
+                self.pipe = Some(pipe);
+            }
+            // Nothing reads the pipe yet
+            Err(e) if is_unconnected(&e) => {}
+            Err(e) => warn!("Failed to open pipe {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl TelemetryProvider for PipeTelemetry {
+    fn open(_: &SharedMemoryNames) -> Result<Self, TelemetryError> {
+        // Only targets write to a pipe
+        Err(TelemetryError::Unavailable)
+    }
+
+    fn create(names: &SharedMemoryNames, size: usize) -> Result<Self, TelemetryError> {
+        let path = pipe_path(&names.mapping);
+        create_fifo(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => TelemetryError::AccessDenied,
+            _ => TelemetryError::Other(Box::new(e)),
+        })?;
+        info!("Writing frames to pipe {}", path.display());
+
+        let mut telemetry = Self {
+            path,
+            buffer: vec![0; size],
+            pipe: None,
+            last_attempt: None,
+        };
+        telemetry.connect();
+        Ok(telemetry)
+    }
+
+    fn wait_for_data(&mut self, _: u32) -> bool {
+        false
+    }
+
+    fn signal_data_ready(&mut self) -> Result<(), TelemetryError> {
+        self.connect();
+        let Some(pipe) = &mut self.pipe else {
+            return Ok(());
+        };
+
+        let len = (self.buffer.len() as u32).to_le_bytes();
+        if let Err(e) = pipe
+            .write_all(&len)
+            .and_then(|_| pipe.write_all(&self.buffer))
+        {
+            warn!(
+                "Pipe {} closed ({}), waiting for a reader to connect again",
+                self.path.display(),
+                e
+            );
+            self.pipe = None;
+        }
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn as_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// Returns the path of the pipe with the given name
+fn pipe_path(name: &str) -> PathBuf {
+    if cfg!(windows) && !name.starts_with(r"\\") {
+        PathBuf::from(format!(r"\\.\pipe\{}", name))
+    } else {
+        PathBuf::from(name)
+    }
+}
+
+/// Returns whether opening the pipe failed because nothing reads it
+fn is_unconnected(e: &io::Error) -> bool {
+    // A FIFO without a reader
+    #[cfg(target_os = "linux")]
+    if e.raw_os_error() == Some(libc::ENXIO) {
+        return true;
+    }
+    // ERROR_PIPE_BUSY, the consumer serves another client
+    #[cfg(windows)]
+    if e.raw_os_error() == Some(231) {
+        return true;
+    }
+    e.kind() == io::ErrorKind::NotFound
+}
+
+#[cfg(target_os = "linux")]
+fn create_fifo(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if path.exists() {
+        return Ok(());
+    }
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_fifo(_: &std::path::Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn open(path: &std::path::Path) -> io::Result<File> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Without a reader, opening a FIFO would block until one comes along
+    let file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+
+    // Writes do block, a frame is never written halfway
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open(path: &std::path::Path) -> io::Result<File> {
+    OpenOptions::new().write(true).open(path)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    #[test]
+    fn test_frames_are_length_prefixed() {
+        let path = std::env::temp_dir().join(format!("teleport-pipe-{}", std::process::id()));
+        let names = SharedMemoryNames {
+            mapping: path.to_string_lossy().into_owned(),
+            event: String::new(),
+        };
+
+        // No reader yet, the frame is dropped
+        let mut telemetry = PipeTelemetry::create(&names, 4).unwrap();
+        telemetry.as_slice_mut().copy_from_slice(b"lost");
+        telemetry.signal_data_ready().unwrap();
+
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        telemetry.last_attempt = None;
+        telemetry.as_slice_mut().copy_from_slice(b"data");
+        telemetry.signal_data_ready().unwrap();
+
+        let mut frame = [0u8; 8];
+        reader.read_exact(&mut frame).unwrap();
+        assert_eq!(&frame[..4], &4u32.to_le_bytes());
+        assert_eq!(&frame[4..], b"data");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
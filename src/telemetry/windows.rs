@@ -2,7 +2,7 @@ use windows::{
     Win32::Foundation::*, Win32::System::Memory::*, Win32::System::Threading::*, core::*,
 };
 
-use super::{TelemetryError, TelemetryProvider};
+use super::{SharedMemoryNames, TelemetryError, TelemetryProvider};
 
 pub struct WindowsTelemetry {
     h_map: HANDLE,
@@ -11,28 +11,96 @@ pub struct WindowsTelemetry {
     size: usize,
 }
 
+// Handles and mapped views belong to the process, not the thread that opened them
+unsafe impl Send for WindowsTelemetry {}
+
+impl WindowsTelemetry {
+    /// Creates the mapping, or with `reuse` also takes over one that already exists
+    fn create_mapping(
+        names: &SharedMemoryNames,
+        size: usize,
+        reuse: bool,
+    ) -> std::result::Result<Self, TelemetryError> {
+        unsafe {
+            let h_map = CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                0,
+                size as u32,
+                &HSTRING::from(names.mapping.as_str()),
+            )
+            .map_err(create_error)?;
+
+            if h_map.is_invalid() {
+                return Err(create_error(windows::core::Error::from_win32()));
+            }
+
+            // A mapping left behind by another instance or held by another application
+            // succeeds as well, but may have a different size and be written by others
+            if GetLastError() == ERROR_ALREADY_EXISTS && !reuse {
+                CloseHandle(h_map)?;
+                return Err(TelemetryError::AlreadyExists);
+            }
+
+            let view = MapViewOfFile(h_map, FILE_MAP_WRITE, 0, 0, size).Value as *mut u8;
+            if view.is_null() {
+                let error = windows::core::Error::from_win32();
+                CloseHandle(h_map)?;
+                return Err(create_error(error));
+            }
+
+            let h_event = CreateEventW(
+                None,
+                false, // auto reset
+                false, // initial state: not signaled
+                &HSTRING::from(names.event.as_str()),
+            )
+            .map_err(create_error)?;
+
+            if h_event.is_invalid() {
+                UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS {
+                    Value: view as *mut _,
+                })?;
+                CloseHandle(h_map)?;
+                return Err(windows::core::Error::from_win32().into());
+            }
+
+            Ok(Self {
+                h_map,
+                h_event,
+                view,
+                size,
+            })
+        }
+    }
+}
+
 impl TelemetryProvider for WindowsTelemetry {
-    fn open() -> std::result::Result<Self, TelemetryError> {
+    fn open(names: &SharedMemoryNames) -> std::result::Result<Self, TelemetryError> {
         unsafe {
             // Try to open the event
             let h_event = match OpenEventW(
                 SYNCHRONIZATION_SYNCHRONIZE,
                 false,
-                w!("Local\\IRSDKDataValidEvent"),
+                &HSTRING::from(names.event.as_str()),
             ) {
                 Ok(handle) => handle,
                 Err(_) => return Err(TelemetryError::Unavailable),
             };
 
             // Try to open the memory mapped file
-            let h_map =
-                match OpenFileMappingW(FILE_MAP_READ.0, false, w!("Local\\IRSDKMemMapFileName")) {
-                    Ok(handle) => handle,
-                    Err(_) => {
-                        CloseHandle(h_event).ok();
-                        return Err(TelemetryError::Unavailable);
-                    }
-                };
+            let h_map = match OpenFileMappingW(
+                FILE_MAP_READ.0,
+                false,
+                &HSTRING::from(names.mapping.as_str()),
+            ) {
+                Ok(handle) => handle,
+                Err(_) => {
+                    CloseHandle(h_event).ok();
+                    return Err(TelemetryError::Unavailable);
+                }
+            };
 
             let h_view = MapViewOfFile(h_map, FILE_MAP_READ, 0, 0, 0);
             let view = h_view.Value as *mut u8;
@@ -68,51 +136,15 @@ impl TelemetryProvider for WindowsTelemetry {
         }
     }
 
-    fn create(size: usize) -> std::result::Result<Self, TelemetryError> {
-        unsafe {
-            let h_map = CreateFileMappingW(
-                INVALID_HANDLE_VALUE,
-                None,
-                PAGE_READWRITE,
-                0,
-                size as u32,
-                w!("Local\\IRSDKMemMapFileName"),
-            )
-            .map_err(|e| TelemetryError::Other(Box::new(e)))?;
-
-            if h_map.is_invalid() {
-                return Err(windows::core::Error::from_win32().into());
-            }
-
-            let view = MapViewOfFile(h_map, FILE_MAP_WRITE, 0, 0, size).Value as *mut u8;
-            if view.is_null() {
-                CloseHandle(h_map)?;
-                return Err(windows::core::Error::from_win32().into());
-            }
-
-            let h_event = CreateEventW(
-                None,
-                false, // auto reset
-                false, // initial state: not signaled
-                w!("Local\\IRSDKDataValidEvent"),
-            )
-            .map_err(|e| TelemetryError::Other(Box::new(e)))?;
-
-            if h_event.is_invalid() {
-                UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS {
-                    Value: view as *mut _,
-                })?;
-                CloseHandle(h_map)?;
-                return Err(windows::core::Error::from_win32().into());
-            }
+    fn create(names: &SharedMemoryNames, size: usize) -> std::result::Result<Self, TelemetryError> {
+        Self::create_mapping(names, size, false)
+    }
 
-            Ok(Self {
-                h_map,
-                h_event,
-                view,
-                size,
-            })
-        }
+    fn create_or_reuse(
+        names: &SharedMemoryNames,
+        size: usize,
+    ) -> std::result::Result<Self, TelemetryError> {
+        Self::create_mapping(names, size, true)
     }
 
     fn wait_for_data(&mut self, timeout_ms: u32) -> bool {
@@ -151,6 +183,62 @@ impl Drop for WindowsTelemetry {
     }
 }
 
+/// Returns whether a shared memory mapping with the given name exists
+pub fn mapping_exists(name: &str) -> bool {
+    unsafe {
+        match OpenFileMappingW(FILE_MAP_READ.0, false, &HSTRING::from(name)) {
+            Ok(handle) => {
+                let _ = CloseHandle(handle);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Returns whether a process runs one of the given executables
+pub fn process_running(executables: &[&str]) -> bool {
+    use windows::Win32::System::Diagnostics::ToolHelp::*;
+
+    unsafe {
+        let Ok(snapshot) = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) else {
+            return false;
+        };
+
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut found = false;
+        let mut next = Process32FirstW(snapshot, &mut entry);
+        while next.is_ok() && !found {
+            let len = entry
+                .szExeFile
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(entry.szExeFile.len());
+            let exe = String::from_utf16_lossy(&entry.szExeFile[..len]);
+            found = executables
+                .iter()
+                .any(|name| exe.eq_ignore_ascii_case(name));
+            next = Process32NextW(snapshot, &mut entry);
+        }
+
+        let _ = CloseHandle(snapshot);
+        found
+    }
+}
+
+/// Tells a mapping or event this process may not create, e.g. a `Global\` name without
+/// administrator rights, from failures that can pass like running low on memory
+fn create_error(error: windows::core::Error) -> TelemetryError {
+    if error.code() == ERROR_ACCESS_DENIED.to_hresult() {
+        TelemetryError::AccessDenied
+    } else {
+        error.into()
+    }
+}
+
 impl From<windows::core::Error> for TelemetryError {
     fn from(err: windows::core::Error) -> Self {
         TelemetryError::Other(Box::new(err))
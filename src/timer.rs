@@ -0,0 +1,47 @@
+/// Raises the system timer resolution to 1 ms while alive, so waits and sleeps are accurate
+/// enough for a 60Hz cadence. The default resolution on Windows is 15.6 ms, which is
+/// coarser than a single frame. Does nothing on other platforms.
+pub struct TimerResolution {
+    #[cfg(windows)]
+    raised: bool,
+}
+
+#[cfg(windows)]
+impl TimerResolution {
+    const PERIOD_MS: u32 = 1;
+
+    pub fn raise() -> Self {
+        use log::{info, warn};
+        use windows::Win32::Media::{TIMERR_NOERROR, timeBeginPeriod};
+
+        let raised = unsafe { timeBeginPeriod(Self::PERIOD_MS) } == TIMERR_NOERROR;
+        if raised {
+            info!(
+                "Raised the system timer resolution to {} ms while running (this affects the whole system)",
+                Self::PERIOD_MS
+            );
+        } else {
+            warn!("Failed to raise the system timer resolution, frame timing may be less accurate");
+        }
+
+        Self { raised }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for TimerResolution {
+    fn drop(&mut self) {
+        if self.raised {
+            unsafe {
+                windows::Win32::Media::timeEndPeriod(Self::PERIOD_MS);
+            }
+        }
+    }
+}
+
+#[cfg(not(windows))]
+impl TimerResolution {
+    pub fn raise() -> Self {
+        Self {}
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Whether timestamps are in local time (--local) rather than UTC
+static LOCAL: AtomicBool = AtomicBool::new(false);
+
+/// Stamps stats lines in local time instead of UTC from now on
+pub fn set_local(local: bool) {
+    LOCAL.store(local, Ordering::Relaxed);
+}
+
+/// Returns the wall-clock time since the Unix epoch, 0 if the clock is set before it
+pub fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Returns the current wall-clock time in ISO 8601 with milliseconds, e.g.
+/// `2025-03-14T18:05:09.120Z`, or with --local the local time and its offset
+pub fn timestamp() -> String {
+    let now = now();
+    let offset_minutes = LOCAL
+        .load(Ordering::Relaxed)
+        .then(|| local_offset_minutes(now.as_secs() as i64));
+    format(now, offset_minutes)
+}
+
+/// Formats a time since the Unix epoch in UTC, or at `offset_minutes` from it
+fn format(since_epoch: Duration, offset_minutes: Option<i32>) -> String {
+    let local_secs = since_epoch.as_secs() as i64 + offset_minutes.unwrap_or(0) as i64 * 60;
+    let (year, month, day) = civil_from_days(local_secs.div_euclid(86_400));
+    let secs_of_day = local_secs.rem_euclid(86_400);
+    let zone = match offset_minutes {
+        None => "Z".to_string(),
+        Some(offset) => {
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.unsigned_abs();
+            format!("{}{:02}:{:02}", sign, offset / 60, offset % 60)
+        }
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+        zone
+    )
+}
+
+/// Converts days since the Unix epoch to a proleptic Gregorian year, month and day
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Counted from 0000-03-01, so the leap day is the last of the year
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(target_os = "linux")]
+fn local_offset_minutes(unix_secs: i64) -> i32 {
+    let time = unix_secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    (tm.tm_gmtoff / 60) as i32
+}
+
+#[cfg(windows)]
+fn local_offset_minutes(_: i64) -> i32 {
+    use windows::Win32::System::Time::{GetTimeZoneInformation, TIME_ZONE_INFORMATION};
+
+    // Which of the biases applies right now, as returned by GetTimeZoneInformation
+    const TIME_ZONE_ID_STANDARD: u32 = 1;
+    const TIME_ZONE_ID_DAYLIGHT: u32 = 2;
+
+    let mut info = TIME_ZONE_INFORMATION::default();
+    let bias = match unsafe { GetTimeZoneInformation(&mut info) } {
+        TIME_ZONE_ID_DAYLIGHT => info.Bias + info.DaylightBias,
+        TIME_ZONE_ID_STANDARD => info.Bias + info.StandardBias,
+        _ => info.Bias,
+    };
+    -bias
+}
+
+// Other platforms stamp local time as UTC
+#[cfg(not(any(target_os = "linux", windows)))]
+fn local_offset_minutes(_: i64) -> i32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps_are_iso_8601() {
+        let time = Duration::from_millis(1_741_975_509_120);
+        assert_eq!(format(time, None), "2025-03-14T18:05:09.120Z");
+        assert_eq!(format(time, Some(60)), "2025-03-14T19:05:09.120+01:00");
+        assert_eq!(format(time, Some(-330)), "2025-03-14T12:35:09.120-05:30");
+
+        // Leap days, and the offset carrying over into the next day
+        let leap_day = Duration::from_secs(951_782_400);
+        assert_eq!(format(leap_day, None), "2000-02-29T00:00:00.000Z");
+        assert_eq!(format(leap_day, Some(-60)), "2000-02-28T23:00:00.000-01:00");
+        assert_eq!(
+            format(Duration::ZERO, Some(0)),
+            "1970-01-01T00:00:00.000+00:00"
+        );
+    }
+}
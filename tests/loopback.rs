@@ -0,0 +1,739 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::UdpSocket;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use iracing_teleport::codec::{Lz4Format, Quality};
+use iracing_teleport::config::{BridgeConfig, SourceConfig, TargetConfig};
+use iracing_teleport::dictionary::{MAX_DICTIONARY_SIZE, Trainer};
+use iracing_teleport::error::TeleportError;
+use iracing_teleport::protocol::{self, MAX_DATAGRAM_SIZE};
+use iracing_teleport::scheduling::Priority;
+use iracing_teleport::telemetry::{
+    MAX_TELEMETRY_SIZE, SharedMemoryNames, TelemetryError, TelemetryProvider,
+};
+use iracing_teleport::{bridge, source, target};
+
+// Mappings that sources can open, and the latest data targets signaled, by mapping name
+static PUBLISHED: Mutex<Option<HashMap<String, Vec<u8>>>> = Mutex::new(None);
+static RECEIVED: Mutex<Option<HashMap<String, Vec<u8>>>> = Mutex::new(None);
+
+/// In-process stand-in for named shared memory
+struct TestTelemetry {
+    name: String,
+    buffer: Vec<u8>,
+}
+
+impl TelemetryProvider for TestTelemetry {
+    fn open(names: &SharedMemoryNames) -> Result<Self, TelemetryError> {
+        let published = PUBLISHED.lock().unwrap();
+        let buffer = published
+            .as_ref()
+            .and_then(|mappings| mappings.get(&names.mapping))
+            .ok_or(TelemetryError::Unavailable)?;
+
+        Ok(Self {
+            name: names.mapping.clone(),
+            buffer: buffer.clone(),
+        })
+    }
+
+    fn create(names: &SharedMemoryNames, size: usize) -> Result<Self, TelemetryError> {
+        Ok(Self {
+            name: names.mapping.clone(),
+            buffer: vec![0; size],
+        })
+    }
+
+    fn wait_for_data(&mut self, _: u32) -> bool {
+        thread::sleep(Duration::from_millis(5));
+        true
+    }
+
+    fn signal_data_ready(&mut self) -> Result<(), TelemetryError> {
+        RECEIVED
+            .lock()
+            .unwrap()
+            .get_or_insert_default()
+            .insert(self.name.clone(), self.buffer.clone());
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn as_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+
+    fn size(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn names(mapping: &str) -> SharedMemoryNames {
+    SharedMemoryNames {
+        mapping: mapping.to_string(),
+        event: format!("{}Event", mapping),
+    }
+}
+
+fn spawn<F, E>(run: F) -> (Sender<()>, JoinHandle<Result<(), E>>)
+where
+    F: FnOnce(mpsc::Receiver<()>) -> Result<(), E> + Send + 'static,
+    E: Send + 'static,
+{
+    let (shutdown_tx, shutdown_rx) = mpsc::channel();
+    (shutdown_tx, thread::spawn(move || run(shutdown_rx)))
+}
+
+type Run<C> = fn(&C, mpsc::Receiver<()>) -> Result<(), TeleportError>;
+
+/// How the source and target are run
+struct Pipeline {
+    run_source: Run<SourceConfig>,
+    run_target: Run<TargetConfig>,
+    // Time between starting the source and the target
+    target_delay: Duration,
+    keyframe_ms: u64,
+    compression_threshold: f64,
+    verify: bool,
+    dict: Option<PathBuf>,
+    lz4_format: Lz4Format,
+    quality: Quality,
+    fec: u8,
+    max_size: usize,
+    // Share of the datagrams the source drops or reorders on purpose
+    drop_rate: f64,
+    reorder: f64,
+    // Pass the stream through a bridge between the source and the target
+    bridge: bool,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            run_source: source::run_with::<TestTelemetry>,
+            run_target: target::run_with::<TestTelemetry>,
+            target_delay: Duration::ZERO,
+            keyframe_ms: 1000,
+            compression_threshold: 1.0,
+            verify: false,
+            dict: None,
+            lz4_format: Lz4Format::Block,
+            quality: Quality::Balanced,
+            fec: 0,
+            max_size: MAX_TELEMETRY_SIZE,
+            drop_rate: 0.0,
+            reorder: 0.0,
+            bridge: false,
+        }
+    }
+}
+
+/// Runs a source and a target over loopback and returns what the target's mapping ends
+/// up with once it matches `mapping`
+fn teleport(name: &str, mapping: Vec<u8>) -> Option<Vec<u8>> {
+    teleport_with(name, mapping, Pipeline::default())
+}
+
+/// Source options for sending the mapping with the given names to a port on loopback
+fn source_config(names: &SharedMemoryNames, port: u16, pipeline: &Pipeline) -> SourceConfig {
+    SourceConfig {
+        bind: "127.0.0.1:0".to_string(),
+        fd: None,
+        target: format!("127.0.0.1:{}", port),
+        unicast: true,
+        id: 0,
+        heartbeat_ms: 1000,
+        keyframe_ms: pipeline.keyframe_ms,
+        dscp: None,
+        out_interface: None,
+        multicast_loop: None,
+        pace: None,
+        compression_threshold: pipeline.compression_threshold,
+        fec: pipeline.fec,
+        redundancy: 1,
+        verify: pipeline.verify,
+        dict: pipeline.dict.clone(),
+        lz4_format: pipeline.lz4_format,
+        quality: pipeline.quality,
+        max_bandwidth: None,
+        skip_unchanged: false,
+        adaptive: false,
+        stall_timeout: 5,
+        mmap_name: names.mapping.clone(),
+        event_name: names.event.clone(),
+        max_size: pipeline.max_size,
+        cpu_affinity: None,
+        priority: Priority::Normal,
+        duration: None,
+        count: None,
+        ready_signal: false,
+        stats_export: None,
+        health_addr: None,
+        status_line: false,
+        drop_rate: pipeline.drop_rate,
+        drop_seed: Some(1),
+        reorder: pipeline.reorder,
+        dry_run: false,
+        print_header: false,
+        vars: Vec::new(),
+        region: None,
+        ibt: None,
+        ibt_speed: 1.0,
+        ibt_start: 0.0,
+        simulate: false,
+        simulate_size: 35880,
+    }
+}
+
+fn teleport_with(name: &str, mapping: Vec<u8>, pipeline: Pipeline) -> Option<Vec<u8>> {
+    PUBLISHED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(name.to_string(), mapping.clone());
+
+    let port = free_port();
+    let source_port = if pipeline.bridge { free_port() } else { port };
+    let names = names(name);
+
+    let target_config = TargetConfig {
+        bind: format!("127.0.0.1:{}", port),
+        fd: None,
+        group: "239.255.0.1".to_string(),
+        unicast: true,
+        filter_id: None,
+        mmap_name: names.mapping.clone(),
+        event_name: names.event.clone(),
+        sink: None,
+        max_size: pipeline.max_size,
+        cpu_affinity: None,
+        priority: Priority::Normal,
+        duration: None,
+        count: None,
+        ready_signal: false,
+        stats_export: None,
+        health_addr: None,
+        api_addr: None,
+        status_line: false,
+        drop_rate: 0.0,
+        drop_seed: None,
+        idle_timeout: 10,
+        startup_timeout: 0,
+        max_age: 0,
+        jitter_buffer: 0,
+        reassembly_depth: 1,
+        bind_retry: 0,
+        rejoin: 60,
+        interface_autoselect: false,
+        interface_probe: 2000,
+        report_ms: 1000,
+        busy_poll: false,
+        force: false,
+        precreate: false,
+        dump_frames: 0,
+        interpolate: false,
+        dict: pipeline.dict.clone(),
+        lz4_format: pipeline.lz4_format,
+        export_json: None,
+        ws: None,
+        vars: Vec::new(),
+    };
+    let source_config = source_config(&names, source_port, &pipeline);
+
+    let Pipeline {
+        run_source,
+        run_target,
+        target_delay,
+        ..
+    } = pipeline;
+    let bridge_config = BridgeConfig {
+        in_bind: format!("127.0.0.1:{}", source_port),
+        in_group: "239.255.0.1".to_string(),
+        in_unicast: true,
+        filter_id: None,
+        out_bind: "127.0.0.1:0".to_string(),
+        out_target: Some(format!("127.0.0.1:{}", port)),
+        out_unicast: true,
+    };
+    let bridge = pipeline
+        .bridge
+        .then(|| spawn(move |shutdown| bridge::run(&bridge_config, shutdown)));
+    let (source_tx, source) = spawn(move |shutdown| run_source(&source_config, shutdown));
+    thread::sleep(target_delay);
+    let (target_tx, target) = spawn(move |shutdown| run_target(&target_config, shutdown));
+
+    // Wait until the target has written an update that matches the source
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let received = loop {
+        let received = RECEIVED
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|received| received.get(name).cloned());
+
+        if received.as_ref() == Some(&mapping) || Instant::now() >= deadline {
+            break received;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    source_tx.send(()).unwrap();
+    target_tx.send(()).unwrap();
+    source.join().unwrap().expect("source failed");
+    target.join().unwrap().expect("target failed");
+    if let Some((bridge_tx, bridge)) = bridge {
+        bridge_tx.send(()).unwrap();
+        bridge.join().unwrap().expect("bridge failed");
+    }
+
+    received
+}
+
+#[test]
+fn test_mapping_is_teleported() {
+    // Larger than a single datagram and not an iRSDK mapping
+    let mapping: Vec<u8> = (0..40_000).map(|i| (i * 7 % 251) as u8).collect();
+
+    let received = teleport("LoopbackRaw", mapping.clone());
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+/// Builds an iRSDK mapping with session info
+fn irsdk_mapping() -> Vec<u8> {
+    const SESSION_INFO_OFFSET: usize = 4096;
+    const SESSION_INFO_LEN: usize = 8192;
+
+    let mut mapping = vec![0u8; 32 * 1024];
+    let mut put = |index: usize, value: i32| {
+        mapping[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    put(0, 2); // ver
+    put(3, 1); // session_info_update
+    put(4, SESSION_INFO_LEN as i32);
+    put(5, SESSION_INFO_OFFSET as i32);
+
+    let yaml = b"---\nWeekendInfo:\n TrackName: loopback\n...\n";
+    mapping[SESSION_INFO_OFFSET..SESSION_INFO_OFFSET + yaml.len()].copy_from_slice(yaml);
+    for (i, byte) in mapping[SESSION_INFO_OFFSET + SESSION_INFO_LEN..]
+        .iter_mut()
+        .enumerate()
+    {
+        *byte = (i % 13) as u8;
+    }
+
+    mapping
+}
+
+#[test]
+fn test_irsdk_mapping_with_session_info_is_teleported() {
+    let mapping = irsdk_mapping();
+
+    // The session info is sent separately from the updates and stitched back together
+    let received = teleport("LoopbackIrsdk", mapping.clone());
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_uncompressed_updates_are_teleported() {
+    let mapping = irsdk_mapping();
+
+    // A threshold of 0 sends the updates and the session info as they are
+    let received = teleport_with(
+        "LoopbackUncompressed",
+        mapping.clone(),
+        Pipeline {
+            compression_threshold: 0.0,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_verified_updates_are_teleported() {
+    let mapping = irsdk_mapping();
+
+    // The target checks the updates and the session info against the source's hashes
+    let received = teleport_with(
+        "LoopbackVerify",
+        mapping.clone(),
+        Pipeline {
+            verify: true,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_incompressible_updates_are_teleported() {
+    // Noise LZ4 expands, as large as --max-size allows and with a hash on top
+    let mut state = 0x2545_f491_u32;
+    let mapping: Vec<u8> = (0..64 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+
+    let received = teleport_with(
+        "LoopbackIncompressible",
+        mapping.clone(),
+        Pipeline {
+            verify: true,
+            max_size: mapping.len(),
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_dictionary_compressed_updates_are_teleported() {
+    let mapping = irsdk_mapping();
+    let mut trainer = Trainer::new();
+    trainer.add(&mapping);
+    let path = env::temp_dir().join(format!("loopback-{}.dict", std::process::id()));
+    fs::write(&path, trainer.finish(MAX_DICTIONARY_SIZE)).unwrap();
+
+    let received = teleport_with(
+        "LoopbackDictionary",
+        mapping.clone(),
+        Pipeline {
+            dict: Some(path.clone()),
+            verify: true,
+            ..Default::default()
+        },
+    );
+    let _ = fs::remove_file(&path);
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_lz4_frames_are_teleported() {
+    let mapping = irsdk_mapping();
+
+    let received = teleport_with(
+        "LoopbackLz4Frame",
+        mapping.clone(),
+        Pipeline {
+            lz4_format: Lz4Format::Frame,
+            verify: true,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_every_quality_is_teleported() {
+    let mapping = irsdk_mapping();
+
+    for (name, quality, lz4_format) in [
+        ("LoopbackFast", Quality::Fast, Lz4Format::Block),
+        ("LoopbackMax", Quality::Max, Lz4Format::Block),
+        ("LoopbackMaxFrame", Quality::Max, Lz4Format::Frame),
+    ] {
+        let received = teleport_with(
+            name,
+            mapping.clone(),
+            Pipeline {
+                quality,
+                lz4_format,
+                ..Default::default()
+            },
+        );
+        assert!(
+            received == Some(mapping.clone()),
+            "target mapping doesn't match the source at {:?}",
+            quality
+        );
+    }
+}
+
+#[test]
+fn test_fec_recovers_from_dropped_datagrams() {
+    let mapping = irsdk_mapping();
+
+    // Every update and session info gets through as long as one of its datagrams does
+    let received = teleport_with(
+        "LoopbackDropped",
+        mapping.clone(),
+        Pipeline {
+            fec: 2,
+            drop_rate: 0.5,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_reordered_datagrams_are_reassembled() {
+    let mapping = irsdk_mapping();
+
+    // Sent uncompressed, the updates span several datagrams to shuffle
+    let received = teleport_with(
+        "LoopbackReordered",
+        mapping.clone(),
+        Pipeline {
+            compression_threshold: 0.0,
+            reorder: 0.5,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_late_target_requests_keyframe() {
+    let mapping = irsdk_mapping();
+
+    // Without periodic keyframes, the session info only arrives when the target asks for it
+    let received = teleport_with(
+        "LoopbackLateTarget",
+        mapping.clone(),
+        Pipeline {
+            target_delay: Duration::from_millis(500),
+            keyframe_ms: 0,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_late_target_behind_bridge_requests_keyframe() {
+    let mapping = irsdk_mapping();
+
+    // The bridge forwards the datagrams as they are and the keyframe request back upstream
+    let received = teleport_with(
+        "LoopbackBridge",
+        mapping.clone(),
+        Pipeline {
+            target_delay: Duration::from_millis(500),
+            keyframe_ms: 0,
+            bridge: true,
+            ..Default::default()
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}
+
+#[test]
+fn test_dry_run_sends_nothing() {
+    let name = "LoopbackDryRun";
+    PUBLISHED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(name.to_string(), irsdk_mapping());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let source_config = SourceConfig {
+        dry_run: true,
+        ..source_config(&names(name), port, &Pipeline::default())
+    };
+    let (source_tx, source) =
+        spawn(move |shutdown| source::run_with::<TestTelemetry>(&source_config, shutdown));
+
+    let mut buf = [0u8; 64];
+    let received = socket.recv(&mut buf);
+
+    source_tx.send(()).unwrap();
+    source.join().unwrap().expect("source failed");
+    assert!(received.is_err(), "a dry run sent a datagram");
+}
+
+#[test]
+fn test_count_stops_the_source() {
+    let name = "LoopbackCount";
+    PUBLISHED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(name.to_string(), irsdk_mapping());
+
+    let source_config = SourceConfig {
+        dry_run: true,
+        count: Some(3),
+        ..source_config(&names(name), free_port(), &Pipeline::default())
+    };
+    let (_source_tx, source) =
+        spawn(move |shutdown| source::run_with::<TestTelemetry>(&source_config, shutdown));
+
+    // Stops on its own without a shutdown signal
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !source.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(
+        source.is_finished(),
+        "source didn't stop after --count updates"
+    );
+    source.join().unwrap().expect("source failed");
+}
+
+#[test]
+fn test_source_says_goodbye_when_it_stops() {
+    let name = "LoopbackGoodbye";
+    PUBLISHED
+        .lock()
+        .unwrap()
+        .get_or_insert_default()
+        .insert(name.to_string(), irsdk_mapping());
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+
+    let source_config = SourceConfig {
+        count: Some(3),
+        ..source_config(&names(name), port, &Pipeline::default())
+    };
+    let (_source_tx, source) =
+        spawn(move |shutdown| source::run_with::<TestTelemetry>(&source_config, shutdown));
+    source.join().unwrap().expect("source failed");
+
+    // The updates are followed by the goodbye, sent a few times
+    let mut flags = Vec::new();
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+    while let Ok(len) = socket.recv(&mut buf) {
+        flags.extend(protocol::datagram_info(&buf[..len]).map(|info| info.flags));
+    }
+    assert!(
+        flags
+            .last()
+            .is_some_and(|&flags| protocol::is_goodbye(flags)),
+        "source stopped without a goodbye"
+    );
+    let goodbyes = flags
+        .iter()
+        .filter(|&&flags| protocol::is_goodbye(flags))
+        .count();
+    assert!(goodbyes > 1 && goodbyes < flags.len());
+}
+
+/// Runs an async source or target on its own runtime until `shutdown` receives
+#[cfg(feature = "async")]
+fn block_on<F>(
+    shutdown: mpsc::Receiver<()>,
+    run: impl FnOnce(tokio::task::JoinHandle<()>) -> F,
+) -> Result<(), TeleportError>
+where
+    F: Future<Output = Result<(), TeleportError>>,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let shutdown = tokio::task::spawn_blocking(move || {
+            let _ = shutdown.recv();
+        });
+        run(shutdown).await
+    })
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_late_target_requests_keyframe_async() {
+    let mapping = irsdk_mapping();
+
+    let received = teleport_with(
+        "LoopbackAsync",
+        mapping.clone(),
+        Pipeline {
+            run_source: |config, shutdown| {
+                block_on(shutdown, |shutdown| async {
+                    source::run_async_with::<TestTelemetry>(config, async {
+                        let _ = shutdown.await;
+                    })
+                    .await
+                })
+            },
+            run_target: |config, shutdown| {
+                block_on(shutdown, |shutdown| async {
+                    target::run_async_with::<TestTelemetry>(config, async {
+                        let _ = shutdown.await;
+                    })
+                    .await
+                })
+            },
+            target_delay: Duration::from_millis(500),
+            keyframe_ms: 0,
+            compression_threshold: 1.0,
+            verify: false,
+            dict: None,
+            lz4_format: Lz4Format::Block,
+            quality: Quality::Balanced,
+            fec: 0,
+            max_size: MAX_TELEMETRY_SIZE,
+            drop_rate: 0.0,
+            reorder: 0.0,
+            bridge: false,
+        },
+    );
+    assert!(
+        received == Some(mapping),
+        "target mapping doesn't match the source"
+    );
+}